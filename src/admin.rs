@@ -0,0 +1,152 @@
+use crate::query_log::QueryLog;
+use crate::resolver::RecursiveResolver;
+use crate::upstream_stats::format_upstream_stats;
+use anyhow::bail;
+use std::net::{Ipv4Addr, SocketAddr};
+use std::sync::Arc;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::level_filters::LevelFilter;
+use tracing::warn;
+
+/// Changes the level of the tracing output at runtime
+pub type LogLevelSetter = Box<dyn Fn(LevelFilter) -> anyhow::Result<()> + Send + Sync>;
+
+/// Binds the admin interface to the given port on localhost only, as there is no
+/// authentication. Done separately from serve() to have bind failures stop the startup.
+pub async fn bind(port: u16) -> anyhow::Result<TcpListener> {
+    Ok(TcpListener::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)).await?)
+}
+
+/// Serves the admin interface, a line based text protocol where each command is
+/// answered by its output, if any, followed by a line with 'ok' or 'error: <reason>'
+pub async fn serve(
+    listener: TcpListener,
+    resolver: Arc<RecursiveResolver>,
+    set_log_level: LogLevelSetter,
+    query_log: Option<Arc<QueryLog>>,
+) -> anyhow::Result<()> {
+    let state = Arc::new(AdminState { resolver, set_log_level, query_log });
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let state = state.clone();
+        tokio::spawn(async move {
+            if let Err(e) = handle(stream, &state).await {
+                warn!(%peer, error = %e, "Admin connection failed");
+            }
+        });
+    }
+}
+
+/// What the admin commands operate on
+struct AdminState {
+    resolver: Arc<RecursiveResolver>,
+    set_log_level: LogLevelSetter,
+    query_log: Option<Arc<QueryLog>>,
+}
+
+async fn handle(stream: TcpStream, state: &AdminState) -> anyhow::Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut lines = BufReader::new(read).lines();
+    while let Some(line) = lines.next_line().await? {
+        let response = match execute(&line, state) {
+            Ok(output) => output + "ok\n",
+            Err(e) => format!("error: {e}\n"),
+        };
+        write.write_all(response.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+fn execute(line: &str, state: &AdminState) -> anyhow::Result<String> {
+    let resolver = &state.resolver;
+    let words: Vec<&str> = line.split_whitespace().collect();
+    match words[..] {
+        ["flush"] => {
+            resolver.flush_cache();
+            Ok(String::new())
+        }
+        ["flush-negative", zone] => {
+            resolver.flush_negative(&zone.parse()?);
+            Ok(String::new())
+        }
+        ["cache-dump"] => Ok(resolver.dump_cache().iter().map(|r| format!("{r}\n")).collect()),
+        ["cache-export"] => Ok(resolver.export_cache()),
+        ["stats"] => Ok(format!("cache-entries {}\n", resolver.cache_len())),
+        ["upstream-stats"] => Ok(format_upstream_stats(&resolver.upstream_stats())),
+        ["reload-blocklist"] => bail!("there is no blocklist to reload"),
+        ["set-log-level", level] => {
+            (state.set_log_level)(level.parse()?)?;
+            Ok(String::new())
+        }
+        ["recent-queries"] => match &state.query_log {
+            Some(log) => Ok(log.entries().iter().map(|e| format!("{e}\n")).collect()),
+            None => bail!("the query log is not enabled"),
+        },
+        _ => bail!("unknown command '{line}'"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::admin::{bind, serve};
+    use crate::fake_backend::FakeBackend;
+    use crate::resolver::RecursiveResolver;
+    use crate::{a, answer};
+    use anyhow::Result;
+    use hickory_proto::op::{Header, Message};
+    use hickory_proto::rr::RecordType::A;
+    use hickory_proto::rr::{rdata, RData, Record};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::sync::{Arc, Mutex};
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpStream;
+    use tracing::level_filters::LevelFilter;
+
+    #[tokio::test]
+    async fn test_admin_commands() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+        let resolver = Arc::new(resolver);
+        resolver.resolve(&"a.b.".parse()?, A).await?;
+
+        let level = Arc::new(Mutex::new(LevelFilter::DEBUG));
+        let set_level = level.clone();
+        let listener = bind(0).await?;
+        let port = listener.local_addr()?.port();
+        tokio::spawn(serve(
+            listener,
+            resolver.clone(),
+            Box::new(move |l| {
+                *set_level.lock().unwrap() = l;
+                Ok(())
+            }),
+            None,
+        ));
+
+        let stream = TcpStream::connect(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port)).await?;
+        let (read, mut write) = stream.into_split();
+        write.write_all(b"stats\nflush\nstats\nset-log-level warn\nset-log-level loud\n").await?;
+        write.write_all(b"recent-queries\ncache-export\nupstream-stats\n").await?;
+        write.write_all(b"flush-negative b.\n").await?;
+        drop(write);
+        let mut lines = BufReader::new(read).lines();
+        let mut output = Vec::new();
+        while let Some(line) = lines.next_line().await? {
+            output.push(line);
+        }
+        assert_eq!(output[..5], ["cache-entries 1", "ok", "ok", "cache-entries 0", "ok"]);
+        assert_eq!(output[5], "ok");
+        assert!(output[6].starts_with("error: "));
+        assert_eq!(output[7], "error: the query log is not enabled");
+        // the cache was flushed, so there is nothing to export
+        assert_eq!(output[8], "ok");
+        assert!(output[9].starts_with("10.0.0.1 queries 1 successes 1 servfails 0"));
+        assert_eq!(output[10], "ok");
+        assert_eq!(output[11], "ok");
+        assert_eq!(*level.lock().unwrap(), LevelFilter::WARN);
+        assert_eq!(resolver.cache_len(), 0);
+        Ok(())
+    }
+}
@@ -1,15 +1,27 @@
-use std::fmt::Debug;
+use std::fmt::{Debug, Display, Formatter};
+use std::io;
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 
+use crate::pacing::Pacing;
 use crate::resolver::ResolutionError;
+use crate::resolver::ResolutionError::{Inconsistent, ProtocolError, ServFail, UpstreamError};
+use crate::tcp;
+use crate::tcp::TcpPool;
 use async_trait::async_trait;
-use hickory_proto::op::{Message, Query};
+use hickory_proto::error::ProtoError;
+use hickory_proto::op::{Edns, Message, Query, ResponseCode};
+use hickory_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
 use hickory_proto::rr::Name;
 use hickory_proto::rr::RecordType;
-use hickory_proto::serialize::binary::BinDecodable;
-use tokio::net::UdpSocket;
+use hickory_proto::serialize::binary::{BinDecodable, BinEncodable, BinEncoder};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use socket2::SockRef;
+use std::sync::Mutex;
+use std::time::Duration;
+use tokio::net::{TcpSocket, TcpStream, UdpSocket};
 use tracing::field::Empty;
-use tracing::instrument;
+use tracing::{debug, instrument};
 
 /// Max size for the UDP receive buffer as recommended by
 /// [RFC6891](https://datatracker.ietf.org/doc/html/rfc6891#section-6.2.5).
@@ -17,6 +29,66 @@ pub const MAX_RECEIVE_BUFFER_SIZE: usize = 4096;
 
 const DEFAULT_TARGET_PORT: u16 = 53;
 
+/// The length of the header of a message, RFC 1035 section 4.1.1
+const HEADER_LENGTH: usize = 12;
+
+/// The length of the shortest question, for the root name, RFC 1035 section 4.1.2
+const MIN_QUESTION_LENGTH: usize = 5;
+
+/// The length of the shortest record, for the root name without rdata, RFC 1035 section 4.1.3
+const MIN_RECORD_LENGTH: usize = 11;
+
+/// The EDNS option code used for Extended DNS Errors
+const EDE_OPTION_CODE: u16 = 15;
+
+/// An Extended DNS Error, as defined in [RFC8914](https://datatracker.ietf.org/doc/html/rfc8914),
+/// that a server used to explain why it failed to answer
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedError {
+    pub info_code: u16,
+    pub extra_text: String,
+}
+
+impl ExtendedError {
+    /// The info code for errors that none of the others describe
+    pub const OTHER: u16 = 0;
+
+    /// The info code for answers that failed DNSSEC validation
+    pub const DNSSEC_BOGUS: u16 = 6;
+
+    /// The info code for failing to reach the upstreams
+    pub const NETWORK_ERROR: u16 = 23;
+
+    /// Returns the extended error of the message, if there is one
+    fn from_message(message: &Message) -> Option<Self> {
+        let edns = message.extensions().as_ref()?;
+        let Some(EdnsOption::Unknown(_, data)) = edns.option(EdnsCode::from(EDE_OPTION_CODE))
+        else {
+            return None;
+        };
+        let (info_code, extra_text) = data.split_first_chunk::<2>()?;
+        Some(ExtendedError {
+            info_code: u16::from_be_bytes(*info_code),
+            extra_text: String::from_utf8_lossy(extra_text).into_owned(),
+        })
+    }
+
+    /// Returns the EDNS option that carries the error
+    pub(crate) fn to_option(&self) -> EdnsOption {
+        let data = [&self.info_code.to_be_bytes(), self.extra_text.as_bytes()].concat();
+        EdnsOption::Unknown(EDE_OPTION_CODE, data)
+    }
+}
+
+impl Display for ExtendedError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.extra_text.as_str() {
+            "" => write!(f, "{}", self.info_code),
+            text => write!(f, "{} ({})", self.info_code, text),
+        }
+    }
+}
+
 /// A backend represents something that can pass on queries and potentially return responses
 /// from the remote that the query was sent to.
 #[async_trait]
@@ -27,6 +99,28 @@ pub trait Backend: Debug {
         to_resolve: &Name,
         record_type: RecordType,
     ) -> Result<Message, ResolutionError>;
+
+    /// Like query, with the options of the resolution that sends it. Backends without EDNS
+    /// ignore the buffer size.
+    async fn query_with_options(
+        &self,
+        target: IpAddr,
+        to_resolve: &Name,
+        record_type: RecordType,
+        _options: QueryOptions,
+    ) -> Result<Message, ResolutionError> {
+        self.query(target, to_resolve, record_type).await
+    }
+}
+
+/// Settings for a single query, overriding those of the backend
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct QueryOptions {
+    /// The EDNS UDP payload size to advertise, instead of the configured sizes
+    pub buffer_size: Option<u16>,
+    /// Set the CD bit, for the upstream to answer even with data that fails its own
+    /// validation, RFC 4035 section 3.2.2
+    pub checking_disabled: bool,
 }
 
 /// A Backend implementation that provides the DNS query request/response
@@ -34,83 +128,414 @@ pub trait Backend: Debug {
 #[derive(Debug)]
 pub struct UdpBackend {
     target_port: u16,
+    source: Option<IpAddr>,
+    /// The DSCP value to mark outgoing queries with
+    dscp: Option<u8>,
+    /// If names are compressed when serializing queries
+    compress_names: bool,
+    /// If the DO bit is set, asking for the DNSSEC records
+    dnssec_ok: bool,
+    /// The EDNS buffer sizes to advertise, in order, on truncated responses
+    edns_buffer_sizes: Vec<u16>,
+    /// Keeps TCP connections open for reuse, if set
+    tcp_pool: Option<TcpPool>,
+    /// If every query is sent over TCP, without trying UDP first
+    tcp_only: bool,
+    /// Limits the rate of queries to each server, if set
+    pacing: Option<Pacing>,
+    /// Used to generate query ids
+    rng: Mutex<StdRng>,
 }
 
 impl UdpBackend {
     pub fn new() -> Self {
-        UdpBackend { target_port: DEFAULT_TARGET_PORT }
+        UdpBackend {
+            target_port: DEFAULT_TARGET_PORT,
+            source: None,
+            dscp: None,
+            compress_names: true,
+            dnssec_ok: false,
+            edns_buffer_sizes: Vec::new(),
+            tcp_pool: None,
+            tcp_only: false,
+            pacing: None,
+            rng: Mutex::new(StdRng::from_entropy()),
+        }
+    }
+
+    /// Creates a UdpBackend that sends its queries from the given local address,
+    /// useful on multi-homed hosts
+    pub fn with_source(source: IpAddr) -> Self {
+        UdpBackend { source: Some(source), ..Self::new() }
+    }
+
+    /// Marks outgoing queries with the given DSCP value, for networks that prioritize
+    /// traffic based on it. Only the lower six bits are used.
+    pub fn with_dscp(self, dscp: u8) -> Self {
+        UdpBackend { dscp: Some(dscp & 0x3f), ..self }
+    }
+
+    /// Sends the names of queries uncompressed, for servers that mishandle compression
+    pub fn without_name_compression(self) -> Self {
+        UdpBackend { compress_names: false, ..self }
+    }
+
+    /// Sets the DO bit in outgoing queries, to have the RRSIG records of the answers included
+    pub fn with_dnssec_ok(self) -> Self {
+        UdpBackend { dnssec_ok: true, ..self }
+    }
+
+    /// Advertises the first of the EDNS buffer sizes, and retries truncated responses over
+    /// UDP with each of the following sizes before falling back to TCP. A conservative first
+    /// size, such as 1232, avoids fragmentation for the responses that fit in it.
+    pub fn with_edns_buffer_sizes(self, edns_buffer_sizes: Vec<u16>) -> Self {
+        UdpBackend { edns_buffer_sizes, ..self }
+    }
+
+    /// Keeps TCP connections open for reuse until they have been idle for `idle_timeout`,
+    /// or the shorter time the server asks for with the EDNS keepalive option
+    pub fn with_tcp_idle_timeout(self, idle_timeout: Duration) -> Self {
+        UdpBackend { tcp_pool: Some(TcpPool::new(idle_timeout)), ..self }
+    }
+
+    /// Sends every query over TCP, never using UDP. Where UDP gets spoofed or mangled by
+    /// middleboxes this is more robust, at the cost of a connection per query unless they
+    /// are kept open with with_tcp_idle_timeout().
+    pub fn tcp_only(self) -> Self {
+        UdpBackend { tcp_only: true, ..self }
+    }
+
+    /// Sends at most `queries_per_second` queries to any one server, after an initial burst
+    /// of that many, delaying the queries over the rate. The queries that would be delayed
+    /// too long fail as if the server was overloaded.
+    pub fn with_rate_limit(self, queries_per_second: u32) -> Self {
+        UdpBackend { pacing: Some(Pacing::new(queries_per_second)), ..self }
+    }
+
+    /// Sends the queries to another port than 53, for servers running on the local host
+    #[cfg(test)]
+    pub fn with_target_port(self, target_port: u16) -> Self {
+        UdpBackend { target_port, ..self }
+    }
+
+    /// Makes the generated query ids predictable, only useful for reproducing bugs
+    /// as this makes it easier to spoof responses
+    pub fn seeded(self, seed: u64) -> Self {
+        UdpBackend { rng: Mutex::new(StdRng::seed_from_u64(seed)), ..self }
     }
 }
 
-async fn connect(target: IpAddr, target_port: u16) -> Result<UdpSocket, ResolutionError> {
-    let local = SocketAddr::new(
-        match target {
-            IpAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
-            IpAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
-        },
-        0,
-    );
-    let socket = UdpSocket::bind(local).await?;
+/// Picks the local address to send queries to target from
+fn local_address(target: IpAddr, source: Option<IpAddr>) -> Result<IpAddr, ResolutionError> {
+    match (source, target) {
+        (Some(source), target) if source.is_ipv4() != target.is_ipv4() => {
+            Err(ServFail(format!("source address {source} can't be used to query {target}")))
+        }
+        (Some(source), _) => Ok(source),
+        (None, IpAddr::V4(_)) => Ok(IpAddr::V4(Ipv4Addr::UNSPECIFIED)),
+        (None, IpAddr::V6(_)) => Ok(IpAddr::V6(Ipv6Addr::UNSPECIFIED)),
+    }
+}
+
+/// Sets the DSCP value, which lives in the upper six bits of the IPv4 TOS and the IPv6
+/// traffic class fields
+fn set_dscp(socket: SockRef, target: IpAddr, dscp: Option<u8>) -> io::Result<()> {
+    let Some(dscp) = dscp else {
+        return Ok(());
+    };
+    let tos = u32::from(dscp) << 2;
+    match target {
+        IpAddr::V4(_) => socket.set_tos(tos),
+        IpAddr::V6(_) => socket.set_tclass_v6(tos),
+    }
+}
+
+async fn connect(
+    target: IpAddr,
+    target_port: u16,
+    source: Option<IpAddr>,
+    dscp: Option<u8>,
+) -> Result<UdpSocket, ResolutionError> {
+    let local = local_address(target, source)?;
+    let socket = UdpSocket::bind(SocketAddr::new(local, 0)).await?;
+    set_dscp(SockRef::from(&socket), target, dscp)?;
     socket.connect(SocketAddr::new(target, target_port)).await?;
     Ok(socket)
 }
 
-#[async_trait]
-impl Backend for UdpBackend {
-    // It looks a little weird to have status be set to error, but this is being overwritten
-    // unless the ? operator makes the execution return early
-    #[instrument(fields(otel.status_code = "Error", result = Empty, %to_resolve, %record_type, response_code = Empty))]
-    async fn query(
+/// Creates and binds, but doesn't connect, a TCP socket for querying target
+fn tcp_socket(
+    target: IpAddr,
+    source: Option<IpAddr>,
+    dscp: Option<u8>,
+) -> Result<TcpSocket, ResolutionError> {
+    let socket = match target {
+        IpAddr::V4(_) => TcpSocket::new_v4()?,
+        IpAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    set_dscp(SockRef::from(&socket), target, dscp)?;
+    socket.bind(SocketAddr::new(local_address(target, source)?, 0))?;
+    Ok(socket)
+}
+
+async fn connect_tcp(
+    target: IpAddr,
+    target_port: u16,
+    source: Option<IpAddr>,
+    dscp: Option<u8>,
+) -> Result<TcpStream, ResolutionError> {
+    let socket = tcp_socket(target, source, dscp)?;
+    Ok(socket.connect(SocketAddr::new(target, target_port)).await?)
+}
+
+/// Sends a query over an established TCP connection and reads the response
+async fn exchange(stream: &mut TcpStream, request: &[u8]) -> Result<Message, ResolutionError> {
+    tcp::write_bytes(stream, request).await?;
+    tcp::read_message(stream).await
+}
+
+impl UdpBackend {
+    /// Creates a query with a new id, with EDNS if it is needed for the DO bit or to
+    /// advertise the given buffer size
+    fn make_request(
         &self,
-        target: IpAddr,
         to_resolve: &Name,
         record_type: RecordType,
-    ) -> Result<Message, ResolutionError> {
-        let socket = connect(target, self.target_port).await?;
+        size: Option<u16>,
+    ) -> Message {
+        let mut request = make_query(to_resolve, record_type, self.rng.lock().unwrap().gen());
+        if self.dnssec_ok || size.is_some() {
+            let mut edns = Edns::new();
+            edns.set_dnssec_ok(self.dnssec_ok)
+                .set_max_payload(size.unwrap_or(MAX_RECEIVE_BUFFER_SIZE as u16));
+            request.set_edns(edns);
+        }
+        request
+    }
 
-        let request = make_query(to_resolve, record_type);
-        socket.send(request.to_vec()?.as_slice()).await?;
+    /// Sends the query over UDP. None is returned if the response filled the whole
+    /// receive buffer, as it may have been clipped
+    async fn query_udp(
+        &self,
+        target: IpAddr,
+        request: &Message,
+    ) -> Result<Option<Message>, ResolutionError> {
+        let socket = connect(target, self.target_port, self.source, self.dscp).await?;
+        socket.send(&encode(request, self.compress_names)?).await?;
         let mut buf = vec![0u8; MAX_RECEIVE_BUFFER_SIZE];
         let read_count = socket.recv(&mut buf).await?;
 
-        let message = Message::from_bytes(&buf[..read_count])?;
+        // a datagram filling the whole buffer is larger than what was advertised, which no
+        // well-behaved server sends, so it is not trusted
+        match read_count < buf.len() {
+            true => Ok(Some(parse_message(&buf[..read_count])?)),
+            false => {
+                debug!(%target, "UDP response fills the receive buffer");
+                Ok(None)
+            }
+        }
+    }
+
+    /// Repeats a query over TCP, used when the UDP response was truncated
+    async fn query_tcp(
+        &self,
+        target: IpAddr,
+        request: &Message,
+    ) -> Result<Message, ResolutionError> {
+        let Some(pool) = &self.tcp_pool else {
+            let request = encode(request, self.compress_names)?;
+            let mut stream = connect_tcp(target, self.target_port, self.source, self.dscp).await?;
+            return exchange(&mut stream, &request).await;
+        };
+        // signals that the connection may be kept open, RFC 7828 section 3.2.1
+        let mut request = request.clone();
+        let edns = request.extensions_mut().get_or_insert_with(Edns::new);
+        edns.options_mut().insert(EdnsOption::Unknown(tcp::KEEPALIVE_OPTION_CODE, Vec::new()));
+        let request = encode(&request, self.compress_names)?;
+
+        // the server may have closed an idle connection, so that is retried on a new one
+        let pooled = match pool.take(target) {
+            Some(mut stream) => exchange(&mut stream, &request).await.ok().map(|r| (stream, r)),
+            None => None,
+        };
+        let (stream, response) = match pooled {
+            Some(pooled) => pooled,
+            None => {
+                let mut stream =
+                    connect_tcp(target, self.target_port, self.source, self.dscp).await?;
+                let response = exchange(&mut stream, &request).await?;
+                (stream, response)
+            }
+        };
+        pool.put(target, stream, tcp::keepalive(&response));
+        Ok(response)
+    }
+
+    /// Sends the query advertising the first of the buffer sizes, retrying with the next
+    /// one while the response is truncated and then over TCP. A response that may have been
+    /// clipped is retried over TCP right away.
+    async fn query_with_sizes(
+        &self,
+        target: IpAddr,
+        to_resolve: &Name,
+        record_type: RecordType,
+        sizes: &[u16],
+        checking_disabled: bool,
+    ) -> Result<Message, ResolutionError> {
+        if let Some(pacing) = &self.pacing {
+            pacing.wait(target).await?;
+        }
+        let mut sizes = sizes.iter().copied();
+        let mut request = self.make_request(to_resolve, record_type, sizes.next());
+        request.set_checking_disabled(checking_disabled);
+        let message = loop {
+            if self.tcp_only {
+                break self.query_tcp(target, &request).await?;
+            }
+            match self.query_udp(target, &request).await? {
+                Some(message) if !message.truncated() => break message,
+                // a larger buffer won't help with a response that overflowed the receive buffer
+                None => break self.query_tcp(target, &request).await?,
+                Some(_) => {}
+            }
+            match sizes.next() {
+                Some(size) => {
+                    debug!(%target, size, "Retrying with a larger EDNS buffer size");
+                    request = self.make_request(to_resolve, record_type, Some(size));
+                    request.set_checking_disabled(checking_disabled);
+                }
+                None => break self.query_tcp(target, &request).await?,
+            }
+        };
+        if message.id() != request.id() {
+            return Err(Inconsistent(format!("{target} responded with another message id")));
+        }
         let span = tracing::Span::current();
         span.record("otel.status_code", "Unset");
         span.record("result", format!("{:?}", message));
         span.record("response_code", format!("{}", message.header().response_code()));
+        if message.response_code() == ResponseCode::ServFail {
+            if let Some(e) = ExtendedError::from_message(&message) {
+                return Err(UpstreamError(e));
+            }
+        }
         Ok(message)
     }
 }
 
-fn make_query(name: &Name, record_type: RecordType) -> Message {
+#[async_trait]
+impl Backend for UdpBackend {
+    // It looks a little weird to have status be set to error, but this is being overwritten
+    // unless the ? operator makes the execution return early
+    #[instrument(skip(self), fields(otel.status_code = "Error", result = Empty, %to_resolve, %record_type, response_code = Empty))]
+    async fn query(
+        &self,
+        target: IpAddr,
+        to_resolve: &Name,
+        record_type: RecordType,
+    ) -> Result<Message, ResolutionError> {
+        self.query_with_sizes(target, to_resolve, record_type, &self.edns_buffer_sizes, false).await
+    }
+
+    #[instrument(skip(self), fields(otel.status_code = "Error", result = Empty, %to_resolve, %record_type, response_code = Empty))]
+    async fn query_with_options(
+        &self,
+        target: IpAddr,
+        to_resolve: &Name,
+        record_type: RecordType,
+        options: QueryOptions,
+    ) -> Result<Message, ResolutionError> {
+        let sizes = match options.buffer_size {
+            Some(size) => vec![size],
+            None => self.edns_buffer_sizes.clone(),
+        };
+        let checking_disabled = options.checking_disabled;
+        self.query_with_sizes(target, to_resolve, record_type, &sizes, checking_disabled).await
+    }
+}
+
+/// Parses a message received from the network. Name compression pointers,
+/// RFC 1035 section 4.1.4, are only followed when they point to an earlier offset than
+/// the pointer itself, so a crafted pointer that refers to itself or forms a loop with
+/// others is an error rather than something that is followed forever. The section counts
+/// of the header must fit in the length of the message before anything is allocated for
+/// them, as a header can claim up to 65535 records of each section.
+pub fn parse_message(bytes: &[u8]) -> Result<Message, ResolutionError> {
+    let malformed = |reason: String| ProtocolError(ProtoError::from(reason));
+    if bytes.len() > usize::from(u16::MAX) {
+        return Err(malformed(format!("{} octets don't fit in a message", bytes.len())));
+    }
+    let Some((header, _)) = bytes.split_first_chunk::<HEADER_LENGTH>() else {
+        return Err(malformed(format!("{} octets are too few for the header", bytes.len())));
+    };
+    let count = |at: usize| usize::from(u16::from_be_bytes([header[at], header[at + 1]]));
+    let records = count(6) + count(8) + count(10);
+    let needed = HEADER_LENGTH + count(4) * MIN_QUESTION_LENGTH + records * MIN_RECORD_LENGTH;
+    if needed > bytes.len() {
+        return Err(malformed(format!(
+            "the section counts need at least {needed} octets, the message has {}",
+            bytes.len()
+        )));
+    }
+    Ok(Message::from_bytes(bytes)?)
+}
+
+/// Serializes the message, like Message::to_vec() but with optional name compression
+fn encode(message: &Message, compress_names: bool) -> Result<Vec<u8>, ResolutionError> {
+    let mut buf = Vec::with_capacity(512);
+    let mut encoder = BinEncoder::new(&mut buf);
+    encoder.set_canonical_names(!compress_names);
+    message.emit(&mut encoder)?;
+    Ok(buf)
+}
+
+fn make_query(name: &Name, record_type: RecordType, id: u16) -> Message {
     let mut query = Query::new();
     query.set_name(name.clone()).set_query_type(record_type);
     let mut message = Message::new();
     message.add_query(query);
     message.set_recursion_desired(true);
-    message.set_id(rand::random());
+    message.set_id(id);
     message.set_authentic_data(true);
     message
 }
 
 #[cfg(test)]
 mod test {
-    use hickory_proto::op::{Message, ResponseCode};
-    use hickory_proto::rr::rdata::A;
+    use hickory_proto::op::{Edns, Message, ResponseCode};
+    use hickory_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+    use hickory_proto::rr::rdata::{A, AAAA, NS, TXT};
     use hickory_proto::rr::{Name, RData, Record, RecordType};
     use hickory_proto::serialize::binary::BinDecodable;
-    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
     use std::str::FromStr;
-    use tokio::net::UdpSocket;
+    use std::time::Duration;
+    use tokio::net::{TcpListener, UdpSocket};
     use tokio::task::JoinHandle;
 
     use crate::backend::Backend;
-    use crate::backend::{UdpBackend, MAX_RECEIVE_BUFFER_SIZE};
-    use crate::resolver::ResolutionError;
+    use crate::backend::{
+        connect, encode, make_query, parse_message, tcp_socket, ExtendedError, UdpBackend,
+        MAX_RECEIVE_BUFFER_SIZE,
+    };
+    use crate::cache::CacheResponse;
+    use crate::resolver::{RecursiveResolver, ResolutionError, ResolveOptions};
+    use crate::tcp;
     use anyhow::Result;
+    use rand::Rng;
+    use socket2::SockRef;
 
+    /// Starts a server that responds to a single request, the JoinHandle returns the
+    /// address of the peer that sent the request
     async fn verify_request_send_response(
-    ) -> Result<(u16, JoinHandle<Result<(), ResolutionError>>), ResolutionError> {
+    ) -> Result<(u16, JoinHandle<Result<SocketAddr, ResolutionError>>), ResolutionError> {
+        respond_once(make_response).await
+    }
+
+    /// Like verify_request_send_response, with the response created by the given function
+    async fn respond_once(
+        make_response: fn(Message) -> Message,
+    ) -> Result<(u16, JoinHandle<Result<SocketAddr, ResolutionError>>), ResolutionError> {
         let server_socket =
             UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await?;
         let port = server_socket.local_addr()?.port();
@@ -120,7 +545,7 @@ mod test {
             let req = Message::from_bytes(&buf[..read_count])?;
             let resp = make_response(req);
             server_socket.send_to(resp.to_vec()?.as_slice(), peer).await?;
-            Ok(())
+            Ok(peer)
         });
         Ok((port, handler))
     }
@@ -142,7 +567,7 @@ mod test {
     async fn test_udp_interaction() -> Result<()> {
         let (port, handle) = verify_request_send_response().await?;
 
-        let b = UdpBackend { target_port: port };
+        let b = UdpBackend { target_port: port, ..UdpBackend::new() };
         let message =
             b.query(IpAddr::V4(Ipv4Addr::LOCALHOST), &"stacey.a.b".parse()?, RecordType::A).await?;
         assert_eq!(message.response_code(), ResponseCode::NoError);
@@ -156,4 +581,495 @@ mod test {
         handle.await??;
         Ok(())
     }
+
+    #[tokio::test]
+    async fn test_resolve_checking_disabled() -> Result<()> {
+        let server_socket =
+            UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await?;
+        let port = server_socket.local_addr()?.port();
+        let handle = tokio::spawn(async move {
+            let mut flags = Vec::new();
+            for _ in 0..2 {
+                let mut buf = vec![0u8; MAX_RECEIVE_BUFFER_SIZE];
+                let (read_count, peer) = server_socket.recv_from(&mut buf).await?;
+                let request = Message::from_bytes(&buf[..read_count])?;
+                flags.push(request.checking_disabled());
+                let mut response = make_response(request);
+                response.set_authoritative(true);
+                server_socket.send_to(&response.to_vec()?, peer).await?;
+            }
+            Ok::<_, ResolutionError>(flags)
+        });
+
+        let resolver = RecursiveResolver::builder()
+            .backend(UdpBackend { target_port: port, ..UdpBackend::new() })
+            .roots(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)])
+            .build();
+        let options = ResolveOptions { checking_disabled: true, ..ResolveOptions::default() };
+        let name: Name = "stacey.a.b.".parse()?;
+        resolver.resolve_with_options(&name, RecordType::A, options).await?;
+        // another type, as the first answer is cached
+        resolver.resolve(&name, RecordType::AAAA).await?;
+        assert_eq!(handle.await??, [true, false]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_truncated_response() -> Result<()> {
+        let (port, handle) = respond_once(|request| {
+            let mut message = make_response(request);
+            message.take_answers();
+            message.set_truncated(true);
+            message
+        })
+        .await?;
+        let listener =
+            TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)).await?;
+        let tcp_handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let request = tcp::read_message(&mut stream).await?;
+            tcp::write_message(&mut stream, &make_response(request)).await
+        });
+
+        let b = UdpBackend { target_port: port, ..UdpBackend::new() };
+        let message =
+            b.query(IpAddr::V4(Ipv4Addr::LOCALHOST), &"stacey.a.b".parse()?, RecordType::A).await?;
+        assert!(!message.truncated());
+        assert_eq!(message.answers().len(), 1);
+        handle.await??;
+        tcp_handle.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_oversized_udp_response() -> Result<()> {
+        let server_socket =
+            UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await?;
+        let port = server_socket.local_addr()?.port();
+        let handle = tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_RECEIVE_BUFFER_SIZE];
+            let (read_count, peer) = server_socket.recv_from(&mut buf).await?;
+            let mut response = make_response(Message::from_bytes(&buf[..read_count])?);
+            response.take_answers();
+            let mut bytes = response.to_vec()?;
+            bytes.resize(MAX_RECEIVE_BUFFER_SIZE, 0);
+            server_socket.send_to(&bytes, peer).await?;
+            Ok::<(), ResolutionError>(())
+        });
+        let listener =
+            TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)).await?;
+        let tcp_handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let request = tcp::read_message(&mut stream).await?;
+            tcp::write_message(&mut stream, &make_response(request)).await
+        });
+
+        // the larger buffer size is skipped, the server only answers one query over UDP
+        let b = UdpBackend { target_port: port, ..UdpBackend::new() }
+            .with_edns_buffer_sizes(vec![1232, 4096]);
+        let message =
+            b.query(IpAddr::V4(Ipv4Addr::LOCALHOST), &"stacey.a.b".parse()?, RecordType::A).await?;
+        // only the response over TCP has an answer
+        assert_eq!(message.answers().len(), 1);
+        handle.await??;
+        tcp_handle.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_edns_buffer_size_ladder() -> Result<()> {
+        let server_socket =
+            UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await?;
+        let port = server_socket.local_addr()?.port();
+        // truncates the responses to the queries advertising less than 4096 bytes
+        let handle = tokio::spawn(async move {
+            let mut sizes = Vec::new();
+            for _ in 0..2 {
+                let mut buf = vec![0u8; MAX_RECEIVE_BUFFER_SIZE];
+                let (read_count, peer) = server_socket.recv_from(&mut buf).await?;
+                let request = Message::from_bytes(&buf[..read_count])?;
+                let size = request.extensions().as_ref().map(Edns::max_payload);
+                let mut response = make_response(request);
+                if size < Some(4096) {
+                    response.take_answers();
+                    response.set_truncated(true);
+                }
+                server_socket.send_to(&response.to_vec()?, peer).await?;
+                sizes.push(size);
+            }
+            Ok::<_, ResolutionError>(sizes)
+        });
+
+        // there is no TCP listener, so falling back to TCP fails
+        let b = UdpBackend { target_port: port, ..UdpBackend::new() }
+            .with_edns_buffer_sizes(vec![1232, 4096]);
+        let message =
+            b.query(IpAddr::V4(Ipv4Addr::LOCALHOST), &"stacey.a.b".parse()?, RecordType::A).await?;
+        assert!(!message.truncated());
+        assert_eq!(message.answers().len(), 1);
+        assert_eq!(handle.await??, [Some(1232), Some(4096)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_with_buffer_size() -> Result<()> {
+        let server_socket =
+            UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await?;
+        let port = server_socket.local_addr()?.port();
+        let handle = tokio::spawn(async move {
+            let mut sizes = Vec::new();
+            for _ in 0..2 {
+                let mut buf = vec![0u8; MAX_RECEIVE_BUFFER_SIZE];
+                let (read_count, peer) = server_socket.recv_from(&mut buf).await?;
+                let request = Message::from_bytes(&buf[..read_count])?;
+                sizes.push(request.extensions().as_ref().map(Edns::max_payload));
+                server_socket.send_to(&make_response(request).to_vec()?, peer).await?;
+            }
+            Ok::<_, ResolutionError>(sizes)
+        });
+
+        let resolver = RecursiveResolver::builder()
+            .backend(UdpBackend { target_port: port, ..UdpBackend::new() })
+            .roots(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)])
+            .caching(false)
+            .build();
+        let name: Name = "stacey.a.b.".parse()?;
+        let options = ResolveOptions { edns_buffer_size: Some(1232), ..ResolveOptions::default() };
+        resolver.resolve_with_options(&name, RecordType::A, options).await?;
+        // the size only applies to the resolution it was given for
+        resolver.resolve(&name, RecordType::A).await?;
+        assert_eq!(handle.await??, [Some(1232), None]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prime() -> Result<()> {
+        let server_socket =
+            UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await?;
+        let port = server_socket.local_addr()?.port();
+        let handle = tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_RECEIVE_BUFFER_SIZE];
+            let (read_count, peer) = server_socket.recv_from(&mut buf).await?;
+            let request = Message::from_bytes(&buf[..read_count])?;
+            let mut response = Message::new();
+            response.set_id(request.id());
+            response.set_authoritative(true);
+            response.add_queries(request.queries().iter().cloned());
+            for (i, letter) in ('a'..='m').enumerate() {
+                let server = Name::from_str(&format!("{letter}.root-servers.net."))?;
+                let ns = RData::NS(NS(server.clone()));
+                response.add_answer(Record::from_rdata(Name::root(), 518400, ns));
+                let a = RData::A(A::new(198, 51, 100, i as u8));
+                response.add_additional(Record::from_rdata(server.clone(), 518400, a));
+                let aaaa = RData::AAAA(AAAA::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, i as u16));
+                response.add_additional(Record::from_rdata(server, 518400, aaaa));
+            }
+            // the response doesn't fit the buffer size of a client without EDNS
+            let payload = request.extensions().as_ref().map(Edns::max_payload);
+            assert!(response.to_vec()?.len() > 512);
+            server_socket.send_to(&response.to_vec()?, peer).await?;
+            Ok::<_, anyhow::Error>(payload)
+        });
+
+        let resolver = RecursiveResolver::builder()
+            .backend(UdpBackend::new().with_target_port(port))
+            .roots(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)])
+            .build();
+        let (ns, glue) = resolver.prime().await?;
+        assert_eq!(ns.len(), 13);
+        assert_eq!(glue.len(), 26);
+        assert_eq!(handle.await??, Some(4096));
+        // resolution starts from the primed nameservers
+        let CacheResponse::Referral(ns, glue) =
+            resolver.get_cached(&"a.b.".parse()?, RecordType::A)
+        else {
+            panic!("the root nameservers are not cached");
+        };
+        assert_eq!((ns.len(), glue.len()), (13, 13));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tcp_connection_reuse() -> Result<()> {
+        let listener =
+            TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await?;
+        let port = listener.local_addr()?.port();
+        // accepts a single connection, so the second query must reuse it
+        let tcp_handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            for _ in 0..2 {
+                let request = tcp::read_message(&mut stream).await?;
+                let edns = request.extensions().as_ref().expect("keepalive is signalled");
+                assert!(edns.option(EdnsCode::from(tcp::KEEPALIVE_OPTION_CODE)).is_some());
+                tcp::write_message(&mut stream, &make_response(request)).await?;
+            }
+            Ok::<(), ResolutionError>(())
+        });
+
+        let b = UdpBackend { target_port: port, ..UdpBackend::new() }
+            .with_tcp_idle_timeout(Duration::from_secs(10));
+        for id in [1, 2] {
+            let request = make_query(&"stacey.a.b".parse()?, RecordType::A, id);
+            let response = b.query_tcp(IpAddr::V4(Ipv4Addr::LOCALHOST), &request).await?;
+            assert_eq!(response.id(), id);
+        }
+        tcp_handle.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tcp_only() -> Result<()> {
+        let listener =
+            TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await?;
+        let port = listener.local_addr()?.port();
+        // any query over UDP would end up here
+        let udp = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)).await?;
+        let tcp_handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let request = tcp::read_message(&mut stream).await?;
+            tcp::write_message(&mut stream, &make_response(request)).await
+        });
+
+        let b = UdpBackend { target_port: port, ..UdpBackend::new() }.tcp_only();
+        let message =
+            b.query(IpAddr::V4(Ipv4Addr::LOCALHOST), &"stacey.a.b".parse()?, RecordType::A).await?;
+        assert_eq!(message.answers().len(), 1);
+        tcp_handle.await??;
+        let mut buf = [0u8; MAX_RECEIVE_BUFFER_SIZE];
+        assert!(udp.try_recv(&mut buf).is_err());
+        Ok(())
+    }
+
+    /// A response with TXT records too large for a UDP response, each with several strings
+    fn make_large_txt_response(request: Message) -> Message {
+        let mut message = Message::new();
+        message.add_query(request.query().unwrap().clone());
+        message.set_id(request.id());
+        for i in 0..20 {
+            let txt = TXT::new(vec![format!("v=spf{i}"), "a".repeat(250), "b".repeat(250)]);
+            message.add_answer(Record::from_rdata(
+                Name::from_str("a.b.").unwrap(),
+                600,
+                RData::TXT(txt),
+            ));
+        }
+        message
+    }
+
+    #[tokio::test]
+    async fn test_large_txt_over_tcp() -> Result<()> {
+        let (port, handle) = respond_once(|request| {
+            let mut message = make_large_txt_response(request);
+            message.take_answers();
+            message.set_truncated(true);
+            message
+        })
+        .await?;
+        let listener =
+            TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)).await?;
+        let tcp_handle = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            let request = tcp::read_message(&mut stream).await?;
+            tcp::write_message(&mut stream, &make_large_txt_response(request)).await
+        });
+
+        let b = UdpBackend { target_port: port, ..UdpBackend::new() };
+        let message =
+            b.query(IpAddr::V4(Ipv4Addr::LOCALHOST), &"a.b.".parse()?, RecordType::TXT).await?;
+        let expected = make_large_txt_response(make_query(&"a.b.".parse()?, RecordType::TXT, 1));
+        assert!(expected.to_vec()?.len() > MAX_RECEIVE_BUFFER_SIZE);
+        assert_eq!(message.answers(), expected.answers());
+        handle.await??;
+        tcp_handle.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_extended_error() -> Result<()> {
+        let (port, handle) = respond_once(|request| {
+            let mut message = Message::new();
+            message.add_query(request.query().unwrap().clone());
+            message.set_id(request.id());
+            message.set_response_code(ResponseCode::ServFail);
+            // info code 6 is "DNSSEC Bogus"
+            let mut edns = Edns::new();
+            edns.options_mut()
+                .insert(EdnsOption::Unknown(15, b"\x00\x06signature expired".to_vec()));
+            message.set_edns(edns);
+            message
+        })
+        .await?;
+
+        let b = UdpBackend { target_port: port, ..UdpBackend::new() };
+        let result = b.query(IpAddr::V4(Ipv4Addr::LOCALHOST), &"a.b".parse()?, RecordType::A).await;
+        let expected = ExtendedError { info_code: 6, extra_text: "signature expired".to_string() };
+        assert!(matches!(result, Err(ResolutionError::UpstreamError(e)) if e == expected));
+        handle.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dnssec_ok() -> Result<()> {
+        let (port, handle) = respond_once(|request| {
+            let mut message = make_response(request.clone());
+            if !request.extensions().as_ref().is_some_and(Edns::dnssec_ok) {
+                message.set_response_code(ResponseCode::Refused);
+            }
+            message
+        })
+        .await?;
+        let b = UdpBackend { target_port: port, ..UdpBackend::new().with_dnssec_ok() };
+        let result =
+            b.query(IpAddr::V4(Ipv4Addr::LOCALHOST), &"stacey.a.b".parse()?, RecordType::A).await?;
+        assert_eq!(result.response_code(), ResponseCode::NoError);
+        handle.await??;
+        Ok(())
+    }
+
+    #[test]
+    fn test_make_query_max_length_name() -> Result<()> {
+        let labels: Vec<String> = [63, 63, 63, 61].iter().map(|len| "a".repeat(*len)).collect();
+        let name = Name::from_str(&format!("{}.", labels.join(".")))?;
+        let bytes = make_query(&name, RecordType::A, 4711).to_vec()?;
+        let parsed = Message::from_bytes(&bytes)?;
+        assert_eq!(parsed.query().unwrap().name(), &name);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_compression_pointer_loops() -> Result<()> {
+        let header = make_query(&Name::root(), RecordType::A, 4711).to_vec()?[..12].to_vec();
+        let question = |name: &[u8]| -> Vec<u8> { [&header[..], name, &[0, 1, 0, 1]].concat() };
+        // the question name starts at offset 12, pointing at itself
+        assert!(parse_message(&question(&[0xc0, 12])).is_err());
+        // a label followed by a pointer back to the start of the name
+        assert!(parse_message(&question(&[1, b'a', 0xc0, 12])).is_err());
+        // two pointers pointing at each other
+        assert!(parse_message(&question(&[0xc0, 14, 0xc0, 12])).is_err());
+        // a pointer past the end of the message
+        assert!(parse_message(&question(&[0xc0, 0xff])).is_err());
+        // a pointer to an earlier name is fine
+        let mut bytes = question(&[1, b'a', 0]);
+        bytes[7] = 1;
+        bytes.extend_from_slice(&[0xc0, 12, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4, 10, 0, 0, 1]);
+        let parsed = parse_message(&bytes)?;
+        assert_eq!(parsed.answers()[0].name(), &Name::from_str("a.")?);
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_mutated_messages() -> Result<()> {
+        let name = Name::from_str("www.example.com.")?;
+        let mut message = make_query(&name, RecordType::A, 4711);
+        message.add_answer(Record::from_rdata(name.clone(), 60, RData::A(A::new(10, 0, 0, 1))));
+        message.add_answer(Record::from_rdata(name, 60, RData::A(A::new(10, 0, 0, 2))));
+        let bytes = encode(&message, true)?;
+        // the labels of the question name, and the names of the answers pointing to it
+        let labels = [12, 16, 24, 28];
+        let answers = [33, 49];
+        assert!(answers.iter().all(|at| bytes[*at..*at + 2] == [0xc0, 12]));
+
+        // every position set to a compression pointer to every offset parses or fails cleanly
+        for position in 12..bytes.len() - 1 {
+            for offset in 0..bytes.len() as u8 {
+                let mut mutated = bytes.clone();
+                mutated[position] = 0xc0;
+                mutated[position + 1] = offset;
+                let result = parse_message(&mutated);
+                let offset = usize::from(offset);
+                let in_name = labels.contains(&position) || answers.contains(&position);
+                // a name that points to itself or further on is never followed
+                if in_name && offset >= position {
+                    assert!(matches!(result, Err(ResolutionError::ProtocolError(_))), "{result:?}");
+                }
+                // the answer names may point at any label of the question name
+                let label = labels[..3].iter().position(|l| *l == offset);
+                if let (Some(answer), Some(label)) =
+                    (answers.iter().position(|a| *a == position), label)
+                {
+                    let suffix = Name::from_str("www.example.com.")?.trim_to(3 - label);
+                    assert_eq!(result?.answers()[answer].name(), &suffix);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_section_counts() -> Result<()> {
+        let bytes = make_query(&Name::root(), RecordType::A, 4711).to_vec()?;
+        assert!(parse_message(&bytes).is_ok());
+        assert!(matches!(parse_message(&bytes[..11]), Err(ResolutionError::ProtocolError(_))));
+        // a header that claims every record it can, with nothing after the question
+        for at in [6, 8, 10] {
+            let mut mutated = bytes.clone();
+            mutated[at..at + 2].copy_from_slice(&[0xff, 0xff]);
+            let result = parse_message(&mutated);
+            assert!(matches!(result, Err(ResolutionError::ProtocolError(_))), "{result:?}");
+        }
+        let mut mutated = bytes.clone();
+        mutated[4..6].copy_from_slice(&[0, 2]);
+        assert!(matches!(parse_message(&mutated), Err(ResolutionError::ProtocolError(_))));
+        Ok(())
+    }
+
+    #[test]
+    fn test_seeded_query_ids() {
+        let ids =
+            |b: UdpBackend| -> Vec<u16> { (0..5).map(|_| b.rng.lock().unwrap().gen()).collect() };
+        assert_eq!(ids(UdpBackend::new().seeded(42)), ids(UdpBackend::new().seeded(42)));
+    }
+
+    #[test]
+    fn test_encode_without_compression() -> Result<()> {
+        let name = Name::from_str("www.example.com.")?;
+        let mut message = make_query(&name, RecordType::A, 4711);
+        // with a name that occurs twice there is something to compress
+        message.add_answer(Record::from_rdata(name.clone(), 60, RData::A(A::new(10, 0, 0, 1))));
+        let wire_name = b"\x03www\x07example\x03com\x00";
+        let count =
+            |bytes: &[u8]| bytes.windows(wire_name.len()).filter(|w| w == wire_name).count();
+
+        assert_eq!(count(&encode(&message, true)?), 1);
+        let bytes = encode(&message, false)?;
+        assert_eq!(count(&bytes), 2);
+        let decoded = Message::from_bytes(&bytes)?;
+        assert_eq!(decoded.queries(), message.queries());
+        assert_eq!(decoded.answers(), message.answers());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_source_address() -> Result<()> {
+        let (port, handle) = verify_request_send_response().await?;
+
+        // any address in 127.0.0.0/8 can be bound on linux
+        let source = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 2));
+        let b = UdpBackend { target_port: port, ..UdpBackend::with_source(source) };
+        b.query(IpAddr::V4(Ipv4Addr::LOCALHOST), &"stacey.a.b".parse()?, RecordType::A).await?;
+        assert_eq!(source, handle.await??.ip());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dscp() -> Result<()> {
+        let target = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let socket = connect(target, 53, None, Some(46)).await?;
+        // expedited forwarding, 46, is 0xb8 as the TOS byte
+        assert_eq!(SockRef::from(&socket).tos()?, 0xb8);
+        let socket = tcp_socket(target, None, Some(10))?;
+        assert_eq!(SockRef::from(&socket).tos()?, 0x28);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_source_address_family_mismatch() -> Result<()> {
+        let b = UdpBackend::with_source(IpAddr::V6(Ipv6Addr::LOCALHOST));
+        let result = b.query(IpAddr::V4(Ipv4Addr::LOCALHOST), &"a.b".parse()?, RecordType::A).await;
+        assert_eq!(
+            "Server failure: source address ::1 can't be used to query 127.0.0.1",
+            result.unwrap_err().to_string()
+        );
+        Ok(())
+    }
 }
@@ -1,33 +1,227 @@
 use crate::cache::CacheResponse::{Authoritative, Referral};
 use crate::target::get_name_if_ns;
+use anyhow::bail;
+use hickory_proto::op::{Message, ResponseCode};
+use hickory_proto::rr::dnssec::rdata::DNSSECRData;
 use hickory_proto::rr::{Name, RData, Record, RecordType};
+use hickory_proto::serialize::binary::BinEncodable;
 use lru::LruCache;
+use rand::{thread_rng, Rng};
 use std::collections::{HashMap, HashSet};
 use std::fmt::{Debug, Formatter};
 use std::hash::Hash;
 use std::num::NonZeroUsize;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Mutex;
 use std::time::{Duration, Instant};
 use tracing::{debug, instrument, warn};
 
+/// The ttl that records are given when served after they have expired, RFC 8767 section 4
+const STALE_TTL: u64 = 30;
+
+/// Records can be answered stale for at most this long after they expired, RFC 8767
+/// section 5 suggests between one and three days but the shorter time keeps stale answers rare
+pub(crate) const MAX_STALE: Duration = Duration::from_secs(3600);
+
 #[derive(Debug)]
 pub(crate) struct Cache<K: Hash + Eq, V> {
     lru: Mutex<LruCache<K, ValueWithTTL<V>>>,
+    /// The maximum fraction of the ttl that an entry may expire early
+    ttl_jitter: f64,
+    ttl_limits: TtlLimits,
+    /// Log the entries that are removed because of the capacity or their ttl
+    log_evictions: bool,
+    size_limit: Option<SizeLimit<K, V>>,
+    /// The summed size of the entries, only kept track of with a size limit
+    bytes: AtomicUsize,
+    /// How long expired entries are kept for, to be answered stale
+    keep_expired: Duration,
+}
+
+/// Limits the approximate number of bytes that the entries of a cache occupy
+#[derive(Debug)]
+struct SizeLimit<K, V> {
+    max_bytes: usize,
+    size: fn(&K, &V) -> usize,
+}
+
+/// The lowest and highest ttl, in seconds, that records are cached with
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TtlRange {
+    pub min: u32,
+    pub max: u32,
+}
+
+impl Default for TtlRange {
+    fn default() -> Self {
+        TtlRange { min: 0, max: u32::MAX }
+    }
+}
+
+/// Parses '<min>:<max>', for example '60:86400'
+impl FromStr for TtlRange {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((min, max)) = s.split_once(':') else {
+            bail!("expected '<min>:<max>', got '{s}'");
+        };
+        let range = TtlRange { min: min.parse()?, max: max.parse()? };
+        if range.min > range.max {
+            bail!("the min ttl {} is larger than the max ttl {}", range.min, range.max);
+        }
+        Ok(range)
+    }
+}
+
+/// A TtlRange that applies to the records of a single type
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TtlOverride {
+    pub record_type: RecordType,
+    pub range: TtlRange,
+}
+
+/// Parses '<type>=<min>:<max>', for example 'NS=3600:86400'
+impl FromStr for TtlOverride {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((record_type, range)) = s.split_once('=') else {
+            bail!("expected '<type>=<min>:<max>', got '{s}'");
+        };
+        Ok(TtlOverride { record_type: record_type.parse()?, range: range.parse()? })
+    }
+}
+
+/// The ttl ranges to clamp the ttl of cached records to, by type, falling back to the
+/// range that applies to all types. The records of referrals can have ranges of their own.
+#[derive(Debug, Clone, Default)]
+pub struct TtlLimits {
+    pub default: TtlRange,
+    pub overrides: Vec<TtlOverride>,
+    /// Applies to the NS records of delegations instead of the ranges above. Delegations
+    /// rarely change, so they can be trusted for longer.
+    pub delegation: Option<TtlRange>,
+    /// Applies to the glue of referrals instead of the ranges above, to have the addresses
+    /// of nameservers checked more often than their delegation
+    pub glue: Option<TtlRange>,
+}
+
+/// Where cached records come from, which decides the ttl range that applies to them
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Source {
+    Answer,
+    Delegation,
+    Glue,
+}
+
+impl TtlLimits {
+    fn clamp(&self, record_type: RecordType, source: Source, ttl: u32) -> u32 {
+        let referral = match source {
+            Source::Answer => None,
+            Source::Delegation => self.delegation,
+            Source::Glue => self.glue,
+        };
+        let range = self.overrides.iter().find(|o| o.record_type == record_type);
+        let range = referral.unwrap_or_else(|| range.map_or(self.default, |o| o.range));
+        ttl.clamp(range.min, range.max)
+    }
 }
 
 struct ValueWithTTL<V> {
     value: V,
     valid_before: Instant,
+    /// The size of the entry, as measured by the size limit
+    size: usize,
 }
 
 /// This is an LRU cache with TTL support with locking to enable multiple threads getting and
 /// storing values.
 impl<K: Hash + Eq + Debug, V: Clone + Debug> Cache<K, V> {
     pub(crate) fn new(capacity: NonZeroUsize) -> Cache<K, V> {
-        Cache { lru: Mutex::new(LruCache::new(capacity)) }
+        Cache {
+            lru: Mutex::new(LruCache::new(capacity)),
+            ttl_jitter: 0.0,
+            ttl_limits: TtlLimits::default(),
+            log_evictions: false,
+            size_limit: None,
+            bytes: AtomicUsize::new(0),
+            keep_expired: Duration::ZERO,
+        }
+    }
+
+    /// Keeps the entries for `keep_expired` after they expire, for get_stale to find them.
+    /// Only the limits on the size of the cache remove them before that.
+    pub(crate) fn with_expired_kept(mut self, keep_expired: Duration) -> Self {
+        self.keep_expired = keep_expired;
+        self
+    }
+
+    /// Makes entries expire up to `ttl_jitter` times their ttl earlier, chosen at random.
+    /// This spreads out the expiry of entries stored at the same time with the same ttl,
+    /// such as the records of a referral, so that they don't all need to be re-resolved at once.
+    pub(crate) fn with_ttl_jitter(mut self, ttl_jitter: f64) -> Self {
+        self.ttl_jitter = ttl_jitter;
+        self
     }
+
+    /// Changes the ttl that records are cached with, limiting it to a configured range
+    pub(crate) fn with_ttl_limits(mut self, ttl_limits: TtlLimits) -> Self {
+        self.ttl_limits = ttl_limits;
+        self
+    }
+
+    /// The ttl that entries are cached with at most, unless their type has a range of its own
+    pub(crate) fn max_ttl(&self) -> u32 {
+        self.ttl_limits.default.max
+    }
+
+    /// Logs every entry that is evicted to make room for another, or removed once it has
+    /// expired, at debug level. This helps understanding how much the cache churns.
+    pub(crate) fn with_eviction_logging(mut self) -> Self {
+        self.log_evictions = true;
+        self
+    }
+
+    /// Evicts the least recently used entries when the entries together are larger than
+    /// `max_bytes`, in addition to the limit on the number of entries. The capacity alone
+    /// lets a few huge entries occupy lots of memory. `size` approximates the size of an
+    /// entry, in bytes.
+    pub(crate) fn with_max_bytes(mut self, max_bytes: usize, size: fn(&K, &V) -> usize) -> Self {
+        self.size_limit = Some(SizeLimit { max_bytes, size });
+        self
+    }
+
     fn store_with_ttl(&self, key: K, value: V, valid_before: Instant) {
-        self.lru.lock().unwrap().put(key, ValueWithTTL { value, valid_before });
+        let size = self.size_limit.as_ref().map_or(0, |limit| (limit.size)(&key, &value));
+        let mut guard = self.lru.lock().unwrap();
+        let replaced = guard.contains(&key);
+        let evicted = guard.push(key, ValueWithTTL { value, valid_before, size });
+        self.bytes.fetch_add(size, Ordering::Relaxed);
+        if let Some((evicted, with_ttl)) = evicted {
+            self.bytes.fetch_sub(with_ttl.size, Ordering::Relaxed);
+            if !replaced {
+                self.evicted(&evicted, "capacity");
+            }
+        }
+        let Some(limit) = &self.size_limit else {
+            return;
+        };
+        // an entry larger than the limit on its own evicts itself as well
+        while self.bytes.load(Ordering::Relaxed) > limit.max_bytes {
+            let Some((evicted, with_ttl)) = guard.pop_lru() else {
+                break;
+            };
+            self.bytes.fetch_sub(with_ttl.size, Ordering::Relaxed);
+            self.evicted(&evicted, "size");
+        }
+    }
+
+    fn evicted(&self, key: &K, reason: &str) {
+        if self.log_evictions {
+            debug!(?key, reason, "Evicted from the cache");
+        }
     }
 
     #[instrument(name = "cache-get", skip(self), fields(hit = false, expired = false))]
@@ -36,8 +230,13 @@ impl<K: Hash + Eq + Debug, V: Clone + Debug> Cache<K, V> {
         let span = tracing::Span::current();
         let with_ttl = guard.get(key)?;
         if with_ttl.valid_before < now {
-            // the value has expired, remove it
-            guard.pop(key);
+            // the value has expired, remove it unless it can still be answered stale
+            if with_ttl.valid_before + self.keep_expired < now {
+                if let Some(with_ttl) = guard.pop(key) {
+                    self.bytes.fetch_sub(with_ttl.size, Ordering::Relaxed);
+                }
+                self.evicted(key, "expired");
+            }
             span.record("expired", true);
             None
         } else {
@@ -45,9 +244,97 @@ impl<K: Hash + Eq + Debug, V: Clone + Debug> Cache<K, V> {
             Some((with_ttl.value.clone(), with_ttl.valid_before - now))
         }
     }
+
+    /// Stores the value, valid for `ttl` from `now`
+    pub(crate) fn insert(&self, key: K, value: V, ttl: Duration, now: Instant) {
+        self.store_with_ttl(key, value, now + ttl);
+    }
+
+    pub(crate) fn get(&self, key: &K, now: Instant) -> Option<V> {
+        self.get_with_remaining_ttl(key, now).map(|(value, _)| value)
+    }
+
+    /// Returns the value even if it has expired, as long as it expired at most `max_stale`
+    /// ago and is still kept, and the time it has left or has been expired for. Expired
+    /// entries are kept.
+    fn get_stale(&self, key: &K, now: Instant, max_stale: Duration) -> Option<(V, Duration)> {
+        let guard = self.lru.lock().unwrap();
+        let with_ttl = guard.peek(key)?;
+        match with_ttl.valid_before.checked_duration_since(now) {
+            Some(remaining) => Some((with_ttl.value.clone(), remaining)),
+            None if with_ttl.valid_before + max_stale.min(self.keep_expired) >= now => {
+                Some((with_ttl.value.clone(), Duration::ZERO))
+            }
+            None => None,
+        }
+    }
+
+    /// Removes the entry of the key, if there is one
+    pub(crate) fn remove(&self, key: &K) {
+        if let Some(with_ttl) = self.lru.lock().unwrap().pop(key) {
+            self.bytes.fetch_sub(with_ttl.size, Ordering::Relaxed);
+        }
+    }
+
+    /// Removes the entries with a value that `remove` returns true for
+    pub(crate) fn remove_matching(&self, remove: impl Fn(&V) -> bool)
+    where
+        K: Clone,
+    {
+        let mut guard = self.lru.lock().unwrap();
+        let keys: Vec<K> = guard
+            .iter()
+            .filter(|(_, with_ttl)| remove(&with_ttl.value))
+            .map(|(k, _)| k.clone())
+            .collect();
+        for key in keys {
+            if let Some(with_ttl) = guard.pop(&key) {
+                self.bytes.fetch_sub(with_ttl.size, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Removes all entries
+    pub(crate) fn clear(&self) {
+        let mut guard = self.lru.lock().unwrap();
+        guard.clear();
+        self.bytes.store(0, Ordering::Relaxed);
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.lru.lock().unwrap().len()
+    }
+
+    /// Returns the values that haven't expired and their remaining ttl, without
+    /// affecting the order of eviction
+    fn entries(&self, now: Instant) -> Vec<(V, Duration)> {
+        let guard = self.lru.lock().unwrap();
+        guard
+            .iter()
+            .filter(|(_, with_ttl)| with_ttl.valid_before >= now)
+            .map(|(_, with_ttl)| (with_ttl.value.clone(), with_ttl.valid_before - now))
+            .collect()
+    }
 }
 
-pub(crate) type DnsCache = Cache<Query, Vec<Record>>;
+pub(crate) type DnsCache = Cache<Query, CachedRecords>;
+
+/// The size of the name of the query and the records, uncompressed in the wire format. This
+/// ignores the overhead of the entry in memory, which is about the same for all entries.
+fn records_size(query: &Query, value: &CachedRecords) -> usize {
+    let records: usize = value.records.iter().map(|r| r.to_bytes().map_or(0, |b| b.len())).sum();
+    query.to_resolve.len() + records
+}
+
+/// The records of an RRset and where they came from. The NS records of a delegation, from
+/// the authority section of a referral, are followed to the child zone but, unlike the NS
+/// records at the zone apex, RFC 2181 section 5.4.1, they are not authoritative and never
+/// returned as an answer.
+#[derive(Debug, Clone)]
+pub(crate) struct CachedRecords {
+    records: Vec<Record>,
+    source: Source,
+}
 
 #[derive(Hash, Eq, PartialEq, Clone)]
 pub(crate) struct Query {
@@ -62,6 +349,51 @@ impl Debug for Query {
     }
 }
 
+/// A cached NXDOMAIN or NODATA response, RFC 2308
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct NegativeAnswer {
+    /// The apex of the zone that the negative answer comes from, the owner of its SOA
+    pub zone: Name,
+    pub nxdomain: bool,
+    pub ttl: Duration,
+    /// The SOA, NSEC and NSEC3 records of the authority section and their signatures, which
+    /// prove that the name or the type doesn't exist, RFC 4035 section 3.1.3
+    pub proof: Vec<Record>,
+}
+
+impl NegativeAnswer {
+    /// Creates a NegativeAnswer from the SOA in the authority section of an NXDOMAIN or
+    /// NODATA response. Without an SOA the response can't be cached, RFC 2308 section 5.
+    pub(crate) fn from_response(response: &Message, max_ttl: u32) -> Option<Self> {
+        let soa = response.name_servers().iter().find(|r| r.record_type() == RecordType::SOA)?;
+        Some(NegativeAnswer {
+            zone: soa.name().clone(),
+            nxdomain: response.response_code() == ResponseCode::NXDomain,
+            ttl: Duration::from_secs(u64::from(negative_ttl(soa, max_ttl)?)),
+            proof: response
+                .name_servers()
+                .iter()
+                .filter(|r| {
+                    matches!(
+                        r.record_type(),
+                        RecordType::SOA | RecordType::NSEC | RecordType::NSEC3 | RecordType::RRSIG
+                    )
+                })
+                .cloned()
+                .collect(),
+        })
+    }
+}
+
+/// The ttl of a negative answer, the smaller of the ttl of its SOA record and the minimum
+/// field of the SOA, RFC 2308 section 5, and at most max_ttl
+fn negative_ttl(soa: &Record, max_ttl: u32) -> Option<u32> {
+    let Some(RData::SOA(rdata)) = soa.data() else {
+        return None;
+    };
+    Some(soa.ttl().min(rdata.minimum()).min(max_ttl))
+}
+
 #[derive(Debug, PartialEq)]
 pub(crate) enum CacheResponse {
     /// the AA flag was set on the message containing this answer
@@ -74,6 +406,11 @@ pub(crate) enum CacheResponse {
 
 /// Some convenient methods for Caches that holds DNS data
 impl DnsCache {
+    /// Limits the size of the cached records, as they would be in the wire format
+    pub(crate) fn with_max_record_bytes(self, max_bytes: usize) -> Self {
+        self.with_max_bytes(max_bytes, records_size)
+    }
+
     /// extracts the ttl from the Record to be stored, to make it a bit more ergonomic to use
     #[instrument(name = "cache-store", skip(self), fields(count = value.len()))]
     pub(crate) fn store(&self, query: Query, value: Vec<Record>, now: Instant) {
@@ -82,13 +419,20 @@ impl DnsCache {
 
     // This lives in a private method to avoid generating tracing spans for all the stores
     // that gets spawned by store_referral when the top level span is enough
-    fn inner_store(&self, query: Query, value: Vec<Record>, now: Instant) {
-        let min_ttl = value.iter().map(Record::ttl).min().unwrap_or(0);
+    fn inner_store(&self, query: Query, records: Vec<Record>, now: Instant) {
+        self.store_from(query, records, Source::Answer, now)
+    }
+
+    fn store_from(&self, query: Query, records: Vec<Record>, source: Source, now: Instant) {
+        let min_ttl = records.iter().map(Record::ttl).min().unwrap_or(0);
+        // records with a zero ttl are meant to not be cached at all, even with a min ttl
         if min_ttl == 0 {
             return;
         }
+        let min_ttl = self.ttl_limits.clamp(query.record_type, source, min_ttl);
         let min_ttl = Duration::from_secs(min_ttl as u64);
-        self.store_with_ttl(query, value, now + min_ttl);
+        let jitter = min_ttl.mul_f64(thread_rng().gen_range(0.0..=self.ttl_jitter));
+        self.store_with_ttl(query, CachedRecords { records, source }, now + min_ttl - jitter);
     }
 
     /// a version of store that will validate referral style responses and
@@ -104,26 +448,110 @@ impl DnsCache {
         if !eligible(&name_servers, &glue, to_resolve) {
             return;
         }
+        let glue = in_bailiwick_glue(&name_servers, glue);
         for (query, records) in make_referral_query(&name_servers) {
-            self.inner_store(query, records, now)
+            let is_ns = query.record_type == RecordType::NS;
+            // the NS records from the apex of the zone outrank those of the delegation
+            if is_ns && self.get_entry(&query, now).is_some_and(|(_, delegation)| !delegation) {
+                continue;
+            }
+            let source = if is_ns { Source::Delegation } else { Source::Answer };
+            self.store_from(query, records, source, now)
         }
         for (query, records) in make_referral_query(&glue) {
-            self.inner_store(query, records, now)
+            self.store_from(query, records, Source::Glue, now)
+        }
+    }
+
+    /// Stores each RRset in `records` under its own name and type, except for the one
+    /// answering `query` which is stored separately once any CNAMEs have been followed
+    pub(crate) fn store_related(&self, records: Vec<Record>, query: &Query, now: Instant) {
+        for (key, records) in make_referral_query(&records) {
+            if key != *query {
+                self.inner_store(key, records, now)
+            }
         }
     }
 
+    /// Returns all the records in the cache, with their remaining ttl
+    pub(crate) fn dump(&self, now: Instant) -> Vec<Record> {
+        self.entries(now)
+            .into_iter()
+            .flat_map(|(value, ttl)| update_ttl((value.records, ttl)))
+            .collect()
+    }
+
+    /// Returns the cached records in zone file format, one record per line with owner,
+    /// remaining ttl, class, type and rdata. The records are grouped by owner name.
+    pub(crate) fn zone_file(&self, now: Instant) -> String {
+        let mut records = self.dump(now);
+        records.sort_by(|a, b| a.name().cmp(b.name()));
+        records.iter().map(|r| format!("{r}\n")).collect()
+    }
+
+    /// Returns the answer to the query even if it expired at most `max_stale` ago, RFC 8767.
+    /// Expired records are given the ttl of STALE_TTL.
+    pub(crate) fn get_stale_answer(
+        &self,
+        query: &Query,
+        now: Instant,
+        max_stale: Duration,
+    ) -> Option<Vec<Record>> {
+        let (value, remaining) = self.get_stale(query, now, max_stale)?;
+        if value.source == Source::Delegation {
+            return None;
+        }
+        let ttl = match remaining.is_zero() {
+            true => Duration::from_secs(STALE_TTL),
+            false => remaining,
+        };
+        Some(update_ttl((value.records, ttl)))
+    }
+
     fn get_and_update_ttl(&self, query: &Query, now: Instant) -> Option<Vec<Record>> {
-        self.get_with_remaining_ttl(query, now).map(update_ttl)
+        self.get_entry(query, now).map(|(records, _)| records)
+    }
+
+    /// Returns the records with updated ttl, and if they are the NS records of a delegation
+    fn get_entry(&self, query: &Query, now: Instant) -> Option<(Vec<Record>, bool)> {
+        self.get_with_remaining_ttl(query, now).map(|(value, ttl)| {
+            let delegation = value.source == Source::Delegation;
+            (update_ttl((value.records, ttl)), delegation)
+        })
+    }
+
+    /// Returns true if the cached records for the query are the glue of a referral, rather
+    /// than an answer from the zone of the name
+    pub(crate) fn holds_glue(&self, query: &Query, now: Instant) -> bool {
+        self.get_with_remaining_ttl(query, now)
+            .is_some_and(|(value, _)| value.source == Source::Glue)
     }
 
     pub(crate) fn get_best_record(&self, query: &Query, now: Instant) -> CacheResponse {
-        if let Some(records) = self.get_and_update_ttl(query, now) {
-            return Authoritative(records);
+        match self.get_entry(query, now) {
+            Some((records, false)) => return Authoritative(records),
+            // only the servers of the zone itself can answer for its apex NS records
+            Some((records, true)) => {
+                return Referral(records.clone(), self.fetch_glue(&records, now));
+            }
+            None => {}
+        }
+        let mut parents = parents(&query.to_resolve);
+        // the root nameservers, if they have been primed
+        if !query.to_resolve.is_root() {
+            parents.push(Name::root());
         }
-        for parent in parents(&query.to_resolve) {
+        for parent in parents {
+            let is_root = parent.is_root();
             let q = Query { to_resolve: parent, record_type: RecordType::NS };
             if let Some(records) = self.get_and_update_ttl(&q, now) {
-                return Referral(records.clone(), self.fetch_glue(&records, now));
+                let glue = self.fetch_glue(&records, now);
+                // the names of the root nameservers can only be resolved through the roots
+                // themselves, so without their addresses resolution starts at the built in ones
+                if is_root && glue.is_empty() {
+                    break;
+                }
+                return Referral(records, glue);
             }
         }
         CacheResponse::None
@@ -164,7 +592,12 @@ fn make_referral_query(records: &Vec<Record>) -> HashMap<Query, Vec<Record>> {
         return result;
     }
     for record in records {
-        let query = Query { to_resolve: record.name().clone(), record_type: record.record_type() };
+        // the RRSIG records are kept with the RRset that they cover
+        let record_type = match record.data() {
+            Some(RData::DNSSEC(DNSSECRData::RRSIG(sig))) => sig.type_covered(),
+            _ => record.record_type(),
+        };
+        let query = Query { to_resolve: record.name().clone(), record_type };
         result.entry(query).or_insert_with(Vec::new).push(record.clone());
     }
     result
@@ -177,7 +610,7 @@ fn eligible(name_servers: &Vec<Record>, glue: &Vec<Record>, to_resolve: &Name) -
     let mut names = HashSet::new();
     for name_server in name_servers {
         if let Some(RData::NS(ns)) = name_server.data() {
-            names.insert(ns.0.to_string());
+            names.insert(&ns.0);
         }
 
         if !name_server.name().zone_of(to_resolve) {
@@ -186,7 +619,8 @@ fn eligible(name_servers: &Vec<Record>, glue: &Vec<Record>, to_resolve: &Name) -
         }
     }
     for glue in glue {
-        if !names.contains(&glue.name().to_string()) {
+        // Name compares and hashes case-insensitively, as servers might not echo the casing
+        if !names.contains(glue.name()) {
             debug!(%glue, "Glue record without matching NS");
             return false;
         }
@@ -194,6 +628,23 @@ fn eligible(name_servers: &Vec<Record>, glue: &Vec<Record>, to_resolve: &Name) -
     true
 }
 
+/// Returns the glue records within the delegated zone. Any server in the path can add
+/// addresses for names outside of it, so those names are resolved instead of trusting
+/// the glue, as the addresses would otherwise end up in the cache.
+fn in_bailiwick_glue(name_servers: &[Record], glue: Vec<Record>) -> Vec<Record> {
+    glue.into_iter()
+        .filter(|g| {
+            let in_bailiwick = name_servers
+                .iter()
+                .any(|ns| ns.record_type() == RecordType::NS && ns.name().zone_of(g.name()));
+            if !in_bailiwick {
+                debug!(glue = %g, "Not caching out-of-bailiwick glue");
+            }
+            in_bailiwick
+        })
+        .collect()
+}
+
 /// Creates and returns a copy of Vec<Record> replacing the ttl value in each of the records with
 /// the passed duration.
 fn update_ttl(item: (Vec<Record>, Duration)) -> Vec<Record> {
@@ -211,12 +662,17 @@ fn update_ttl(item: (Vec<Record>, Duration)) -> Vec<Record> {
 mod tests {
     use crate::cache::CacheResponse::{Authoritative, Referral};
     use crate::cache::{
-        eligible, make_referral_query, parents, update_ttl, Cache, CacheResponse, DnsCache, Query,
+        eligible, in_bailiwick_glue, make_referral_query, parents, update_ttl, Cache,
+        CacheResponse, DnsCache, NegativeAnswer, Query, TtlLimits, TtlOverride, TtlRange,
     };
     use crate::{a, name, ns};
     use anyhow::Result;
+    use hickory_proto::op::{Message, ResponseCode};
+    use hickory_proto::rr::dnssec::rdata::{DNSSECRData, DS, RRSIG};
+    use hickory_proto::rr::dnssec::{Algorithm, DigestType};
+    use hickory_proto::rr::rdata::{SOA, TXT};
     use hickory_proto::rr::{rdata, Name, RData, Record, RecordType};
-    use std::collections::HashMap;
+    use std::collections::{HashMap, HashSet};
     use std::num::NonZeroUsize;
     use std::str::FromStr;
     use std::time::{Duration, Instant};
@@ -253,6 +709,81 @@ mod tests {
         assert!(cache.get_with_remaining_ttl(&"key42".to_owned(), now).is_none());
     }
 
+    /// Collects the formatted log output
+    #[derive(Clone, Default)]
+    struct LogBuffer(std::sync::Arc<std::sync::Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_eviction_logging() {
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .with_max_level(tracing::Level::DEBUG)
+            .with_ansi(false)
+            .with_writer(move || writer.clone())
+            .finish();
+        tracing::subscriber::with_default(subscriber, || {
+            let cache = Cache::new(NonZeroUsize::new(2).unwrap()).with_eviction_logging();
+            let now = Instant::now();
+            for i in 0..4 {
+                cache.insert(format!("key{i}"), "value", Duration::from_secs(10), now);
+            }
+            // replacing a value isn't an eviction
+            cache.insert("key3".to_string(), "value", Duration::from_secs(10), now);
+            cache.get(&"key2".to_string(), now + Duration::from_secs(20));
+        });
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone()).unwrap();
+        let evictions: Vec<&str> = logs.lines().filter(|l| l.contains("Evicted")).collect();
+        assert_eq!(evictions.len(), 3, "{logs}");
+        assert!(evictions[0].contains(r#"key="key0" reason="capacity""#), "{logs}");
+        assert!(evictions[1].contains(r#"key="key1" reason="capacity""#), "{logs}");
+        assert!(evictions[2].contains(r#"key="key2" reason="expired""#), "{logs}");
+    }
+
+    #[test]
+    fn test_max_bytes() -> Result<()> {
+        let cache = DnsCache::new(NonZeroUsize::new(10).unwrap()).with_max_record_bytes(200);
+        let now = Instant::now();
+        for i in 0..3 {
+            let name = format!("host{i}.example.com");
+            cache.store(query!(&name, RecordType::A), vec![a!(&name, "127.0.0.1")], now);
+        }
+        assert_eq!(cache.len(), 3);
+        // the TXT record alone takes up most of the budget
+        let txt = Record::from_rdata(
+            name!("big.example.com."),
+            60,
+            RData::TXT(TXT::new(vec!["a".repeat(100)])),
+        );
+        cache.store(query!("big.example.com", RecordType::TXT), vec![txt], now);
+        assert_eq!(cache.len(), 2);
+        assert!(cache.get(&query!("host0.example.com", RecordType::A), now).is_none());
+        assert!(cache.get(&query!("host1.example.com", RecordType::A), now).is_none());
+        assert!(cache.get(&query!("host2.example.com", RecordType::A), now).is_some());
+        assert!(cache.get(&query!("big.example.com", RecordType::TXT), now).is_some());
+
+        // an entry larger than the budget isn't kept
+        let huge = Record::from_rdata(
+            name!("huge.example.com."),
+            60,
+            RData::TXT(TXT::new(vec!["a".repeat(250)])),
+        );
+        cache.store(query!("huge.example.com", RecordType::TXT), vec![huge], now);
+        assert_eq!(cache.len(), 0);
+        Ok(())
+    }
+
     #[test]
     fn test_update_ttl() -> Result<()> {
         let mut record = a!("example.com", "127.0.0.1");
@@ -279,6 +810,84 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_ttl_limits() -> Result<()> {
+        let limits = TtlLimits {
+            default: TtlRange { min: 0, max: 600 },
+            overrides: vec![
+                TtlOverride::from_str("NS=3600:86400")?,
+                TtlOverride::from_str("A=0:300")?,
+            ],
+            ..TtlLimits::default()
+        };
+        let cache = DnsCache::new(NonZeroUsize::new(10).unwrap()).with_ttl_limits(limits);
+        let mut ns = ns!("example.com.", "ns.example.com.");
+        ns.set_ttl(1200);
+        let mut a = a!("ns.example.com.", "10.0.0.1");
+        a.set_ttl(1200);
+        let mx = RData::MX(rdata::MX::new(10, name!("mail.example.com.")));
+        let mx = Record::from_rdata(name!("example.com."), 1200, mx);
+        let when = Instant::now();
+        cache.store(query!("example.com.", RecordType::NS), vec![ns], when);
+        cache.store(query!("ns.example.com.", RecordType::A), vec![a], when);
+        cache.store(query!("example.com.", RecordType::MX), vec![mx], when);
+
+        // the same source ttl ends up different for each type
+        let ttl = |query: Query| cache.get_and_update_ttl(&query, when).map(|r| r[0].ttl());
+        assert_eq!(ttl(query!("example.com.", RecordType::NS)), Some(3600));
+        assert_eq!(ttl(query!("ns.example.com.", RecordType::A)), Some(300));
+        assert_eq!(ttl(query!("example.com.", RecordType::MX)), Some(600));
+        Ok(())
+    }
+
+    #[test]
+    fn test_referral_ttl_limits() -> Result<()> {
+        let limits = TtlLimits {
+            delegation: Some(TtlRange { min: 3600, max: 86400 }),
+            glue: Some(TtlRange { min: 0, max: 60 }),
+            ..TtlLimits::default()
+        };
+        let cache = DnsCache::new(NonZeroUsize::new(10).unwrap()).with_ttl_limits(limits);
+        let mut ns = ns!("example.com.", "ns.example.com.");
+        ns.set_ttl(300);
+        let mut glue = a!("ns.example.com.", "10.0.0.1");
+        glue.set_ttl(300);
+        let when = Instant::now();
+        cache.store_referral(vec![ns], vec![glue], &name!("www.example.com."), when);
+
+        // the delegation and its glue come with the same ttl but are kept for different times
+        let ttl = |query: Query| cache.get_and_update_ttl(&query, when).map(|r| r[0].ttl());
+        assert_eq!(ttl(query!("example.com.", RecordType::NS)), Some(3600));
+        assert_eq!(ttl(query!("ns.example.com.", RecordType::A)), Some(60));
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_ttl_override() {
+        assert!(TtlOverride::from_str("NS=10:20").is_ok());
+        assert!(TtlOverride::from_str("NS=20:10").is_err());
+        assert!(TtlOverride::from_str("NS:10:20").is_err());
+        assert!(TtlOverride::from_str("XYZZY=10:20").is_err());
+    }
+
+    #[test]
+    fn test_ttl_jitter() -> Result<()> {
+        let cache = DnsCache::new(NonZeroUsize::new(100).unwrap()).with_ttl_jitter(0.05);
+        let now = Instant::now();
+        for i in 0..100 {
+            let mut record = a!(format!("host{i}.example.com"), "127.0.0.1");
+            record.set_ttl(1000);
+            cache.store(query!(format!("host{i}.example.com"), RecordType::A), vec![record], now);
+        }
+        let expiries: HashSet<Instant> =
+            cache.lru.lock().unwrap().iter().map(|(_, v)| v.valid_before).collect();
+        assert!(expiries.len() > 1);
+        let earliest = now + Duration::from_secs(950);
+        let latest = now + Duration::from_secs(1000);
+        assert!(expiries.iter().all(|e| (earliest..=latest).contains(e)));
+        Ok(())
+    }
+
     #[test]
     fn test_get_and_update_ttl() -> Result<()> {
         let mut record = a!("example.com", "127.0.0.1");
@@ -294,6 +903,101 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_negative_answer() -> Result<()> {
+        let soa = SOA::new(name!("ns.example."), name!("hostmaster.example."), 1, 2, 3, 4, 5);
+        let soa = Record::from_rdata(name!("example."), 300, RData::SOA(soa));
+        let mut response = Message::new();
+        response.set_response_code(ResponseCode::NXDomain);
+        response.add_name_server(soa.clone());
+        response.add_name_server(ns!("example.", "ns.example."));
+        // the response for a.b.example. is scoped to the zone of the SOA, with the minimum
+        // field as the ttl as it is smaller than the ttl of the SOA record. Only the SOA is
+        // kept to prove it, as there are no NSEC records.
+        let expected = NegativeAnswer {
+            zone: name!("example."),
+            nxdomain: true,
+            ttl: Duration::from_secs(5),
+            proof: vec![soa],
+        };
+        assert_eq!(NegativeAnswer::from_response(&response, u32::MAX), Some(expected));
+
+        response.take_name_servers();
+        assert_eq!(NegativeAnswer::from_response(&response, u32::MAX), None);
+        Ok(())
+    }
+
+    #[test]
+    fn test_negative_ttl() -> Result<()> {
+        let soa = |ttl: u32, minimum: u32| {
+            let soa =
+                SOA::new(name!("ns.example."), name!("hostmaster.example."), 1, 2, 3, 4, minimum);
+            Ok::<_, anyhow::Error>(Record::from_rdata(name!("example."), ttl, RData::SOA(soa)))
+        };
+        let ttl = |ttl: u32, minimum: u32, max_ttl: u32| -> Result<Option<Duration>> {
+            let mut response = Message::new();
+            response.add_name_server(soa(ttl, minimum)?);
+            // NODATA and NXDOMAIN responses get the same ttl
+            let nodata = NegativeAnswer::from_response(&response, max_ttl).map(|n| n.ttl);
+            response.set_response_code(ResponseCode::NXDomain);
+            let nxdomain = NegativeAnswer::from_response(&response, max_ttl).map(|n| n.ttl);
+            assert_eq!(nodata, nxdomain);
+            Ok(nxdomain)
+        };
+        assert_eq!(ttl(60, 300, u32::MAX)?, Some(Duration::from_secs(60)));
+        assert_eq!(ttl(300, 60, u32::MAX)?, Some(Duration::from_secs(60)));
+        assert_eq!(ttl(3600, 900, 300)?, Some(Duration::from_secs(300)));
+        Ok(())
+    }
+
+    #[test]
+    fn test_dump() -> Result<()> {
+        let mut short = a!("a.example.com", "127.0.0.1");
+        short.set_ttl(5);
+        let mut long = a!("b.example.com", "127.0.0.2");
+        long.set_ttl(47);
+        let cache = DnsCache::new(NonZeroUsize::new(10).unwrap());
+        let when = Instant::now();
+        cache.store(query!("a.example.com", RecordType::A), vec![short], when);
+        cache.store(query!("b.example.com", RecordType::A), vec![long.clone()], when);
+
+        // the expired entry is left out, but not removed
+        long.set_ttl(37);
+        assert_eq!(cache.dump(when + Duration::from_secs(10)), [long]);
+        assert_eq!(cache.len(), 2);
+        cache.clear();
+        assert_eq!(cache.len(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn test_zone_file() -> Result<()> {
+        let cache = DnsCache::new(NonZeroUsize::new(10).unwrap());
+        let when = Instant::now();
+        cache.store(
+            query!("b.example.com.", RecordType::A),
+            vec![a!("b.example.com.", "10.0.0.2")],
+            when,
+        );
+        let ns =
+            vec![ns!("example.com.", "ns1.example.com."), ns!("example.com.", "ns2.example.com.")];
+        cache.store(query!("example.com.", RecordType::NS), ns, when);
+        cache.store(
+            query!("a.example.com.", RecordType::A),
+            vec![a!("a.example.com.", "10.0.0.1")],
+            when,
+        );
+
+        assert_eq!(
+            cache.zone_file(when + Duration::from_secs(10)),
+            "example.com. 50 IN NS ns1.example.com.\n\
+             example.com. 50 IN NS ns2.example.com.\n\
+             a.example.com. 50 IN A 10.0.0.1\n\
+             b.example.com. 50 IN A 10.0.0.2\n"
+        );
+        Ok(())
+    }
+
     #[test]
     fn test_eligible() -> Result<()> {
         let to_resolve: Name = "example.com.".parse()?;
@@ -317,6 +1021,32 @@ mod tests {
             &vec![a!("dns.foo.com", "127.0.0.1")],
             &to_resolve
         ));
+        // parsing lowercases names, from_ascii keeps the casing like names off the wire
+        let ns = RData::NS(rdata::NS(Name::from_ascii("dns.FOO.com")?));
+        let glue = RData::A(rdata::A("127.0.0.1".parse()?));
+        assert!(eligible(
+            &vec![Record::from_rdata(Name::from_ascii("COM")?, 60, ns)],
+            &vec![Record::from_rdata(Name::from_ascii("dns.foo.COM")?, 60, glue)],
+            &to_resolve
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn test_in_bailiwick_glue() -> Result<()> {
+        let name_servers =
+            vec![ns!("b.example.", "ns.b.example."), ns!("b.example.", "ns.c.example.")];
+        let glue = vec![a!("ns.b.example.", "10.0.0.1"), a!("ns.c.example.", "10.0.0.2")];
+        assert_eq!(in_bailiwick_glue(&name_servers, glue.clone()), [glue[0].clone()]);
+
+        let cache = DnsCache::new(NonZeroUsize::new(10).unwrap());
+        cache.store_referral(name_servers, glue, &name!("a.b.example."), Instant::now());
+        let cached =
+            cache.get_and_update_ttl(&query!("ns.b.example.", RecordType::A), Instant::now());
+        assert!(cached.is_some());
+        let cached =
+            cache.get_and_update_ttl(&query!("ns.c.example.", RecordType::A), Instant::now());
+        assert!(cached.is_none());
         Ok(())
     }
 
@@ -351,6 +1081,35 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_store_signatures() -> Result<()> {
+        let cache = DnsCache::new(NonZeroUsize::new(10).unwrap());
+        let rrsig = RRSIG::new(
+            RecordType::DS,
+            Algorithm::RSASHA256,
+            1,
+            60,
+            2,
+            1,
+            4711,
+            Name::root(),
+            vec![0],
+        );
+        let rrsig = Record::from_rdata(name!("com."), 60, RData::DNSSEC(DNSSECRData::RRSIG(rrsig)));
+        let ds = DS::new(4711, Algorithm::RSASHA256, DigestType::SHA256, vec![0; 32]);
+        let ds = Record::from_rdata(name!("com."), 60, RData::DNSSEC(DNSSECRData::DS(ds)));
+        cache.store_referral(
+            vec![ns!("com.", "a.com."), rrsig.clone(), ds.clone()],
+            vec![a!("a.com.", "127.0.0.1")],
+            &name!("example.com."),
+            Instant::now(),
+        );
+        // the signature is kept with the DS records it covers
+        let result = cache.get_and_update_ttl(&query!("com.", RecordType::DS), Instant::now());
+        assert_eq!(Some(vec![rrsig, ds]), result);
+        Ok(())
+    }
+
     #[test]
     fn test_store_invalid_referral() -> Result<()> {
         let cache = DnsCache::new(NonZeroUsize::new(10).unwrap());
@@ -425,6 +1184,39 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_delegation_and_apex_ns() -> Result<()> {
+        let cache = DnsCache::new(NonZeroUsize::new(10).unwrap());
+        let delegation = vec![ns!("b.example.com.", "ns.b.example.com.")];
+        let glue = vec![a!("ns.b.example.com.", "10.0.0.1")];
+        cache.store_referral(
+            delegation.clone(),
+            glue.clone(),
+            &name!("a.b.example.com."),
+            Instant::now(),
+        );
+
+        // the NS records of the delegation are followed, not returned as an answer
+        let ns_query = query!("b.example.com.", RecordType::NS);
+        let expected = Referral(delegation.clone(), glue.clone());
+        assert_eq!(cache.get_best_record(&ns_query, Instant::now()), expected);
+
+        let apex = vec![
+            ns!("b.example.com.", "ns.b.example.com."),
+            ns!("b.example.com.", "ns2.b.example.com."),
+        ];
+        cache.store(ns_query.clone(), apex.clone(), Instant::now());
+        assert_eq!(cache.get_best_record(&ns_query, Instant::now()), Authoritative(apex.clone()));
+        // a later referral doesn't replace the apex records
+        cache.store_referral(delegation, glue, &name!("c.b.example.com."), Instant::now());
+        assert_eq!(cache.get_best_record(&ns_query, Instant::now()), Authoritative(apex.clone()));
+        let a_query = query!("a.b.example.com.", RecordType::A);
+        assert!(
+            matches!(cache.get_best_record(&a_query, Instant::now()), Referral(ns, _) if ns == apex)
+        );
+        Ok(())
+    }
+
     #[test]
     fn tes_get_best_record_none() -> Result<()> {
         let cache = DnsCache::new(NonZeroUsize::new(1).unwrap());
@@ -0,0 +1,81 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+// tokio's clock, which the tests can pause and advance
+use tokio::time::Instant;
+
+/// The number of failed queries in a row that makes an upstream considered overloaded
+const OVERLOAD_FAILURES: u32 = 3;
+
+/// Keeps track of upstreams that keep failing, which is often how overload and rate
+/// limiting shows. An overloaded upstream isn't queried until its cooldown has passed.
+#[derive(Debug)]
+pub(crate) struct Cooldowns {
+    duration: Duration,
+    state: Mutex<HashMap<IpAddr, Upstream>>,
+}
+
+#[derive(Debug, Default)]
+struct Upstream {
+    /// The number of failures since the last successful query
+    failures: u32,
+    /// When the upstream may be queried again, if it is cooling down
+    until: Option<Instant>,
+}
+
+impl Cooldowns {
+    pub(crate) fn new(duration: Duration) -> Self {
+        Cooldowns { duration, state: Mutex::new(HashMap::new()) }
+    }
+
+    /// Returns when the cooldown of the upstream ends, if it is cooling down at `now`
+    pub(crate) fn cooling_down(&self, upstream: IpAddr, now: Instant) -> Option<Instant> {
+        let state = self.state.lock().unwrap();
+        state.get(&upstream).and_then(|u| u.until).filter(|until| *until > now)
+    }
+
+    /// Records a failed query, starting a cooldown if the upstream keeps failing
+    pub(crate) fn failed(&self, upstream: IpAddr, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        let entry = state.entry(upstream).or_default();
+        entry.failures += 1;
+        if entry.failures >= OVERLOAD_FAILURES {
+            entry.failures = 0;
+            entry.until = Some(now + self.duration);
+        }
+    }
+
+    pub(crate) fn succeeded(&self, upstream: IpAddr) {
+        self.state.lock().unwrap().remove(&upstream);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cooldown::{Cooldowns, OVERLOAD_FAILURES};
+    use std::net::IpAddr;
+    use std::time::Duration;
+    use tokio::time::Instant;
+
+    #[test]
+    fn test_cooldown() {
+        let cooldowns = Cooldowns::new(Duration::from_secs(30));
+        let upstream = IpAddr::from([10, 0, 0, 1]);
+        let now = Instant::now();
+        for _ in 1..OVERLOAD_FAILURES {
+            cooldowns.failed(upstream, now);
+        }
+        assert_eq!(cooldowns.cooling_down(upstream, now), None);
+        cooldowns.failed(upstream, now);
+        let until = now + Duration::from_secs(30);
+        assert_eq!(cooldowns.cooling_down(upstream, now + Duration::from_secs(10)), Some(until));
+        // the cooldown is over, and a success resets the failure count
+        assert_eq!(cooldowns.cooling_down(upstream, until), None);
+        cooldowns.failed(upstream, until);
+        cooldowns.succeeded(upstream);
+        cooldowns.failed(upstream, until);
+        cooldowns.failed(upstream, until);
+        assert_eq!(cooldowns.cooling_down(upstream, until), None);
+    }
+}
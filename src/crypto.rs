@@ -0,0 +1,431 @@
+use std::cmp::Ordering;
+
+const SHA1_INITIAL: [u32; 5] = [0x67452301, 0xefcdab89, 0x98badcfe, 0x10325476, 0xc3d2e1f0];
+
+const SHA256_INITIAL: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+const SHA256_ROUNDS: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+/// The DER encoded DigestInfo prefix of a SHA-256 digest in a PKCS #1 v1.5 signature,
+/// RFC 8017 section 9.2
+const SHA256_DIGEST_INFO: [u8; 19] = [
+    0x30, 0x31, 0x30, 0x0d, 0x06, 0x09, 0x60, 0x86, 0x48, 0x01, 0x65, 0x03, 0x04, 0x02, 0x01, 0x05,
+    0x00, 0x04, 0x20,
+];
+
+/// The smallest RSA modulus that signatures are accepted with, as shorter keys can be
+/// factored with modest effort
+const MIN_MODULUS_BITS: usize = 1024;
+
+/// Pads the data to a whole number of 64 byte blocks, with its length in bits at the end,
+/// as both SHA-1 and SHA-256 do, FIPS 180-4 section 5.1.1
+fn padded(data: &[u8]) -> Vec<u8> {
+    let mut padded = data.to_vec();
+    padded.push(0x80);
+    while padded.len() % 64 != 56 {
+        padded.push(0);
+    }
+    padded.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+    padded
+}
+
+/// Returns the SHA-1 digest of the data, FIPS 180-4. It is only used for the hashed owner
+/// names of NSEC3 records, RFC 5155 section 5, where its weaknesses don't matter.
+pub(crate) fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut state = SHA1_INITIAL;
+    for block in padded(data).chunks_exact(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e] = state;
+        for (i, word) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5a827999),
+                20..=39 => (b ^ c ^ d, 0x6ed9eba1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8f1bbcdc),
+                _ => (b ^ c ^ d, 0xca62c1d6),
+            };
+            let t = a.rotate_left(5).wrapping_add(f).wrapping_add(e).wrapping_add(k);
+            (e, d, c, b, a) = (d, c, b.rotate_left(30), a, t.wrapping_add(*word));
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+    let mut digest = [0u8; 20];
+    for (chunk, s) in digest.chunks_exact_mut(4).zip(state) {
+        chunk.copy_from_slice(&s.to_be_bytes());
+    }
+    digest
+}
+
+/// Returns the SHA-256 digest of the data, FIPS 180-4
+pub(crate) fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut state = SHA256_INITIAL;
+    for block in padded(data).chunks_exact(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in block.chunks_exact(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16].wrapping_add(s0).wrapping_add(w[i - 7]).wrapping_add(s1);
+        }
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut h] = state;
+        for i in 0..64 {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = h
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(SHA256_ROUNDS[i])
+                .wrapping_add(w[i]);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            (h, g, f, e, d, c, b, a) = (g, f, e, d.wrapping_add(t1), c, b, a, t1.wrapping_add(t2));
+        }
+        for (s, v) in state.iter_mut().zip([a, b, c, d, e, f, g, h]) {
+            *s = s.wrapping_add(v);
+        }
+    }
+    let mut digest = [0u8; 32];
+    for (chunk, s) in digest.chunks_exact_mut(4).zip(state) {
+        chunk.copy_from_slice(&s.to_be_bytes());
+    }
+    digest
+}
+
+/// Verifies an RSASSA-PKCS1-v1_5 signature with SHA-256 of the message, RFC 8017 section
+/// 8.2.2. The public key is in the DNSKEY format of RFC 3110 section 2, the exponent length,
+/// the exponent and then the modulus. hickory-proto only verifies signatures when built
+/// with one of its crypto backends, which is why it is done here. Only public data is
+/// handled, so nothing needs to be constant time.
+pub(crate) fn verify_rsa_sha256(public_key: &[u8], message: &[u8], signature: &[u8]) -> bool {
+    let Some((exponent, modulus)) = split_public_key(public_key) else {
+        return false;
+    };
+    let modulus = Uint::from_be_bytes(modulus);
+    let length = modulus.byte_len();
+    // the signature is as long as the modulus, RFC 8017 section 8.2.2 step 1
+    if modulus.bit_len() < MIN_MODULUS_BITS || !modulus.bit(0) || signature.len() != length {
+        return false;
+    }
+    let signature = Uint::from_be_bytes(signature);
+    if signature.compare(&modulus) != Ordering::Less {
+        return false;
+    }
+    let Some(expected) = pkcs1_encoding(message, length) else {
+        return false;
+    };
+    signature.pow_mod(exponent, &modulus).to_be_bytes(length) == expected
+}
+
+/// Splits an RFC 3110 public key into the exponent and the modulus
+fn split_public_key(public_key: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (length, rest) = match public_key {
+        [0, high, low, rest @ ..] => (usize::from(u16::from_be_bytes([*high, *low])), rest),
+        [length, rest @ ..] => (usize::from(*length), rest),
+        [] => return None,
+    };
+    match rest.len() > length {
+        true => Some(rest.split_at(length)),
+        false => None,
+    }
+}
+
+/// The EMSA-PKCS1-v1_5 encoding of the SHA-256 digest of the message, RFC 8017 section 9.2,
+/// or None if the length leaves room for less than the 8 bytes of padding it requires
+fn pkcs1_encoding(message: &[u8], length: usize) -> Option<Vec<u8>> {
+    let padding = length.checked_sub(3 + SHA256_DIGEST_INFO.len() + 32)?;
+    if padding < 8 {
+        return None;
+    }
+    let mut encoded = vec![0x00, 0x01];
+    encoded.resize(2 + padding, 0xff);
+    encoded.push(0x00);
+    encoded.extend_from_slice(&SHA256_DIGEST_INFO);
+    encoded.extend_from_slice(&sha256(message));
+    Some(encoded)
+}
+
+/// An unsigned integer of any size, as the little endian 32 bit limbs, for the modular
+/// exponentiation of RSA
+#[derive(Debug, Clone, PartialEq)]
+struct Uint(Vec<u32>);
+
+impl Uint {
+    fn from_be_bytes(bytes: &[u8]) -> Self {
+        let limbs = bytes
+            .rchunks(4)
+            .map(|chunk| chunk.iter().fold(0u32, |limb, byte| (limb << 8) | u32::from(*byte)));
+        Uint(limbs.collect()).normalized()
+    }
+
+    /// Returns the big endian bytes, padded with leading zeros to the length
+    fn to_be_bytes(&self, length: usize) -> Vec<u8> {
+        let bytes: Vec<u8> = self.0.iter().rev().flat_map(|limb| limb.to_be_bytes()).collect();
+        let significant = &bytes[bytes.iter().take_while(|b| **b == 0).count()..];
+        let mut padded = vec![0; length.saturating_sub(significant.len())];
+        padded.extend_from_slice(significant);
+        padded
+    }
+
+    fn normalized(mut self) -> Self {
+        while self.0.last() == Some(&0) {
+            self.0.pop();
+        }
+        self
+    }
+
+    fn bit_len(&self) -> usize {
+        self.0.last().map_or(0, |top| self.0.len() * 32 - top.leading_zeros() as usize)
+    }
+
+    fn byte_len(&self) -> usize {
+        self.bit_len().div_ceil(8)
+    }
+
+    fn bit(&self, i: usize) -> bool {
+        self.0.get(i / 32).is_some_and(|limb| limb >> (i % 32) & 1 == 1)
+    }
+
+    /// Returns self modulo the modulus, by binary long division
+    fn rem(&self, modulus: &Uint) -> Uint {
+        let mut remainder = Uint(Vec::new());
+        for i in (0..self.bit_len()).rev() {
+            remainder.shift_left_one(self.bit(i));
+            if remainder.compare(modulus) != Ordering::Less {
+                remainder.sub_assign(modulus);
+            }
+        }
+        remainder
+    }
+
+    fn shift_left_one(&mut self, low_bit: bool) {
+        let mut carry = u32::from(low_bit);
+        for limb in self.0.iter_mut() {
+            let next = *limb >> 31;
+            *limb = (*limb << 1) | carry;
+            carry = next;
+        }
+        if carry != 0 {
+            self.0.push(carry);
+        }
+    }
+
+    /// Subtracts the other value, which must not be larger
+    fn sub_assign(&mut self, other: &Uint) {
+        let mut borrow = 0i64;
+        for i in 0..self.0.len() {
+            let t = i64::from(self.0[i]) - i64::from(other.0.get(i).copied().unwrap_or(0)) - borrow;
+            borrow = i64::from(t < 0);
+            self.0[i] = t.rem_euclid(1 << 32) as u32;
+        }
+        *self = Uint(std::mem::take(&mut self.0)).normalized();
+    }
+
+    fn compare(&self, other: &Uint) -> Ordering {
+        self.0.len().cmp(&other.0.len()).then_with(|| self.0.iter().rev().cmp(other.0.iter().rev()))
+    }
+
+    /// Returns self to the power of the big endian exponent, modulo the modulus, which
+    /// must be odd. The multiplications are done in the Montgomery form.
+    fn pow_mod(&self, exponent: &[u8], modulus: &Uint) -> Uint {
+        let limbs = modulus.0.len();
+        // R is 2^(32 * limbs), and R^2 mod n takes values into the Montgomery form
+        let mut r2 = Uint(vec![0; 2 * limbs]);
+        r2.0.push(1);
+        let r2 = r2.rem(modulus);
+        let montgomery = Montgomery::new(modulus);
+        let base = montgomery.mul(&self.rem(modulus), &r2);
+        let mut result = montgomery.mul(&Uint(vec![1]), &r2);
+        for byte in exponent {
+            for i in (0..8).rev() {
+                result = montgomery.mul(&result, &result);
+                if byte >> i & 1 == 1 {
+                    result = montgomery.mul(&result, &base);
+                }
+            }
+        }
+        montgomery.mul(&result, &Uint(vec![1]))
+    }
+}
+
+/// Multiplication modulo an odd modulus n, as a * b / R mod n
+struct Montgomery<'a> {
+    modulus: &'a Uint,
+    /// -1 / n mod 2^32
+    inverse: u32,
+}
+
+impl<'a> Montgomery<'a> {
+    fn new(modulus: &'a Uint) -> Self {
+        // Newton's method, every step doubles the number of correct low bits
+        let n = modulus.0[0];
+        let mut inverse = 1u32;
+        for _ in 0..5 {
+            inverse = inverse.wrapping_mul(2u32.wrapping_sub(n.wrapping_mul(inverse)));
+        }
+        Montgomery { modulus, inverse: inverse.wrapping_neg() }
+    }
+
+    /// The coarsely integrated operand scanning method, with a and b less than n
+    fn mul(&self, a: &Uint, b: &Uint) -> Uint {
+        let n = &self.modulus.0;
+        let limbs = n.len();
+        let limb = |x: &Uint, i: usize| u64::from(x.0.get(i).copied().unwrap_or(0));
+        let mut t = vec![0u32; limbs + 2];
+        for i in 0..limbs {
+            let mut carry = 0u64;
+            for (j, t_j) in t.iter_mut().take(limbs).enumerate() {
+                let x = u64::from(*t_j) + limb(a, i) * limb(b, j) + carry;
+                *t_j = x as u32;
+                carry = x >> 32;
+            }
+            let x = u64::from(t[limbs]) + carry;
+            t[limbs] = x as u32;
+            t[limbs + 1] = (x >> 32) as u32;
+
+            let m = u64::from(t[0].wrapping_mul(self.inverse));
+            let mut carry = (u64::from(t[0]) + m * u64::from(n[0])) >> 32;
+            for j in 1..limbs {
+                let x = u64::from(t[j]) + m * u64::from(n[j]) + carry;
+                t[j - 1] = x as u32;
+                carry = x >> 32;
+            }
+            let x = u64::from(t[limbs]) + carry;
+            t[limbs - 1] = x as u32;
+            t[limbs] = t[limbs + 1] + (x >> 32) as u32;
+            t[limbs + 1] = 0;
+        }
+        let mut result = Uint(t).normalized();
+        if result.compare(self.modulus) != Ordering::Less {
+            result.sub_assign(self.modulus);
+        }
+        result
+    }
+}
+
+/// A 1024 bit RSA key for signing test zones, the minimum size that is accepted
+#[cfg(test)]
+pub(crate) mod test_key {
+    use crate::crypto::{pkcs1_encoding, Uint};
+    use crate::dnssec::decode_hex;
+
+    const MODULUS: &str = "BBCFD05A4E7B71F99C12EC5F9057FA13A2B4407B28EA2A8E4288D9123868E71B\
+                           647600D3D3A7DFD95E75A5AAC28918A38D29CFBE06ED758D45C6ADCFD67C34C8\
+                           BD79C327810D8E6AD5DBB0FA2014A8A95E8B719B4786AF606B345004B9CD5EF4\
+                           F0419F29F0097CC6D76CE869B51B22E192889603273EB3A2CA22C0D127BB2111";
+    const PRIVATE_EXPONENT: &str = "468D337CA91CD05CEF1760BA2974131A4B35A13F62C84C8232564C0CFF29D11F\
+                                    4626D9FAB8F8C82B54C3A71F3582E0C197A6CC465B3DDAB9DE2B5B61C6F5D3B1\
+                                    AC16F62538E387715A6AB73EB2B4CDB66B7F7B0469D86A40FFE66F4199219C3A\
+                                    50D7B77A0D5FCC13A5CEB91E21DE041627F0FD923F8DD37E920C9B466ED4B251";
+
+    /// The public key in the DNSKEY format, with the exponent 65537
+    pub(crate) fn public_key() -> Vec<u8> {
+        [&[3, 1, 0, 1][..], &decode_hex(MODULUS).unwrap()].concat()
+    }
+
+    /// Returns the RSASSA-PKCS1-v1_5 signature with SHA-256 of the message
+    pub(crate) fn sign(message: &[u8]) -> Vec<u8> {
+        let modulus = Uint::from_be_bytes(&decode_hex(MODULUS).unwrap());
+        let encoded = pkcs1_encoding(message, modulus.byte_len()).unwrap();
+        let encoded = Uint::from_be_bytes(&encoded);
+        let exponent = decode_hex(PRIVATE_EXPONENT).unwrap();
+        encoded.pow_mod(&exponent, &modulus).to_be_bytes(modulus.byte_len())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::crypto::{pkcs1_encoding, sha1, sha256, test_key, verify_rsa_sha256};
+    use crate::dnssec::decode_hex;
+
+    #[test]
+    fn test_sha1() {
+        let abc = decode_hex("A9993E364706816ABA3E25717850C26C9CD0D89D");
+        assert_eq!(abc.unwrap(), sha1(b"abc"));
+        let empty = decode_hex("DA39A3EE5E6B4B0D3255BFEF95601890AFD80709");
+        assert_eq!(empty.unwrap(), sha1(b""));
+        let two_blocks = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        let digest = decode_hex("84983E441C3BD26EBAAE4AA1F95129E5E54670F1");
+        assert_eq!(digest.unwrap(), sha1(two_blocks));
+    }
+
+    #[test]
+    fn test_sha256() {
+        let abc = decode_hex("BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD");
+        assert_eq!(abc.unwrap(), sha256(b"abc"));
+        let empty = decode_hex("E3B0C44298FC1C149AFBF4C8996FB92427AE41E4649B934CA495991B7852B855");
+        assert_eq!(empty.unwrap(), sha256(b""));
+        // the padding of a 56 byte message takes another block
+        let two_blocks = b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq";
+        let digest = decode_hex("248D6A61D20638B8E5C026930C3E6039A33CE45964FF2167F6ECEDD419DB06C1");
+        assert_eq!(digest.unwrap(), sha256(two_blocks));
+    }
+
+    #[test]
+    fn test_verify_rsa_sha256() {
+        // made with an independent implementation
+        let signature = decode_hex(
+            "5470A8F008EB209F41CF7898D39C92093ECA496993EC3F050BC6F3D51982AF36\
+             E50BA29DAED13E5C16144042AC65BBCCD38463C4338EB2CABFDFD5180CFC4C79\
+             AD5F569C22E921F0CC369F38AA55C99A7BE922D5004B548BBE42A3A1C1E37B6F\
+             CA60398ED6B3D34C9A8A35B794AD064F1F0B30F26538BCF5DA7C3C5A6C45EF19",
+        )
+        .unwrap();
+        assert!(verify_rsa_sha256(&test_key::public_key(), b"abc", &signature));
+        assert_eq!(signature, test_key::sign(b"abc"));
+        assert!(!verify_rsa_sha256(&test_key::public_key(), b"abd", &signature));
+
+        let mut broken = signature.clone();
+        broken[10] ^= 1;
+        assert!(!verify_rsa_sha256(&test_key::public_key(), b"abc", &broken));
+        // RFC 8017 section 8.2.2 step 1, the signature is exactly as long as the modulus
+        let padded = [&[0], signature.as_slice()].concat();
+        assert!(!verify_rsa_sha256(&test_key::public_key(), b"abc", &padded));
+        assert!(!verify_rsa_sha256(&test_key::public_key(), b"abc", &signature[1..]));
+        assert!(!verify_rsa_sha256(&[], b"abc", &signature));
+        assert!(!verify_rsa_sha256(&[3, 1, 0], b"abc", &signature));
+    }
+
+    #[test]
+    fn test_verify_rsa_sha256_short_key() {
+        // a valid signature, with a 512 bit key
+        let modulus = decode_hex(
+            "D3F0FF99BD71575F2956102A70C472749B1DBB9A486AA3715B02D87C760C1B86\
+             5EFE41C3858E61D1D167A414250BE7E94FC1025683589C65D7D041873A80EB61",
+        );
+        let signature = decode_hex(
+            "D31C517448ED1D5EC84DD6E7E76D4D30134402F4E69C13E8097BBB05DE5A80A4\
+             42E0CFCB2BDD964B2AFDA111E7B721BAF1E33571E23FD62B16FEC7D8E80148C5",
+        );
+        let public_key = [&[3, 1, 0, 1][..], &modulus.unwrap()].concat();
+        assert!(!verify_rsa_sha256(&public_key, b"abc", &signature.unwrap()));
+    }
+
+    #[test]
+    fn test_pkcs1_encoding_length() {
+        // the 8 bytes of padding, RFC 8017 section 9.2 step 3
+        assert_eq!(pkcs1_encoding(b"abc", 62).map(|e| e.len()), Some(62));
+        assert_eq!(pkcs1_encoding(b"abc", 61), None);
+        assert_eq!(pkcs1_encoding(b"abc", 20), None);
+    }
+}
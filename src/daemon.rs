@@ -1,22 +1,265 @@
-use crate::backend::MAX_RECEIVE_BUFFER_SIZE;
-use crate::resolver::{RecursiveResolver, ResolutionError};
-use hickory_proto::op::{Message, ResponseCode};
-use hickory_proto::serialize::binary::BinDecodable;
+use crate::backend::{parse_message, ExtendedError, MAX_RECEIVE_BUFFER_SIZE};
+use crate::cache::CacheResponse;
+use crate::dnssec::ValidationStatus;
+use crate::query_log::QueryLog;
+use crate::resolver::{RecursiveResolver, ResolutionError, ResolveOptions};
+use crate::retransmit::{original_response, Arrival, Retransmits};
+use crate::rrl::{Action, ResponseRateLimiter};
+use crate::status::StatusZone;
+use crate::upstream_stats::format_upstream_stats;
+use crate::validation::ValidatedRecords;
+use crate::zone::ZoneAnswer;
+use anyhow::bail;
+use hickory_proto::op::{Edns, Header, Message, MessageType, Query, ResponseCode};
+use hickory_proto::rr::rdata::HINFO;
+use hickory_proto::rr::RecordType::{NSEC, NSEC3, RRSIG};
+use hickory_proto::rr::{Name, RData, Record, RecordType};
+use hickory_proto::serialize::binary::{BinDecodable, BinDecoder};
 use std::net::{IpAddr, Ipv4Addr, SocketAddr};
 use std::ops::Deref;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::net::UdpSocket;
+use tracing::{debug, warn};
 
-pub async fn daemon(resolver: RecursiveResolver, listen_port: u16) -> anyhow::Result<()> {
+/// The record types that are resolved to answer a query for ANY
+const ANY_TYPES: [RecordType; 6] = [
+    RecordType::A,
+    RecordType::AAAA,
+    RecordType::MX,
+    RecordType::TXT,
+    RecordType::NS,
+    RecordType::SOA,
+];
+
+/// The size of the responses that every client can take over UDP
+const MIN_PAYLOAD_SIZE: usize = 512;
+
+/// The ttl of the HINFO record that queries for ANY are answered with when refused
+const ANY_HINFO_TTL: u32 = 300;
+
+/// How long to wait before trying again when priming fails
+const PRIMING_RETRY_INTERVAL: Duration = Duration::from_secs(10);
+
+/// How often the upstream stats are saved
+const UPSTREAM_STATS_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Settings that changes how the daemon responds to queries
+#[derive(Debug, Default)]
+pub struct DaemonConfig {
+    /// If the cache holds a delegation covering the queried name, respond with the
+    /// delegating NS records in the authority section instead of recursing
+    pub serve_referrals: bool,
+    /// Names that are resolved in the background on startup, to populate the cache
+    pub warmup: Vec<Name>,
+    /// Keeps track of the most recent queries, if set
+    pub query_log: Option<Arc<QueryLog>>,
+    /// Limits the rate of identical responses to the same clients, if set
+    pub rate_limiter: Option<ResponseRateLimiter>,
+    /// Puts the address records of this family first in the answers, if set. Clients that
+    /// connect to the first address they get, such as with happy eyeballs, then prefer it.
+    pub address_family: Option<AddressFamily>,
+    /// How to handle the queries that need recursion before the roots have been primed
+    pub startup: StartupPolicy,
+    /// Answer queries for ANY with a synthesized HINFO record instead of resolving them,
+    /// RFC 8482. This keeps the daemon from being used to amplify traffic with them.
+    pub refuse_any: bool,
+    /// When to include an OPT record in the responses
+    pub opt_record: OptPolicy,
+    /// Answers the retransmits of a query with the response to the original, if set
+    pub retransmits: Option<Retransmits>,
+    /// Answers the names of this zone with TXT records describing the daemon, if set
+    pub status_zone: Option<StatusZone>,
+}
+
+/// When responses include an OPT record
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum OptPolicy {
+    /// If the query had one, RFC 6891 section 7
+    #[default]
+    Echo,
+    /// In every response, for testing clients
+    Always,
+    /// In no response, for legacy clients that break on one they didn't ask for
+    Never,
+}
+
+impl FromStr for OptPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "echo" => Ok(OptPolicy::Echo),
+            "always" => Ok(OptPolicy::Always),
+            "never" => Ok(OptPolicy::Never),
+            _ => bail!("expected 'echo', 'always' or 'never', got '{s}'"),
+        }
+    }
+}
+
+/// How the queries that arrive before the root nameservers have been primed are handled.
+/// Queries answered from the cache or without recursion are answered either way.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum StartupPolicy {
+    /// Resolve them starting from the built in roots
+    #[default]
+    Resolve,
+    /// Answer them with SERVFAIL right away
+    ServFail,
+    /// Hold them until priming completes, answering with SERVFAIL if it takes longer
+    /// than the timeout
+    Queue(Duration),
+}
+
+/// Parses 'resolve', 'servfail' or 'queue:<seconds>'
+impl FromStr for StartupPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "resolve" => Ok(StartupPolicy::Resolve),
+            None if s == "servfail" => Ok(StartupPolicy::ServFail),
+            Some(("queue", seconds)) => {
+                Ok(StartupPolicy::Queue(Duration::from_secs(seconds.parse()?)))
+            }
+            _ => bail!("expected 'resolve', 'servfail' or 'queue:<seconds>', got '{s}'"),
+        }
+    }
+}
+
+/// The family of the addresses of A and AAAA records
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum AddressFamily {
+    Ipv4,
+    Ipv6,
+}
+
+impl AddressFamily {
+    fn record_type(self) -> RecordType {
+        match self {
+            AddressFamily::Ipv4 => RecordType::A,
+            AddressFamily::Ipv6 => RecordType::AAAA,
+        }
+    }
+}
+
+impl FromStr for AddressFamily {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "ipv4" => Ok(AddressFamily::Ipv4),
+            "ipv6" => Ok(AddressFamily::Ipv6),
+            _ => bail!("expected 'ipv4' or 'ipv6', got '{s}'"),
+        }
+    }
+}
+
+pub async fn daemon(
+    resolver: Arc<RecursiveResolver>,
+    listen_port: u16,
+    config: DaemonConfig,
+) -> anyhow::Result<()> {
     let sock =
         UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), listen_port)).await?;
     let r = Arc::new(sock);
-    let resolver = Arc::new(resolver);
+    let (startup, names) = (resolver.clone(), config.warmup.clone());
+    // the warmup names are resolved from the primed root nameservers
+    tokio::spawn(async move {
+        prime(&startup).await;
+        warmup(startup, names).await
+    });
+    let config = Arc::new(config);
 
     let mut buf = [0; MAX_RECEIVE_BUFFER_SIZE];
     loop {
-        let (msg, peer) = read_message(r.deref(), &mut buf).await?;
-        tokio::spawn(handle(r.clone(), msg, peer, resolver.clone()));
+        let (bytes_read, peer) = r.recv_from(&mut buf).await?;
+        match parse_message(&buf[..bytes_read]) {
+            Ok(msg) => {
+                tokio::spawn(handle(r.clone(), msg, peer, resolver.clone(), config.clone()));
+            }
+            Err(e) => {
+                debug!(%peer, error = %e, "Malformed query");
+                if let Some(response) = malformed_response(&buf[..bytes_read]) {
+                    send(r.deref(), &response, peer).await;
+                }
+            }
+        }
+    }
+}
+
+/// Parses the contents of a warmup file, one name per line. Empty lines and
+/// lines starting with '#' are ignored.
+pub fn parse_warmup(contents: &str) -> anyhow::Result<Vec<Name>> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| Ok(Name::from_str(line)?))
+        .collect()
+}
+
+/// Caches the root nameservers, more of them than the built in ones. Until priming
+/// succeeds, it is retried and resolution starts from the built in roots.
+async fn prime(resolver: &RecursiveResolver) {
+    loop {
+        match resolver.prime().await {
+            Ok((ns, glue)) => {
+                debug!(
+                    nameservers = ns.len(),
+                    addresses = glue.len(),
+                    "Primed the root nameservers"
+                );
+                return;
+            }
+            Err(e) => warn!(error = %e, "Failed to prime the root nameservers"),
+        }
+        tokio::time::sleep(PRIMING_RETRY_INTERVAL).await;
+    }
+}
+
+/// Returns false if the query is to be answered with SERVFAIL as the roots haven't been
+/// primed, waiting for the priming to complete if the policy says so
+async fn ready(resolver: &RecursiveResolver, policy: StartupPolicy) -> bool {
+    match policy {
+        StartupPolicy::Resolve => true,
+        StartupPolicy::ServFail => resolver.is_primed(),
+        StartupPolicy::Queue(timeout) => {
+            tokio::time::timeout(timeout, resolver.primed()).await.is_ok()
+        }
+    }
+}
+
+/// Saves the upstream stats to the file every minute, for the next start to restore them
+pub async fn save_upstream_stats(resolver: Arc<RecursiveResolver>, path: PathBuf) {
+    let mut interval = tokio::time::interval(UPSTREAM_STATS_INTERVAL);
+    loop {
+        interval.tick().await;
+        let contents = format_upstream_stats(&resolver.upstream_stats());
+        if let Err(e) = write_atomically(&path, contents).await {
+            warn!(path = %path.display(), error = %e, "Failed to save the upstream stats");
+        }
+    }
+}
+
+/// Writes the file next to `path` and then moves it into place, so that a process dying
+/// halfway leaves the previous contents rather than a truncated file
+async fn write_atomically(path: &Path, contents: String) -> std::io::Result<()> {
+    let mut temporary = path.as_os_str().to_owned();
+    temporary.push(".tmp");
+    tokio::fs::write(&temporary, contents).await?;
+    tokio::fs::rename(&temporary, path).await
+}
+
+/// Resolves the A records of each name, one at a time to not compete too much
+/// with the queries from real clients. Failures are logged and otherwise ignored.
+async fn warmup(resolver: Arc<RecursiveResolver>, names: Vec<Name>) {
+    for name in names {
+        if let Err(e) = resolver.resolve(&name, RecordType::A).await {
+            warn!(%name, error = %e, "Failed to resolve name during warmup");
+        }
     }
 }
 
@@ -25,66 +268,1242 @@ async fn handle(
     msg: Message,
     peer: SocketAddr,
     resolver: Arc<RecursiveResolver>,
+    config: Arc<DaemonConfig>,
 ) -> anyhow::Result<()> {
-    let response = resolve(msg, &resolver).await;
+    let query = msg.query().cloned();
+    let arrival = config.retransmits.as_ref().map(|r| r.check(peer, &msg, Instant::now()));
+    let response = match arrival {
+        None => answer(msg, peer, &resolver, &config).await,
+        Some(Arrival::First(sender)) => {
+            let response = answer(msg, peer, &resolver, &config).await;
+            sender.send_replace(Some(response.clone()));
+            response
+        }
+        Some(Arrival::Retransmit(original)) => {
+            debug!(%peer, id = msg.id(), "Retransmitted query");
+            let Some(response) = original_response(original).await else {
+                return Ok(());
+            };
+            response
+        }
+    };
+    let Some(response) = rate_limit(response, query.as_ref(), peer, &config, Instant::now()) else {
+        return Ok(());
+    };
     socket.send_to(response.to_vec()?.as_slice(), peer).await?;
     Ok(())
 }
 
-async fn resolve(message: Message, resolver: &RecursiveResolver) -> Message {
+/// Applies the response rate limiting, returning None if the response is to be dropped
+fn rate_limit(
+    mut response: Message,
+    query: Option<&Query>,
+    peer: SocketAddr,
+    config: &DaemonConfig,
+    now: Instant,
+) -> Option<Message> {
+    let (Some(rate_limiter), Some(query)) = (&config.rate_limiter, query) else {
+        return Some(response);
+    };
+    match rate_limiter.check(peer.ip(), query, response.response_code(), now) {
+        Action::Send => Some(response),
+        Action::Truncate => {
+            truncate(&mut response);
+            Some(response)
+        }
+        Action::Drop => None,
+    }
+}
+
+/// Resolves the query and adds it to the query log, if enabled
+async fn answer(
+    msg: Message,
+    peer: SocketAddr,
+    resolver: &RecursiveResolver,
+    config: &DaemonConfig,
+) -> Message {
+    let Some(query_log) = &config.query_log else {
+        return resolve(msg, resolver, config).await;
+    };
+    let start = Instant::now();
+    let query = msg.clone();
+    let response = resolve(msg, resolver, config).await;
+    query_log.record(peer, &query, &response, start.elapsed());
+    response
+}
+
+/// Resolves the query of the message. The RRSIG, NSEC and NSEC3 records are only included
+/// if the client set the DO bit, or asked for them, RFC 4035 section 3.2.1.
+async fn resolve(message: Message, resolver: &RecursiveResolver, config: &DaemonConfig) -> Message {
+    let dnssec_ok = message.extensions().as_ref().is_some_and(Edns::dnssec_ok);
+    let query_type = message.query().map(Query::query_type);
+    let payload_size = client_payload_size(&message);
+    let client_edns = message.extensions().is_some();
+    let mut response = resolve_records(message, resolver, config).await;
+    if dnssec_ok {
+        let edns = response.extensions_mut().get_or_insert_with(Edns::new);
+        edns.set_dnssec_ok(true).set_max_payload(MAX_RECEIVE_BUFFER_SIZE as u16);
+    } else {
+        let answers = response.take_answers();
+        response.insert_answers(without_dnssec(answers, query_type));
+        let authority = response.take_name_servers();
+        response.insert_name_servers(without_dnssec(authority, None));
+        let additionals = response.take_additionals();
+        response.insert_additionals(without_dnssec(additionals, None));
+    }
+    if let Some(family) = config.address_family {
+        let answers = response.take_answers();
+        response.insert_answers(order_addresses(answers, family));
+    }
+    let include_opt = match config.opt_record {
+        OptPolicy::Echo => client_edns,
+        OptPolicy::Always => true,
+        OptPolicy::Never => false,
+    };
+    match include_opt {
+        true => {
+            response.extensions_mut().get_or_insert_with(|| {
+                let mut edns = Edns::new();
+                edns.set_max_payload(MAX_RECEIVE_BUFFER_SIZE as u16);
+                edns
+            });
+        }
+        false => *response.extensions_mut() = None,
+    }
+    // every response ends up here, the ones answered locally as well as the recursed ones
+    if response.to_vec().is_ok_and(|bytes| bytes.len() > payload_size) {
+        truncate(&mut response);
+    }
+    response
+}
+
+/// The size of the largest response the client can take over UDP, RFC 6891 section 6.2.5.
+/// Without EDNS, this is 512 bytes, RFC 1035 section 4.2.1.
+fn client_payload_size(message: &Message) -> usize {
+    let edns = message.extensions().as_ref();
+    edns.map_or(MIN_PAYLOAD_SIZE, |e| usize::from(e.max_payload()).max(MIN_PAYLOAD_SIZE))
+}
+
+/// Empties the sections of the response and sets the TC bit, to make the client retry over
+/// TCP. The OPT record is kept.
+fn truncate(response: &mut Message) {
+    response.take_answers();
+    response.take_name_servers();
+    response.take_additionals();
+    response.set_truncated(true);
+}
+
+/// Removes the DNSSEC records other than those of the queried type, for a client that
+/// didn't set the DO bit
+fn without_dnssec(records: Vec<Record>, query_type: Option<RecordType>) -> Vec<Record> {
+    records
+        .into_iter()
+        .filter(|r| {
+            !matches!(r.record_type(), RRSIG | NSEC | NSEC3) || Some(r.record_type()) == query_type
+        })
+        .collect()
+}
+
+/// Moves the address records of the family ahead of those of the other family, leaving
+/// the other records, such as the CNAME records leading up to them, where they are
+fn order_addresses(answers: Vec<Record>, family: AddressFamily) -> Vec<Record> {
+    let is_address = |r: &Record| matches!(r.record_type(), RecordType::A | RecordType::AAAA);
+    let mut addresses: Vec<Record> = answers.iter().filter(|r| is_address(r)).cloned().collect();
+    addresses.sort_by_key(|r| r.record_type() != family.record_type());
+    let mut addresses = addresses.into_iter();
+    answers
+        .into_iter()
+        .map(|r| match is_address(&r) {
+            true => addresses.next().expect("as many addresses as in the answers"),
+            false => r,
+        })
+        .collect()
+}
+
+async fn resolve_records(
+    message: Message,
+    resolver: &RecursiveResolver,
+    config: &DaemonConfig,
+) -> Message {
     let mut response = Message::new();
     response.set_id(message.id());
-    let Some(query) = message.query() else {
+    response.set_message_type(MessageType::Response);
+    response.set_op_code(message.op_code());
+    // the CD bit is copied to the response, and skips the validation, RFC 4035 section 3.2.2
+    response.set_checking_disabled(message.checking_disabled());
+    response.set_recursion_desired(message.recursion_desired());
+    response.set_recursion_available(true);
+    // more than one question is a format error too, RFC 9619
+    let [query] = message.queries() else {
+        response.add_queries(message.queries().iter().cloned());
         response.set_response_code(ResponseCode::FormErr);
         return response;
     };
 
-    match resolver.resolve(query.name(), query.query_type()).await {
-        Ok(records) => {
-            for r in records {
-                response.add_answer(r);
+    let status = config.status_zone.as_ref();
+    if let Some(answer) = status.and_then(|z| z.lookup(query.name(), query.query_type(), resolver))
+    {
+        response.set_authoritative(true);
+        match answer {
+            ZoneAnswer::Records(records) => response.insert_answers(records),
+            ZoneAnswer::NoData => {}
+            ZoneAnswer::NxDomain => {
+                response.set_response_code(ResponseCode::NXDomain);
+            }
+        }
+        return response;
+    }
+
+    // the cache holds records from outside the served zones too, such as nameserver addresses
+    if !resolver.serves(query.name()) && (!message.recursion_desired() || config.serve_referrals) {
+        response.set_response_code(ResponseCode::Refused);
+        return response;
+    }
+
+    if !message.recursion_desired() {
+        // only answer with what is already known, without recursing
+        match resolver.get_cached(query.name(), query.query_type()) {
+            CacheResponse::Authoritative(records) => response.insert_answers(records),
+            CacheResponse::Referral(ns, glue) => {
+                response.insert_name_servers(ns);
+                response.insert_additionals(glue);
+            }
+            CacheResponse::None => {
+                response.set_response_code(ResponseCode::Refused);
             }
         }
-        Err(ResolutionError::NxDomain) => {
-            response.set_response_code(ResponseCode::NXDomain);
+        return response;
+    }
+
+    if config.serve_referrals {
+        if let CacheResponse::Referral(ns, glue) =
+            resolver.get_cached(query.name(), query.query_type())
+        {
+            response.insert_name_servers(ns);
+            response.insert_additionals(glue);
+            return response;
         }
-        Err(_) => {
+    }
+
+    if query.query_type() == RecordType::ANY && config.refuse_any {
+        // the HINFO says that the query was refused, RFC 8482 section 4.2, which needs no
+        // recursion and so no primed roots
+        let hinfo = HINFO::new("RFC8482".to_string(), String::new());
+        response.add_answer(Record::from_rdata(
+            query.name().clone(),
+            ANY_HINFO_TTL,
+            RData::HINFO(hinfo),
+        ));
+        return response;
+    }
+
+    if !ready(resolver, config.startup).await {
+        response.set_response_code(ResponseCode::ServFail);
+        return response;
+    }
+
+    if query.query_type() == RecordType::ANY {
+        let result = resolver.resolve_types(query.name(), &ANY_TYPES).await;
+        if result.records.is_empty() {
+            // report the error of the first type that failed
+            if let Some(e) = ANY_TYPES.iter().find_map(|t| result.errors.get(t)) {
+                response.set_response_code(response_code(e));
+            }
+        }
+        response.insert_answers(result.records);
+        return response;
+    }
+
+    let options = ResolveOptions {
+        checking_disabled: message.checking_disabled(),
+        ..ResolveOptions::default()
+    };
+    match resolver.resolve_validated(query.name(), query.query_type(), options).await {
+        Ok(ValidatedRecords { status: ValidationStatus::Bogus(reason), .. }) => {
             response.set_response_code(ResponseCode::ServFail);
+            if message.extensions().is_some() {
+                let info_code = ExtendedError::DNSSEC_BOGUS;
+                add_extended_error(&mut response, ExtendedError { info_code, extra_text: reason });
+            }
+        }
+        Ok(ValidatedRecords { records, status }) => {
+            // RFC 4035 section 3.2.3, only set for clients that can tell what it means
+            let dnssec_aware = message.authentic_data()
+                || message.extensions().as_ref().is_some_and(Edns::dnssec_ok);
+            response.set_authentic_data(status == ValidationStatus::Secure && dnssec_aware);
+            let nodata = records.is_empty();
+            for r in records {
+                response.add_answer(r);
+            }
+            // the resolver is the authority for the negative answers it makes up itself
+            if let Some(soa) = resolver.synthetic_authority(query.name()).filter(|_| nodata) {
+                response.set_authoritative(true);
+                response.add_name_server(soa);
+            }
+        }
+        Err(e) => {
+            response.set_response_code(response_code(&e));
+            if let (ResolutionError::NxDomain, Some(soa)) =
+                (&e, resolver.synthetic_authority(query.name()))
+            {
+                response.set_authoritative(true);
+                response.add_name_server(soa);
+            }
+            // an extended error can only be sent to clients that use EDNS
+            if let (Some(error), Some(_)) = (extended_error(&e), message.extensions()) {
+                add_extended_error(&mut response, error);
+            }
         }
     }
     response
 }
 
-async fn read_message(socket: &UdpSocket, buf: &mut [u8]) -> anyhow::Result<(Message, SocketAddr)> {
-    let (bytes_read, addr) = socket.recv_from(buf).await?;
-    Ok((Message::from_bytes(&buf[..bytes_read])?, addr))
+/// Adds the extended error to the response, RFC 8914, which only clients that use EDNS
+/// are sent
+fn add_extended_error(response: &mut Message, error: ExtendedError) {
+    let mut edns = Edns::new();
+    edns.set_max_payload(MAX_RECEIVE_BUFFER_SIZE as u16);
+    edns.options_mut().insert(error.to_option());
+    response.set_edns(edns);
+}
+
+/// The extended error that explains a failed resolution to the client, RFC 8914
+fn extended_error(error: &ResolutionError) -> Option<ExtendedError> {
+    match error {
+        ResolutionError::Inconsistent(reason) => {
+            Some(ExtendedError { info_code: ExtendedError::OTHER, extra_text: reason.clone() })
+        }
+        // passed on, so the client learns what the upstream said went wrong
+        ResolutionError::UpstreamError(error) => Some(error.clone()),
+        // the text tells when the upstream is queried again, for the client to retry then
+        ResolutionError::Overloaded { .. } => Some(ExtendedError {
+            info_code: ExtendedError::NETWORK_ERROR,
+            extra_text: error.to_string(),
+        }),
+        _ => None,
+    }
+}
+
+fn response_code(error: &ResolutionError) -> ResponseCode {
+    match error {
+        ResolutionError::NxDomain => ResponseCode::NXDomain,
+        ResolutionError::Refused => ResponseCode::Refused,
+        _ => ResponseCode::ServFail,
+    }
+}
+
+/// Creates the FORMERR response to a message that couldn't be parsed, with the question
+/// if that much of it could be. Nothing is sent back if not even the header is complete.
+fn malformed_response(bytes: &[u8]) -> Option<Message> {
+    let mut decoder = BinDecoder::new(bytes);
+    let header = Header::read(&mut decoder).ok()?;
+    if header.message_type() == MessageType::Response {
+        return None;
+    }
+    let mut response = Message::new();
+    response.set_id(header.id());
+    response.set_message_type(MessageType::Response);
+    response.set_op_code(header.op_code());
+    response.set_recursion_desired(header.recursion_desired());
+    response.set_response_code(ResponseCode::FormErr);
+    if header.query_count() > 0 {
+        if let Ok(query) = Query::read(&mut decoder) {
+            response.add_query(query);
+        }
+    }
+    Some(response)
+}
+
+async fn send(socket: &UdpSocket, response: &Message, peer: SocketAddr) {
+    let result = match response.to_vec() {
+        Ok(bytes) => socket.send_to(&bytes, peer).await.map(|_| ()).map_err(anyhow::Error::from),
+        Err(e) => Err(e.into()),
+    };
+    if let Err(e) = result {
+        warn!(%peer, error = %e, "Failed to send response");
+    }
 }
 
 #[cfg(test)]
 mod test {
-    use crate::daemon::resolve;
-    use crate::fake_backend::ServFailBackend;
+    use crate::backend::{ExtendedError, UdpBackend, MAX_RECEIVE_BUFFER_SIZE};
+    use crate::cache::CacheResponse;
+    use crate::daemon::{
+        answer, handle, malformed_response, parse_warmup, rate_limit, resolve, warmup,
+        AddressFamily, DaemonConfig, OptPolicy, StartupPolicy,
+    };
+    use crate::fake_backend::{FakeBackend, ServFailBackend};
+    use crate::hosts::parse_hosts;
+    use crate::query_log::QueryLog;
     use crate::resolver::RecursiveResolver;
-    use hickory_proto::op::{Message, Query, ResponseCode};
+    use crate::retransmit::Retransmits;
+    use crate::rrl::ResponseRateLimiter;
+    use crate::status::StatusZone;
+    use crate::synthetic::SyntheticSoa;
+    use crate::tcp;
+    use crate::validation::tests::signed_zones;
+    use crate::zone::StaticZone;
+    use crate::{a, answer, name, ns, refer};
+    use anyhow::Result;
+    use hickory_proto::op::{Edns, Header, Message, MessageType, Query, ResponseCode};
+    use hickory_proto::rr::dnssec::rdata::{DNSSECRData, NSEC, RRSIG};
+    use hickory_proto::rr::dnssec::Algorithm;
+    use hickory_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+    use hickory_proto::rr::RecordType::A;
+    use hickory_proto::rr::{rdata, Name, RData, Record, RecordType};
+    use hickory_proto::serialize::binary::BinDecodable;
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
+    use tokio::net::{TcpListener, UdpSocket};
 
     #[tokio::test]
     async fn test_resolve_non_query() {
         // no query set, should return a servfail
         let mut msg = Message::new();
         msg.set_id(4711);
-        let response = resolve(msg, &RecursiveResolver::new()).await;
+        let response = resolve(msg, &RecursiveResolver::new(), &DaemonConfig::default()).await;
         assert_eq!(response.header().response_code(), ResponseCode::FormErr);
+        assert_eq!(response.message_type(), MessageType::Response);
         assert_eq!(4711, response.id());
     }
 
+    #[tokio::test]
+    async fn test_resolve_multiple_questions() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+        let mut msg = Message::new();
+        msg.set_id(4714);
+        msg.set_recursion_desired(true);
+        msg.add_query(Query::query(name!("a.b."), A));
+        msg.add_query(Query::query(name!("c.d."), A));
+        let response = resolve(msg, &resolver, &DaemonConfig::default()).await;
+        assert_eq!(response.response_code(), ResponseCode::FormErr);
+        assert_eq!(response.id(), 4714);
+        // the first question is not answered on its own
+        assert!(response.answers().is_empty());
+        assert_eq!(response.queries().len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_malformed_query() -> Result<()> {
+        let mut msg = Message::new();
+        msg.set_id(4713);
+        msg.set_recursion_desired(true);
+        msg.add_query(Query::query(name!("a.b."), A));
+        let mut bytes = msg.to_vec()?;
+        // claims to have an answer record that isn't there
+        bytes[6..8].copy_from_slice(&1u16.to_be_bytes());
+        assert!(Message::from_bytes(&bytes).is_err());
+
+        let response = malformed_response(&bytes).expect("the header is complete");
+        assert_eq!(response.response_code(), ResponseCode::FormErr);
+        assert_eq!(response.message_type(), MessageType::Response);
+        assert_eq!(response.id(), 4713);
+        assert!(response.recursion_desired());
+        assert_eq!(response.queries(), [Query::query(name!("a.b."), A)]);
+        assert!(malformed_response(&bytes[..8]).is_none());
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_resolve_servfail() {
         let resolver = RecursiveResolver::with_backend(ServFailBackend {}, vec![]);
         let mut msg = Message::new();
         msg.set_id(4712);
+        msg.set_recursion_desired(true);
         msg.add_query(Query::new());
-        let response = resolve(msg, &resolver).await;
+        let response = resolve(msg, &resolver, &DaemonConfig::default()).await;
         assert_eq!(response.header().response_code(), ResponseCode::ServFail);
         assert_eq!(4712, response.id());
     }
+
+    #[tokio::test]
+    async fn test_resolve_extended_error() -> Result<()> {
+        let mut b = FakeBackend::new();
+        let mut answer = answer!(a!("a.b.", "10.0.0.42"));
+        answer.add_query(Query::query(name!("c.d."), A));
+        b.add("10.0.0.1", "a.b.", A, answer)?;
+        let resolver = RecursiveResolver::builder()
+            .backend(b)
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .strict(true)
+            .build();
+        let mut msg = Message::new();
+        msg.set_recursion_desired(true);
+        msg.add_query(Query::query(name!("a.b."), A));
+        msg.set_edns(Edns::new());
+        let response = resolve(msg, &resolver, &DaemonConfig::default()).await;
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+        let edns = response.extensions().as_ref().expect("the client uses EDNS");
+        let Some(EdnsOption::Unknown(_, data)) = edns.option(EdnsCode::from(15)) else {
+            panic!("no extended error in {edns:?}");
+        };
+        assert_eq!(data, b"\x00\x0010.0.0.1 answered another question");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_upstream_extended_error() -> Result<()> {
+        let udp = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await?;
+        let port = udp.local_addr()?.port();
+        let udp_handle = tokio::spawn(async move {
+            let mut buf = [0; 512];
+            let (len, peer) = udp.recv_from(&mut buf).await?;
+            let request = Message::from_vec(&buf[..len])?;
+            let mut response = Message::new();
+            response.set_id(request.id());
+            response.set_message_type(MessageType::Response);
+            response.add_queries(request.queries().iter().cloned());
+            response.set_response_code(ResponseCode::ServFail);
+            let mut edns = Edns::new();
+            let error = ExtendedError { info_code: 6, extra_text: "signature expired".into() };
+            edns.options_mut().insert(error.to_option());
+            response.set_edns(edns);
+            udp.send_to(&response.to_vec()?, peer).await?;
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let resolver = RecursiveResolver::builder()
+            .backend(UdpBackend::new().with_target_port(port))
+            .roots(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)])
+            .build();
+        let mut msg = Message::new();
+        msg.set_recursion_desired(true);
+        msg.add_query(Query::query(name!("www.example."), A));
+        msg.set_edns(Edns::new());
+        let response = resolve(msg, &resolver, &DaemonConfig::default()).await;
+        udp_handle.await??;
+
+        // the extended error makes it to the client over the wire
+        let response = Message::from_vec(&response.to_vec()?)?;
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+        let edns = response.extensions().as_ref().expect("the client uses EDNS");
+        let Some(EdnsOption::Unknown(_, data)) = edns.option(EdnsCode::from(15)) else {
+            panic!("no extended error in {edns:?}");
+        };
+        assert_eq!(data, b"\x00\x06signature expired");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_overloaded() -> Result<()> {
+        tokio::time::pause();
+        let resolver = RecursiveResolver::builder()
+            .backend(FakeBackend::new())
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .upstream_cooldown(Duration::from_secs(30))
+            .build();
+        let query = || {
+            let mut msg = Message::new();
+            msg.set_recursion_desired(true);
+            msg.add_query(Query::query(Name::from_ascii("a.b.").unwrap(), A));
+            msg.set_edns(Edns::new());
+            msg
+        };
+        // the upstream fails until it is cooling down
+        for _ in 0..3 {
+            resolve(query(), &resolver, &DaemonConfig::default()).await;
+        }
+        tokio::time::advance(Duration::from_secs(10)).await;
+        let response = resolve(query(), &resolver, &DaemonConfig::default()).await;
+        let response = Message::from_vec(&response.to_vec()?)?;
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+        let edns = response.extensions().as_ref().expect("the client uses EDNS");
+        let Some(EdnsOption::Unknown(_, data)) = edns.option(EdnsCode::from(15)) else {
+            panic!("no extended error in {edns:?}");
+        };
+        assert_eq!(data, b"\x00\x1710.0.0.1 is overloaded, retry after 20 seconds");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_synthetic_soa() -> Result<()> {
+        let soa: SyntheticSoa = "ns.lab. hostmaster.lab. 7 3600 600 86400 60".parse()?;
+        let resolver = RecursiveResolver::builder()
+            .backend(FakeBackend::new())
+            .local_records(parse_hosts("10.0.0.7 printer.lab\n")?)
+            .synthetic_soa(soa.clone())
+            .build();
+        let config = DaemonConfig::default();
+        let reverse = name!("7.0.0.10.in-addr.arpa.");
+        let resolve_reverse = |record_type| {
+            let mut msg = Message::new();
+            msg.set_recursion_desired(true);
+            msg.add_query(Query::query(reverse.clone(), record_type));
+            resolve(msg, &resolver, &config)
+        };
+        let response = resolve_reverse(RecordType::PTR).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(response.answers().len(), 1);
+        assert!(!response.authoritative());
+        assert!(response.name_servers().is_empty());
+        let response = resolve_reverse(RecordType::AAAA).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.authoritative());
+        assert_eq!(response.name_servers(), [soa.record(&name!("in-addr.arpa."))]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_static_zone() -> Result<()> {
+        let zone = StaticZone::parse(
+            "$ORIGIN lab.\n@ 60 IN SOA ns.lab. hostmaster.lab. 1 3600 600 86400 60\n\
+             www 60 IN A 10.0.0.80\n*.apps 60 IN A 10.0.0.81\n",
+            None,
+        )?;
+        let soa = zone.soa().clone();
+        let resolver = RecursiveResolver::builder()
+            .backend(FakeBackend::new())
+            .static_zones(vec![zone])
+            .build();
+        let config = DaemonConfig::default();
+        let resolve_a = |name: Name| {
+            let mut msg = Message::new();
+            msg.set_recursion_desired(true);
+            msg.add_query(Query::query(name, A));
+            resolve(msg, &resolver, &config)
+        };
+
+        let response = resolve_a(name!("www.lab.")).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.name_servers().is_empty());
+        assert_eq!(response.answers(), [a!("www.lab.", "10.0.0.80")]);
+        let response = resolve_a(name!("grafana.apps.lab.")).await;
+        assert_eq!(response.answers(), [a!("grafana.apps.lab.", "10.0.0.81")]);
+        let response = resolve_a(name!("mail.lab.")).await;
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert!(response.authoritative());
+        assert_eq!(response.name_servers(), [soa]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_truncated_upstream() -> Result<()> {
+        let records = vec![
+            a!("www.example.", "10.0.0.1"),
+            a!("www.example.", "10.0.0.2"),
+            a!("www.example.", "10.0.0.3"),
+        ];
+        let answers = records.clone();
+        let full_response = move |request: &Message| {
+            let mut response = Message::new();
+            response.set_id(request.id());
+            response.set_message_type(MessageType::Response);
+            response.set_authoritative(true);
+            response.add_queries(request.queries().iter().cloned());
+            response.insert_answers(answers.clone());
+            response
+        };
+        // the upstream truncates every response over UDP, only having them complete over TCP
+        let udp = UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await?;
+        let port = udp.local_addr()?.port();
+        let tcp = TcpListener::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port)).await?;
+        let udp_response = full_response.clone();
+        let udp_handle = tokio::spawn(async move {
+            let mut buf = vec![0u8; MAX_RECEIVE_BUFFER_SIZE];
+            let (read_count, peer) = udp.recv_from(&mut buf).await?;
+            let request = Message::from_bytes(&buf[..read_count])?;
+            let mut response = udp_response(&request);
+            response.take_answers();
+            response.set_truncated(true);
+            udp.send_to(&response.to_vec()?, peer).await?;
+            Ok::<_, anyhow::Error>(())
+        });
+        let tcp_handle = tokio::spawn(async move {
+            let (mut stream, _) = tcp.accept().await?;
+            let request = tcp::read_message(&mut stream).await?;
+            tcp::write_message(&mut stream, &full_response(&request)).await?;
+            Ok::<_, anyhow::Error>(())
+        });
+
+        let resolver = RecursiveResolver::builder()
+            .backend(UdpBackend::new().with_target_port(port))
+            .roots(vec![IpAddr::V4(Ipv4Addr::LOCALHOST)])
+            .build();
+        let mut msg = Message::new();
+        msg.set_recursion_desired(true);
+        msg.add_query(Query::query(name!("www.example."), A));
+        let response = resolve(msg, &resolver, &DaemonConfig::default()).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(!response.truncated());
+        assert_eq!(response.answers(), records);
+        udp_handle.await??;
+        tcp_handle.await??;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_truncated_to_client() -> Result<()> {
+        let mut contents =
+            "$ORIGIN lab.\n@ 60 IN SOA ns.lab. hostmaster.lab. 1 3600 600 86400 60\n".to_string();
+        for i in 0..40 {
+            contents.push_str(&format!("many 60 IN A 10.0.0.{i}\n"));
+        }
+        let resolver = RecursiveResolver::builder()
+            .backend(FakeBackend::new())
+            .static_zones(vec![StaticZone::parse(&contents, None)?])
+            .build();
+        let mut msg = Message::new();
+        msg.set_id(4715);
+        msg.set_recursion_desired(true);
+        msg.add_query(Query::query(name!("many.lab."), A));
+
+        // 40 addresses don't fit in the 512 bytes of a client without EDNS
+        let response = resolve(msg.clone(), &resolver, &DaemonConfig::default()).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.truncated());
+        assert!(response.answers().is_empty());
+        assert_eq!(response.id(), 4715);
+
+        let mut edns = Edns::new();
+        edns.set_max_payload(1232);
+        msg.set_edns(edns);
+        let response = resolve(msg, &resolver, &DaemonConfig::default()).await;
+        assert!(!response.truncated());
+        assert_eq!(response.answers().len(), 40);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_opt_record() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+        let mut msg = Message::new();
+        msg.set_recursion_desired(true);
+        msg.add_query(Query::query(name!("a.b."), A));
+        let mut with_edns = msg.clone();
+        with_edns.set_edns(Edns::new());
+        let has_opt = |policy: OptPolicy, msg: Message| {
+            let resolver = &resolver;
+            async move {
+                let config = DaemonConfig { opt_record: policy, ..DaemonConfig::default() };
+                let response = resolve(msg, resolver, &config).await;
+                assert_eq!(response.answers().len(), 1);
+                response.extensions().is_some()
+            }
+        };
+
+        assert!(has_opt(OptPolicy::Echo, with_edns.clone()).await);
+        assert!(!has_opt(OptPolicy::Echo, msg.clone()).await);
+        assert!(has_opt(OptPolicy::Always, msg).await);
+        assert!(!has_opt(OptPolicy::Never, with_edns).await);
+        assert_eq!("never".parse::<OptPolicy>()?, OptPolicy::Never);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_serve_referrals() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, refer!(ns!("b.", "ns.b."), a!("ns.b.", "10.0.0.2")))?;
+        b.add("10.0.0.2", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+        // populates the cache with the delegation of b
+        resolver.resolve(&"a.b.".parse()?, A).await?;
+
+        let mut msg = Message::new();
+        msg.set_recursion_desired(true);
+        msg.add_query(Query::query("c.b.".parse()?, A));
+        let config = DaemonConfig { serve_referrals: true, ..DaemonConfig::default() };
+        let response = resolve(msg, &resolver, &config).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.answers().is_empty());
+        assert_eq!(response.name_servers(), [ns!("b.", "ns.b.")]);
+        assert_eq!(response.additionals(), [a!("ns.b.", "10.0.0.2")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+        let config = DaemonConfig {
+            rate_limiter: Some(ResponseRateLimiter::new(2)),
+            ..DaemonConfig::default()
+        };
+        let now = Instant::now();
+        let query = Query::query(name!("a.b."), A);
+        let mut msg = Message::new();
+        msg.set_recursion_desired(true);
+        msg.add_query(query.clone());
+        let limit = |response, peer, now| rate_limit(response, Some(&query), peer, &config, now);
+
+        // the clients of a /24 share the limit
+        let mut sent = Vec::new();
+        for i in 1..=10 {
+            let peer = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, i)), 4711);
+            let response = resolve(msg.clone(), &resolver, &config).await;
+            sent.push(limit(response, peer, now).map(|r| r.truncated()));
+        }
+        let expected = [Some(false), Some(false), None, Some(true), None, Some(true)];
+        assert_eq!(sent[..6], expected);
+        assert_eq!(sent.iter().filter(|s| s.is_none()).count(), 4);
+
+        let other = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(198, 51, 100, 1)), 4711);
+        let response = resolve(msg.clone(), &resolver, &config).await;
+        let response = limit(response, other, now).expect("not limited");
+        assert_eq!(response.answers(), [a!("a.b.", "10.0.0.42")]);
+        // the limit applies per second
+        let peer = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 1)), 4711);
+        let response = resolve(msg, &resolver, &config).await;
+        let later = now + Duration::from_secs(1);
+        assert!(limit(response, peer, later).is_some_and(|r| !r.truncated()));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retransmit() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        b.delay("10.0.0.1", "a.b.", A, Duration::from_millis(50))?;
+        let b = Arc::new(b);
+        let resolver = Arc::new(RecursiveResolver::with_backend(
+            b.clone(),
+            vec![IpAddr::V4("10.0.0.1".parse()?)],
+        ));
+        let retransmits = Some(Retransmits::new(Duration::from_secs(5)));
+        let config = Arc::new(DaemonConfig { retransmits, ..DaemonConfig::default() });
+        let localhost = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0);
+        let server = Arc::new(UdpSocket::bind(localhost).await?);
+        let client = UdpSocket::bind(localhost).await?;
+        let mut msg = Message::new();
+        msg.set_id(4711);
+        msg.set_recursion_desired(true);
+        msg.add_query(Query::query(name!("a.b."), A));
+
+        let peer = client.local_addr()?;
+        let handle = |msg: Message| {
+            tokio::spawn(handle(server.clone(), msg, peer, resolver.clone(), config.clone()))
+        };
+        // the retransmit arrives while the original is still being resolved
+        let original = handle(msg.clone());
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        let retransmit = handle(msg.clone());
+        original.await??;
+        retransmit.await??;
+        let mut buf = [0; MAX_RECEIVE_BUFFER_SIZE];
+        for _ in 0..2 {
+            let read_count = client.recv(&mut buf).await?;
+            let response = Message::from_bytes(&buf[..read_count])?;
+            assert_eq!(response.id(), 4711);
+            assert_eq!(response.answers(), [a!("a.b.", "10.0.0.42")]);
+        }
+        assert_eq!(b.query_count(), 1);
+
+        // a query with another id is not a retransmit, and is answered from the cache
+        msg.set_id(4712);
+        handle(msg).await??;
+        let read_count = client.recv(&mut buf).await?;
+        assert_eq!(Message::from_bytes(&buf[..read_count])?.id(), 4712);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_refused() -> Result<()> {
+        let resolver = RecursiveResolver::builder()
+            .backend(ServFailBackend {})
+            .served_zones(vec!["b.".parse()?])
+            .build();
+        let mut msg = Message::new();
+        msg.set_recursion_desired(true);
+        msg.add_query(Query::query("a.c.".parse()?, A));
+        let response = resolve(msg, &resolver, &DaemonConfig::default()).await;
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+
+        // resolving a.b. caches the address of its nameserver in c., which isn't served either
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, refer!(ns!("b.", "ns.c.")))?;
+        b.add("10.0.0.1", "ns.c.", A, answer!(a!("ns.c.", "10.0.0.2")))?;
+        b.add("10.0.0.2", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        let resolver = RecursiveResolver::builder()
+            .backend(b)
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .served_zones(vec!["b.".parse()?])
+            .build();
+        resolver.resolve(&name!("a.b."), A).await?;
+        let mut msg = Message::new();
+        msg.add_query(Query::query(name!("ns.c."), A));
+        let response = resolve(msg, &resolver, &DaemonConfig::default()).await;
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+        assert!(response.answers().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_checking_disabled() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        for cd in [true, false] {
+            let mut msg = Message::new();
+            msg.set_checking_disabled(cd);
+            msg.set_recursion_desired(true);
+            msg.add_query(Query::query("a.b.".parse()?, A));
+            let response = resolve(msg, &resolver, &DaemonConfig::default()).await;
+            assert_eq!(cd, response.checking_disabled());
+            assert_eq!(response.answers(), [a!("a.b.", "10.0.0.42")]);
+        }
+
+        // the answer that fails validation is only returned to the clients that set CD
+        let resolver = signed_zones(true)?;
+        for cd in [true, false] {
+            let mut msg = Message::new();
+            msg.set_checking_disabled(cd);
+            msg.set_recursion_desired(true);
+            msg.add_query(Query::query(name!("broken.secure."), A));
+            let response = resolve(msg, &resolver, &DaemonConfig::default()).await;
+            match cd {
+                true => {
+                    assert_eq!(response.response_code(), ResponseCode::NoError);
+                    assert_eq!(response.answers(), [a!("broken.secure.", "10.0.0.66")]);
+                }
+                false => {
+                    assert_eq!(response.response_code(), ResponseCode::ServFail);
+                    assert!(response.answers().is_empty());
+                }
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_dnssec_ok() -> Result<()> {
+        let rrsig =
+            RRSIG::new(A, Algorithm::ECDSAP256SHA256, 2, 60, 2, 1, 4711, name!("b."), vec![0]);
+        let rrsig = Record::from_rdata(name!("a.b."), 60, RData::DNSSEC(DNSSECRData::RRSIG(rrsig)));
+        let mut signed = answer!(a!("a.b.", "10.0.0.42"));
+        signed.add_answer(rrsig.clone());
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, signed)?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        for dnssec_ok in [true, false] {
+            let mut msg = Message::new();
+            msg.set_recursion_desired(true);
+            msg.add_query(Query::query(name!("a.b."), A));
+            let mut edns = Edns::new();
+            edns.set_dnssec_ok(dnssec_ok);
+            msg.set_edns(edns);
+            let response = resolve(msg, &resolver, &DaemonConfig::default()).await;
+            match dnssec_ok {
+                true => assert_eq!(response.answers(), [a!("a.b.", "10.0.0.42"), rrsig.clone()]),
+                false => assert_eq!(response.answers(), [a!("a.b.", "10.0.0.42")]),
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_dnssec_ok_sections() -> Result<()> {
+        // the glue of the delegation of c.b. is signed, as is the NSEC record of a.b.
+        let sig = |name: &str, covered| -> Result<Record> {
+            let rrsig =
+                RRSIG::new(covered, Algorithm::RSASHA256, 3, 60, 2, 1, 4711, name!("b."), vec![0]);
+            Ok(Record::from_rdata(name!(name), 60, RData::DNSSEC(DNSSECRData::RRSIG(rrsig))))
+        };
+        let mut referral = refer!(ns!("c.b.", "ns.c.b."), a!("ns.c.b.", "10.0.0.2"));
+        referral.add_additional(sig("ns.c.b.", A)?);
+        let nsec = NSEC::new(name!("d.b."), vec![A, RecordType::NSEC, RecordType::RRSIG]);
+        let nsec = Record::from_rdata(name!("a.b."), 60, RData::DNSSEC(DNSSECRData::NSEC(nsec)));
+        let mut signed = answer!(nsec.clone());
+        signed.add_answer(sig("a.b.", RecordType::NSEC)?);
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.c.b.", A, referral)?;
+        b.add("10.0.0.2", "www.c.b.", A, answer!(a!("www.c.b.", "10.0.0.80")))?;
+        b.add("10.0.0.1", "a.b.", RecordType::NSEC, signed)?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+        resolver.resolve(&name!("www.c.b."), A).await?;
+
+        for dnssec_ok in [true, false] {
+            let mut edns = Edns::new();
+            edns.set_dnssec_ok(dnssec_ok);
+            // only the delegation of c.b. is cached for mail.c.b.
+            let mut msg = Message::new();
+            msg.add_query(Query::query(name!("mail.c.b."), A));
+            msg.set_edns(edns.clone());
+            let response = resolve(msg, &resolver, &DaemonConfig::default()).await;
+            assert_eq!(response.name_servers(), [ns!("c.b.", "ns.c.b.")]);
+            let glue = response.additionals();
+            assert_eq!(glue.iter().any(|r| r.record_type() == RecordType::RRSIG), dnssec_ok);
+
+            // the NSEC record is asked for, so it is included but not its signature
+            let mut msg = Message::new();
+            msg.set_recursion_desired(true);
+            msg.add_query(Query::query(name!("a.b."), RecordType::NSEC));
+            msg.set_edns(edns);
+            let response = resolve(msg, &resolver, &DaemonConfig::default()).await;
+            let expected = match dnssec_ok {
+                true => vec![nsec.clone(), sig("a.b.", RecordType::NSEC)?],
+                false => vec![nsec.clone()],
+            };
+            assert_eq!(response.answers(), expected);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_validated() -> Result<()> {
+        let resolver = signed_zones(true)?;
+        for (name, response_code, authentic_data) in [
+            ("www.secure.", ResponseCode::NoError, true),
+            ("www.insecure.", ResponseCode::NoError, false),
+            ("broken.secure.", ResponseCode::ServFail, false),
+        ] {
+            let mut msg = Message::new();
+            msg.set_recursion_desired(true);
+            msg.add_query(Query::query(name!(name), A));
+            let mut edns = Edns::new();
+            edns.set_dnssec_ok(true);
+            msg.set_edns(edns);
+            let response = resolve(msg, &resolver, &DaemonConfig::default()).await;
+            assert_eq!(response_code, response.response_code(), "{name}");
+            assert_eq!(authentic_data, response.authentic_data(), "{name}");
+        }
+
+        let mut msg = Message::new();
+        msg.set_recursion_desired(true);
+        msg.add_query(Query::query(name!("broken.secure."), A));
+        msg.set_edns(Edns::new());
+        let response = resolve(msg, &resolver, &DaemonConfig::default()).await;
+        assert!(response.answers().is_empty());
+        let edns = response.extensions().as_ref().expect("the client uses EDNS");
+        let Some(EdnsOption::Unknown(_, data)) = edns.option(EdnsCode::from(15)) else {
+            panic!("no extended error in {edns:?}");
+        };
+        assert_eq!(data, b"\x00\x06no valid signature for broken.secure. A");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_any() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        let mut msg = Message::new();
+        msg.set_recursion_desired(true);
+        msg.add_query(Query::query("a.b.".parse()?, RecordType::ANY));
+        let response = resolve(msg, &resolver, &DaemonConfig::default()).await;
+        // the other types fails, but we answer with the A record we have
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(response.answers(), [a!("a.b.", "10.0.0.42")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_address_family() -> Result<()> {
+        let aaaa = Record::from_rdata(name!("a.b."), 60, RData::AAAA("2001:db8::42".parse()?));
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        b.add("10.0.0.1", "a.b.", RecordType::AAAA, answer!(aaaa.clone()))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+        let mut msg = Message::new();
+        msg.set_recursion_desired(true);
+        msg.add_query(Query::query(name!("a.b."), RecordType::ANY));
+
+        let response = resolve(msg.clone(), &resolver, &DaemonConfig::default()).await;
+        assert_eq!(response.answers(), [a!("a.b.", "10.0.0.42"), aaaa.clone()]);
+        let config =
+            DaemonConfig { address_family: Some(AddressFamily::Ipv6), ..DaemonConfig::default() };
+        let response = resolve(msg, &resolver, &config).await;
+        assert_eq!(response.answers(), [aaaa, a!("a.b.", "10.0.0.42")]);
+
+        assert_eq!("ipv4".parse::<AddressFamily>()?, AddressFamily::Ipv4);
+        assert!("AAAA".parse::<AddressFamily>().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_startup_policy() -> Result<()> {
+        let mut priming = answer!(ns!(".", "ns.root."));
+        priming.add_additional(a!("ns.root.", "10.0.0.1"));
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", ".", RecordType::NS, priming)?;
+        b.delay("10.0.0.1", ".", RecordType::NS, Duration::from_millis(50))?;
+        b.add("10.0.0.1", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        let resolver = Arc::new(
+            RecursiveResolver::builder()
+                .backend(b)
+                .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+                .build(),
+        );
+        let mut msg = Message::new();
+        msg.set_recursion_desired(true);
+        msg.add_query(Query::query(name!("a.b."), A));
+
+        let servfail = DaemonConfig { startup: StartupPolicy::ServFail, ..DaemonConfig::default() };
+        let response = resolve(msg.clone(), &resolver, &servfail).await;
+        assert_eq!(response.response_code(), ResponseCode::ServFail);
+
+        let priming = tokio::spawn({
+            let resolver = resolver.clone();
+            async move { resolver.prime().await }
+        });
+        let startup = StartupPolicy::Queue(Duration::from_secs(5));
+        let queue = DaemonConfig { startup, ..DaemonConfig::default() };
+        let response = resolve(msg.clone(), &resolver, &queue).await;
+        // the query was held until priming completed
+        assert!(resolver.is_primed());
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert_eq!(response.answers(), [a!("a.b.", "10.0.0.42")]);
+        priming.await??;
+        let response = resolve(msg, &resolver, &servfail).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+
+        assert_eq!("queue:5".parse::<StartupPolicy>()?, startup);
+        assert!("queue".parse::<StartupPolicy>().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_status_zone() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        let b = Arc::new(b);
+        let resolver =
+            RecursiveResolver::with_backend(b.clone(), vec![IpAddr::V4("10.0.0.1".parse()?)]);
+        let status_zone = Some(StatusZone::new(Name::from_str("_resolver.status.example")?));
+        let config = DaemonConfig { status_zone, ..DaemonConfig::default() };
+        let resolve_txt = |name: &str, record_type: RecordType| {
+            let mut msg = Message::new();
+            msg.set_recursion_desired(true);
+            msg.add_query(Query::query(Name::from_str(name).unwrap(), record_type));
+            resolve(msg, &resolver, &config)
+        };
+        let txt = |response: &Message| -> Vec<String> {
+            let txt = response.answers().iter().filter_map(|r| r.data()?.as_txt());
+            txt.flat_map(|t| t.iter().map(|s| String::from_utf8_lossy(s).to_string())).collect()
+        };
+
+        let response = resolve_txt("version._resolver.status.example.", RecordType::TXT).await;
+        assert!(response.authoritative());
+        assert_eq!(txt(&response), [env!("CARGO_PKG_VERSION")]);
+        assert_eq!(response.answers()[0].ttl(), 0);
+        let response = resolve_txt("uptime._resolver.status.example.", RecordType::TXT).await;
+        assert_eq!(txt(&response), ["0"]);
+        let response = resolve_txt("Cache-Size._resolver.status.example.", RecordType::TXT).await;
+        assert_eq!(txt(&response), ["0"]);
+        resolver.resolve(&name!("a.b."), A).await?;
+        let response = resolve_txt("cache-size._resolver.status.example.", RecordType::TXT).await;
+        assert_eq!(txt(&response), [resolver.cache_len().to_string()]);
+        assert_ne!(resolver.cache_len(), 0);
+        let response = resolve_txt("version._resolver.status.example.", A).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        assert!(response.answers().is_empty());
+        let response = resolve_txt("other._resolver.status.example.", RecordType::TXT).await;
+        assert_eq!(response.response_code(), ResponseCode::NXDomain);
+        assert_eq!(b.query_count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_refused_any() -> Result<()> {
+        let b = Arc::new(FakeBackend::new());
+        let resolver =
+            RecursiveResolver::with_backend(b.clone(), vec![IpAddr::V4("10.0.0.1".parse()?)]);
+        let mut msg = Message::new();
+        msg.set_recursion_desired(true);
+        msg.add_query(Query::query("a.b.".parse()?, RecordType::ANY));
+        // the roots are not primed, yet the query is answered rather than failing
+        let startup = StartupPolicy::ServFail;
+        let config = DaemonConfig { refuse_any: true, startup, ..DaemonConfig::default() };
+        let response = resolve(msg, &resolver, &config).await;
+        assert_eq!(response.response_code(), ResponseCode::NoError);
+        let hinfo = rdata::HINFO::new("RFC8482".to_string(), String::new());
+        assert_eq!(
+            response.answers(),
+            [Record::from_rdata(name!("a.b."), 300, RData::HINFO(hinfo))]
+        );
+        assert_eq!(b.query_count(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_non_recursive() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, refer!(ns!("b.", "ns.b."), a!("ns.b.", "10.0.0.2")))?;
+        b.add("10.0.0.2", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+        resolver.resolve(&"a.b.".parse()?, A).await?;
+
+        let non_recursive = |name: &str| -> Result<Message> {
+            let mut msg = Message::new();
+            msg.add_query(Query::query(name.parse()?, A));
+            Ok(msg)
+        };
+        let config = DaemonConfig::default();
+        let response = resolve(non_recursive("a.b.")?, &resolver, &config).await;
+        assert_eq!(response.answers(), [a!("a.b.", "10.0.0.42")]);
+        assert!(response.recursion_available());
+        assert!(!response.recursion_desired());
+
+        // c.b. is not cached, but the delegation of b. is
+        let response = resolve(non_recursive("c.b.")?, &resolver, &config).await;
+        assert!(response.answers().is_empty());
+        assert_eq!(response.name_servers(), [ns!("b.", "ns.b.")]);
+
+        let response = resolve(non_recursive("c.d.")?, &resolver, &config).await;
+        assert_eq!(response.response_code(), ResponseCode::Refused);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_query_log() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+        let query_log = Arc::new(QueryLog::new(10));
+        let config = DaemonConfig { query_log: Some(query_log.clone()), ..DaemonConfig::default() };
+
+        for name in ["a.b.", "c.d.", "a.b."] {
+            let mut msg = Message::new();
+            msg.set_recursion_desired(true);
+            msg.add_query(Query::query(name.parse()?, A));
+            answer(msg, "127.0.0.1:4711".parse()?, &resolver, &config).await;
+        }
+        let entries: Vec<(String, ResponseCode, usize)> = query_log
+            .entries()
+            .iter()
+            .map(|e| (e.name.to_string(), e.response_code, e.answer_count))
+            .collect();
+        assert_eq!(
+            entries,
+            [
+                ("a.b.".to_string(), ResponseCode::NoError, 1),
+                ("c.d.".to_string(), ResponseCode::ServFail, 0),
+                ("a.b.".to_string(), ResponseCode::NoError, 1)
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_warmup() -> Result<()> {
+        let names = parse_warmup("# popular names\na.b.\n\n  c.d.  \n")?;
+        assert_eq!(names, vec![name!("a.b."), name!("c.d.")]);
+        assert!(parse_warmup("a..b").is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_warmup() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+        let resolver = Arc::new(resolver);
+
+        // c.d. fails to resolve, which doesn't stop the warmup
+        warmup(resolver.clone(), vec![name!("c.d."), name!("a.b.")]).await;
+        assert_eq!(
+            resolver.get_cached(&name!("a.b."), A),
+            CacheResponse::Authoritative(vec![a!("a.b.", "10.0.0.42")])
+        );
+        Ok(())
+    }
 }
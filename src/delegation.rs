@@ -0,0 +1,388 @@
+use crate::resolver::{RecursiveResolver, ResolutionError};
+use crate::target::get_name_if_ns;
+use async_recursion::async_recursion;
+use hickory_proto::op::ResponseCode;
+use hickory_proto::rr::{Name, RData, Record, RecordType};
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+
+/// How many glueless nameservers deep the address of a nameserver is resolved, which stops
+/// nameservers that need each other's addresses from looping
+const MAX_GLUELESS_DEPTH: usize = 4;
+
+/// The outcome of walking the delegation chain of a name from the root, one
+/// entry for each zone cut that was found on the way
+#[derive(Debug)]
+pub struct DelegationReport {
+    pub levels: Vec<ZoneCut>,
+}
+
+/// The nameservers of a zone, how each of them responded and the problems found
+#[derive(Debug)]
+pub struct ZoneCut {
+    pub zone: Name,
+    pub servers: Vec<ServerCheck>,
+    pub problems: Vec<String>,
+}
+
+#[derive(Debug)]
+pub struct ServerCheck {
+    /// The name of the nameserver, None for the configured root servers
+    pub name: Option<Name>,
+    pub ip: Option<IpAddr>,
+    pub outcome: Outcome,
+}
+
+/// A nameserver of a zone and all of its addresses
+#[derive(Debug, PartialEq)]
+pub struct NameserverAddresses {
+    pub name: Name,
+    pub addresses: Vec<IpAddr>,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+    /// The server delegated to the given, more specific, zone
+    Referral(Name),
+    /// The server answered authoritatively
+    Answer,
+    NxDomain,
+    /// The server could not be queried, or gave an unusable response
+    Failed(String),
+}
+
+impl RecursiveResolver {
+    /// Walks the delegation chain of `to_check` from the root, querying every nameserver
+    /// of each zone cut. Unlike resolve(), the responses of the nameservers are not cached
+    /// and no CNAMEs are followed. The A and AAAA addresses of nameservers without glue are
+    /// resolved from the roots with the same uncached queries, and each address is checked.
+    pub async fn check_delegation(&self, to_check: &Name) -> DelegationReport {
+        let mut levels = Vec::new();
+        let mut zone = Name::root();
+        let mut servers: Vec<(Option<Name>, Vec<IpAddr>)> =
+            self.roots().iter().map(|ip| (None, vec![*ip])).collect();
+        // every referral must lead to a more specific zone, which bounds the number of levels
+        loop {
+            let mut cut = ZoneCut { zone: zone.clone(), servers: Vec::new(), problems: Vec::new() };
+            let mut next = None;
+            for (name, ips) in servers {
+                let ips = match (&name, ips.is_empty()) {
+                    (Some(name), true) => match self.resolve_uncached(name, 0).await {
+                        Ok(ips) => ips,
+                        Err(e) => {
+                            let outcome = Outcome::Failed(format!("could not be resolved: {e}"));
+                            cut.servers.push(ServerCheck {
+                                name: Some(name.clone()),
+                                ip: None,
+                                outcome,
+                            });
+                            continue;
+                        }
+                    },
+                    _ => ips,
+                };
+                for ip in ips {
+                    let outcome = self.check_server(ip, &zone, to_check, &mut next).await;
+                    cut.servers.push(ServerCheck { name: name.clone(), ip: Some(ip), outcome });
+                }
+            }
+            cut.problems = find_problems(&cut.servers);
+            levels.push(cut);
+
+            let Some((child, ns, glue)) = next else {
+                break;
+            };
+            zone = child;
+            servers = ns
+                .iter()
+                .filter_map(|r| match get_name_if_ns(r) {
+                    Some(Ok(name)) => Some((Some(name.clone()), addresses_of(name, &glue))),
+                    _ => None,
+                })
+                .collect();
+        }
+        DelegationReport { levels }
+    }
+
+    /// Resolves the NS records of `zone` and the A and AAAA records of each nameserver.
+    /// Nameservers that can't be resolved are returned without addresses.
+    pub async fn resolve_nameservers(
+        &self,
+        zone: &Name,
+    ) -> Result<Vec<NameserverAddresses>, ResolutionError> {
+        let mut result = Vec::new();
+        for record in self.resolve(zone, RecordType::NS).await? {
+            let Some(Ok(name)) = get_name_if_ns(&record) else {
+                continue;
+            };
+            let types = [RecordType::A, RecordType::AAAA];
+            let addresses = self
+                .resolve_types(name, &types)
+                .await
+                .records
+                .iter()
+                .filter_map(|r| match r.data() {
+                    Some(RData::A(a)) => Some(IpAddr::V4(a.0)),
+                    Some(RData::AAAA(aaaa)) => Some(IpAddr::V6(aaaa.0)),
+                    _ => None,
+                })
+                .collect();
+            result.push(NameserverAddresses { name: name.clone(), addresses });
+        }
+        Ok(result)
+    }
+
+    /// Resolves the A and AAAA addresses of a glueless nameserver from the roots, with
+    /// uncached queries like those of the servers that are checked. The addresses come
+    /// from the first server that answers authoritatively for the name.
+    #[async_recursion]
+    async fn resolve_uncached(
+        &self,
+        name: &Name,
+        depth: usize,
+    ) -> Result<Vec<IpAddr>, ResolutionError> {
+        if depth > MAX_GLUELESS_DEPTH {
+            return Err(ResolutionError::ServFail("too many glueless nameservers".to_string()));
+        }
+        let mut zone = Name::root();
+        let mut servers = self.roots().to_vec();
+        loop {
+            let mut referral = None;
+            let mut error = ResolutionError::ServFail("no nameservers to ask".to_string());
+            for ip in servers {
+                let message = match self.backend().query(ip, name, RecordType::A).await {
+                    Ok(message) => message,
+                    Err(e) => {
+                        error = e;
+                        continue;
+                    }
+                };
+                if message.response_code() == ResponseCode::NXDomain {
+                    return Err(ResolutionError::NxDomain);
+                }
+                if message.header().authoritative() {
+                    let mut addresses = addresses_of(name, message.answers());
+                    // a nameserver without IPv6 addresses may well fail this query
+                    if let Ok(message) = self.backend().query(ip, name, RecordType::AAAA).await {
+                        addresses.extend(addresses_of(name, message.answers()));
+                    }
+                    if addresses.is_empty() {
+                        return Err(ResolutionError::ServFail("no addresses".to_string()));
+                    }
+                    return Ok(addresses);
+                }
+                let ns = message.name_servers();
+                match ns.iter().find(|r| r.record_type() == RecordType::NS).map(Record::name) {
+                    Some(child) if child != &zone && zone.zone_of(child) && child.zone_of(name) => {
+                        referral =
+                            Some((child.clone(), ns.to_vec(), message.additionals().to_vec()));
+                        break;
+                    }
+                    _ => error = ResolutionError::ServFail(format!("lame response from {ip}")),
+                }
+            }
+            let Some((child, ns, glue)) = referral else {
+                return Err(error);
+            };
+            zone = child;
+            let names: Vec<&Name> = ns.iter().filter_map(|r| get_name_if_ns(r)?.ok()).collect();
+            servers = names.iter().flat_map(|name| addresses_of(name, &glue)).collect();
+            // without glue, the first nameserver that can be resolved is used
+            for name in names {
+                if !servers.is_empty() {
+                    break;
+                }
+                servers = self.resolve_uncached(name, depth + 1).await.unwrap_or_default();
+            }
+        }
+    }
+
+    /// Queries a single nameserver of `zone`. The first referral to a child zone is kept
+    /// in `next`, to be followed once all the servers of this zone have been checked.
+    async fn check_server(
+        &self,
+        ip: IpAddr,
+        zone: &Name,
+        to_check: &Name,
+        next: &mut Option<(Name, Vec<Record>, Vec<Record>)>,
+    ) -> Outcome {
+        let message = match self.backend().query(ip, to_check, RecordType::A).await {
+            Ok(message) => message,
+            Err(e) => return Outcome::Failed(e.to_string()),
+        };
+        if message.response_code() == ResponseCode::NXDomain {
+            return Outcome::NxDomain;
+        }
+        if message.header().authoritative() {
+            return Outcome::Answer;
+        }
+        let ns = message.name_servers();
+        let Some(child) = ns.iter().find(|r| r.record_type() == RecordType::NS).map(Record::name)
+        else {
+            return Outcome::Failed(format!(
+                "lame, rcode {} without referral",
+                message.response_code()
+            ));
+        };
+        if child == zone || !zone.zone_of(child) || !child.zone_of(to_check) {
+            return Outcome::Failed(format!("lame, referral to {child} doesn't lead closer"));
+        }
+        if next.is_none() {
+            *next = Some((child.clone(), ns.to_vec(), message.additionals().to_vec()));
+        }
+        Outcome::Referral(child.clone())
+    }
+}
+
+/// Returns the A and AAAA addresses of `name` among the records
+fn addresses_of(name: &Name, records: &[Record]) -> Vec<IpAddr> {
+    records
+        .iter()
+        .filter(|r| r.name() == name)
+        .filter_map(|r| match r.data() {
+            Some(RData::A(a)) => Some(IpAddr::V4(a.0)),
+            Some(RData::AAAA(aaaa)) => Some(IpAddr::V6(aaaa.0)),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Looks for failing servers and servers that disagree with each other
+fn find_problems(servers: &[ServerCheck]) -> Vec<String> {
+    let mut problems = Vec::new();
+    let failed = servers.iter().filter(|s| matches!(s.outcome, Outcome::Failed(_))).count();
+    if failed > 0 {
+        problems.push(format!("{failed} of {} nameservers failed", servers.len()));
+    }
+    let mut outcomes: Vec<&Outcome> = Vec::new();
+    for server in servers {
+        if !matches!(server.outcome, Outcome::Failed(_)) && !outcomes.contains(&&server.outcome) {
+            outcomes.push(&server.outcome);
+        }
+    }
+    if outcomes.len() > 1 {
+        problems.push("the nameservers responded inconsistently".to_string());
+    }
+    problems
+}
+
+impl Display for DelegationReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for level in &self.levels {
+            writeln!(f, "{}", level.zone)?;
+            for server in &level.servers {
+                match (&server.name, &server.ip) {
+                    (Some(name), Some(ip)) => write!(f, "  {name} ({ip})")?,
+                    (Some(name), None) => write!(f, "  {name}")?,
+                    (None, Some(ip)) => write!(f, "  {ip}")?,
+                    (None, None) => write!(f, "  unknown")?,
+                }
+                match &server.outcome {
+                    Outcome::Referral(zone) => writeln!(f, ": referral to {zone}")?,
+                    Outcome::Answer => writeln!(f, ": answer")?,
+                    Outcome::NxDomain => writeln!(f, ": no such name")?,
+                    Outcome::Failed(e) => writeln!(f, ": failed, {e}")?,
+                }
+            }
+            for problem in &level.problems {
+                writeln!(f, "  problem: {problem}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cache::CacheResponse;
+    use crate::delegation::{NameserverAddresses, Outcome};
+    use crate::fake_backend::FakeBackend;
+    use crate::resolver::RecursiveResolver;
+    use crate::{a, answer, name, ns, refer};
+    use anyhow::Result;
+    use hickory_proto::op::{Header, Message};
+    use hickory_proto::rr::RecordType::{A, AAAA, NS};
+    use hickory_proto::rr::{rdata, Name, RData, Record};
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    #[tokio::test]
+    async fn test_check_delegation() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.a.b.", A, refer!(ns!("b.", "ns.b."), a!("ns.b.", "10.0.0.2")))?;
+        let mut referral = refer!(ns!("a.b.", "ns1.a.b."), a!("ns1.a.b.", "10.0.0.3"));
+        referral.add_name_server(ns!("a.b.", "ns2.a.b."));
+        referral.add_additional(a!("ns2.a.b.", "10.0.0.4"));
+        b.add("10.0.0.2", "www.a.b.", A, referral)?;
+        b.add("10.0.0.3", "www.a.b.", A, answer!(a!("www.a.b.", "10.0.0.42")))?;
+        // 10.0.0.4 has no response configured, so it fails
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        let report = resolver.check_delegation(&name!("www.a.b.")).await;
+        let zones: Vec<&Name> = report.levels.iter().map(|l| &l.zone).collect();
+        assert_eq!(zones, [&Name::root(), &name!("b."), &name!("a.b.")]);
+        assert_eq!(report.levels[0].servers[0].outcome, Outcome::Referral(name!("b.")));
+        assert_eq!(report.levels[1].servers[0].outcome, Outcome::Referral(name!("a.b.")));
+        assert!(report.levels[..2].iter().all(|l| l.problems.is_empty()));
+
+        let mut servers: Vec<_> = report.levels[2].servers.iter().collect();
+        servers.sort_by_key(|s| s.ip);
+        assert_eq!(servers[0].outcome, Outcome::Answer);
+        assert!(matches!(servers[1].outcome, Outcome::Failed(_)));
+        assert_eq!(report.levels[2].problems, ["1 of 2 nameservers failed"]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_check_delegation_glueless() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.b.", A, refer!(ns!("b.", "ns.c.")))?;
+        b.add("10.0.0.1", "ns.c.", A, refer!(ns!("c.", "ns.c."), a!("ns.c.", "10.0.0.5")))?;
+        b.add("10.0.0.5", "ns.c.", A, answer!(a!("ns.c.", "10.0.0.2")))?;
+        let aaaa = RData::AAAA(rdata::AAAA("2001:db8::2".parse()?));
+        b.add("10.0.0.5", "ns.c.", AAAA, answer!(Record::from_rdata(name!("ns.c."), 60, aaaa)))?;
+        b.add("10.0.0.2", "www.b.", A, answer!(a!("www.b.", "10.0.0.42")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        // both addresses of ns.c. are checked, and only the IPv4 one has a response
+        let report = resolver.check_delegation(&name!("www.b.")).await;
+        let servers = &report.levels[1].servers;
+        let ips: Vec<Option<IpAddr>> = servers.iter().map(|s| s.ip).collect();
+        assert_eq!(ips, [Some("10.0.0.2".parse()?), Some("2001:db8::2".parse()?)]);
+        assert_eq!(servers[0].outcome, Outcome::Answer);
+        assert!(matches!(servers[1].outcome, Outcome::Failed(_)));
+        // the address was looked up without the cache
+        let cached = resolver.get_cached(&name!("ns.c."), A);
+        assert!(matches!(cached, CacheResponse::None), "{cached:?}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_nameservers() -> Result<()> {
+        let mut referral = refer!(ns!("b.", "ns1.b."), a!("ns1.b.", "10.0.0.2"));
+        referral.add_name_server(ns!("b.", "ns2.c."));
+        let mut nameservers = answer!(ns!("b.", "ns1.b."));
+        nameservers.add_answer(ns!("b.", "ns2.c."));
+        let aaaa = RData::AAAA(rdata::AAAA("2001:db8::2".parse()?));
+
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "b.", NS, referral)?;
+        b.add("10.0.0.2", "b.", NS, nameservers)?;
+        b.add("10.0.0.2", "ns1.b.", AAAA, answer!(Record::from_rdata(name!("ns1.b."), 60, aaaa)))?;
+        b.add("10.0.0.1", "ns2.c.", A, answer!(a!("ns2.c.", "10.0.0.3")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        let result = resolver.resolve_nameservers(&name!("b.")).await?;
+        assert_eq!(
+            result,
+            [
+                NameserverAddresses {
+                    name: name!("ns1.b."),
+                    addresses: vec!["10.0.0.2".parse()?, "2001:db8::2".parse()?]
+                },
+                NameserverAddresses { name: name!("ns2.c."), addresses: vec!["10.0.0.3".parse()?] },
+            ]
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,447 @@
+use crate::crypto::sha1;
+use crate::resolver::DNAME;
+use hickory_proto::rr::dnssec::rdata::{DNSSECRData, NSEC, NSEC3};
+use hickory_proto::rr::{Name, RData, Record, RecordType};
+use hickory_proto::serialize::binary::BinEncodable;
+
+/// The most NSEC3 hash iterations that are computed, a denial that takes more is treated as
+/// insecure, RFC 9276 section 3.2
+const MAX_ITERATIONS: u16 = 150;
+
+/// What the NSEC or NSEC3 records of a negative answer prove, RFC 4035 section 5.4 and
+/// RFC 5155 section 8
+#[derive(Debug, PartialEq)]
+pub(crate) enum Proof {
+    /// The name, or the type at the name, doesn't exist
+    Denied,
+    /// An opt-out NSEC3 record covers the name, so there may be an unsigned delegation
+    /// there, or the hashes take too many iterations to check
+    Insecure,
+    /// The records don't prove it
+    Missing,
+}
+
+/// What the records prove about the name not existing in the zone
+pub(crate) fn nxdomain(zone: &Name, name: &Name, records: &[Record]) -> Proof {
+    match Nsec3Chain::new(zone, records) {
+        Some(chain) if chain.iterations > MAX_ITERATIONS => Proof::Insecure,
+        Some(chain) => chain.nxdomain(name),
+        None => NsecChain::new(zone, records).nxdomain(name),
+    }
+}
+
+/// What the records prove about the name having no records of the type in the zone
+pub(crate) fn nodata(
+    zone: &Name,
+    name: &Name,
+    record_type: RecordType,
+    records: &[Record],
+) -> Proof {
+    match Nsec3Chain::new(zone, records) {
+        Some(chain) if chain.iterations > MAX_ITERATIONS => Proof::Insecure,
+        Some(chain) => chain.nodata(name, record_type),
+        None => NsecChain::new(zone, records).nodata(name, record_type),
+    }
+}
+
+/// The NSEC records of a zone that came with a negative answer
+struct NsecChain<'a> {
+    nsecs: Vec<(&'a Name, &'a NSEC)>,
+}
+
+impl<'a> NsecChain<'a> {
+    fn new(zone: &Name, records: &'a [Record]) -> Self {
+        let nsecs = records.iter().filter_map(|r| match r.data() {
+            Some(RData::DNSSEC(DNSSECRData::NSEC(nsec)))
+                if zone.zone_of(r.name()) && zone.zone_of(nsec.next_domain_name()) =>
+            {
+                Some((r.name(), nsec))
+            }
+            _ => None,
+        });
+        NsecChain { nsecs: nsecs.collect() }
+    }
+
+    /// The NSEC record that covers the name, along with the closest encloser that it proves,
+    /// the longest ancestor of the name that the owner or the next name is within
+    fn covering(&self, name: &Name) -> Option<(&'a NSEC, Name)> {
+        self.nsecs.iter().find_map(|(owner, nsec)| {
+            let next = nsec.next_domain_name();
+            let covers = match *owner < next {
+                true => *owner < name && name < next,
+                // the last NSEC record of the zone wraps around to the apex
+                false => *owner < name || name < next,
+            };
+            // the names below a delegation or a DNAME are not in the zone
+            let cut = *owner != name && owner.zone_of(name) && !below_is_zone(nsec.type_bit_maps());
+            match covers && !cut {
+                true => {
+                    let (by_owner, by_next) = (ancestor(name, owner), ancestor(name, next));
+                    match by_owner.num_labels() >= by_next.num_labels() {
+                        true => Some((*nsec, by_owner)),
+                        false => Some((*nsec, by_next)),
+                    }
+                }
+                false => None,
+            }
+        })
+    }
+
+    fn matching(&self, name: &Name) -> Option<&'a NSEC> {
+        self.nsecs.iter().find(|(owner, _)| *owner == name).map(|(_, nsec)| *nsec)
+    }
+
+    fn nxdomain(&self, name: &Name) -> Proof {
+        let Some((_, encloser)) = self.covering(name) else {
+            return Proof::Missing;
+        };
+        match wildcard(&encloser).and_then(|w| self.covering(&w)) {
+            Some(_) => Proof::Denied,
+            None => Proof::Missing,
+        }
+    }
+
+    fn nodata(&self, name: &Name, record_type: RecordType) -> Proof {
+        if let Some(nsec) = self.matching(name) {
+            return denies(nsec.type_bit_maps(), record_type);
+        }
+        let Some((nsec, encloser)) = self.covering(name) else {
+            return Proof::Missing;
+        };
+        // an empty non-terminal is covered by the NSEC record of the name before its
+        // descendants, RFC 4035 section 3.1.3.2
+        let next = nsec.next_domain_name();
+        if next != name && name.zone_of(next) {
+            return Proof::Denied;
+        }
+        match wildcard(&encloser).and_then(|w| self.matching(&w)) {
+            Some(nsec) => denies(nsec.type_bit_maps(), record_type),
+            None => Proof::Missing,
+        }
+    }
+}
+
+/// The NSEC3 records of a zone that came with a negative answer, those with the parameters
+/// of the first one, RFC 5155 section 8.2
+struct Nsec3Chain<'a> {
+    zone: Name,
+    iterations: u16,
+    salt: &'a [u8],
+    /// The hashes of the owner names, with the records
+    nsec3s: Vec<(Vec<u8>, &'a NSEC3)>,
+}
+
+impl<'a> Nsec3Chain<'a> {
+    fn new(zone: &Name, records: &'a [Record]) -> Option<Self> {
+        let nsec3s: Vec<(Vec<u8>, &NSEC3)> = records
+            .iter()
+            .filter_map(|r| match r.data() {
+                Some(RData::DNSSEC(DNSSECRData::NSEC3(nsec3))) if r.name().base_name() == *zone => {
+                    let hash = decode_base32hex(r.name().iter().next()?)?;
+                    (hash.len() == nsec3.next_hashed_owner_name().len()).then_some((hash, nsec3))
+                }
+                _ => None,
+            })
+            .collect();
+        let (_, first) = nsec3s.first()?;
+        let (iterations, salt) = (first.iterations(), first.salt());
+        let nsec3s = nsec3s
+            .into_iter()
+            .filter(|(_, n)| n.iterations() == iterations && n.salt() == salt)
+            .collect();
+        Some(Nsec3Chain { zone: zone.clone(), iterations, salt, nsec3s })
+    }
+
+    fn hash(&self, name: &Name) -> Option<Vec<u8>> {
+        nsec3_hash(name, self.salt, self.iterations)
+    }
+
+    fn matching(&self, name: &Name) -> Option<&'a NSEC3> {
+        let hash = self.hash(name)?;
+        self.nsec3s.iter().find(|(owner, _)| *owner == hash).map(|(_, nsec3)| *nsec3)
+    }
+
+    fn covering(&self, name: &Name) -> Option<&'a NSEC3> {
+        let hash = self.hash(name)?;
+        self.nsec3s.iter().find_map(|(owner, nsec3)| {
+            let next = nsec3.next_hashed_owner_name();
+            let covers = match owner.as_slice() < next {
+                true => *owner < hash && hash.as_slice() < next,
+                false => *owner < hash || hash.as_slice() < next,
+            };
+            covers.then_some(*nsec3)
+        })
+    }
+
+    /// The closest encloser of the name, a matching NSEC3 record for it and one covering the
+    /// name one label longer, the next closer name, RFC 5155 section 8.3
+    fn closest_encloser(&self, name: &Name) -> Option<(Name, &'a NSEC3)> {
+        let mut next_closer = name.clone();
+        while next_closer != self.zone && self.zone.zone_of(&next_closer) {
+            let encloser = next_closer.base_name();
+            if let Some(nsec3) = self.matching(&encloser) {
+                if !below_is_zone(nsec3.type_bit_maps()) {
+                    return None;
+                }
+                return Some((encloser, self.covering(&next_closer)?));
+            }
+            next_closer = encloser;
+        }
+        None
+    }
+
+    fn nxdomain(&self, name: &Name) -> Proof {
+        let Some((encloser, next_closer)) = self.closest_encloser(name) else {
+            return Proof::Missing;
+        };
+        match wildcard(&encloser).and_then(|w| self.covering(&w)) {
+            Some(_) if next_closer.opt_out() => Proof::Insecure,
+            Some(_) => Proof::Denied,
+            None => Proof::Missing,
+        }
+    }
+
+    fn nodata(&self, name: &Name, record_type: RecordType) -> Proof {
+        if let Some(nsec3) = self.matching(name) {
+            return denies(nsec3.type_bit_maps(), record_type);
+        }
+        let Some((encloser, next_closer)) = self.closest_encloser(name) else {
+            return Proof::Missing;
+        };
+        // an opt-out span leaves out the unsigned delegations, RFC 5155 section 8.6
+        if record_type == RecordType::DS && next_closer.opt_out() {
+            return Proof::Insecure;
+        }
+        match wildcard(&encloser).and_then(|w| self.matching(&w)) {
+            Some(nsec3) => denies(nsec3.type_bit_maps(), record_type),
+            None => Proof::Missing,
+        }
+    }
+}
+
+/// Whether the types at a name that exists prove it has no records of the type. The DS
+/// records are on the parent side of a delegation, and the other records on the child side.
+fn denies(types: &[RecordType], record_type: RecordType) -> Proof {
+    let apex = types.contains(&RecordType::SOA);
+    let cut = types.contains(&RecordType::NS) && !apex;
+    let absent = !types.contains(&record_type) && !types.contains(&RecordType::CNAME);
+    match absent
+        && (record_type == RecordType::DS && !apex || record_type != RecordType::DS && !cut)
+    {
+        true => Proof::Denied,
+        false => Proof::Missing,
+    }
+}
+
+/// Returns false if the names below the owner of the types are in another zone or are
+/// redirected by a DNAME, as the records of the owner can't prove anything about them
+fn below_is_zone(types: &[RecordType]) -> bool {
+    let cut = types.contains(&RecordType::NS) && !types.contains(&RecordType::SOA);
+    !cut && !types.contains(&DNAME)
+}
+
+/// The longest ancestor of the name that the other name is within
+fn ancestor(name: &Name, other: &Name) -> Name {
+    let mut ancestor = name.clone();
+    while !ancestor.zone_of(other) {
+        ancestor = ancestor.base_name();
+    }
+    ancestor
+}
+
+/// The wildcard name directly below the closest encloser, the source of synthesis
+fn wildcard(encloser: &Name) -> Option<Name> {
+    Name::from_ascii("*").ok()?.append_domain(encloser).ok()
+}
+
+/// The NSEC3 hash of the name, the SHA-1 digest of its canonical wire format and the salt,
+/// digested again with the salt for the number of iterations, RFC 5155 section 5
+pub(crate) fn nsec3_hash(name: &Name, salt: &[u8], iterations: u16) -> Option<Vec<u8>> {
+    let mut hash = sha1(&[name.to_lowercase().to_bytes().ok()?.as_slice(), salt].concat());
+    for _ in 0..iterations {
+        hash = sha1(&[hash.as_slice(), salt].concat());
+    }
+    Some(hash.to_vec())
+}
+
+/// Decodes the Base 32 Encoding with Extended Hex Alphabet of the hashed owner name of an
+/// NSEC3 record, RFC 4648 section 7, which is without padding and case-insensitive
+fn decode_base32hex(label: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let (mut bits, mut count) = (0u32, 0);
+    for c in label {
+        let value = match c.to_ascii_lowercase() {
+            c @ b'0'..=b'9' => c - b'0',
+            c @ b'a'..=b'v' => c - b'a' + 10,
+            _ => return None,
+        };
+        bits = (bits << 5) | u32::from(value);
+        count += 5;
+        if count >= 8 {
+            count -= 8;
+            decoded.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+    // the leftover bits are only padding
+    (bits == 0).then_some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::denial::{nodata, nsec3_hash, nxdomain, Proof};
+    use crate::name;
+    use anyhow::Result;
+    use hickory_proto::rr::dnssec::rdata::{DNSSECRData, NSEC, NSEC3};
+    use hickory_proto::rr::dnssec::Nsec3HashAlgorithm;
+    use hickory_proto::rr::RecordType::{A, AAAA, CNAME, DS, NS, SOA};
+    use hickory_proto::rr::{Name, RData, Record, RecordType};
+    use std::str::FromStr;
+
+    const SALT: [u8; 4] = [0xaa, 0xbb, 0xcc, 0xdd];
+
+    /// The names of example., which has a delegation to d.example., the empty non-terminal
+    /// w.example. and the wildcard *.w.example.
+    const NAMES: [(&str, &[RecordType]); 5] = [
+        ("example.", &[SOA, NS]),
+        ("a.example.", &[A]),
+        ("d.example.", &[NS]),
+        ("w.example.", &[]),
+        ("*.w.example.", &[A]),
+    ];
+
+    fn encode_base32hex(data: &[u8]) -> String {
+        let alphabet = b"0123456789abcdefghijklmnopqrstuv";
+        let (mut encoded, mut bits, mut count) = (String::new(), 0u32, 0);
+        for byte in data {
+            bits = (bits << 8) | u32::from(*byte);
+            count += 8;
+            while count >= 5 {
+                count -= 5;
+                encoded.push(alphabet[(bits >> count) as usize & 31] as char);
+            }
+        }
+        if count > 0 {
+            encoded.push(alphabet[(bits << (5 - count)) as usize & 31] as char);
+        }
+        encoded
+    }
+
+    fn nsec(owner: &str, next: &str, types: &[RecordType]) -> Result<Record> {
+        let nsec = NSEC::new(name!(next), types.to_vec());
+        Ok(Record::from_rdata(name!(owner), 300, RData::DNSSEC(DNSSECRData::NSEC(nsec))))
+    }
+
+    /// The NSEC records of the names of example., the empty non-terminal has none
+    fn nsec_chain() -> Result<Vec<Record>> {
+        Ok(vec![
+            nsec("example.", "a.example.", &[SOA, NS])?,
+            nsec("a.example.", "d.example.", &[A])?,
+            nsec("d.example.", "*.w.example.", &[NS])?,
+            nsec("*.w.example.", "example.", &[A])?,
+        ])
+    }
+
+    /// The NSEC3 records of the names, hashed with SALT, in the order of the hashes. With
+    /// opt_out, the delegations are left out.
+    fn nsec3_chain(opt_out: bool, iterations: u16) -> Result<Vec<Record>> {
+        let mut hashed = Vec::new();
+        for (name, types) in NAMES {
+            if !opt_out || types.contains(&SOA) || !types.contains(&NS) {
+                hashed.push((nsec3_hash(&name!(name), &SALT, iterations).unwrap(), types));
+            }
+        }
+        hashed.sort();
+        let mut records = Vec::new();
+        for (i, (hash, types)) in hashed.iter().enumerate() {
+            let next = hashed[(i + 1) % hashed.len()].0.clone();
+            let algorithm = Nsec3HashAlgorithm::SHA1;
+            let nsec3 =
+                NSEC3::new(algorithm, opt_out, iterations, SALT.to_vec(), next, types.to_vec());
+            let owner = name!(&format!("{}.example.", encode_base32hex(hash)));
+            records.push(Record::from_rdata(owner, 300, RData::DNSSEC(DNSSECRData::NSEC3(nsec3))));
+        }
+        Ok(records)
+    }
+
+    #[test]
+    fn test_nsec3_hash() -> Result<()> {
+        // from RFC 5155 appendix A
+        let hash = nsec3_hash(&name!("example."), &SALT, 12).unwrap();
+        assert_eq!("0p9mhaveqvm6t7vbl5lop2u3t2rp3tom", encode_base32hex(&hash));
+        let hash = nsec3_hash(&name!("A.EXAMPLE."), &SALT, 12).unwrap();
+        assert_eq!("35mthgpgcu1qg68fab165klnsnk3dpvl", encode_base32hex(&hash));
+        Ok(())
+    }
+
+    #[test]
+    fn test_nsec_nxdomain() -> Result<()> {
+        let (zone, chain) = (name!("example."), nsec_chain()?);
+        assert_eq!(Proof::Denied, nxdomain(&zone, &name!("b.example."), &chain));
+        // the wildcard at the closest encloser must be denied as well
+        assert_eq!(Proof::Missing, nxdomain(&zone, &name!("b.example."), &chain[1..]));
+        assert_eq!(Proof::Missing, nxdomain(&zone, &name!("a.example."), &chain));
+        // the names below a delegation are not in the zone
+        assert_eq!(Proof::Missing, nxdomain(&zone, &name!("x.d.example."), &chain));
+        // nor are the records of another zone
+        assert_eq!(Proof::Missing, nxdomain(&name!("other."), &name!("b.example."), &chain));
+        Ok(())
+    }
+
+    #[test]
+    fn test_nsec_nodata() -> Result<()> {
+        let (zone, chain) = (name!("example."), nsec_chain()?);
+        assert_eq!(Proof::Denied, nodata(&zone, &name!("a.example."), AAAA, &chain));
+        assert_eq!(Proof::Missing, nodata(&zone, &name!("a.example."), A, &chain));
+        assert_eq!(Proof::Missing, nodata(&zone, &name!("a.example."), AAAA, &chain[2..]));
+        let alias = [nsec("a.example.", "d.example.", &[CNAME])?];
+        assert_eq!(Proof::Missing, nodata(&zone, &name!("a.example."), AAAA, &alias));
+        // the DS records of a delegation are on the parent side, the others on the child side
+        assert_eq!(Proof::Denied, nodata(&zone, &name!("d.example."), DS, &chain));
+        assert_eq!(Proof::Missing, nodata(&zone, &name!("d.example."), A, &chain));
+        assert_eq!(Proof::Missing, nodata(&zone, &name!("example."), DS, &chain));
+        // the empty non-terminal, and the types that the wildcard has no records of
+        assert_eq!(Proof::Denied, nodata(&zone, &name!("w.example."), A, &chain));
+        assert_eq!(Proof::Denied, nodata(&zone, &name!("z.w.example."), AAAA, &chain));
+        assert_eq!(Proof::Missing, nodata(&zone, &name!("z.w.example."), A, &chain));
+        Ok(())
+    }
+
+    #[test]
+    fn test_nsec3_nxdomain() -> Result<()> {
+        let (zone, chain) = (name!("example."), nsec3_chain(false, 12)?);
+        assert_eq!(Proof::Denied, nxdomain(&zone, &name!("b.example."), &chain));
+        assert_eq!(Proof::Missing, nxdomain(&zone, &name!("a.example."), &chain));
+        assert_eq!(Proof::Missing, nxdomain(&zone, &name!("x.d.example."), &chain));
+        // the closest encloser needs a matching record
+        let apex = nsec3_hash(&zone, &SALT, 12).unwrap();
+        let owner = name!(&format!("{}.example.", encode_base32hex(&apex)));
+        let without_apex: Vec<Record> =
+            chain.iter().filter(|r| *r.name() != owner).cloned().collect();
+        assert_eq!(Proof::Missing, nxdomain(&zone, &name!("b.example."), &without_apex));
+
+        let opt_out = nsec3_chain(true, 12)?;
+        assert_eq!(Proof::Insecure, nxdomain(&zone, &name!("b.example."), &opt_out));
+        let expensive = nsec3_chain(false, 151)?;
+        assert_eq!(Proof::Insecure, nxdomain(&zone, &name!("b.example."), &expensive));
+        Ok(())
+    }
+
+    #[test]
+    fn test_nsec3_nodata() -> Result<()> {
+        let (zone, chain) = (name!("example."), nsec3_chain(false, 12)?);
+        assert_eq!(Proof::Denied, nodata(&zone, &name!("a.example."), AAAA, &chain));
+        assert_eq!(Proof::Missing, nodata(&zone, &name!("a.example."), A, &chain));
+        assert_eq!(Proof::Denied, nodata(&zone, &name!("d.example."), DS, &chain));
+        assert_eq!(Proof::Missing, nodata(&zone, &name!("d.example."), A, &chain));
+        assert_eq!(Proof::Denied, nodata(&zone, &name!("w.example."), A, &chain));
+        assert_eq!(Proof::Denied, nodata(&zone, &name!("z.w.example."), AAAA, &chain));
+        assert_eq!(Proof::Missing, nodata(&zone, &name!("z.w.example."), A, &chain));
+
+        // the unsigned delegation is left out of the opt-out chain
+        let opt_out = nsec3_chain(true, 12)?;
+        assert_eq!(Proof::Insecure, nodata(&zone, &name!("d.example."), DS, &opt_out));
+        assert_eq!(Proof::Missing, nodata(&zone, &name!("d.example."), A, &opt_out));
+        Ok(())
+    }
+}
@@ -0,0 +1,341 @@
+use crate::crypto::{sha256, verify_rsa_sha256};
+use anyhow::{anyhow, bail};
+use hickory_proto::rr::dnssec::rdata::{DNSSECRData, DNSKEY, DS, SIG};
+use hickory_proto::rr::dnssec::tbs::rrset_tbs_with_sig;
+use hickory_proto::rr::dnssec::{Algorithm, DigestType};
+use hickory_proto::rr::{Name, RData, Record};
+use hickory_proto::serialize::binary::BinEncodable;
+use std::str::FromStr;
+
+/// The IANA root zone KSK trust anchors, as published in
+/// [root-anchors.xml](https://data.iana.org/root-anchors/root-anchors.xml).
+/// Each entry holds key tag, algorithm, digest type and the hex encoded digest.
+const ROOT_ANCHORS: [(u16, u8, u8, &str); 2] = [
+    // KSK-2017
+    (20326, 8, 2, "E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D"),
+    // KSK-2024
+    (38696, 8, 2, "683D2D0ACB8C9B712A1948B27F741219298D0A450D612C483AF444A4C0FB2B16"),
+];
+
+/// A trust anchor in DS form, the starting point for building a chain of trust.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TrustAnchor {
+    zone: Name,
+    ds: DS,
+}
+
+impl TrustAnchor {
+    pub fn new(zone: Name, ds: DS) -> Self {
+        TrustAnchor { zone, ds }
+    }
+
+    /// The trust anchors for the root zone that are embedded in this binary
+    pub fn root_anchors() -> Vec<TrustAnchor> {
+        ROOT_ANCHORS
+            .iter()
+            .map(|(key_tag, algorithm, digest_type, digest)| {
+                let ds = DS::new(
+                    *key_tag,
+                    Algorithm::from_u8(*algorithm),
+                    DigestType::from_u8(*digest_type).expect("embedded digest type is valid"),
+                    decode_hex(digest).expect("embedded digest is valid hex"),
+                );
+                TrustAnchor::new(Name::root(), ds)
+            })
+            .collect()
+    }
+
+    /// Checks if the given DNSKEY, found at `zone` is the key this anchor refers to
+    fn matches(&self, zone: &Name, key: &DNSKEY) -> bool {
+        *zone == self.zone && key.secure_entry_point() && ds_matches(&self.ds, zone, key)
+    }
+}
+
+/// How an answer fared in DNSSEC validation, RFC 4033 section 5
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValidationStatus {
+    /// There is a chain of trust from a trust anchor to every RRset of the answer
+    Secure,
+    /// Part of the answer is from a zone without a chain of trust, as its parent has no DS
+    /// record for it, or from this resolver itself
+    Insecure,
+    /// The answer should be signed, but the signatures are missing, have expired or don't
+    /// verify, with the reason why
+    Bogus(String),
+    /// The answer was not validated, as the resolver doesn't validate or the client asked
+    /// it not to
+    Indeterminate,
+}
+
+/// Returns true if the DS record refers to the DNSKEY, found at `zone`. Only SHA-256
+/// digests, RFC 4509, can be verified.
+fn ds_matches(ds: &DS, zone: &Name, key: &DNSKEY) -> bool {
+    key.zone_key()
+        && !key.revoke()
+        && key.algorithm() == ds.algorithm()
+        && key.calculate_key_tag().is_ok_and(|tag| tag == ds.key_tag())
+        && ds.digest_type() == DigestType::SHA256
+        && ds_digest(zone, key).is_some_and(|digest| digest == ds.digest())
+}
+
+/// The SHA-256 digest of the owner name and rdata of the DNSKEY, RFC 4034 section 5.1.4
+pub(crate) fn ds_digest(zone: &Name, key: &DNSKEY) -> Option<[u8; 32]> {
+    let owner = zone.to_lowercase().to_bytes().ok()?;
+    let rdata = RData::DNSSEC(DNSSECRData::DNSKEY(key.clone())).to_bytes().ok()?;
+    Some(sha256(&[owner, rdata].concat()))
+}
+
+/// Returns true if the DS records use an algorithm and digest type that can be verified.
+/// A zone with only other DS records is treated as unsigned, RFC 4035 section 5.2.
+pub(crate) fn supported(ds: &[DS]) -> bool {
+    ds.iter()
+        .any(|ds| ds.algorithm() == Algorithm::RSASHA256 && ds.digest_type() == DigestType::SHA256)
+}
+
+/// Returns the DNSKEY records from `keys` that one of the DS records refers to
+pub(crate) fn keys_for_ds(zone: &Name, keys: &[Record], ds: &[DS]) -> Vec<Record> {
+    keys.iter()
+        .filter(|r| match r.data() {
+            Some(RData::DNSSEC(DNSSECRData::DNSKEY(key))) => {
+                ds.iter().any(|ds| ds_matches(ds, zone, key))
+            }
+            _ => false,
+        })
+        .cloned()
+        .collect()
+}
+
+/// Returns true if one of the RRSIG records that are given along with the RRset is a
+/// valid signature by one of the keys at `now`, RFC 4035 section 5.3. Only RSASHA256
+/// signatures, RFC 5702, can be verified.
+pub(crate) fn verify_rrset(rrset: &[Record], keys: &[Record], now: u32) -> bool {
+    rrset.iter().any(|record| match record.data() {
+        Some(RData::DNSSEC(DNSSECRData::RRSIG(sig))) => {
+            verify_signature(record, sig, rrset, keys, now)
+        }
+        _ => false,
+    })
+}
+
+fn verify_signature(
+    record: &Record,
+    sig: &SIG,
+    rrset: &[Record],
+    keys: &[Record],
+    now: u32,
+) -> bool {
+    if sig.algorithm() != Algorithm::RSASHA256
+        || now < sig.sig_inception()
+        || now > sig.sig_expiration()
+    {
+        return false;
+    }
+    let Ok(signed) = rrset_tbs_with_sig(record.name(), record.dns_class(), sig, rrset) else {
+        return false;
+    };
+    keys.iter().any(|r| match r.data() {
+        Some(RData::DNSSEC(DNSSECRData::DNSKEY(key))) => {
+            r.name() == sig.signer_name()
+                && key.zone_key()
+                && !key.revoke()
+                && key.algorithm() == sig.algorithm()
+                && key.calculate_key_tag().is_ok_and(|tag| tag == sig.key_tag())
+                && verify_rsa_sha256(key.public_key(), signed.as_ref(), sig.sig())
+        }
+        _ => false,
+    })
+}
+
+/// Parses the DS style presentation format, `<zone> <key tag> <algorithm> <digest type> <digest>`
+/// for example `. 20326 8 2 E06D44B8...`
+impl FromStr for TrustAnchor {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [zone, key_tag, algorithm, digest_type, digest] = fields[..] else {
+            bail!("expected '<zone> <key tag> <algorithm> <digest type> <digest>', got '{s}'");
+        };
+        let ds = DS::new(
+            key_tag.parse()?,
+            Algorithm::from_u8(algorithm.parse()?),
+            DigestType::from_u8(digest_type.parse()?)?,
+            decode_hex(digest)?,
+        );
+        Ok(TrustAnchor::new(zone.parse()?, ds))
+    }
+}
+
+pub(crate) fn decode_hex(s: &str) -> anyhow::Result<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        bail!("invalid hex string '{s}'");
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).map_err(|_| anyhow!("invalid hex '{s}'")))
+        .collect()
+}
+
+/// Performs DNSSEC validation, starting from a set of trust anchors.
+#[derive(Debug)]
+pub struct Validator {
+    anchors: Vec<TrustAnchor>,
+}
+
+impl Validator {
+    pub fn new(anchors: Vec<TrustAnchor>) -> Self {
+        Validator { anchors }
+    }
+
+    /// Returns true if there is a trust anchor for the zone
+    pub(crate) fn anchored(&self, zone: &Name) -> bool {
+        self.anchors.iter().any(|a| a.zone == *zone)
+    }
+
+    /// Returns the DNSKEY records from `keys` that are covered by one of the trust anchors
+    pub fn trusted_keys(&self, zone: &Name, keys: &[Record]) -> Vec<Record> {
+        keys.iter()
+            .filter(|r| match r.data() {
+                Some(RData::DNSSEC(DNSSECRData::DNSKEY(key))) => {
+                    self.anchors.iter().any(|a| a.matches(zone, key))
+                }
+                _ => false,
+            })
+            .cloned()
+            .collect()
+    }
+}
+
+/// Signs test zones with the key of crypto::test_key, which is the key of every zone
+#[cfg(test)]
+pub(crate) mod test_signer {
+    use crate::crypto::test_key;
+    use crate::dnssec::ds_digest;
+    use hickory_proto::rr::dnssec::rdata::{DNSSECRData, DNSKEY, DS, RRSIG};
+    use hickory_proto::rr::dnssec::tbs::rrset_tbs_with_sig;
+    use hickory_proto::rr::dnssec::{Algorithm, DigestType};
+    use hickory_proto::rr::{Name, RData, Record};
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    fn key() -> DNSKEY {
+        DNSKEY::new(true, true, false, Algorithm::RSASHA256, test_key::public_key())
+    }
+
+    pub(crate) fn dnskey(zone: &Name) -> Record {
+        Record::from_rdata(zone.clone(), 3600, RData::DNSSEC(DNSSECRData::DNSKEY(key())))
+    }
+
+    /// The DS record of the key of the zone
+    pub(crate) fn ds(zone: &Name) -> Record {
+        let digest = ds_digest(zone, &key()).unwrap().to_vec();
+        let tag = key().calculate_key_tag().unwrap();
+        let ds = DS::new(tag, Algorithm::RSASHA256, DigestType::SHA256, digest);
+        Record::from_rdata(zone.clone(), 3600, RData::DNSSEC(DNSSECRData::DS(ds)))
+    }
+
+    /// Returns the RRSIG record of the zone over the RRset, valid from an hour ago for a day
+    pub(crate) fn sign(rrset: &[Record], zone: &Name) -> Record {
+        let first = &rrset[0];
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32;
+        let rrsig = |sig| {
+            RRSIG::new(
+                first.record_type(),
+                Algorithm::RSASHA256,
+                first.name().num_labels(),
+                first.ttl(),
+                now + 86400,
+                now - 3600,
+                key().calculate_key_tag().unwrap(),
+                zone.clone(),
+                sig,
+            )
+        };
+        let signed = rrset_tbs_with_sig(first.name(), first.dns_class(), &rrsig(Vec::new()), rrset);
+        let rrsig = rrsig(test_key::sign(signed.unwrap().as_ref()));
+        Record::from_rdata(
+            first.name().clone(),
+            first.ttl(),
+            RData::DNSSEC(DNSSECRData::RRSIG(rrsig)),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::dnssec::{keys_for_ds, test_signer, verify_rrset, TrustAnchor, Validator};
+    use crate::{a, name};
+    use anyhow::Result;
+    use hickory_proto::rr::dnssec::rdata::{DNSSECRData, DNSKEY};
+    use hickory_proto::rr::dnssec::Algorithm;
+    use hickory_proto::rr::{rdata, Name, RData, Record};
+    use std::str::FromStr;
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// The KSK-2017 root key, which has key tag 20326
+    fn root_ksk() -> Record {
+        let public_key = hickory_proto::rr::dnssec::TrustAnchor::default().get(1).to_vec();
+        let key = DNSKEY::new(true, true, false, Algorithm::RSASHA256, public_key);
+        Record::from_rdata(Name::root(), 172800, RData::DNSSEC(DNSSECRData::DNSKEY(key)))
+    }
+
+    #[test]
+    fn test_embedded_anchor() {
+        let validator = Validator::new(TrustAnchor::root_anchors());
+        let keys = vec![root_ksk()];
+        assert_eq!(keys, validator.trusted_keys(&Name::root(), &keys));
+    }
+
+    #[test]
+    fn test_overridden_anchor() -> Result<()> {
+        let anchor = TrustAnchor::from_str(". 4711 8 2 0000")?;
+        let validator = Validator::new(vec![anchor]);
+        assert!(validator.trusted_keys(&Name::root(), &[root_ksk()]).is_empty());
+
+        // the digest is verified, not only the key tag
+        let anchor = TrustAnchor::from_str(". 20326 8 2 E06D44B8")?;
+        let validator = Validator::new(vec![anchor]);
+        assert!(validator.trusted_keys(&Name::root(), &[root_ksk()]).is_empty());
+
+        let digest = "E06D44B80B8F1D39A95C0B0D7C65D08458E880409BBC683457104237C7F8EC8D";
+        let anchor = TrustAnchor::from_str(&format!(". 20326 8 2 {digest}"))?;
+        let validator = Validator::new(vec![anchor]);
+        assert_eq!(1, validator.trusted_keys(&Name::root(), &[root_ksk()]).len());
+        // the anchor is only valid for the zone it was configured for
+        assert!(validator.trusted_keys(&name!("com."), &[root_ksk()]).is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_verify_rrset() -> Result<()> {
+        let zone = name!("b.");
+        let keys = [test_signer::dnskey(&zone)];
+        let rrset = vec![a!("a.b.", "10.0.0.1"), a!("a.b.", "10.0.0.2")];
+        let signed = [rrset.clone(), vec![test_signer::sign(&rrset, &zone)]].concat();
+        assert!(verify_rrset(&signed, &keys, now()));
+        // the signature only covers the records it was made for
+        let other = a!("a.b.", "10.0.0.3");
+        assert!(!verify_rrset(&[&signed[..], &[other]].concat(), &keys, now()));
+        // ...and is only valid for the time it was made for
+        assert!(!verify_rrset(&signed, &keys, now() + 2 * 86400));
+        // ...and only with the key of the zone that made it
+        assert!(!verify_rrset(&signed, &[test_signer::dnskey(&name!("c."))], now()));
+        assert!(!verify_rrset(&rrset, &keys, now()));
+
+        let ds = test_signer::ds(&zone);
+        let Some(RData::DNSSEC(DNSSECRData::DS(ds))) = ds.data() else { panic!("not a DS") };
+        assert_eq!(keys.to_vec(), keys_for_ds(&zone, &keys, std::slice::from_ref(ds)));
+        assert!(keys_for_ds(&name!("c."), &keys, std::slice::from_ref(ds)).is_empty());
+        Ok(())
+    }
+
+    fn now() -> u32 {
+        SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as u32
+    }
+
+    #[test]
+    fn test_parse_invalid_anchor() {
+        assert!(TrustAnchor::from_str(". 20326 8 2").is_err());
+        assert!(TrustAnchor::from_str(". 20326 8 2 E06").is_err());
+        assert!(TrustAnchor::from_str(". 20326 8 2 XY").is_err());
+    }
+}
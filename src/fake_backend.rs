@@ -1,17 +1,33 @@
 use std::collections::HashMap;
 use std::fmt::{Debug, Formatter};
+use std::io;
 use std::net::IpAddr;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
 
 use async_trait::async_trait;
 use hickory_proto::op::Message;
 use hickory_proto::rr::{Name, RecordType};
 
-use crate::backend::Backend;
-use crate::resolver::ResolutionError;
+use crate::backend::{Backend, QueryOptions};
 use crate::resolver::ResolutionError::ServFail;
+use crate::resolver::{fqdn, ResolutionError};
 
 pub struct FakeBackend {
     answers: HashMap<QueryKey, Message>,
+    /// Simulated network trouble, by query
+    faults: HashMap<QueryKey, Fault>,
+    query_count: AtomicUsize,
+}
+
+/// How the responses to a query are held up on the way
+#[derive(Default)]
+struct Fault {
+    /// The time before the response arrives, or is given up on if dropped
+    delay: Duration,
+    /// The number of queries left to drop before responding
+    drops: AtomicUsize,
 }
 
 pub struct ServFailBackend {}
@@ -36,7 +52,11 @@ impl Debug for FakeBackend {
 
 impl FakeBackend {
     pub fn new() -> Self {
-        FakeBackend { answers: HashMap::new() }
+        FakeBackend {
+            answers: HashMap::new(),
+            faults: HashMap::new(),
+            query_count: AtomicUsize::new(0),
+        }
     }
     pub fn add(
         &mut self,
@@ -45,21 +65,48 @@ impl FakeBackend {
         record_type: RecordType,
         message: Message,
     ) -> Result<(), ResolutionError> {
-        let key = QueryKey {
-            target: IpAddr::V4(ip.parse().expect("Failed to parse IP")),
-            name: name.parse()?,
-            record_type,
-        };
-        self.answers.insert(key, message);
+        self.answers.insert(QueryKey::new(ip, name, record_type)?, message);
+        Ok(())
+    }
+
+    /// Delays the responses to the query, as a slow or distant server would
+    pub fn delay(
+        &mut self,
+        ip: &str,
+        name: &str,
+        record_type: RecordType,
+        delay: Duration,
+    ) -> Result<(), ResolutionError> {
+        self.faults.entry(QueryKey::new(ip, name, record_type)?).or_default().delay = delay;
+        Ok(())
+    }
+
+    /// Drops the first `count` queries, which then fail as if they timed out, before the
+    /// query is answered as usual. Any delay applies to the dropped queries as well.
+    pub fn drop_first(
+        &mut self,
+        ip: &str,
+        name: &str,
+        record_type: RecordType,
+        count: usize,
+    ) -> Result<(), ResolutionError> {
+        let fault = self.faults.entry(QueryKey::new(ip, name, record_type)?).or_default();
+        fault.drops = AtomicUsize::new(count);
         Ok(())
     }
 
     pub fn get(&self, target: IpAddr, name: &Name, record_type: RecordType) -> Option<Message> {
-        let key = QueryKey { target, name: name.clone(), record_type };
+        let key = QueryKey { target, name: fqdn(name), record_type };
 
         self.answers.get(&key).cloned()
     }
+
+    /// The number of queries this backend has received
+    pub fn query_count(&self) -> usize {
+        self.query_count.load(Ordering::Relaxed)
+    }
 }
+
 #[derive(PartialEq, Eq, Hash)]
 pub struct QueryKey {
     target: IpAddr,
@@ -67,6 +114,16 @@ pub struct QueryKey {
     record_type: RecordType,
 }
 
+impl QueryKey {
+    fn new(ip: &str, name: &str, record_type: RecordType) -> Result<Self, ResolutionError> {
+        Ok(QueryKey {
+            target: IpAddr::V4(ip.parse().expect("Failed to parse IP")),
+            name: fqdn(&name.parse()?),
+            record_type,
+        })
+    }
+}
+
 #[async_trait]
 impl Backend for FakeBackend {
     async fn query(
@@ -75,8 +132,99 @@ impl Backend for FakeBackend {
         name: &Name,
         record_type: RecordType,
     ) -> Result<Message, ResolutionError> {
+        self.query_count.fetch_add(1, Ordering::Relaxed);
+        let key = QueryKey { target, name: fqdn(name), record_type };
+        if let Some(fault) = self.faults.get(&key) {
+            tokio::time::sleep(fault.delay).await;
+            if fault
+                .drops
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| n.checked_sub(1))
+                .is_ok()
+            {
+                let message = format!("dropped query for {name} {record_type} to {target}");
+                return Err(io::Error::new(io::ErrorKind::TimedOut, message).into());
+            }
+        }
         self.get(target, name, record_type).ok_or(ServFail(format!(
             "Could not find response for {name} {record_type} at {target}"
         )))
     }
 }
+
+/// Makes it possible to inspect a backend after handing it to a resolver
+#[async_trait]
+impl<B: Backend + Send + Sync> Backend for Arc<B> {
+    async fn query(
+        &self,
+        target: IpAddr,
+        name: &Name,
+        record_type: RecordType,
+    ) -> Result<Message, ResolutionError> {
+        self.as_ref().query(target, name, record_type).await
+    }
+
+    async fn query_with_options(
+        &self,
+        target: IpAddr,
+        name: &Name,
+        record_type: RecordType,
+        options: QueryOptions,
+    ) -> Result<Message, ResolutionError> {
+        self.as_ref().query_with_options(target, name, record_type, options).await
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::backend::Backend;
+    use crate::fake_backend::FakeBackend;
+    use crate::resolver::{RecursiveResolver, ResolutionError};
+    use crate::{a, answer, name};
+    use anyhow::Result;
+    use hickory_proto::op::{Header, Message};
+    use hickory_proto::rr::RecordType::A;
+    use hickory_proto::rr::{rdata, Name, RData, Record};
+    use std::io;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    #[tokio::test]
+    async fn test_delay() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        b.delay("10.0.0.1", "a.b.", A, Duration::from_millis(50))?;
+        let target = IpAddr::V4("10.0.0.1".parse()?);
+
+        let start = Instant::now();
+        let response = b.query(target, &name!("a.b."), A).await?;
+        assert_eq!(response.answers(), [a!("a.b.", "10.0.0.42")]);
+        assert!(start.elapsed() >= Duration::from_millis(50));
+        // the delay only applies to the query it was given for
+        let start = Instant::now();
+        assert!(b.query(target, &name!("c.b."), A).await.is_err());
+        assert!(start.elapsed() < Duration::from_millis(50));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_drop_first() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        b.drop_first("10.0.0.1", "a.b.", A, 2)?;
+        let resolver = RecursiveResolver::builder()
+            .backend(b)
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .build();
+
+        for _ in 0..2 {
+            let result = resolver.resolve(&name!("a.b."), A).await;
+            let Err(ResolutionError::IOError(e)) = result else {
+                panic!("expected the query to time out, got {result:?}");
+            };
+            assert_eq!(e.kind(), io::ErrorKind::TimedOut);
+        }
+        assert_eq!(resolver.resolve(&name!("a.b."), A).await?, [a!("a.b.", "10.0.0.42")]);
+        Ok(())
+    }
+}
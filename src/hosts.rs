@@ -0,0 +1,67 @@
+use anyhow::bail;
+use hickory_proto::rr::rdata::PTR;
+use hickory_proto::rr::{Name, RData, Record};
+use std::net::IpAddr;
+use std::str::FromStr;
+
+/// The ttl of the records made from a hosts file
+const HOSTS_TTL: u32 = 300;
+
+/// Parses the contents of a hosts file into PTR records for the reverse lookups of the
+/// addresses, in in-addr.arpa. and ip6.arpa. Each line holds an address followed by its
+/// host names, the first of which is the canonical one that the PTR record points to.
+/// Anything after a '#' is a comment.
+pub fn parse_hosts(contents: &str) -> anyhow::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for line in contents.lines() {
+        let line = line.split('#').next().unwrap_or_default();
+        let mut fields = line.split_whitespace();
+        let Some(address) = fields.next() else {
+            continue;
+        };
+        let Some(host) = fields.next() else {
+            bail!("expected '<address> <host name>', got '{}'", line.trim());
+        };
+        let mut host = Name::from_str(host)?;
+        host.set_fqdn(true);
+        let reverse = Name::from(IpAddr::from_str(address)?);
+        records.push(Record::from_rdata(reverse, HOSTS_TTL, RData::PTR(PTR(host))));
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod test {
+    use crate::hosts::parse_hosts;
+    use crate::name;
+    use anyhow::Result;
+    use hickory_proto::rr::{Name, RData};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_parse_hosts() -> Result<()> {
+        let records = parse_hosts(
+            "# internal hosts\n\n10.0.0.1 gw.lab gateway\n2001:db8::1 ns.lab. # the resolver\n",
+        )?;
+        let names: Vec<&Name> = records.iter().map(|r| r.name()).collect();
+        assert_eq!(
+            names,
+            [
+                &name!("1.0.0.10.in-addr.arpa."),
+                &name!("1.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.0.8.b.d.0.1.0.0.2.ip6.arpa."),
+            ]
+        );
+        let hosts: Vec<String> = records
+            .iter()
+            .filter_map(|r| match r.data() {
+                Some(RData::PTR(ptr)) => Some(ptr.0.to_string()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(hosts, ["gw.lab.", "ns.lab."]);
+
+        assert!(parse_hosts("10.0.0.1\n").is_err());
+        assert!(parse_hosts("10.0.0 gw.lab\n").is_err());
+        Ok(())
+    }
+}
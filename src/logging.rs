@@ -0,0 +1,206 @@
+use anyhow::bail;
+use serde_json::{json, Map, Value};
+use std::fmt::Debug;
+use std::io::Write;
+use std::str::FromStr;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Subscriber};
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::Context;
+use tracing_subscriber::registry::LookupSpan;
+use tracing_subscriber::Layer;
+
+/// How the logs written to stderr are formatted
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LogFormat {
+    /// Human readable lines, as formatted by tracing_subscriber
+    Text,
+    /// A JSON object per line, see JsonLayer
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(LogFormat::Text),
+            "json" => Ok(LogFormat::Json),
+            _ => bail!("expected 'text' or 'json', got '{s}'"),
+        }
+    }
+}
+
+/// Writes every event as a line of JSON, for log aggregation. The object holds the time
+/// in seconds since the epoch, the level, the target and the fields of the event, and the
+/// spans it happened within from the outermost, each with its name and fields.
+pub struct JsonLayer<W> {
+    make_writer: W,
+}
+
+/// The fields of a span, kept in its extensions as they are recorded
+struct SpanFields(Map<String, Value>);
+
+struct JsonVisitor<'a>(&'a mut Map<String, Value>);
+
+impl Visit for JsonVisitor<'_> {
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.0.insert(field.name().to_string(), json!(value));
+    }
+
+    fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+        self.0.insert(field.name().to_string(), json!(format!("{value:?}")));
+    }
+}
+
+impl<W: for<'a> MakeWriter<'a> + 'static> JsonLayer<W> {
+    pub fn new(make_writer: W) -> Self {
+        JsonLayer { make_writer }
+    }
+}
+
+impl<S, W> Layer<S> for JsonLayer<W>
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    W: for<'a> MakeWriter<'a> + 'static,
+{
+    fn on_new_span(&self, attrs: &Attributes<'_>, id: &Id, ctx: Context<'_, S>) {
+        let mut fields = Map::new();
+        attrs.record(&mut JsonVisitor(&mut fields));
+        if let Some(span) = ctx.span(id) {
+            span.extensions_mut().insert(SpanFields(fields));
+        }
+    }
+
+    fn on_record(&self, id: &Id, values: &Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(fields) = extensions.get_mut::<SpanFields>() {
+            values.record(&mut JsonVisitor(&mut fields.0));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        let mut fields = Map::new();
+        event.record(&mut JsonVisitor(&mut fields));
+        let spans: Vec<Value> = ctx
+            .event_scope(event)
+            .into_iter()
+            .flat_map(|scope| scope.from_root())
+            .map(|span| {
+                let mut object = Map::new();
+                object.insert("name".to_string(), json!(span.name()));
+                if let Some(fields) = span.extensions().get::<SpanFields>() {
+                    object.extend(fields.0.clone());
+                }
+                Value::Object(object)
+            })
+            .collect();
+        let metadata = event.metadata();
+        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let line = json!({
+            "timestamp": timestamp.as_secs_f64(),
+            "level": metadata.level().as_str(),
+            "target": metadata.target(),
+            "fields": fields,
+            "spans": spans,
+        });
+        // there is nowhere to report a failure to write the logs to
+        let _ = writeln!(self.make_writer.make_writer(), "{line}");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::fake_backend::FakeBackend;
+    use crate::logging::{JsonLayer, LogFormat};
+    use crate::name;
+    use crate::resolver::RecursiveResolver;
+    use anyhow::Result;
+    use hickory_proto::op::{Message, ResponseCode};
+    use hickory_proto::rr::RecordType::A;
+    use hickory_proto::rr::{rdata, Name, RData, Record, RecordType};
+    use serde_json::{json, Value};
+    use std::net::IpAddr;
+    use std::str::FromStr;
+    use std::sync::{Arc, Mutex};
+    use tracing::level_filters::LevelFilter;
+    use tracing_subscriber::layer::SubscriberExt;
+    use tracing_subscriber::{Layer, Registry};
+
+    #[derive(Clone, Default)]
+    struct LogBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for LogBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_json_logs() -> Result<()> {
+        let mut nxdomain = Message::new();
+        nxdomain.set_response_code(ResponseCode::NXDomain);
+        nxdomain.set_authoritative(true);
+        let soa = rdata::SOA::new(name!("ns.b."), name!("hostmaster.b."), 1, 3600, 600, 86400, 60);
+        nxdomain.add_name_server(Record::from_rdata(name!("b."), 60, RData::SOA(soa)));
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, nxdomain)?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+        let logs = LogBuffer::default();
+        let writer = logs.clone();
+        let layer = JsonLayer::new(move || writer.clone()).with_filter(LevelFilter::DEBUG);
+        let _guard = tracing::subscriber::set_default(Registry::default().with(layer));
+
+        // the second time the answer comes from the negative cache, which is logged
+        for _ in 0..2 {
+            assert!(resolver.resolve(&name!("a.b."), RecordType::A).await.is_err());
+        }
+
+        let logs = String::from_utf8(logs.0.lock().unwrap().clone())?;
+        let lines: Vec<Value> = logs.lines().map(serde_json::from_str).collect::<Result<_, _>>()?;
+        let line = lines
+            .iter()
+            .find(|l| l["fields"]["message"] == "Negative answer from the cache")
+            .unwrap_or_else(|| panic!("no negative cache hit was logged: {logs}"));
+        assert_eq!(line["level"], "DEBUG");
+        assert_eq!(line["fields"]["zone"], "b.");
+        let outermost = &line["spans"][0];
+        assert_eq!(outermost["name"], "resolve_with_options");
+        assert_eq!(outermost["to_resolve"], "a.b.");
+        assert_eq!(outermost["otel.kind"], "server");
+        // the resolver itself, with all its configuration, is left out
+        assert!(outermost.get("self").is_none(), "{outermost}");
+        assert_eq!(line["spans"].as_array().map(Vec::len), Some(2), "{line}");
+        assert_eq!(line["spans"][1]["name"], "resolve_inner");
+        assert_eq!(line["spans"][1]["depth"], json!(1));
+
+        assert_eq!(LogFormat::from_str("json")?, LogFormat::Json);
+        assert!(LogFormat::from_str("xml").is_err());
+        Ok(())
+    }
+}
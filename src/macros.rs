@@ -8,7 +8,14 @@ macro_rules! ns {
 #[macro_export]
 macro_rules! a {
     ($name:expr, $target:expr) => {
-        Record::from_rdata($name.parse()?, 60, RData::A(rdata::A(($target.parse()?))))
+        Record::from_rdata($name.parse()?, 60, RData::A(rdata::A($target.parse()?)))
+    };
+}
+
+#[macro_export]
+macro_rules! cname {
+    ($name:expr, $target:expr) => {
+        Record::from_rdata($name.parse()?, 60, RData::CNAME(rdata::CNAME($target.parse()?)))
     };
 }
 
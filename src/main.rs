@@ -1,4 +1,17 @@
-use crate::resolver::RecursiveResolver;
+use crate::admin::LogLevelSetter;
+use crate::backend::UdpBackend;
+use crate::cache::{TtlLimits, TtlOverride, TtlRange};
+use crate::daemon::{AddressFamily, DaemonConfig, OptPolicy, StartupPolicy};
+use crate::dnssec::TrustAnchor;
+use crate::logging::{JsonLayer, LogFormat};
+use crate::query_log::QueryLog;
+use crate::resolver::{RecursiveResolver, ResolveOptions, StubZone};
+use crate::retransmit::Retransmits;
+use crate::rrl::ResponseRateLimiter;
+use crate::special_use::LocalUsePolicy;
+use crate::status::StatusZone;
+use crate::synthetic::SyntheticSoa;
+use crate::zone::StaticZone;
 use anyhow::Result;
 use clap::{Parser, Subcommand};
 use hickory_proto::rr::domain::Name;
@@ -9,24 +22,232 @@ use opentelemetry_otlp::WithExportConfig;
 use opentelemetry_sdk::trace::Config;
 use opentelemetry_sdk::{runtime, Resource};
 use opentelemetry_semantic_conventions::resource::{SERVICE_NAME, SERVICE_VERSION};
+use std::fs;
+use std::net::IpAddr;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::Duration;
 use tracing::level_filters::LevelFilter;
+use tracing::warn;
 use tracing_subscriber::layer::SubscriberExt;
-use tracing_subscriber::{Layer, Registry};
+use tracing_subscriber::{reload, Layer, Registry};
 
+mod admin;
 mod backend;
 mod cache;
+mod cooldown;
+mod crypto;
 mod daemon;
+mod delegation;
+mod denial;
+mod dnssec;
 #[cfg(test)]
 mod fake_backend;
+mod hosts;
+mod logging;
 #[cfg(test)]
 mod macros;
+mod outage;
+mod pacing;
+mod query_log;
 mod resolver;
+mod retransmit;
+mod rrl;
+mod special_use;
+mod status;
+mod synthetic;
 mod target;
+mod tcp;
+mod upstream_stats;
+mod validation;
+mod zone;
 
 #[derive(Parser)]
 struct Cli {
     #[command(subcommand)]
     command: Commands,
+
+    /// Replaces the embedded root trust anchors, in the format
+    /// '<zone> <key tag> <algorithm> <digest type> <digest>'. Can be given multiple times
+    #[arg(long, global = true)]
+    trust_anchor: Vec<TrustAnchor>,
+
+    /// Validate the answers with DNSSEC, from the trust anchors, setting the AD bit on the
+    /// Secure ones and answering SERVFAIL for the Bogus ones. Implies --dnssec-ok. Only
+    /// RSASHA256 signatures are verified, and negative answers need NSEC or NSEC3 records.
+    #[arg(long, global = true)]
+    validate: bool,
+
+    /// The number of distinct nameservers to try, where available, before failing a query
+    #[arg(long, global = true)]
+    min_nameservers: Option<usize>,
+
+    /// The number of nameservers of a delegation that are tried at most
+    #[arg(long, global = true)]
+    max_nameservers: Option<usize>,
+
+    /// Only follow referrals to nameservers within the delegated zone
+    #[arg(long, global = true)]
+    strict_bailiwick: bool,
+
+    /// The local address to send outgoing queries from
+    #[arg(long, global = true)]
+    source_address: Option<IpAddr>,
+
+    /// The maximum number of queries to send to resolve a single name
+    #[arg(long, global = true)]
+    max_queries: Option<usize>,
+
+    /// Resolve the addresses of up to this many glueless nameservers of a referral concurrently
+    #[arg(long, global = true)]
+    glueless_parallelism: Option<usize>,
+
+    /// Advertise this EDNS UDP payload size when asking the roots for their nameservers
+    #[arg(long, global = true, value_parser = clap::value_parser!(u16).range(512..))]
+    priming_buffer_size: Option<u16>,
+
+    /// How the names of home.arpa. and local. without local records or a stub zone are
+    /// answered: 'nxdomain' or 'recurse' to resolve them like other names
+    #[arg(long, global = true, default_value = "nxdomain")]
+    local_use_policy: LocalUsePolicy,
+
+    /// Stop querying an upstream for this many seconds once it has failed several times in a row
+    #[arg(long, global = true)]
+    upstream_cooldown: Option<u64>,
+
+    /// Pass over glue addresses for this many seconds once they have failed several times in a
+    /// row, resolving the nameserver names instead
+    #[arg(long, global = true)]
+    stale_glue_timeout: Option<u64>,
+
+    /// Answer with records that expired up to an hour ago once no upstream has responded for
+    /// this many seconds, until one does again
+    #[arg(long, global = true)]
+    outage_window: Option<u64>,
+
+    /// Cache records with a ttl in this range, in seconds, given as '<min>:<max>'
+    #[arg(long, global = true)]
+    ttl_range: Option<TtlRange>,
+
+    /// Cache records of a type with a ttl in this range, overriding --ttl-range. Given as
+    /// '<type>=<min>:<max>', for example 'NS=3600:86400'. Can be given multiple times
+    #[arg(long, global = true)]
+    ttl_override: Vec<TtlOverride>,
+
+    /// Cache the NS records of delegations with a ttl in this range, given as '<min>:<max>',
+    /// overriding --ttl-range and --ttl-override
+    #[arg(long, global = true)]
+    delegation_ttl_range: Option<TtlRange>,
+
+    /// Cache the glue of referrals with a ttl in this range, given as '<min>:<max>',
+    /// overriding --ttl-range and --ttl-override
+    #[arg(long, global = true)]
+    glue_ttl_range: Option<TtlRange>,
+
+    /// A hosts file with addresses and their host names, to answer reverse lookups of the
+    /// addresses from, without recursing
+    #[arg(long, global = true)]
+    hosts_file: Option<PathBuf>,
+
+    /// A zone file, with an $ORIGIN, for a zone to answer authoritatively from its records.
+    /// Can be given multiple times
+    #[arg(long, global = true)]
+    zone_file: Vec<PathBuf>,
+
+    /// A file to keep the stats of the upstreams in, restored on startup and saved by the
+    /// daemon every minute, to not start out trying the upstreams that never responded
+    #[arg(long, global = true)]
+    upstream_stats_file: Option<PathBuf>,
+
+    /// The SOA to answer from the hosts file with, given as
+    /// '<mname> <rname> <serial> <refresh> <retry> <expire> <minimum>'
+    #[arg(long, global = true)]
+    synthetic_soa: Option<SyntheticSoa>,
+
+    /// Fail resolution, with SERVFAIL, on responses that break the rules instead of dropping
+    /// the offending records or trying other nameservers
+    #[arg(long, global = true)]
+    strict: bool,
+
+    /// Check CNAME chains that cross zones for loops and excessive length as a whole
+    #[arg(long, global = true)]
+    strict_cnames: bool,
+
+    /// Evict cache entries once the cached records take up more than this many bytes
+    #[arg(long, global = true)]
+    max_cache_bytes: Option<usize>,
+
+    /// Log the cache entries that are evicted to make room or removed once expired, at debug level
+    #[arg(long, global = true)]
+    log_cache_evictions: bool,
+
+    /// Answer only from the cache, failing instead of sending queries when it can't answer
+    #[arg(long, global = true)]
+    cache_only: bool,
+
+    /// Don't cache anything, making every resolution start from the roots
+    #[arg(long, global = true)]
+    no_cache: bool,
+
+    /// Try the nameservers in the order they are listed instead of in random order, for
+    /// reproducing problems with the selection of nameservers
+    #[arg(long, global = true)]
+    no_shuffle: bool,
+
+    /// Mark outgoing queries with this DSCP value
+    #[arg(long, global = true, value_parser = clap::value_parser!(u8).range(0..64))]
+    dscp: Option<u8>,
+
+    /// Set the DO bit in outgoing queries and pass the RRSIG records on to clients that set
+    /// it, for them to validate. The resolver itself only validates with --validate.
+    #[arg(long, global = true)]
+    dnssec_ok: bool,
+
+    /// The EDNS buffer size to advertise. Given multiple times, in increasing order,
+    /// truncated responses are retried over UDP with each of the following sizes before
+    /// falling back to TCP
+    #[arg(long, global = true, value_parser = clap::value_parser!(u16).range(512..=4096))]
+    edns_buffer_size: Vec<u16>,
+
+    /// Keep TCP connections open for reuse until they have been idle for this many seconds
+    #[arg(long, global = true)]
+    tcp_idle_timeout: Option<u64>,
+
+    /// Send at most this many queries per second to any one server, after a burst of that
+    /// many, delaying the rest
+    #[arg(long, global = true)]
+    outbound_rate_limit: Option<u32>,
+
+    /// Send every outbound query over TCP, never over UDP
+    #[arg(long, global = true)]
+    tcp_only: bool,
+
+    /// Send the names in queries uncompressed, for servers that mishandle compression
+    #[arg(long, global = true)]
+    no_name_compression: bool,
+
+    /// Write logs to stderr, as 'text' or as 'json' with an object per line. Without it,
+    /// the traces are only exported over OTLP
+    #[arg(long, global = true, env = "LOG_FORMAT")]
+    log_format: Option<LogFormat>,
+
+    /// Seeds all randomness, making nameserver selection and query ids predictable. Only
+    /// intended for reproducing bugs
+    #[arg(long, global = true)]
+    seed: Option<u64>,
+
+    /// Sort the records of answers by name, type and data, making the output stable
+    #[arg(long, global = true)]
+    sort_answers: bool,
+
+    /// Only resolve names within this zone, refusing other queries. Can be given multiple times
+    #[arg(long, global = true)]
+    serve_zone: Vec<Name>,
+
+    /// Resolve the names within a zone starting at its authoritative servers instead of the
+    /// roots, given as '<zone>=<address>[,<address>...]'. Can be given multiple times
+    #[arg(long, global = true)]
+    stub_zone: Vec<StubZone>,
 }
 
 #[derive(Subcommand)]
@@ -34,34 +255,359 @@ enum Commands {
     Daemon {
         #[arg(short, long, default_value_t = 53)]
         port: u16,
+
+        /// Respond with the delegation from the cache, if there is one, instead of recursing
+        #[arg(long)]
+        serve_referrals: bool,
+
+        /// A file with names, one per line, to resolve in the background on startup
+        #[arg(long)]
+        warmup: Option<PathBuf>,
+
+        /// Serve the admin interface on this port on localhost
+        #[arg(long)]
+        admin_port: Option<u16>,
+
+        /// Keep this many of the most recent queries, to list over the admin interface
+        #[arg(long, value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..))]
+        query_log: Option<usize>,
+
+        /// Send at most this many identical responses per second to the clients of a /24 or
+        /// /56 prefix. Beyond that, responses are dropped or truncated to make clients use TCP
+        #[arg(long, value_parser = clap::value_parser!(u32).range(1..))]
+        response_rate_limit: Option<u32>,
+
+        /// Put the addresses of this family, 'ipv4' or 'ipv6', first in the answers
+        #[arg(long)]
+        prefer_family: Option<AddressFamily>,
+
+        /// How to handle queries that need recursion before the root nameservers have been
+        /// primed: 'resolve' from the built in roots, answer 'servfail', or hold them for up
+        /// to a number of seconds with 'queue:<seconds>'
+        #[arg(long, default_value = "resolve")]
+        startup: StartupPolicy,
+
+        /// Answer queries for ANY with an HINFO record saying that they are refused, RFC 8482,
+        /// instead of resolving a set of common types for them
+        #[arg(long)]
+        refuse_any: bool,
+
+        /// When to include an OPT record in responses: 'echo' it if the query had one,
+        /// 'always' or 'never'
+        #[arg(long, default_value = "echo")]
+        opt_record: OptPolicy,
+
+        /// Answer the queries that a client retransmits within this many seconds of the
+        /// original with the response to it, rather than resolving them again
+        #[arg(long)]
+        retransmit_window: Option<u64>,
+
+        /// Answer TXT queries for version, uptime and cache-size within this zone with the
+        /// version of the resolver, the seconds since it started and the size of its cache
+        #[arg(long)]
+        status_zone: Option<Name>,
     },
     Lookup {
         #[arg()]
         name: Name,
 
-        #[arg(short = 't', long, default_value_t = RecordType::A)]
-        record_type: RecordType,
+        /// The record type to look up, can be given multiple times
+        #[arg(short = 't', long, default_values_t = [RecordType::A])]
+        record_type: Vec<RecordType>,
+
+        /// Start resolving at this server instead of the roots, can be given multiple times
+        #[arg(long)]
+        server: Vec<IpAddr>,
+
+        /// Advertise this EDNS UDP payload size in every query of the lookup
+        #[arg(long, value_parser = clap::value_parser!(u16).range(512..=4096))]
+        buffer_size: Option<u16>,
+
+        /// Ignore negative answers in the cache for the name, querying its servers again
+        #[arg(long)]
+        no_negcache: bool,
+
+        /// Ignore the cache for the name altogether, getting a fresh answer for it
+        #[arg(long)]
+        refresh: bool,
+
+        /// Answer with cached records that expired up to this many seconds ago if resolving
+        /// the name fails
+        #[arg(long)]
+        max_stale: Option<u64>,
+
+        /// Only answer from the cache, failing instead of sending queries
+        #[arg(long)]
+        only_if_cached: bool,
+    },
+    /// Lists the root zone DNSKEY records covered by the trust anchors
+    RootKeys,
+    /// Walks the delegation chain of a name from the root, reporting how each nameserver
+    /// on the way responds
+    Check {
+        #[arg()]
+        name: Name,
+    },
+    /// Lists the nameservers of a zone and all of their addresses
+    Nameservers {
+        #[arg()]
+        zone: Name,
+    },
+    /// Shows the zone that a name is in, with the SOA and NS records of the zone
+    Authority {
+        #[arg()]
+        name: Name,
+    },
+    /// Shows the NS records and glue that the parent of a zone delegates it with
+    Delegation {
+        #[arg()]
+        zone: Name,
     },
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
-    setup_tracing()?;
-
     let args = Cli::parse();
+    let set_log_level = setup_tracing(args.log_format)?;
 
-    let resolver = RecursiveResolver::new();
+    let mut builder = RecursiveResolver::builder();
+    if !args.trust_anchor.is_empty() {
+        builder = builder.trust_anchors(args.trust_anchor);
+    }
+    let mut backend = match args.source_address {
+        Some(source) => UdpBackend::with_source(source),
+        None => UdpBackend::new(),
+    };
+    if let Some(dscp) = args.dscp {
+        backend = backend.with_dscp(dscp);
+    }
+    if args.dnssec_ok || args.validate {
+        backend = backend.with_dnssec_ok();
+    }
+    anyhow::ensure!(
+        args.edns_buffer_size.windows(2).all(|sizes| sizes[0] < sizes[1]),
+        "each --edns-buffer-size must be larger than the one before it"
+    );
+    if !args.edns_buffer_size.is_empty() {
+        backend = backend.with_edns_buffer_sizes(args.edns_buffer_size);
+    }
+    if let Some(seconds) = args.tcp_idle_timeout {
+        backend = backend.with_tcp_idle_timeout(Duration::from_secs(seconds));
+    }
+    if let Some(queries_per_second) = args.outbound_rate_limit {
+        backend = backend.with_rate_limit(queries_per_second);
+    }
+    if args.tcp_only {
+        backend = backend.tcp_only();
+    }
+    if args.no_name_compression {
+        backend = backend.without_name_compression();
+    }
+    if let Some(seed) = args.seed {
+        backend = backend.seeded(seed);
+        builder = builder.seed(seed);
+    }
+    builder = builder.backend(backend);
+    if let Some(min_nameservers) = args.min_nameservers {
+        builder = builder.min_nameservers(min_nameservers);
+    }
+    if let Some(max_nameservers) = args.max_nameservers {
+        builder = builder.max_nameservers(max_nameservers);
+    }
+    if let Some(max_queries) = args.max_queries {
+        builder = builder.max_queries(max_queries);
+    }
+    if let Some(glueless_parallelism) = args.glueless_parallelism {
+        builder = builder.glueless_parallelism(glueless_parallelism);
+    }
+    if let Some(priming_buffer_size) = args.priming_buffer_size {
+        builder = builder.priming_buffer_size(priming_buffer_size);
+    }
+    if args.ttl_range.is_some()
+        || !args.ttl_override.is_empty()
+        || args.delegation_ttl_range.is_some()
+        || args.glue_ttl_range.is_some()
+    {
+        builder = builder.ttl_limits(TtlLimits {
+            default: args.ttl_range.unwrap_or_default(),
+            overrides: args.ttl_override,
+            delegation: args.delegation_ttl_range,
+            glue: args.glue_ttl_range,
+        });
+    }
+    if let Some(path) = args.hosts_file {
+        builder = builder.local_records(hosts::parse_hosts(&fs::read_to_string(path)?)?);
+    }
+    if !args.zone_file.is_empty() {
+        let zones = args
+            .zone_file
+            .into_iter()
+            .map(|path| StaticZone::parse(&fs::read_to_string(&path)?, Some(path)));
+        builder = builder.static_zones(zones.collect::<Result<_>>()?);
+    }
+    if let Some(max_cache_bytes) = args.max_cache_bytes {
+        builder = builder.max_cache_bytes(max_cache_bytes);
+    }
+    if let Some(synthetic_soa) = args.synthetic_soa {
+        builder = builder.synthetic_soa(synthetic_soa);
+    }
+    if let Some(path) = args.upstream_stats_file.as_ref().filter(|path| path.exists()) {
+        // stats that can't be read only make the upstreams start out unknown
+        let stats = fs::read_to_string(path)
+            .map_err(anyhow::Error::from)
+            .and_then(|contents| upstream_stats::parse_upstream_stats(&contents));
+        match stats {
+            Ok(stats) => builder = builder.upstream_stats(stats),
+            Err(e) => warn!(path = %path.display(), error = %e, "Ignoring the upstream stats"),
+        }
+    }
+    if let Some(seconds) = args.upstream_cooldown {
+        builder = builder.upstream_cooldown(Duration::from_secs(seconds));
+    }
+    if let Some(seconds) = args.stale_glue_timeout {
+        builder = builder.stale_glue_timeout(Duration::from_secs(seconds));
+    }
+    if let Some(seconds) = args.outage_window {
+        builder = builder.outage_window(Duration::from_secs(seconds));
+    }
+    let resolver = builder
+        .follow_out_of_bailiwick(!args.strict_bailiwick)
+        .served_zones(args.serve_zone)
+        .stub_zones(args.stub_zone)
+        .sort_answers(args.sort_answers)
+        .caching(!args.no_cache)
+        .shuffle_nameservers(!args.no_shuffle)
+        .cache_only(args.cache_only)
+        .strict_cnames(args.strict_cnames)
+        .strict(args.strict)
+        .log_cache_evictions(args.log_cache_evictions)
+        .local_use_policy(args.local_use_policy)
+        .validate(args.validate)
+        .serve_stale(matches!(args.command, Commands::Lookup { max_stale: Some(_), .. }))
+        .build();
     match args.command {
-        Commands::Lookup { name, record_type } => {
-            let result = resolver.resolve(&name, record_type).await?;
-            println!("{:?}", result);
+        Commands::Lookup {
+            name,
+            record_type,
+            server,
+            buffer_size,
+            no_negcache,
+            refresh,
+            max_stale,
+            only_if_cached,
+        } if !server.is_empty()
+            || buffer_size.is_some()
+            || no_negcache
+            || refresh
+            || max_stale.is_some()
+            || only_if_cached =>
+        {
+            let servers = (!server.is_empty()).then_some(server);
+            let options = ResolveOptions {
+                servers,
+                edns_buffer_size: buffer_size,
+                skip_negative_cache: no_negcache,
+                no_cache: refresh,
+                max_stale: max_stale.map(Duration::from_secs),
+                only_if_cached,
+                checking_disabled: false,
+            };
+            for record_type in record_type {
+                match resolver.resolve_with_options(&name, record_type, options.clone()).await {
+                    Ok(result) => println!("{:?}", result),
+                    Err(e) => eprintln!("Failed to resolve {record_type}: {e}"),
+                }
+            }
+        }
+        Commands::Lookup { name, record_type, .. } => {
+            if let [record_type] = record_type[..] {
+                let result = resolver.resolve(&name, record_type).await?;
+                println!("{:?}", result);
+            } else {
+                let result = resolver.resolve_types(&name, &record_type).await;
+                println!("{:?}", result.records);
+                for (record_type, e) in result.errors {
+                    eprintln!("Failed to resolve {record_type}: {e}");
+                }
+            }
+        }
+        Commands::Daemon {
+            port,
+            serve_referrals,
+            warmup,
+            admin_port,
+            query_log,
+            response_rate_limit,
+            prefer_family,
+            startup,
+            refuse_any,
+            opt_record,
+            retransmit_window,
+            status_zone,
+        } => {
+            let warmup = match warmup {
+                Some(path) => daemon::parse_warmup(&fs::read_to_string(path)?)?,
+                None => Vec::new(),
+            };
+            let resolver = Arc::new(resolver);
+            let query_log = query_log.map(|capacity| Arc::new(QueryLog::new(capacity)));
+            if let Some(admin_port) = admin_port {
+                let listener = admin::bind(admin_port).await?;
+                let query_log = query_log.clone();
+                tokio::spawn(admin::serve(listener, resolver.clone(), set_log_level, query_log));
+            }
+            if let Some(path) = args.upstream_stats_file {
+                tokio::spawn(daemon::save_upstream_stats(resolver.clone(), path));
+            }
+            let rate_limiter = response_rate_limit.map(ResponseRateLimiter::new);
+            let retransmits =
+                retransmit_window.map(|seconds| Retransmits::new(Duration::from_secs(seconds)));
+            let config = DaemonConfig {
+                serve_referrals,
+                warmup,
+                query_log,
+                rate_limiter,
+                address_family: prefer_family,
+                startup,
+                refuse_any,
+                opt_record,
+                retransmits,
+                status_zone: status_zone.map(StatusZone::new),
+            };
+            daemon::daemon(resolver, port, config).await?
+        }
+        Commands::Check { name } => {
+            print!("{}", resolver.check_delegation(&name).await);
+        }
+        Commands::Nameservers { zone } => {
+            for ns in resolver.resolve_nameservers(&zone).await? {
+                let addresses: Vec<String> = ns.addresses.iter().map(IpAddr::to_string).collect();
+                println!("{} {}", ns.name, addresses.join(" "));
+            }
+        }
+        Commands::Authority { name } => {
+            let (apex, soa, ns) = resolver.authority_for(&name).await?;
+            println!("zone {apex}");
+            for record in std::iter::once(&soa).chain(&ns) {
+                println!("{record}");
+            }
+        }
+        Commands::Delegation { zone } => {
+            let (ns, glue) = resolver.delegation(&zone).await?;
+            for record in ns.iter().chain(&glue) {
+                println!("{record}");
+            }
+        }
+        Commands::RootKeys => {
+            for key in resolver.trusted_root_keys().await? {
+                println!("{}", key);
+            }
         }
-        Commands::Daemon { port } => daemon::daemon(resolver, port).await?,
     }
     Ok(())
 }
 
-fn setup_tracing() -> Result<()> {
+fn setup_tracing(log_format: Option<LogFormat>) -> Result<LogLevelSetter> {
     let otlp_exporter =
         opentelemetry_otlp::new_exporter().tonic().with_endpoint("http://localhost:4317");
 
@@ -78,11 +624,20 @@ fn setup_tracing() -> Result<()> {
 
     let tracer = provider.tracer("daemon");
 
-    let telemetry =
-        tracing_opentelemetry::layer().with_tracer(tracer).with_filter(LevelFilter::DEBUG);
+    let (filter, handle) = reload::Layer::new(LevelFilter::DEBUG);
+    let telemetry = tracing_opentelemetry::layer().with_tracer(tracer).with_filter(filter);
+
+    let (log_filter, log_handle) = reload::Layer::new(LevelFilter::DEBUG);
+    let logs = log_format.map(|format| match format {
+        LogFormat::Text => tracing_subscriber::fmt::layer().with_writer(std::io::stderr).boxed(),
+        LogFormat::Json => JsonLayer::new(std::io::stderr).boxed(),
+    });
 
-    let subscriber = Registry::default().with(telemetry);
+    let subscriber = Registry::default().with(logs.with_filter(log_filter)).with(telemetry);
 
     tracing::subscriber::set_global_default(subscriber)?;
-    Ok(())
+    Ok(Box::new(move |level| {
+        handle.reload(level)?;
+        Ok(log_handle.reload(level)?)
+    }))
 }
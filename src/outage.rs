@@ -0,0 +1,51 @@
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+/// Detects when no upstream has responded for a while, which is how losing connectivity
+/// shows. During an outage the resolver answers from the expired records in its cache
+/// rather than failing, and it goes back to normal as soon as an upstream responds.
+#[derive(Debug)]
+pub(crate) struct Outage {
+    window: Duration,
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// When the failures since the last response started
+    failing_since: Option<Instant>,
+    /// If the upstreams have been failing for the whole window
+    degraded: bool,
+}
+
+impl Outage {
+    pub(crate) fn new(window: Duration) -> Self {
+        Outage { window, state: Mutex::new(State::default()) }
+    }
+
+    /// Returns true if no upstream has responded for the window
+    pub(crate) fn degraded(&self) -> bool {
+        self.state.lock().unwrap().degraded
+    }
+
+    /// Records a query that had no response, entering degraded mode once every query in
+    /// the window has failed
+    pub(crate) fn failed(&self, now: Instant) {
+        let mut state = self.state.lock().unwrap();
+        let since = *state.failing_since.get_or_insert(now);
+        if !state.degraded && now.duration_since(since) >= self.window {
+            warn!(window = ?self.window, "No upstream responds, answering from expired records");
+            state.degraded = true;
+        }
+    }
+
+    /// Records a response from an upstream, ending degraded mode
+    pub(crate) fn responded(&self) {
+        let mut state = self.state.lock().unwrap();
+        if state.degraded {
+            info!("The upstreams respond again, leaving degraded mode");
+        }
+        *state = State::default();
+    }
+}
@@ -0,0 +1,107 @@
+use crate::resolver::ResolutionError;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Once this many servers are tracked, the ones with a full bucket are forgotten
+const MAX_TRACKED: usize = 10_000;
+
+/// Queries up to this far ahead of the rate may be sent at once
+const BURST: Duration = Duration::from_secs(1);
+
+/// Queries that would have to wait longer than this fail instead, as the client would have
+/// given up on the answer by the time they were sent
+const MAX_DELAY: Duration = Duration::from_secs(2);
+
+/// Limits the rate of queries sent to each server, with the equivalent of a token bucket
+/// per address. Up to a second worth of queries may be sent at once, after which they are
+/// spaced out evenly, up to MAX_DELAY. This keeps a burst of resolutions, or the
+/// sub-resolutions of a single one, from hammering one authoritative server.
+#[derive(Debug)]
+pub(crate) struct Pacing {
+    /// The time between queries to the same server at the rate
+    interval: Duration,
+    /// When the bucket of each server is full again, with the queries sent so far
+    state: Mutex<HashMap<IpAddr, Instant>>,
+}
+
+impl Pacing {
+    pub(crate) fn new(queries_per_second: u32) -> Self {
+        let interval = Duration::from_secs(1) / queries_per_second.max(1);
+        Pacing { interval, state: Mutex::new(HashMap::new()) }
+    }
+
+    /// Takes a token for a query to `target` at `now`, returning how long to wait before
+    /// sending it. Returns None, without taking a token, if that is longer than MAX_DELAY.
+    pub(crate) fn delay(&self, target: IpAddr, now: Instant) -> Option<Duration> {
+        let mut state = self.state.lock().unwrap();
+        if state.len() >= MAX_TRACKED {
+            state.retain(|_, full| *full > now);
+        }
+        let full = state.entry(target).or_insert(now);
+        let next = (*full).max(now) + self.interval;
+        let delay = next.saturating_duration_since(now + BURST);
+        if delay > MAX_DELAY {
+            return None;
+        }
+        *full = next;
+        Some(delay)
+    }
+
+    /// Waits until a query may be sent to `target`, failing if that would take too long
+    pub(crate) async fn wait(&self, target: IpAddr) -> Result<(), ResolutionError> {
+        let Some(delay) = self.delay(target, Instant::now()) else {
+            return Err(ResolutionError::Overloaded {
+                upstream: target,
+                retry_after: self.interval,
+            });
+        };
+        if !delay.is_zero() {
+            tokio::time::sleep(delay).await;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::pacing::{Pacing, MAX_DELAY};
+    use std::net::IpAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_pacing() {
+        let pacing = Pacing::new(10);
+        let target: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+        // a second worth of queries is sent right away
+        for _ in 0..10 {
+            assert_eq!(pacing.delay(target, now), Some(Duration::ZERO));
+        }
+        // and the ones after that are spaced a tenth of a second apart
+        let delays: Vec<Option<u128>> =
+            (0..3).map(|_| pacing.delay(target, now).map(|d| d.as_millis())).collect();
+        assert_eq!(delays, [Some(100), Some(200), Some(300)]);
+        // another server has a bucket of its own
+        assert_eq!(pacing.delay("10.0.0.2".parse().unwrap(), now), Some(Duration::ZERO));
+        // once the delayed queries have been sent, the next one is sent right away
+        let later = now + Duration::from_millis(400);
+        assert_eq!(pacing.delay(target, later), Some(Duration::ZERO));
+        assert_eq!(pacing.delay(target, later).map(|d| d.as_millis()), Some(100));
+    }
+
+    #[test]
+    fn test_max_delay() {
+        let pacing = Pacing::new(10);
+        let target: IpAddr = "10.0.0.1".parse().unwrap();
+        let now = Instant::now();
+        let delays: Vec<Option<Duration>> = (0..40).map(|_| pacing.delay(target, now)).collect();
+        assert_eq!(delays.iter().flatten().max(), Some(&MAX_DELAY));
+        // the queries over the limit fail without taking a token
+        assert_eq!(delays.iter().filter(|d| d.is_none()).count(), 10);
+        assert_eq!(pacing.delay(target, now), None);
+        let later = now + Duration::from_millis(100);
+        assert_eq!(pacing.delay(target, later), Some(MAX_DELAY));
+    }
+}
@@ -0,0 +1,109 @@
+use hickory_proto::op::{Message, ResponseCode};
+use hickory_proto::rr::{Name, RecordType};
+use std::collections::VecDeque;
+use std::fmt::{Display, Formatter};
+use std::net::SocketAddr;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// A bounded log of the most recently answered queries, for debugging live traffic
+#[derive(Debug)]
+pub struct QueryLog {
+    entries: Mutex<VecDeque<QueryLogEntry>>,
+    capacity: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct QueryLogEntry {
+    pub client: SocketAddr,
+    pub name: Name,
+    pub record_type: RecordType,
+    pub response_code: ResponseCode,
+    pub answer_count: usize,
+    pub latency: Duration,
+}
+
+impl QueryLog {
+    pub fn new(capacity: usize) -> Self {
+        QueryLog { entries: Mutex::new(VecDeque::with_capacity(capacity)), capacity }
+    }
+
+    /// Adds the query and response to the log, dropping the oldest entry if it is full.
+    /// Messages without a query are not logged, and nothing is with a capacity of zero.
+    pub fn record(
+        &self,
+        client: SocketAddr,
+        query: &Message,
+        response: &Message,
+        latency: Duration,
+    ) {
+        let Some(q) = query.query().filter(|_| self.capacity > 0) else {
+            return;
+        };
+        let entry = QueryLogEntry {
+            client,
+            name: q.name().clone(),
+            record_type: q.query_type(),
+            response_code: response.response_code(),
+            answer_count: response.answers().len(),
+            latency,
+        };
+        let mut entries = self.entries.lock().unwrap();
+        if entries.len() >= self.capacity {
+            entries.pop_front();
+        }
+        entries.push_back(entry);
+    }
+
+    /// Returns the logged queries, oldest first
+    pub fn entries(&self) -> Vec<QueryLogEntry> {
+        self.entries.lock().unwrap().iter().cloned().collect()
+    }
+}
+
+impl Display for QueryLogEntry {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} {} {} answers={} {}ms",
+            self.client,
+            self.name,
+            self.record_type,
+            self.response_code,
+            self.answer_count,
+            self.latency.as_millis()
+        )
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::query_log::QueryLog;
+    use anyhow::Result;
+    use hickory_proto::op::{Message, Query};
+    use hickory_proto::rr::{Name, RecordType};
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    #[test]
+    fn test_bounded() -> Result<()> {
+        let log = QueryLog::new(2);
+        for name in ["a.b.", "c.d.", "e.f."] {
+            let mut query = Message::new();
+            query.add_query(Query::query(Name::from_str(name)?, RecordType::A));
+            log.record("127.0.0.1:4711".parse()?, &query, &Message::new(), Duration::ZERO);
+        }
+        // queries without a question are not logged
+        log.record("127.0.0.1:4711".parse()?, &Message::new(), &Message::new(), Duration::ZERO);
+
+        let names: Vec<String> = log.entries().iter().map(|e| e.name.to_string()).collect();
+        assert_eq!(names, ["c.d.", "e.f."]);
+
+        let log = QueryLog::new(0);
+        let mut query = Message::new();
+        query.add_query(Query::query(Name::from_str("a.b.")?, RecordType::A));
+        log.record("127.0.0.1:4711".parse()?, &query, &Message::new(), Duration::ZERO);
+        assert!(log.entries().is_empty());
+        Ok(())
+    }
+}
@@ -1,44 +1,498 @@
+use anyhow::bail;
 use async_recursion::async_recursion;
+use futures_util::future::join_all;
 use hickory_proto::error::ProtoError;
 use hickory_proto::op::{Message, ResponseCode};
+use hickory_proto::rr::dnssec::rdata::DNSSECRData;
 use hickory_proto::rr::RecordType::A;
-use hickory_proto::rr::{Name, RData, Record, RecordType};
+use hickory_proto::rr::{rdata, Name, RData, Record, RecordType};
+use hickory_proto::serialize::binary::{BinDecodable, BinEncodable};
 use lazy_static::lazy_static;
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
+use rand::SeedableRng;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr};
 use std::num::NonZeroUsize;
-use std::time::Instant;
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, Instant};
 use thiserror::Error;
+use tokio::sync::watch;
 use tracing::{debug, field::Empty, instrument};
 
-use crate::backend::{Backend, UdpBackend};
-use crate::cache::{Cache, CacheResponse, DnsCache, Query};
+use crate::backend::{Backend, ExtendedError, QueryOptions, UdpBackend};
+use crate::cache::{Cache, CacheResponse, DnsCache, NegativeAnswer, Query, TtlLimits, MAX_STALE};
+use crate::cooldown::Cooldowns;
+use crate::dnssec::{TrustAnchor, Validator};
+use crate::outage::Outage;
 use crate::resolver::QueryResponse::{Answer, Referral};
-use crate::resolver::ResolutionError::{NxDomain, ServFail};
-use crate::target::{NsProvider, RootsProvider, Target, TargetProvider};
+use crate::resolver::ResolutionError::{
+    Inconsistent, NameTooLong, NotCached, NxDomain, Refused, ServFail, TooManyQueries,
+};
+use crate::special_use;
+use crate::special_use::LocalUsePolicy;
+use crate::synthetic::SyntheticSoa;
+use crate::target::{
+    find_in_glue, get_name_if_ns, is_in_bailiwick, NsProvider, RootsProvider, Target,
+    TargetProvider,
+};
+use crate::upstream_stats::{QueryOutcome, UpstreamCounters, UpstreamStats};
+use crate::zone::{StaticZone, ZoneAnswer};
 
 // number of items in the cache
 lazy_static! {
     static ref CACHE_SIZE: NonZeroUsize = NonZeroUsize::new(100_000).unwrap();
 }
 
+/// Cache entries expire up to this fraction of their ttl early
+const CACHE_TTL_JITTER: f64 = 0.05;
+
 #[derive(Debug)]
 pub struct RecursiveResolver {
     backend: Box<dyn Backend + Sync + Send>,
     roots: Vec<IpAddr>,
-    cache: Cache<Query, Vec<Record>>,
+    cache: DnsCache,
+    /// NXDOMAIN and NODATA responses, kept apart from the records
+    negative_cache: Cache<Query, NegativeAnswer>,
+    validator: Validator,
+    /// Validate the answers with DNSSEC, starting from the trust anchors
+    validate: bool,
+    min_nameservers: usize,
+    max_nameservers: usize,
+    follow_out_of_bailiwick: bool,
+    served_zones: Vec<Name>,
+    sort_answers: bool,
+    max_queries: usize,
+    glueless_parallelism: usize,
+    /// Try the nameservers in random order, rather than in the order they are listed
+    shuffle_nameservers: bool,
+    /// The EDNS buffer size advertised in the priming query
+    priming_buffer_size: u16,
+    caching: bool,
+    /// Answer only from the cache, never sending any queries
+    cache_only: bool,
+    strict_cnames: bool,
+    /// Fail resolution on responses that break the rules, instead of filtering them
+    strict: bool,
+    /// Upstreams that keep failing are left alone for a while, if set
+    cooldowns: Option<Cooldowns>,
+    /// Glue addresses that keep failing are passed over for the nameserver names, if set
+    stale_glue: Option<Cooldowns>,
+    /// Expired records are answered while no upstream responds, if set
+    outage: Option<Outage>,
+    /// Answered without recursion, such as the PTR records from a hosts file
+    local_records: HashMap<Query, Vec<Record>>,
+    /// The names of the local records, which are answered with NODATA for other types
+    local_names: HashSet<Name>,
+    synthetic_soa: SyntheticSoa,
+    /// Answered authoritatively from their records, without recursing
+    static_zones: Vec<StaticZone>,
+    /// How the names of home.arpa. and the other local-use domains are answered
+    local_use_policy: LocalUsePolicy,
+    /// Resolved starting at the authoritative servers of the zone, instead of the roots
+    stub_zones: Vec<StubZone>,
+    upstream_stats: UpstreamStats,
+    /// Set once the root nameservers have been primed
+    primed: watch::Sender<bool>,
+    /// Used to pick which nameservers to query
+    rng: Mutex<StdRng>,
 }
 
-impl RecursiveResolver {
-    pub fn new() -> Self {
-        RecursiveResolver {
+/// Used to create a RecursiveResolver with non-default settings
+pub struct RecursiveResolverBuilder {
+    backend: Box<dyn Backend + Sync + Send>,
+    roots: Vec<IpAddr>,
+    trust_anchors: Vec<TrustAnchor>,
+    validate: bool,
+    min_nameservers: usize,
+    max_nameservers: usize,
+    follow_out_of_bailiwick: bool,
+    served_zones: Vec<Name>,
+    sort_answers: bool,
+    max_queries: usize,
+    glueless_parallelism: usize,
+    shuffle_nameservers: bool,
+    priming_buffer_size: u16,
+    caching: bool,
+    cache_only: bool,
+    strict_cnames: bool,
+    strict: bool,
+    upstream_cooldown: Option<Duration>,
+    stale_glue_timeout: Option<Duration>,
+    outage_window: Option<Duration>,
+    serve_stale: bool,
+    upstream_stats: Vec<(IpAddr, UpstreamCounters)>,
+    ttl_limits: TtlLimits,
+    log_cache_evictions: bool,
+    max_cache_bytes: Option<usize>,
+    local_records: Vec<Record>,
+    synthetic_soa: SyntheticSoa,
+    static_zones: Vec<StaticZone>,
+    local_use_policy: LocalUsePolicy,
+    stub_zones: Vec<StubZone>,
+    seed: Option<u64>,
+}
+
+/// The number of distinct nameservers that needs to fail before a resolution
+/// step is considered to have failed
+const DEFAULT_MIN_NAMESERVERS: usize = 3;
+
+/// The number of nameservers of a delegation that are ever tried, so that a referral with
+/// a huge number of NS records doesn't make a resolution step take forever
+const DEFAULT_MAX_NAMESERVERS: usize = 8;
+
+/// The number of queries that a single resolution may send, including the ones needed
+/// to resolve nameserver names and CNAME targets
+const DEFAULT_MAX_QUERIES: usize = 100;
+
+/// Large enough for the NS records of the root with the A and AAAA records of all of
+/// them, which is about 1100 bytes
+const DEFAULT_PRIMING_BUFFER_SIZE: u16 = 4096;
+
+/// The root servers that resolution starts from when the cache holds nothing useful
+const ROOT_SERVERS: [IpAddr; 1] = [
+    IpAddr::V4(Ipv4Addr::new(192, 36, 148, 17)),
+    //IpAddr::V6(Ipv6Addr::new(0x2001, 0x7fe, 0, 0, 0, 0, 0, 0x53)),
+];
+
+impl Default for RecursiveResolverBuilder {
+    fn default() -> Self {
+        RecursiveResolverBuilder {
             backend: Box::new(UdpBackend::new()),
-            roots: vec![
-                IpAddr::V4("192.36.148.17".parse().unwrap()),
-                //IpAddr::V6("2001:7fe::53".parse().unwrap()),
-            ],
-            cache: Cache::new(*CACHE_SIZE),
+            roots: ROOT_SERVERS.to_vec(),
+            trust_anchors: TrustAnchor::root_anchors(),
+            validate: false,
+            min_nameservers: DEFAULT_MIN_NAMESERVERS,
+            max_nameservers: DEFAULT_MAX_NAMESERVERS,
+            follow_out_of_bailiwick: true,
+            served_zones: Vec::new(),
+            sort_answers: false,
+            max_queries: DEFAULT_MAX_QUERIES,
+            glueless_parallelism: 1,
+            shuffle_nameservers: true,
+            priming_buffer_size: DEFAULT_PRIMING_BUFFER_SIZE,
+            caching: true,
+            cache_only: false,
+            strict_cnames: false,
+            strict: false,
+            upstream_cooldown: None,
+            stale_glue_timeout: None,
+            outage_window: None,
+            serve_stale: false,
+            upstream_stats: Vec::new(),
+            ttl_limits: TtlLimits::default(),
+            log_cache_evictions: false,
+            max_cache_bytes: None,
+            local_records: Vec::new(),
+            synthetic_soa: SyntheticSoa::default(),
+            static_zones: Vec::new(),
+            local_use_policy: LocalUsePolicy::default(),
+            stub_zones: Vec::new(),
+            seed: None,
+        }
+    }
+}
+
+impl RecursiveResolverBuilder {
+    pub fn backend(mut self, backend: impl Backend + Send + Sync + 'static) -> Self {
+        self.backend = Box::new(backend);
+        self
+    }
+
+    #[cfg(test)]
+    pub(crate) fn roots(mut self, roots: Vec<IpAddr>) -> Self {
+        self.roots = roots;
+        self
+    }
+
+    /// Replaces the embedded root trust anchors, useful when testing against private roots
+    pub fn trust_anchors(mut self, trust_anchors: Vec<TrustAnchor>) -> Self {
+        self.trust_anchors = trust_anchors;
+        self
+    }
+
+    /// Validates the answers with DNSSEC, for resolve_validated to tell whether they are
+    /// Secure, Insecure or Bogus. The upstreams need to be sent the DO bit for the RRSIG
+    /// records to validate with.
+    pub fn validate(mut self, validate: bool) -> Self {
+        self.validate = validate;
+        self
+    }
+
+    /// Sets how many distinct nameservers to try, where available, before giving up
+    pub fn min_nameservers(mut self, min_nameservers: usize) -> Self {
+        self.min_nameservers = min_nameservers.max(1);
+        self
+    }
+
+    /// Sets how many of the nameservers of a delegation are tried at most. With
+    /// shuffled nameservers they are a random subset of the NS records.
+    pub fn max_nameservers(mut self, max_nameservers: usize) -> Self {
+        self.max_nameservers = max_nameservers.max(1);
+        self
+    }
+
+    /// Controls if referrals to nameservers outside of the delegated zone are followed.
+    /// Setting this to false hardens against some attacks, at the cost of not being
+    /// able to resolve names in zones hosted by third party DNS providers.
+    pub fn follow_out_of_bailiwick(mut self, follow_out_of_bailiwick: bool) -> Self {
+        self.follow_out_of_bailiwick = follow_out_of_bailiwick;
+        self
+    }
+
+    /// Restricts resolution to names within the given zones, other names are refused.
+    /// By default all names are served.
+    pub fn served_zones(mut self, served_zones: Vec<Name>) -> Self {
+        self.served_zones = served_zones;
+        self
+    }
+
+    /// Answers queries for the name and type of these records with them, without recursing.
+    /// Queries for the names and other types get empty answers. This takes precedence
+    /// over the served zones.
+    pub fn local_records(mut self, local_records: Vec<Record>) -> Self {
+        self.local_records = local_records;
+        self
+    }
+
+    /// The SOA that comes with the answers from the local records
+    pub fn synthetic_soa(mut self, synthetic_soa: SyntheticSoa) -> Self {
+        self.synthetic_soa = synthetic_soa;
+        self
+    }
+
+    /// Answers the names within these zones from their records, authoritatively and
+    /// without recursing. The local records take precedence, and these take precedence
+    /// over the served zones.
+    pub fn static_zones(mut self, static_zones: Vec<StaticZone>) -> Self {
+        self.static_zones = static_zones;
+        self
+    }
+
+    /// Sets how the names of home.arpa. and local., the domains for use within a local
+    /// network, are answered when there are no local records, static zone or stub zone for them
+    pub fn local_use_policy(mut self, local_use_policy: LocalUsePolicy) -> Self {
+        self.local_use_policy = local_use_policy;
+        self
+    }
+
+    /// Resolves the names within these zones starting at the given authoritative servers,
+    /// instead of at the roots. A delegation from within a zone that is in the cache is
+    /// still used.
+    pub fn stub_zones(mut self, stub_zones: Vec<StubZone>) -> Self {
+        self.stub_zones = stub_zones;
+        self
+    }
+
+    /// Returns the records of answers sorted by name, type and rdata instead of in the
+    /// order the servers sent them, which gives stable output for tests and diffing
+    pub fn sort_answers(mut self, sort_answers: bool) -> Self {
+        self.sort_answers = sort_answers;
+        self
+    }
+
+    /// Answers only from the cache, failing with NotCached instead of sending queries when
+    /// the cache can't answer. For testing, and for when the upstreams can't be reached.
+    pub fn cache_only(mut self, cache_only: bool) -> Self {
+        self.cache_only = cache_only;
+        self
+    }
+
+    /// Checks the whole CNAME chain of a resolution, across the answers from different
+    /// zones, for loops and for being longer than MAX_CNAME_CHAIN. Without this, only the
+    /// chain within a single answer is checked and the recursion depth limits the rest.
+    pub fn strict_cnames(mut self, strict_cnames: bool) -> Self {
+        self.strict_cnames = strict_cnames;
+        self
+    }
+
+    /// Fails the resolution with Inconsistent when a response breaks the rules, such as
+    /// answer records outside of the zone of the responding server or a question that
+    /// isn't the one asked. By default such records are dropped, and such responses
+    /// are treated like failed queries with the other nameservers tried.
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Limits the number of queries sent to resolve a name, including the ones needed for
+    /// glueless nameservers and CNAME targets. The depth limit doesn't stop a name from
+    /// requiring lots of queries at a shallow depth.
+    pub fn max_queries(mut self, max_queries: usize) -> Self {
+        self.max_queries = max_queries;
+        self
+    }
+
+    /// Resolves the A and AAAA addresses of up to this many glueless nameservers of a
+    /// referral at the same time, instead of one at a time as they are tried. If the first nameserver
+    /// fails, the addresses of the others are already known. The queries needed count
+    /// towards max_queries, which is shared between the concurrent resolutions.
+    pub fn glueless_parallelism(mut self, glueless_parallelism: usize) -> Self {
+        self.glueless_parallelism = glueless_parallelism;
+        self
+    }
+
+    /// Setting this to false makes the nameservers get tried in the order they are listed in
+    /// the referral or the roots, instead of in random order. For reproducing problems with
+    /// the selection of nameservers, and for servers listed in a meaningful order.
+    pub fn shuffle_nameservers(mut self, shuffle_nameservers: bool) -> Self {
+        self.shuffle_nameservers = shuffle_nameservers;
+        self
+    }
+
+    /// The EDNS buffer size to advertise when priming, instead of the ones of the backend.
+    /// A response that still doesn't fit is retried over TCP by the backend.
+    pub fn priming_buffer_size(mut self, priming_buffer_size: u16) -> Self {
+        self.priming_buffer_size = priming_buffer_size;
+        self
+    }
+
+    /// Setting this to false makes every resolution start from the roots without storing
+    /// anything, for measuring the authoritative side and testing resolution logic
+    pub fn caching(mut self, caching: bool) -> Self {
+        self.caching = caching;
+        self
+    }
+
+    /// Stops querying an upstream for the given duration once it has failed several
+    /// times in a row, failing the queries that would need it right away
+    pub fn upstream_cooldown(mut self, upstream_cooldown: Duration) -> Self {
+        self.upstream_cooldown = Some(upstream_cooldown);
+        self
+    }
+
+    /// Passes over glue addresses that have failed several times in a row for the given
+    /// duration, resolving the names of the nameservers instead. This finds the new address
+    /// of a nameserver that has moved while the parent zone still has the old one.
+    pub fn stale_glue_timeout(mut self, stale_glue_timeout: Duration) -> Self {
+        self.stale_glue_timeout = Some(stale_glue_timeout);
+        self
+    }
+
+    /// Answers with the records that expired up to an hour ago, with a short ttl, once no
+    /// upstream has responded for the given duration. This rides out losing connectivity,
+    /// and the records expire as usual again once an upstream responds.
+    pub fn outage_window(mut self, outage_window: Duration) -> Self {
+        self.outage_window = Some(outage_window);
+        self
+    }
+
+    /// Keeps the records for up to an hour after they expire, for the resolutions with the
+    /// max_stale option to answer with. The outage window keeps them as well.
+    pub fn serve_stale(mut self, serve_stale: bool) -> Self {
+        self.serve_stale = serve_stale;
+        self
+    }
+
+    /// Starts out with the upstream stats from an earlier run, for the upstreams that never
+    /// responded to be tried after the others from the start
+    pub fn upstream_stats(mut self, upstream_stats: Vec<(IpAddr, UpstreamCounters)>) -> Self {
+        self.upstream_stats = upstream_stats;
+        self
+    }
+
+    /// Limits the ttl that records are cached with, overriding the ttl of the zones
+    pub fn ttl_limits(mut self, ttl_limits: TtlLimits) -> Self {
+        self.ttl_limits = ttl_limits;
+        self
+    }
+
+    /// Logs the cache entries that are evicted or expire, to understand cache churn
+    pub fn log_cache_evictions(mut self, log_cache_evictions: bool) -> Self {
+        self.log_cache_evictions = log_cache_evictions;
+        self
+    }
+
+    /// Limits the approximate size of the cached records to this many bytes, in addition to
+    /// the limit on the number of entries
+    pub fn max_cache_bytes(mut self, max_cache_bytes: usize) -> Self {
+        self.max_cache_bytes = Some(max_cache_bytes);
+        self
+    }
+
+    /// Makes the selection of nameservers deterministic, for tests and reproducing bugs.
+    /// This also disables the cache ttl jitter.
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    pub fn build(self) -> RecursiveResolver {
+        let (rng, ttl_jitter) = match self.seed {
+            Some(seed) => (StdRng::seed_from_u64(seed), 0.0),
+            None => (StdRng::from_entropy(), CACHE_TTL_JITTER),
+        };
+        let mut cache =
+            Cache::new(*CACHE_SIZE).with_ttl_jitter(ttl_jitter).with_ttl_limits(self.ttl_limits);
+        if self.serve_stale || self.outage_window.is_some() {
+            cache = cache.with_expired_kept(MAX_STALE);
         }
+        let mut negative_cache = Cache::new(*CACHE_SIZE).with_ttl_jitter(ttl_jitter);
+        if let Some(max_bytes) = self.max_cache_bytes {
+            cache = cache.with_max_record_bytes(max_bytes);
+        }
+        if self.log_cache_evictions {
+            cache = cache.with_eviction_logging();
+            negative_cache = negative_cache.with_eviction_logging();
+        }
+        let mut local_records: HashMap<Query, Vec<Record>> = HashMap::new();
+        let local_names = self.local_records.iter().map(|r| r.name().clone()).collect();
+        for record in self.local_records {
+            let query =
+                Query { to_resolve: record.name().clone(), record_type: record.record_type() };
+            local_records.entry(query).or_default().push(record);
+        }
+        RecursiveResolver {
+            backend: self.backend,
+            roots: self.roots,
+            cache,
+            negative_cache,
+            validator: Validator::new(self.trust_anchors),
+            validate: self.validate,
+            min_nameservers: self.min_nameservers,
+            max_nameservers: self.max_nameservers,
+            follow_out_of_bailiwick: self.follow_out_of_bailiwick,
+            served_zones: self.served_zones,
+            sort_answers: self.sort_answers,
+            max_queries: self.max_queries,
+            glueless_parallelism: self.glueless_parallelism.max(1),
+            shuffle_nameservers: self.shuffle_nameservers,
+            priming_buffer_size: self.priming_buffer_size,
+            caching: self.caching,
+            cache_only: self.cache_only,
+            strict_cnames: self.strict_cnames,
+            strict: self.strict,
+            cooldowns: self.upstream_cooldown.map(Cooldowns::new),
+            stale_glue: self.stale_glue_timeout.map(Cooldowns::new),
+            outage: self.outage_window.map(Outage::new),
+            local_records,
+            local_names,
+            synthetic_soa: self.synthetic_soa,
+            static_zones: self.static_zones,
+            local_use_policy: self.local_use_policy,
+            stub_zones: self.stub_zones,
+            upstream_stats: UpstreamStats::restore(self.upstream_stats),
+            primed: watch::Sender::new(false),
+            rng: Mutex::new(rng),
+        }
+    }
+}
+
+impl Default for RecursiveResolver {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RecursiveResolver {
+    pub fn new() -> Self {
+        Self::builder().build()
+    }
+
+    pub fn builder() -> RecursiveResolverBuilder {
+        RecursiveResolverBuilder::default()
     }
 
     #[cfg(test)]
@@ -46,17 +500,338 @@ impl RecursiveResolver {
         backend: impl Backend + Send + Sync + 'static,
         roots: Vec<IpAddr>,
     ) -> Self {
-        RecursiveResolver { backend: Box::new(backend), roots, cache: Cache::new(*CACHE_SIZE) }
+        Self::builder().backend(backend).roots(roots).build()
+    }
+
+    /// Resolves the DNSKEY records of the root zone and returns the ones
+    /// covered by the configured trust anchors
+    pub async fn trusted_root_keys(&self) -> Result<Vec<Record>, ResolutionError> {
+        let keys = self.resolve(&Name::root(), RecordType::DNSKEY).await?;
+        Ok(self.validator.trusted_keys(&Name::root(), &keys))
+    }
+
+    /// Removes everything from the cache
+    pub fn flush_cache(&self) {
+        self.cache.clear();
+        self.negative_cache.clear();
+    }
+
+    /// Removes the negative answers from `zone` and the zones below it from the cache, for
+    /// when names have been added to them
+    pub fn flush_negative(&self, zone: &Name) {
+        let zone = fqdn(zone);
+        self.negative_cache.remove_matching(|negative| zone.zone_of(&negative.zone));
+    }
+
+    /// Returns all the cached records, with their remaining ttl
+    pub fn dump_cache(&self) -> Vec<Record> {
+        self.cache.dump(Instant::now())
+    }
+
+    /// Returns the cached records as the text of a zone file
+    pub fn export_cache(&self) -> String {
+        self.cache.zone_file(Instant::now())
+    }
+
+    /// The number of entries in the cache, each holding the records for a name and type
+    pub fn cache_len(&self) -> usize {
+        self.cache.len()
+    }
+
+    pub(crate) fn backend(&self) -> &(dyn Backend + Sync + Send) {
+        self.backend.as_ref()
+    }
+
+    pub(crate) fn roots(&self) -> &[IpAddr] {
+        &self.roots
+    }
+
+    /// The validator for the answers, if the resolver validates them
+    pub(crate) fn validator(&self) -> Option<&Validator> {
+        self.validate.then_some(&self.validator)
+    }
+
+    fn rng(&self) -> MutexGuard<'_, StdRng> {
+        self.rng.lock().unwrap()
+    }
+
+    /// Provides the servers in random order unless shuffling is disabled, except for the
+    /// ones that have never responded which are tried last
+    fn roots_provider<'a>(&self, servers: &'a [IpAddr]) -> RootsProvider<'a> {
+        let provider = match self.shuffle_nameservers {
+            true => RootsProvider::new(servers, &mut *self.rng()),
+            false => RootsProvider::in_order(servers),
+        };
+        provider.unreachable_last(|ip| self.upstream_stats.unreachable(ip))
+    }
+
+    /// Provides the nameservers of a referral in random order unless shuffling is disabled
+    fn ns_provider(&self, ns: Vec<Record>, mut glue: Vec<Record>) -> NsProvider {
+        glue.retain(|g| !self.is_stale_glue(g));
+        let ns = self.pick_nameservers(ns);
+        match self.shuffle_nameservers {
+            true => NsProvider::new(ns, glue, &mut *self.rng()),
+            false => NsProvider::in_order(ns, glue),
+        }
+    }
+
+    /// Returns at most max_nameservers of the NS records of a delegation, picked at random
+    /// unless the nameservers are tried in order
+    fn pick_nameservers(&self, mut ns: Vec<Record>) -> Vec<Record> {
+        ns.retain(|r| r.record_type() == RecordType::NS);
+        if ns.len() > self.max_nameservers {
+            if self.shuffle_nameservers {
+                ns.shuffle(&mut *self.rng());
+            }
+            ns.truncate(self.max_nameservers);
+        }
+        ns
+    }
+
+    /// Returns how the queries sent to each upstream went, sorted by address
+    pub fn upstream_stats(&self) -> Vec<(IpAddr, UpstreamCounters)> {
+        self.upstream_stats.snapshot()
+    }
+
+    /// Returns true if glue addresses of the upstream are passed over, as it keeps failing
+    fn is_stale(&self, upstream: IpAddr) -> bool {
+        let now = tokio::time::Instant::now();
+        self.stale_glue.as_ref().is_some_and(|s| s.cooling_down(upstream, now).is_some())
+    }
+
+    fn is_stale_glue(&self, record: &Record) -> bool {
+        let stale = match record.data() {
+            Some(RData::A(a)) => self.is_stale(IpAddr::V4(a.0)),
+            Some(RData::AAAA(aaaa)) => self.is_stale(IpAddr::V6(aaaa.0)),
+            _ => false,
+        };
+        if stale {
+            debug!(glue = %record, "Passing over glue that keeps failing");
+        }
+        stale
+    }
+
+    /// Returns an error if the upstream is cooling down after failing too many times
+    fn cooling_down(&self, upstream: IpAddr) -> Option<ResolutionError> {
+        let now = tokio::time::Instant::now();
+        let until = self.cooldowns.as_ref()?.cooling_down(upstream, now)?;
+        Some(ResolutionError::Overloaded { upstream, retry_after: until - now })
+    }
+
+    /// Returns the SOA for the answer to the query if it is answered locally. For the local
+    /// records it is the synthetic SOA, at the reverse zone for the address names and with
+    /// any other name as the apex of its zone, for the
+    /// static zones the SOA of the zone, and for the special-use names the synthetic SOA
+    /// at the special-use domain.
+    pub fn synthetic_authority(&self, to_resolve: &Name) -> Option<Record> {
+        let to_resolve = fqdn(to_resolve);
+        if self.local_names.contains(&to_resolve) {
+            return Some(self.synthetic_soa.record_for(&to_resolve));
+        }
+        match self.static_zone(&to_resolve) {
+            Some(zone) => Some(zone.soa().clone()),
+            None => special_use::special_use_apex(&to_resolve, self.local_use_policy(&to_resolve))
+                .map(|apex| self.synthetic_soa.record(&apex)),
+        }
+    }
+
+    /// Returns the most specific stub zone that the name is within
+    fn stub_zone(&self, to_resolve: &Name) -> Option<&StubZone> {
+        let zones = self.stub_zones.iter().filter(|z| z.zone.zone_of(to_resolve));
+        zones.max_by_key(|z| z.zone.num_labels())
+    }
+
+    /// Returns how a name in the local-use domains is handled. A stub zone for it is
+    /// resolved regardless of the policy, as that is where the local names are.
+    fn local_use_policy(&self, to_resolve: &Name) -> LocalUsePolicy {
+        match self.stub_zone(to_resolve) {
+            Some(_) => LocalUsePolicy::Recurse,
+            None => self.local_use_policy,
+        }
+    }
+
+    /// Returns the delegation of the name from a static zone, if it is below a zone cut
+    /// within one, as the NS records and glue of the cut
+    fn static_delegation(&self, to_resolve: &Name) -> Option<(Vec<Record>, Vec<Record>)> {
+        let delegations = self.static_zones.iter().filter_map(|z| z.delegation(to_resolve));
+        delegations.max_by_key(|(ns, _)| delegated_zone(ns).map_or(0, |z| z.num_labels()))
+    }
+
+    /// Returns the answer to the query from the static zones or the special-use names, the
+    /// names that are answered without recursing
+    fn local_answer(&self, to_resolve: &Name, record_type: RecordType) -> Option<ZoneAnswer> {
+        self.static_zone(to_resolve).and_then(|z| z.lookup(to_resolve, record_type)).or_else(|| {
+            special_use::lookup(to_resolve, record_type, self.local_use_policy(to_resolve))
+        })
+    }
+
+    /// Returns the most specific static zone that answers for the name
+    fn static_zone(&self, to_resolve: &Name) -> Option<&StaticZone> {
+        let zones = self.static_zones.iter().filter(|z| z.answers_for(to_resolve));
+        zones.max_by_key(|z| z.apex().num_labels())
+    }
+
+    /// Returns true if to_resolve is within one of the zones this resolver serves
+    pub(crate) fn serves(&self, to_resolve: &Name) -> bool {
+        self.served_zones.is_empty() || self.served_zones.iter().any(|z| z.zone_of(to_resolve))
+    }
+
+    /// Returns the best data the cache holds for the given name and type, without
+    /// sending any queries
+    pub(crate) fn get_cached(&self, to_resolve: &Name, record_type: RecordType) -> CacheResponse {
+        let query = Query { to_resolve: fqdn(to_resolve), record_type };
+        self.cache.get_best_record(&query, Instant::now())
+    }
+
+    /// Returns the NS records and glue that the parent of `zone` delegates it with. The
+    /// delegation is resolved from the roots, or the closest cached delegation, down to
+    /// the zone cut but no further.
+    pub async fn delegation(
+        &self,
+        zone: &Name,
+    ) -> Result<(Vec<Record>, Vec<Record>), ResolutionError> {
+        ResolutionState::new(self).find_delegation(&fqdn(zone)).await
+    }
+
+    /// Returns the apex of the zone that the name is in, with the SOA and NS records of the
+    /// zone. The apex is found by asking for the SOA of the name and then of each of its
+    /// parents, until one of them has one.
+    pub async fn authority_for(
+        &self,
+        name: &Name,
+    ) -> Result<(Name, Record, Vec<Record>), ResolutionError> {
+        let mut apex = fqdn(name);
+        loop {
+            let soa = match self.resolve(&apex, RecordType::SOA).await {
+                Ok(records) => records
+                    .into_iter()
+                    .find(|r| r.record_type() == RecordType::SOA && *r.name() == apex),
+                Err(NxDomain) => None,
+                Err(e) => return Err(e),
+            };
+            if let Some(soa) = soa {
+                let ns = self.resolve(&apex, RecordType::NS).await?;
+                return Ok((apex, soa, ns));
+            }
+            if apex.is_root() {
+                return Err(ServFail("the root has no SOA".to_string()));
+            }
+            apex = apex.base_name();
+        }
+    }
+
+    /// Asks the roots for the NS records of the root and their addresses, RFC 8109, and
+    /// caches them for resolution to start from. The priming response is too large to fit
+    /// the default UDP payload, so a large EDNS buffer size is advertised.
+    pub async fn prime(&self) -> Result<(Vec<Record>, Vec<Record>), ResolutionError> {
+        let mut state = ResolutionState::new(self);
+        state.edns_buffer_size = Some(self.priming_buffer_size);
+        let result = state.prime().await;
+        if result.is_ok() {
+            self.primed.send_replace(true);
+        }
+        result
+    }
+
+    /// Returns true once the root nameservers have been primed
+    pub fn is_primed(&self) -> bool {
+        *self.primed.borrow()
+    }
+
+    /// Completes once the root nameservers have been primed
+    pub async fn primed(&self) {
+        // the sender is kept by self, so waiting can't fail
+        let _ = self.primed.subscribe().wait_for(|primed| *primed).await;
+    }
+
+    /// Resolves several record types for the same name concurrently. A failure for one
+    /// type doesn't fail the others, the successful RRsets are returned together with
+    /// the errors for the types that failed.
+    pub async fn resolve_types(
+        &self,
+        to_resolve: &Name,
+        record_types: &[RecordType],
+    ) -> MultiTypeResult {
+        let results = join_all(record_types.iter().map(|rt| self.resolve(to_resolve, *rt))).await;
+        let mut result = MultiTypeResult::default();
+        for (record_type, r) in record_types.iter().zip(results) {
+            match r {
+                Ok(records) => result.records.extend(records),
+                Err(e) => {
+                    result.errors.insert(*record_type, e);
+                }
+            }
+        }
+        result
     }
 
-    #[instrument(fields(otel.kind = "server", otel.status_code = Empty, otel.status_message = Empty, %to_resolve))]
     pub async fn resolve(
         &self,
         to_resolve: &Name,
         record_type: RecordType,
     ) -> Result<Vec<Record>, ResolutionError> {
-        let mut state = ResolutionState::new(self);
-        let result = state.resolve_inner(to_resolve, record_type, 1).await;
+        self.resolve_with_options(to_resolve, record_type, ResolveOptions::default()).await
+    }
+
+    /// Resolves the name like resolve, with the options applying to this resolution only
+    #[instrument(skip(self, options), fields(otel.kind = "server", otel.status_code = Empty, otel.status_message = Empty, queries = Empty, %to_resolve))]
+    pub async fn resolve_with_options(
+        &self,
+        to_resolve: &Name,
+        record_type: RecordType,
+        options: ResolveOptions,
+    ) -> Result<Vec<Record>, ResolutionError> {
+        let to_resolve = &fqdn(to_resolve);
+        let query = Query { to_resolve: to_resolve.clone(), record_type };
+        let mut result = match wire_length(to_resolve) {
+            length if length > MAX_NAME_LENGTH => Err(NameTooLong(length)),
+            _ if self.local_records.contains_key(&query) => Ok(self.local_records[&query].clone()),
+            _ if self.local_names.contains(to_resolve) => Ok(Vec::new()),
+            _ => match self.local_answer(to_resolve, record_type) {
+                // the target of an alias in a static zone is resolved like any other
+                Some(ZoneAnswer::Records(records)) if !is_alias(&records, record_type) => {
+                    Ok(records)
+                }
+                Some(ZoneAnswer::NoData) => Ok(Vec::new()),
+                Some(ZoneAnswer::NxDomain) => Err(NxDomain),
+                None if !self.serves(to_resolve) => Err(Refused),
+                _ => {
+                    let mut state = ResolutionState::new(self);
+                    state.start_servers = options.servers;
+                    state.checking_disabled = options.checking_disabled;
+                    state.edns_buffer_size = options.edns_buffer_size;
+                    state.skip_negative_cache = options.skip_negative_cache || options.no_cache;
+                    state.skip_cache = options.no_cache;
+                    state.cache_only |= options.only_if_cached;
+                    // looked up first, as a successful resolution replaces the expired records
+                    let get_stale =
+                        |max_stale| self.cache.get_stale_answer(&query, Instant::now(), max_stale);
+                    let stale = options.max_stale.and_then(get_stale);
+                    let outage_stale = self.outage.as_ref().and_then(|_| get_stale(MAX_STALE));
+                    let result = state.resolve_inner(to_resolve, record_type, 1).await;
+                    tracing::Span::current().record("queries", state.queries);
+                    match (result, stale, outage_stale) {
+                        (Err(e), Some(stale), _) if !matches!(e, NxDomain) => {
+                            debug!(error = %e, "Answering with stale records");
+                            Ok(stale)
+                        }
+                        (Err(e), _, Some(stale))
+                            if !matches!(e, NxDomain)
+                                && self.outage.as_ref().is_some_and(Outage::degraded) =>
+                        {
+                            debug!(error = %e, "Answering with stale records during an outage");
+                            Ok(stale)
+                        }
+                        (result, _, _) => result,
+                    }
+                }
+            },
+        };
+        if let Ok(records) = &mut result {
+            if self.sort_answers {
+                sort_records(records);
+            }
+        }
         if let Err(e) = &result {
             let span = tracing::Span::current();
             span.record("otel.status_code", "Error");
@@ -65,6 +840,70 @@ impl RecursiveResolver {
         result
     }
 }
+/// Sorts records in a canonical order, by name, type and then the wire format of the rdata
+fn sort_records(records: &mut [Record]) {
+    records.sort_by_cached_key(|r| {
+        let rdata = r.data().and_then(|d| d.to_bytes().ok()).unwrap_or_default();
+        (r.name().to_lowercase(), u16::from(r.record_type()), rdata)
+    });
+}
+
+/// A zone whose names are resolved starting at its authoritative servers, rather than the
+/// roots. Unlike forwarding, the servers are sent iterative queries and their referrals
+/// are followed.
+#[derive(Debug, Clone, PartialEq)]
+pub struct StubZone {
+    pub zone: Name,
+    pub servers: Vec<IpAddr>,
+}
+
+/// Parses '<zone>=<address>[,<address>...]', for example 'corp.example.=10.0.0.53'
+impl FromStr for StubZone {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((zone, servers)) = s.split_once('=') else {
+            bail!("expected '<zone>=<address>[,<address>...]', got '{s}'");
+        };
+        let servers = servers.split(',').map(IpAddr::from_str).collect::<Result<Vec<_>, _>>()?;
+        Ok(StubZone { zone: fqdn(&Name::from_str(zone)?), servers })
+    }
+}
+
+/// Settings for a single resolution, overriding those of the resolver
+#[derive(Debug, Clone, Default)]
+pub struct ResolveOptions {
+    /// Start resolving at these servers instead of the roots or the cache, for querying a
+    /// specific set of servers. Referrals from them are followed, and any other names
+    /// needed along the way are resolved the regular way.
+    pub servers: Option<Vec<IpAddr>>,
+    /// The EDNS UDP payload size to advertise in every query, instead of the configured
+    /// sizes, to study how servers and the path deal with fragmentation
+    pub edns_buffer_size: Option<u16>,
+    /// Ignore negative answers in the cache for the name being resolved, to check with the
+    /// authoritative servers whether a name that didn't exist does by now
+    pub skip_negative_cache: bool,
+    /// Ignore the cache altogether for the name being resolved, negative answers as well
+    /// as the records, getting a fresh answer that then replaces the cached one
+    pub no_cache: bool,
+    /// Answer with the cached records even if they expired up to this long ago, RFC 8767,
+    /// if resolving the name again fails. Expired records are kept for MAX_STALE at most,
+    /// by a resolver built with serve_stale.
+    pub max_stale: Option<Duration>,
+    /// Only answer from the cache, failing with NotCached instead of sending queries
+    pub only_if_cached: bool,
+    /// Don't validate the answer, and set the CD bit on the queries, for clients that
+    /// validate the answers themselves, RFC 4035 section 3.2.2
+    pub checking_disabled: bool,
+}
+
+/// The outcome of resolving multiple record types for a name
+#[derive(Debug, Default)]
+pub struct MultiTypeResult {
+    pub records: Vec<Record>,
+    pub errors: HashMap<RecordType, ResolutionError>,
+}
+
 #[derive(Error, Debug)]
 pub enum ResolutionError {
     // RFC 1035 4.1.1 RCODE 3 "Name Error"
@@ -76,98 +915,681 @@ pub enum ResolutionError {
     IOError(#[from] std::io::Error),
     #[error("Protocol error (likely serde related)")]
     ProtocolError(#[from] ProtoError),
+    #[error("The name is {0} octets long, the maximum is 255")]
+    NameTooLong(usize),
+    #[error("The name is outside of the zones served by this resolver")]
+    Refused,
+    #[error("Gave up after sending {0} queries")]
+    TooManyQueries(usize),
+    #[error("Upstream server failure, extended error {0}")]
+    UpstreamError(ExtendedError),
+    #[error("{upstream} is overloaded, retry after {} seconds", .retry_after.as_secs())]
+    Overloaded { upstream: IpAddr, retry_after: Duration },
+    #[error("The cache holds nothing to answer from, and only the cache is used")]
+    NotCached,
+    #[error("Inconsistent response: {0}")]
+    Inconsistent(String),
 }
 pub(crate) struct ResolutionState<'a> {
     resolver: &'a RecursiveResolver,
     seen: Vec<(Name, RecordType)>,
     cache: &'a DnsCache,
+    /// Set the CD bit on the queries
+    checking_disabled: bool,
+    /// The number of queries sent so far
+    queries: usize,
+    /// The number of queries that may be sent in total
+    max_queries: usize,
+    /// Where to start the first resolution, instead of the cache or the roots
+    start_servers: Option<Vec<IpAddr>>,
+    /// The EDNS buffer size to advertise, instead of the ones of the backend
+    edns_buffer_size: Option<u16>,
+    /// Ignore the negative cache for the first resolution
+    skip_negative_cache: bool,
+    /// Ignore the cached records for the first resolution
+    skip_cache: bool,
+    /// Fail with NotCached instead of sending queries, like the cache_only of the resolver
+    cache_only: bool,
+    /// The names of the CNAME chain being followed, across the answers of all zones
+    cname_chain: Vec<Name>,
+    /// The last NXDOMAIN or NODATA response, or negative answer from the cache, with the
+    /// query it answered, for validating the records that prove it
+    pub(crate) denial: Option<(Query, NegativeAnswer)>,
 }
 
 const MAX_RECURSION_DEPTH: u32 = 5;
+
+/// The number of CNAME records a chain may consist of with strict_cnames
+const MAX_CNAME_CHAIN: usize = 8;
 impl<'a> ResolutionState<'a> {
     pub(crate) fn new(resolver: &'a RecursiveResolver) -> Self {
-        ResolutionState { resolver, seen: Vec::new(), cache: &resolver.cache }
+        ResolutionState {
+            resolver,
+            seen: Vec::new(),
+            cache: &resolver.cache,
+            checking_disabled: false,
+            queries: 0,
+            max_queries: resolver.max_queries,
+            start_servers: None,
+            edns_buffer_size: None,
+            skip_negative_cache: false,
+            skip_cache: false,
+            cache_only: resolver.cache_only,
+            cname_chain: Vec::new(),
+            denial: None,
+        }
     }
 
     #[instrument(skip(self), fields(%to_resolve))]
     #[async_recursion]
-    async fn resolve_inner(
+    pub(crate) async fn resolve_inner(
         &mut self,
         to_resolve: &Name,
         record_type: RecordType,
         depth: u32,
     ) -> Result<Vec<Record>, ResolutionError> {
-        let query = Query { to_resolve: to_resolve.clone(), record_type };
-
         if depth > MAX_RECURSION_DEPTH {
             return Err(ServFail(format!(
                 "Refusing to recurse deeper than {}",
                 MAX_RECURSION_DEPTH
             )));
         }
+        // the names that a resolution leads to, such as CNAME targets and the names of
+        // nameservers, are never sent upstream either
+        match self.resolver.local_answer(to_resolve, record_type) {
+            Some(ZoneAnswer::Records(records)) => {
+                let zone = self.resolver.static_zone(to_resolve).map(|z| z.apex().clone());
+                let zone = zone.unwrap_or_else(Name::root);
+                return self.follow_cnames(to_resolve, record_type, records, &zone, depth).await;
+            }
+            Some(ZoneAnswer::NoData) => return Ok(Vec::new()),
+            Some(ZoneAnswer::NxDomain) => return Err(NxDomain),
+            None => {}
+        }
         let query_key = (to_resolve.clone(), record_type);
         if self.seen.contains(&query_key) {
             return Err(ServFail(format!("Broken DNS config, seen {:?} twice", query_key)));
         }
+        // seen only holds the queries in progress, the same nameserver name can legitimately
+        // be needed again in other parts of the resolution, such as for sibling TLDs
         self.seen.push(query_key);
+        let result = self.resolve_query(to_resolve, record_type, depth).await;
+        self.seen.pop();
+        result
+    }
 
-        let mut candidates: Box<dyn TargetProvider + Send> =
-            match self.cache.get_best_record(&query, Instant::now()) {
-                CacheResponse::Authoritative(records) => return Ok(records),
-                CacheResponse::Referral(ns, glue) => Box::new(NsProvider::new(ns, glue)),
-                CacheResponse::None => Box::new(RootsProvider::new(&self.resolver.roots)),
-            };
-        debug!(hostname = %to_resolve, "Resolving");
-        loop {
-            let target = candidates
-                .next()
-                .await?
-                .ok_or_else(|| ServFail("no more nameservers to try".to_string()))?;
-            let target = self.target_to_ip(target, depth).await?;
-            let response = match self.resolver.backend.query(target, to_resolve, record_type).await
+    /// Sends a single query, keeping track of the upstreams that keep failing
+    async fn query(
+        &self,
+        target: IpAddr,
+        to_resolve: &Name,
+        record_type: RecordType,
+    ) -> Result<Message, ResolutionError> {
+        let start = Instant::now();
+        let backend = &self.resolver.backend;
+        let options = QueryOptions {
+            buffer_size: self.edns_buffer_size,
+            checking_disabled: self.checking_disabled,
+        };
+        let result = match options == QueryOptions::default() {
+            true => backend.query(target, to_resolve, record_type).await,
+            false => backend.query_with_options(target, to_resolve, record_type, options).await,
+        };
+        let outcome = match &result {
+            Ok(message)
+                if matches!(
+                    message.response_code(),
+                    ResponseCode::ServFail | ResponseCode::Refused
+                ) =>
             {
-                Err(e) => return Err(e),
-                Ok(message) => {
-                    if message.response_code() == ResponseCode::NXDomain {
-                        return Err(NxDomain);
-                    } else if is_final(&message) {
-                        Answer(message.answers().to_vec())
-                    } else {
-                        Referral(message.name_servers().to_vec(), message.additionals().to_vec())
+                QueryOutcome::ServFail
+            }
+            Ok(_) => QueryOutcome::Success,
+            Err(ResolutionError::UpstreamError(_)) => QueryOutcome::ServFail,
+            Err(_) => QueryOutcome::Failure,
+        };
+        if let Some(outage) = &self.resolver.outage {
+            match outcome {
+                QueryOutcome::Failure => outage.failed(Instant::now()),
+                _ => outage.responded(),
+            }
+        }
+        self.resolver.upstream_stats.record(target, outcome, start.elapsed());
+        // a SERVFAIL can be a problem with this particular server, so try the others. The
+        // same goes for REFUSED, from servers with ACLs or that are not authoritative
+        let result = result.and_then(|message| match message.response_code() {
+            ResponseCode::ServFail => Err(ServFail(format!("{target} responded SERVFAIL"))),
+            ResponseCode::Refused => Err(ServFail(format!("{target} responded REFUSED"))),
+            _ => Ok(message),
+        });
+        let result =
+            result.and_then(|message| match answers_question(&message, to_resolve, record_type) {
+                true => Ok(message),
+                false => Err(Inconsistent(format!("{target} answered another question"))),
+            });
+        for cooldowns in [&self.resolver.cooldowns, &self.resolver.stale_glue].into_iter().flatten()
+        {
+            match result {
+                Ok(_) => cooldowns.succeeded(target),
+                Err(_) => cooldowns.failed(target, tokio::time::Instant::now()),
+            }
+        }
+        result
+    }
+
+    /// Keeps an NXDOMAIN or NODATA response as the denial, and caches it for the negative ttl
+    /// of the SOA that comes with it
+    fn store_negative(&mut self, query: &Query, message: &Message, caching: bool) {
+        let negative = NegativeAnswer::from_response(message, self.cache.max_ttl());
+        if let Some(negative) = negative.as_ref().filter(|_| caching) {
+            let (negative, ttl) = (negative.clone(), negative.ttl);
+            self.resolver.negative_cache.insert(query.clone(), negative, ttl, Instant::now());
+        }
+        self.denial = negative.map(|negative| (query.clone(), negative));
+    }
+
+    /// Caches the other RRsets of an authoritative answer, such as an AAAA record sent
+    /// along with the A record that was asked for. Only records within the zone of the
+    /// responding server are trusted. From the additional section, only the addresses
+    /// of names that the answer and authority sections refer to are kept, such as those
+    /// of MX and SRV targets, to keep unrelated records from polluting the cache.
+    fn store_related(&self, query: &Query, zone: &Name, message: &Message) {
+        let referring = message.answers().iter().chain(message.name_servers());
+        let referred: HashSet<&Name> =
+            referring.flat_map(|r| [Some(r.name()), target_name(r)]).flatten().collect();
+        let additionals = message.additionals().iter().filter(|r| {
+            matches!(r.record_type(), RecordType::A | RecordType::AAAA)
+                && referred.contains(r.name())
+        });
+        let records = message.answers().iter().chain(additionals);
+        let related: Vec<Record> = records.filter(|r| zone.zone_of(r.name())).cloned().collect();
+        self.cache.store_related(related, query, Instant::now());
+    }
+
+    /// Resolves the query, starting from the best match in the cache
+    async fn resolve_query(
+        &mut self,
+        to_resolve: &Name,
+        record_type: RecordType,
+        depth: u32,
+    ) -> Result<Vec<Record>, ResolutionError> {
+        let query = Query { to_resolve: to_resolve.clone(), record_type };
+        // only the first resolution starts at the given servers, not the ones it leads to
+        let start_servers = self.start_servers.take();
+        // the answers of the given servers are kept out of the cache, as they needn't be the
+        // ones the delegation from the roots leads to
+        let caching = self.resolver.caching && start_servers.is_none();
+        let skip_cache = std::mem::take(&mut self.skip_cache);
+        let skip_negative_cache = std::mem::take(&mut self.skip_negative_cache);
+        if caching && skip_negative_cache {
+            // the answer from the servers replaces the negative answer
+            self.resolver.negative_cache.remove(&query);
+        } else if caching {
+            if let Some(negative) = self.resolver.negative_cache.get(&query, Instant::now()) {
+                debug!(zone = %negative.zone, "Negative answer from the cache");
+                let nxdomain = negative.nxdomain;
+                self.denial = Some((query, negative));
+                return if nxdomain { Err(NxDomain) } else { Ok(Vec::new()) };
+            }
+        }
+        let cached = match caching && !skip_cache {
+            true => self.cache.get_best_record(&query, Instant::now()),
+            false => CacheResponse::None,
+        };
+        let stub = match start_servers {
+            None => self.resolver.stub_zone(to_resolve),
+            Some(_) => None,
+        };
+        // a delegation within a static zone is followed like a cached one
+        let cut = match start_servers {
+            None => self.resolver.static_delegation(to_resolve),
+            Some(_) => None,
+        };
+        let labels = |ns: &[Record]| delegated_zone(ns).map(|z| z.num_labels());
+        let cached = match (cached, cut) {
+            (CacheResponse::Referral(ns, glue), Some(cut)) if labels(&ns) >= labels(&cut.0) => {
+                CacheResponse::Referral(ns, glue)
+            }
+            (CacheResponse::Referral(..) | CacheResponse::None, Some((ns, glue))) => {
+                CacheResponse::Referral(ns, glue)
+            }
+            (cached, _) => cached,
+        };
+        // the zone that the nameservers being queried are authoritative for
+        let mut zone = Name::root();
+        let mut candidates: Box<dyn TargetProvider + Send> = match cached {
+            CacheResponse::Authoritative(records) => return Ok(records),
+            // a cached delegation from within the stub zone is closer to the name
+            CacheResponse::Referral(ns, mut glue)
+                if stub.is_none_or(|s| {
+                    delegated_zone(&ns).is_some_and(|z| z.num_labels() > s.zone.num_labels())
+                }) =>
+            {
+                zone = delegated_zone(&ns).unwrap_or(zone);
+                // only the nameservers that may be tried are worth resolving
+                let ns = self.resolver.pick_nameservers(ns);
+                self.resolve_glueless(&ns, &mut glue, depth).await;
+                Box::new(self.resolver.ns_provider(ns, glue))
+            }
+            _ => match stub {
+                Some(stub) => {
+                    zone = stub.zone.clone();
+                    Box::new(self.resolver.roots_provider(&stub.servers))
+                }
+                None => {
+                    let servers = start_servers.as_deref().unwrap_or(&self.resolver.roots);
+                    Box::new(self.resolver.roots_provider(servers))
+                }
+            },
+        };
+        // a cached delegation lets resolution skip the queries to the zones above it
+        debug!(hostname = %to_resolve, %zone, "Resolving");
+        loop {
+            let message = self.ask(&mut candidates, to_resolve, record_type, depth).await?;
+            if message.response_code() == ResponseCode::NXDomain {
+                self.store_negative(&query, &message, caching);
+                return Err(NxDomain);
+            }
+            let response = match classify(&message, to_resolve, record_type, &zone) {
+                Kind::NoData => {
+                    self.store_negative(&query, &message, caching);
+                    Answer(Vec::new())
+                }
+                Kind::Answer => {
+                    let answers = self.in_bailiwick_answers(&message, &zone)?;
+                    if caching {
+                        self.store_related(&query, &zone, &message);
                     }
+                    Answer(answers)
+                }
+                // the DS records of a zone are at the parent side of the cut, RFC 4035 section
+                // 3.1.4.1, so the child has nothing to add to a referral to it
+                Kind::Referral if is_ds_referral(&message, to_resolve, record_type) => Answer(
+                    message
+                        .name_servers()
+                        .iter()
+                        .filter(|r| in_rrset(r, to_resolve, RecordType::DS))
+                        .cloned()
+                        .collect(),
+                ),
+                Kind::Referral => {
+                    Referral(message.name_servers().to_vec(), message.additionals().to_vec())
                 }
             };
             match response {
-                Referral(ns, glue) => {
+                Referral(mut ns, glue) => {
                     debug!(?ns, "Received a redirect");
-                    self.cache.store_referral(ns.clone(), glue.clone(), to_resolve, Instant::now());
+                    if !self.resolver.follow_out_of_bailiwick {
+                        ns.retain(|r| get_name_if_ns(r).is_none() || is_in_bailiwick(r));
+                        if !ns.iter().any(|r| get_name_if_ns(r).is_some()) {
+                            return Err(ServFail(
+                                "referral only contains out-of-bailiwick nameservers".to_string(),
+                            ));
+                        }
+                    }
+                    if caching {
+                        self.cache.store_referral(
+                            ns.clone(),
+                            glue.clone(),
+                            to_resolve,
+                            Instant::now(),
+                        );
+                    }
 
-                    candidates = Box::new(NsProvider::new(ns, glue))
+                    zone = delegated_zone(&ns).unwrap_or(zone);
+                    let mut glue = glue;
+                    let ns = self.resolver.pick_nameservers(ns);
+                    self.resolve_glueless(&ns, &mut glue, depth).await;
+                    candidates = Box::new(self.resolver.ns_provider(ns, glue));
                 }
 
                 Answer(answers) => {
-                    self.cache.store(
-                        Query { to_resolve: to_resolve.clone(), record_type },
-                        answers.clone(),
-                        Instant::now(),
-                    );
+                    let answers =
+                        self.follow_cnames(to_resolve, record_type, answers, &zone, depth).await?;
+                    if caching {
+                        self.cache.store(query, answers.clone(), Instant::now());
+                    }
                     return Ok(answers);
                 }
             }
         }
     }
 
-    async fn target_to_ip(
+    async fn prime(&mut self) -> Result<(Vec<Record>, Vec<Record>), ResolutionError> {
+        let root = Name::root();
+        let mut candidates: Box<dyn TargetProvider + Send> =
+            Box::new(self.resolver.roots_provider(&self.resolver.roots));
+        let message = self.ask(&mut candidates, &root, RecordType::NS, 0).await?;
+        let ns: Vec<Record> = message
+            .answers()
+            .iter()
+            .filter(|r| r.record_type() == RecordType::NS && r.name().is_root())
+            .cloned()
+            .collect();
+        if ns.is_empty() {
+            return Err(ServFail("the priming response holds no root NS records".to_string()));
+        }
+        let targets: HashSet<&Name> = ns.iter().filter_map(target_name).collect();
+        let glue: Vec<Record> = message
+            .additionals()
+            .iter()
+            .filter(|r| matches!(r.record_type(), RecordType::A | RecordType::AAAA))
+            .filter(|r| targets.contains(r.name()))
+            .cloned()
+            .collect();
+        if self.resolver.caching {
+            self.cache.store_referral(ns.clone(), glue.clone(), &root, Instant::now());
+        }
+        Ok((ns, glue))
+    }
+
+    async fn find_delegation(
+        &mut self,
+        zone: &Name,
+    ) -> Result<(Vec<Record>, Vec<Record>), ResolutionError> {
+        let query = Query { to_resolve: zone.clone(), record_type: RecordType::NS };
+        let cached = match self.resolver.caching {
+            true => self.cache.get_best_record(&query, Instant::now()),
+            false => CacheResponse::None,
+        };
+        // cached NS records at the apex hold nothing about the parent side of the cut
+        let (mut ns, mut glue) = match cached {
+            CacheResponse::Referral(ns, glue) => (ns, glue),
+            _ => (Vec::new(), Vec::new()),
+        };
+        loop {
+            let parent = delegated_zone(&ns).unwrap_or_else(Name::root);
+            if parent == *zone {
+                return Ok((ns, glue));
+            }
+            let mut candidates: Box<dyn TargetProvider + Send> = match ns.is_empty() {
+                true => Box::new(self.resolver.roots_provider(&self.resolver.roots)),
+                false => Box::new(self.resolver.ns_provider(ns, glue)),
+            };
+            let message = self.ask(&mut candidates, zone, RecordType::NS, 1).await?;
+            if message.response_code() == ResponseCode::NXDomain {
+                return Err(NxDomain);
+            }
+            match classify(&message, zone, RecordType::NS, &parent) {
+                Kind::Referral => {
+                    ns = message.name_servers().to_vec();
+                    glue = message.additionals().to_vec();
+                    let child = delegated_zone(&ns).unwrap_or_else(Name::root);
+                    if child == parent || !parent.zone_of(&child) || !child.zone_of(zone) {
+                        return Err(ServFail(format!("referral to {child} doesn't lead closer")));
+                    }
+                    if self.resolver.caching {
+                        self.cache.store_referral(ns.clone(), glue.clone(), zone, Instant::now());
+                    }
+                }
+                // the servers of the parent are authoritative for the zone as well
+                Kind::Answer
+                    if message.answers().iter().any(|r| r.record_type() == RecordType::NS) =>
+                {
+                    return Ok((message.answers().to_vec(), message.additionals().to_vec()));
+                }
+                _ => return Err(ServFail(format!("there is no zone cut at {zone}"))),
+            }
+        }
+    }
+
+    /// Returns the answer records within the zone of the responding server. The others
+    /// can't be trusted, and are dropped or with strict fail the resolution.
+    fn in_bailiwick_answers(
+        &self,
+        message: &Message,
+        zone: &Name,
+    ) -> Result<Vec<Record>, ResolutionError> {
+        let (answers, outside): (Vec<Record>, Vec<Record>) =
+            message.answers().iter().cloned().partition(|r| zone.zone_of(r.name()));
+        if let Some(record) = outside.first() {
+            if self.resolver.strict {
+                return Err(Inconsistent(format!(
+                    "the answer from a server for {zone} holds {}, which is out of bailiwick",
+                    record.name()
+                )));
+            }
+            debug!(%zone, ?outside, "Dropping out-of-bailiwick answer records");
+        }
+        Ok(answers)
+    }
+
+    /// Resolves the A and AAAA addresses of glueless nameservers concurrently, when
+    /// configured to, adding them to the glue with the ttl of the NS record. Each
+    /// resolution gets an equal share of the queries left. Failures are left for when the
+    /// nameserver is tried.
+    async fn resolve_glueless(&mut self, ns: &[Record], glue: &mut Vec<Record>, depth: u32) {
+        let parallelism = self.resolver.glueless_parallelism;
+        if parallelism < 2 {
+            return;
+        }
+        let names: Vec<(Name, u32)> = ns
+            .iter()
+            .filter_map(|r| Some((get_name_if_ns(r)?.ok()?.clone(), r.ttl())))
+            .filter(|(name, _)| find_in_glue(name, glue).is_none())
+            .take(parallelism)
+            .collect();
+        if names.len() < 2 {
+            return;
+        }
+        let lookups: Vec<(&Name, u32, RecordType)> = names
+            .iter()
+            .flat_map(|(name, ttl)| [A, RecordType::AAAA].map(|t| (name, *ttl, t)))
+            .collect();
+        let share = self.max_queries.saturating_sub(self.queries) / lookups.len();
+        let mut states: Vec<ResolutionState> = lookups
+            .iter()
+            .map(|_| ResolutionState {
+                seen: self.seen.clone(),
+                checking_disabled: self.checking_disabled,
+                max_queries: share,
+                edns_buffer_size: self.edns_buffer_size,
+                cache_only: self.cache_only,
+                ..ResolutionState::new(self.resolver)
+            })
+            .collect();
+        let resolutions = states
+            .iter_mut()
+            .zip(&lookups)
+            .map(|(s, (name, _, record_type))| s.resolve_inner(name, *record_type, depth + 1));
+        let results = join_all(resolutions).await;
+        self.queries += states.iter().map(|s| s.queries).sum::<usize>();
+        for ((name, ttl, record_type), result) in lookups.into_iter().zip(results) {
+            match result {
+                // the addresses end a CNAME chain, if the name is an alias
+                Ok(records) => glue.extend(
+                    records.into_iter().filter(|r| r.record_type() == record_type).filter_map(
+                        |r| Some(Record::from_rdata(name.clone(), ttl, r.into_data()?)),
+                    ),
+                ),
+                Err(e) => {
+                    debug!(%name, %record_type, error = %e, "Failed to resolve glueless nameserver")
+                }
+            }
+        }
+    }
+
+    /// Queries the candidates one at a time until one of them responds. This fails once
+    /// min_nameservers distinct nameservers have failed, or there are no more to try. A
+    /// nameserver whose address can't be resolved is one that failed.
+    async fn ask(
+        &mut self,
+        candidates: &mut Box<dyn TargetProvider + Send + '_>,
+        to_resolve: &Name,
+        record_type: RecordType,
+        depth: u32,
+    ) -> Result<Message, ResolutionError> {
+        if self.cache_only {
+            return Err(NotCached);
+        }
+        let mut failed: HashSet<IpAddr> = HashSet::new();
+        // the nameservers whose addresses couldn't be resolved, which have failed too
+        let mut unresolved = 0;
+        let mut last_error = None;
+        // the addresses that keep failing, tried once the others have been
+        let mut stale: VecDeque<IpAddr> = VecDeque::new();
+        loop {
+            let target = match candidates.next().await? {
+                Some(target) => match self.target_to_ip(target, depth).await {
+                    Ok(ip) if self.resolver.is_stale(ip) => {
+                        debug!(%ip, "The address keeps failing, trying the other nameservers first");
+                        stale.push_back(ip);
+                        continue;
+                    }
+                    Ok(ip) => ip,
+                    Err(e @ (TooManyQueries(_) | NotCached)) => return Err(e),
+                    Err(e) => {
+                        // an error about the nameserver's name is not one about to_resolve
+                        let e = match e {
+                            e @ ServFail(_) => e,
+                            e => ServFail(format!("failed to resolve a nameserver address: {e}")),
+                        };
+                        unresolved += 1;
+                        if failed.len() + unresolved >= self.resolver.min_nameservers {
+                            return Err(e);
+                        }
+                        debug!(error = %e, "Trying the next nameserver");
+                        last_error = Some(e);
+                        continue;
+                    }
+                },
+                None => match stale.pop_front() {
+                    Some(ip) => ip,
+                    None => {
+                        return Err(last_error
+                            .unwrap_or_else(|| ServFail("no more nameservers to try".to_string())))
+                    }
+                },
+            };
+            let result = match self.resolver.cooling_down(target) {
+                Some(e) => Err(e),
+                None => {
+                    if self.queries >= self.max_queries {
+                        return Err(TooManyQueries(self.queries));
+                    }
+                    self.queries += 1;
+                    self.query(target, to_resolve, record_type).await
+                }
+            };
+            match result {
+                Ok(message) => return Ok(message),
+                Err(e @ Inconsistent(_)) if self.resolver.strict => return Err(e),
+                Err(e) => {
+                    failed.insert(target);
+                    if failed.len() + unresolved >= self.resolver.min_nameservers {
+                        return Err(e);
+                    }
+                    debug!(%target, error = %e, "Query failed, trying the next nameserver");
+                    last_error = Some(e);
+                }
+            }
+        }
+    }
+
+    /// If the answer shows that to_resolve is an alias, follow the chain of CNAME records
+    /// through the answer and resolve the end of the chain if the answer doesn't contain it.
+    /// Where a DNAME record covers a name that has no CNAME, the CNAME is synthesized from
+    /// it and becomes part of the same chain. Queries for CNAME or ANY are answered with
+    /// the records as they are.
+    async fn follow_cnames(
+        &mut self,
+        to_resolve: &Name,
+        record_type: RecordType,
+        answers: Vec<Record>,
+        zone: &Name,
+        depth: u32,
+    ) -> Result<Vec<Record>, ResolutionError> {
+        if record_type == RecordType::CNAME || record_type == RecordType::ANY {
+            return Ok(answers);
+        }
+        // the answer section can hold the records in any order, so the chain is built by
+        // looking up each link by name rather than walking the section
+        let mut name = to_resolve.clone();
+        let mut chain: Vec<Record> = Vec::new();
+        loop {
+            if answers.iter().any(|r| *r.name() == name && r.record_type() == record_type) {
+                chain.extend(answers.iter().filter(|r| in_rrset(r, &name, record_type)).cloned());
+                return Ok(chain);
+            }
+            let explicit =
+                answers.iter().find(|r| *r.name() == name && r.record_type() == RecordType::CNAME);
+            let cname = match explicit {
+                Some(cname) => cname.clone(),
+                None => {
+                    let Some((dname, synthesized)) =
+                        answers.iter().find_map(|r| Some((r, synthesize_cname(r, &name, zone)?)))
+                    else {
+                        break;
+                    };
+                    if !chain.contains(dname) {
+                        chain.push(dname.clone());
+                        let signatures =
+                            answers.iter().filter(|r| is_signature(r, dname.name(), DNAME));
+                        chain.extend(signatures.cloned());
+                    }
+                    synthesized?
+                }
+            };
+            let Some(RData::CNAME(target)) = cname.data() else {
+                return Err(ServFail("inconsistent rdata type".to_string()));
+            };
+            chain.push(cname.clone());
+            let signatures = answers.iter().filter(|r| is_signature(r, &name, RecordType::CNAME));
+            chain.extend(signatures.cloned());
+            if chain.iter().any(|r| *r.name() == target.0) {
+                return Err(ServFail(format!("CNAME loop detected at {}", target.0)));
+            }
+            name = target.0.clone();
+        }
+        if chain.is_empty() {
+            return Ok(answers);
+        }
+        debug!(%name, "Following CNAME");
+        if !zone.zone_of(&name) {
+            debug!(%zone, target = %name, "CNAME target is outside of the zone");
+        }
+        let in_progress = self.cname_chain.len();
+        if self.resolver.strict_cnames {
+            let links = chain.iter().filter(|r| r.record_type() == RecordType::CNAME);
+            let names = links.map(|r| r.name().clone()).collect::<Vec<_>>();
+            if self.cname_chain.iter().chain(&names).any(|n| *n == name) {
+                return Err(ServFail(format!("CNAME loop detected at {name}")));
+            }
+            self.cname_chain.extend(names);
+            if self.cname_chain.len() > MAX_CNAME_CHAIN {
+                return Err(ServFail(format!("CNAME chain longer than {MAX_CNAME_CHAIN}")));
+            }
+        }
+        let result = Box::pin(self.resolve_inner(&name, record_type, depth + 1)).await;
+        self.cname_chain.truncate(in_progress);
+        chain.extend(result?);
+        Ok(chain)
+    }
+
+    /// Returns the address of the nameserver, resolving its name if needed. A stale address
+    /// from the glue in the cache is resolved again past the cache, and kept if nothing
+    /// better turns up. A name that is an alias is followed like any other CNAME, and
+    /// resolving a name that is already being resolved fails, which stops loops.
+    async fn target_to_ip(
         &mut self,
         target: Target,
         depth: u32,
     ) -> Result<IpAddr, ResolutionError> {
-        match target {
-            Target::Ip(ip) => Ok(ip),
-            Target::Name(name) => {
-                first_ip(&mut Box::pin(self.resolve_inner(&name, A, depth + 1)).await?)
-            }
+        let name = match target {
+            Target::Ip(ip) => return Ok(ip),
+            Target::Name(name) => name,
+        };
+        let ip = first_ip(&Box::pin(self.resolve_inner(&name, A, depth + 1)).await?)?;
+        // the stale glue cached with the referral may hide the current address
+        let query = Query { to_resolve: name.clone(), record_type: A };
+        if !self.resolver.is_stale(ip) || !self.cache.holds_glue(&query, Instant::now()) {
+            return Ok(ip);
         }
+        self.skip_cache = true;
+        let fresh = Box::pin(self.resolve_inner(&name, A, depth + 1)).await;
+        // the flag is only taken once the resolution gets as far as the cache
+        self.skip_cache = false;
+        Ok(fresh.and_then(|records| first_ip(&records)).unwrap_or(ip))
     }
 }
 
@@ -179,34 +1601,210 @@ enum QueryResponse {
     Answer(Vec<Record>),
 }
 
-fn is_final(answer: &Message) -> bool {
-    answer.header().authoritative() && !answer.answers().is_empty()
+/// The maximum length of a name in its wire format, RFC 1035 section 2.3.4
+const MAX_NAME_LENGTH: usize = 255;
+
+/// Returns the name as fully qualified. Names from the command line and the API may lack
+/// the trailing dot, and Name hashes the two forms differently even though they compare equal
+pub(crate) fn fqdn(name: &Name) -> Name {
+    let mut name = name.clone();
+    name.set_fqdn(true);
+    name
+}
+
+/// The number of octets a name occupies in its uncompressed wire format, including the
+/// zero length root label. Name only limits its presentation length, which is one
+/// octet shorter than this.
+fn wire_length(name: &Name) -> usize {
+    name.iter().map(|label| label.len() + 1).sum::<usize>() + 1
+}
+
+/// Returns true if the record is part of the RRset with the given name and type, or one
+/// of the RRSIG records signing it
+pub(crate) fn in_rrset(record: &Record, name: &Name, record_type: RecordType) -> bool {
+    (record.name() == name && record.record_type() == record_type)
+        || is_signature(record, name, record_type)
+}
+
+fn is_signature(record: &Record, name: &Name, record_type: RecordType) -> bool {
+    matches!(
+        record.data(),
+        Some(RData::DNSSEC(DNSSECRData::RRSIG(sig))) if sig.type_covered() == record_type
+    ) && record.name() == name
+}
+
+/// The name that a record points to, whose addresses may be in the additional section
+fn target_name(record: &Record) -> Option<&Name> {
+    match record.data()? {
+        RData::CNAME(cname) => Some(&cname.0),
+        RData::MX(mx) => Some(mx.exchange()),
+        RData::NS(ns) => Some(&ns.0),
+        RData::SRV(srv) => Some(srv.target()),
+        _ => None,
+    }
+}
+
+/// The DNAME type, RFC 6672, which hickory-proto doesn't know about
+pub(crate) const DNAME: RecordType = RecordType::Unknown(39);
+
+/// If `record` is a DNAME that covers `name`, returns the CNAME for `name` that it implies,
+/// RFC 6672 section 2.2. The owner of the DNAME is replaced with its target. Only a server
+/// for `zone` or above it can redirect the names within its zone.
+fn synthesize_cname(
+    record: &Record,
+    name: &Name,
+    zone: &Name,
+) -> Option<Result<Record, ResolutionError>> {
+    let Some(RData::Unknown { code: DNAME, rdata }) = record.data() else {
+        return None;
+    };
+    let owner = record.name();
+    if owner == name || !owner.zone_of(name) || !zone.zone_of(owner) {
+        return None;
+    }
+    let prefix = name.num_labels() - owner.num_labels();
+    let synthesize = || -> Result<Record, ResolutionError> {
+        // the target of a DNAME is never compressed
+        let target = Name::from_bytes(rdata.anything())?;
+        let target = Name::from_labels(name.iter().take(prefix as usize))?.append_name(&target)?;
+        Ok(Record::from_rdata(name.clone(), record.ttl(), RData::CNAME(rdata::CNAME(target))))
+    };
+    Some(synthesize())
+}
+
+/// Returns true if the records are the CNAME of an alias, which for any other type than
+/// CNAME and ANY leads on to its target
+fn is_alias(records: &[Record], record_type: RecordType) -> bool {
+    !matches!(record_type, RecordType::CNAME | RecordType::ANY)
+        && records.iter().any(|r| r.record_type() == RecordType::CNAME)
+}
+
+/// The zone that the NS records of a referral delegates to
+fn delegated_zone(ns: &[Record]) -> Option<Name> {
+    ns.iter().find(|r| r.record_type() == RecordType::NS).map(|r| r.name().clone())
+}
+
+/// Returns true if the response to a DS query refers to the zone that the DS records are
+/// for, instead of answering from the parent side of the cut
+fn is_ds_referral(message: &Message, to_resolve: &Name, record_type: RecordType) -> bool {
+    record_type == RecordType::DS
+        && delegated_zone(message.name_servers()).is_some_and(|zone| zone == *to_resolve)
+}
+
+/// What a response to a query is, based on its sections rather than the AA flag alone
+#[derive(Debug, PartialEq)]
+enum Kind {
+    Answer,
+    NoData,
+    Referral,
+}
+
+/// Classifies a NOERROR response from a server for `zone`. Servers are known to set the AA
+/// flag on referrals and to leave it out of answers, so the sections are considered first:
+///
+/// 1. Answer records for the queried name, of the queried type or a CNAME, make an answer.
+///    So does a DNAME within `zone` above the queried name, and any answer record if the AA
+///    flag is set.
+/// 2. NS records in the authority section for a zone below `zone`, and above the queried
+///    name, make a referral, regardless of the AA flag.
+/// 3. An empty answer with an SOA, or the AA flag and no NS records, makes a NODATA response.
+/// 4. Anything else is treated as a referral, to be validated when followed.
+fn classify(response: &Message, to_resolve: &Name, record_type: RecordType, zone: &Name) -> Kind {
+    let answers = response.answers();
+    let matching = answers.iter().any(|r| {
+        (r.name() == to_resolve
+            && (r.record_type() == record_type
+                || r.record_type() == RecordType::CNAME
+                || record_type == RecordType::ANY))
+            || (r.record_type() == DNAME
+                && r.name() != to_resolve
+                && r.name().zone_of(to_resolve)
+                && zone.zone_of(r.name()))
+    });
+    if matching || (response.header().authoritative() && !answers.is_empty()) {
+        return Kind::Answer;
+    }
+    let delegation = response.name_servers().iter().any(|r| {
+        r.record_type() == RecordType::NS
+            && r.name() != zone
+            && zone.zone_of(r.name())
+            && r.name().zone_of(to_resolve)
+    });
+    if delegation {
+        Kind::Referral
+    } else if is_nodata(response) {
+        Kind::NoData
+    } else {
+        Kind::Referral
+    }
+}
+
+/// A NODATA response, RFC 2308 section 2.2, says that the name exists but has no records
+/// of the queried type. It has no answers and an SOA in the authority section, or the AA
+/// bit and no NS records there, as an authority section of NS records makes a referral.
+fn is_nodata(response: &Message) -> bool {
+    let authority = response.name_servers();
+    response.response_code() == ResponseCode::NoError
+        && response.answers().is_empty()
+        && (authority.iter().any(|r| r.record_type() == RecordType::SOA)
+            || (response.header().authoritative()
+                && !authority.iter().any(|r| r.record_type() == RecordType::NS)))
+}
+
+/// Returns true unless the response holds a question other than the one asked. Some
+/// responses, such as FORMERR ones, leave the question section empty.
+fn answers_question(response: &Message, to_resolve: &Name, record_type: RecordType) -> bool {
+    match response.queries() {
+        [] => true,
+        [query] => query.name() == to_resolve && query.query_type() == record_type,
+        _ => false,
+    }
 }
 
-fn first_ip(result: &mut Vec<Record>) -> Result<IpAddr, ResolutionError> {
-    match result.pop() {
-        None => Err(ServFail("unexpected empty result".to_string())),
-        Some(record) => match record.data() {
-            Some(RData::A(a)) => Ok(IpAddr::V4(a.0)),
-            _ => Err(ServFail("no rdata, or wrong type of rdata".to_string())),
-        },
+/// Returns the first address of the result. The result of resolving a nameserver name
+/// that is an alias holds the CNAME chain and its signatures ahead of the addresses.
+fn first_ip(result: &[Record]) -> Result<IpAddr, ResolutionError> {
+    if result.is_empty() {
+        return Err(ServFail("unexpected empty result".to_string()));
     }
+    result
+        .iter()
+        .find_map(|r| match r.data() {
+            Some(RData::A(a)) => Some(IpAddr::V4(a.0)),
+            _ => None,
+        })
+        .ok_or_else(|| ServFail("no rdata, or wrong type of rdata".to_string()))
 }
 
 #[cfg(test)]
 mod test {
     use anyhow::Result;
-    use hickory_proto::op::{Header, Message};
+    use hickory_proto::op::{Header, Message, ResponseCode};
+    use hickory_proto::rr::dnssec::rdata::{DNSSECRData, DNSKEY, DS};
+    use hickory_proto::rr::dnssec::{Algorithm, DigestType};
     use hickory_proto::rr::{rdata, Record};
     use hickory_proto::rr::{Name, RData, RecordType};
-    use std::net::{IpAddr, Ipv4Addr};
+    use hickory_proto::serialize::binary::BinEncodable;
+    use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+    use std::str::FromStr;
+    use std::sync::Arc;
+    use std::time::{Duration, Instant};
     use tracing::Level;
     use tracing_subscriber::FmtSubscriber;
-    use RecordType::A;
+    use RecordType::{A, AAAA, CNAME, MX, NS, PTR, SOA};
 
+    use crate::cache::{CacheResponse, Query, MAX_STALE};
     use crate::fake_backend::FakeBackend;
-    use crate::resolver::{is_final, RecursiveResolver, ResolutionError};
-    use crate::{a, answer, ns, refer};
+    use crate::hosts::parse_hosts;
+    use crate::resolver::{
+        classify, is_nodata, synthesize_cname, wire_length, Kind, RecursiveResolver,
+        RecursiveResolverBuilder, ResolutionError, ResolutionState, ResolveOptions, StubZone,
+        DEFAULT_MAX_NAMESERVERS, DNAME, ROOT_SERVERS,
+    };
+    use crate::special_use::LocalUsePolicy;
+    use crate::upstream_stats::{format_upstream_stats, parse_upstream_stats};
+    use crate::zone::StaticZone;
+    use crate::{a, answer, cname, name, ns, refer};
 
     #[ctor::ctor]
     fn init() {
@@ -216,23 +1814,46 @@ mod test {
     }
 
     #[test]
-    fn test_is_final() {
+    fn test_classify() -> Result<()> {
+        let qname = name!("a.b.");
+        let from_root = |m: &Message| classify(m, &qname, A, &Name::root());
         // not authoritative, no answer
         let m = Message::default();
-        assert!(!is_final(&m));
+        assert_eq!(from_root(&m), Kind::Referral);
 
         // authoritative, no answer
         let mut m = Message::new();
         m.set_header(*Header::new().set_authoritative(true));
-        assert!(!is_final(&m));
+        assert_eq!(from_root(&m), Kind::NoData);
 
-        // not authoritative, answer
+        // not authoritative, an answer for some other name
         m.set_header(Header::new());
         m.add_answer(Record::new());
-        assert!(!is_final(&m));
+        assert_eq!(from_root(&m), Kind::Referral);
 
         m.set_header(*Header::new().set_authoritative(true));
-        assert!(is_final(&m));
+        assert_eq!(from_root(&m), Kind::Answer);
+
+        // not authoritative, an answer for the queried name
+        let mut m = answer!(a!("a.b.", "10.0.0.1"));
+        m.set_authoritative(false);
+        assert_eq!(from_root(&m), Kind::Answer);
+        let mut m = answer!(cname!("a.b.", "c.d."));
+        m.set_authoritative(false);
+        assert_eq!(from_root(&m), Kind::Answer);
+
+        // a referral with the AA flag set
+        let mut m = refer!(ns!("b.", "ns.b."), a!("ns.b.", "10.0.0.2"));
+        m.set_authoritative(true);
+        assert_eq!(from_root(&m), Kind::Referral);
+
+        // NODATA with the NS records of the zone itself in the authority section
+        let mut m = nodata("b.")?;
+        m.add_name_server(ns!("b.", "ns.b."));
+        assert_eq!(classify(&m, &qname, A, &name!("b.")), Kind::NoData);
+        // ...but from the root it is a referral
+        assert_eq!(from_root(&m), Kind::Referral);
+        Ok(())
     }
 
     #[tokio::test]
@@ -259,6 +1880,1674 @@ mod test {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_min_nameservers() -> Result<()> {
+        let mut b = FakeBackend::new();
+        let mut referral = refer!(ns!("b.", "ns1.b."), a!("ns1.b.", "10.0.0.2"));
+        referral.add_name_server(ns!("b.", "ns2.b."));
+        referral.add_name_server(ns!("b.", "ns3.b."));
+        referral.add_additional(a!("ns2.b.", "10.0.0.3"));
+        referral.add_additional(a!("ns3.b.", "10.0.0.4"));
+        b.add("10.0.0.1", "a.b.", A, referral)?;
+        // only the third nameserver responds, the FakeBackend fails for the others
+        b.add("10.0.0.4", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        let roots = vec![IpAddr::V4("10.0.0.1".parse()?)];
+
+        let resolver = RecursiveResolver::builder().backend(b).roots(roots).build();
+        let result = resolver.resolve(&"a.b.".parse()?, A).await?;
+        assert_eq!(result, [a!("a.b.", "10.0.0.42")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_all_nameservers_fail() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, refer!(ns!("b.", "ns1.b."), a!("ns1.b.", "10.0.0.2")))?;
+        let roots = vec![IpAddr::V4("10.0.0.1".parse()?)];
+
+        // there is only one nameserver available, so we fail before reaching the minimum
+        let resolver = RecursiveResolver::builder().backend(b).roots(roots).build();
+        let result = resolver.resolve(&"a.b.".parse()?, A).await;
+        assert_eq!(
+            "Server failure: Could not find response for a.b. A at 10.0.0.2",
+            result.unwrap_err().to_string()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_types_partial_failure() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        let result = resolver.resolve_types(&"a.b.".parse()?, &[A, RecordType::AAAA]).await;
+        assert_eq!(result.records, [a!("a.b.", "10.0.0.42")]);
+        assert_eq!(1, result.errors.len());
+        assert!(matches!(result.errors.get(&RecordType::AAAA), Some(ResolutionError::ServFail(_))));
+        Ok(())
+    }
+
+    fn out_of_bailiwick_backend() -> Result<FakeBackend> {
+        let mut b = FakeBackend::new();
+        // the nameserver for b. is hosted in the unrelated zone d.
+        b.add("10.0.0.1", "a.b.", A, refer!(ns!("b.", "ns.c.d.")))?;
+        b.add("10.0.0.1", "ns.c.d.", A, answer!(a!("ns.c.d.", "10.0.0.2")))?;
+        b.add("10.0.0.2", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        Ok(b)
+    }
+
+    #[tokio::test]
+    async fn test_follow_out_of_bailiwick() -> Result<()> {
+        let resolver = RecursiveResolver::builder()
+            .backend(out_of_bailiwick_backend()?)
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .build();
+        let result = resolver.resolve(&"a.b.".parse()?, A).await?;
+        assert_eq!(result, [a!("a.b.", "10.0.0.42")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_refuse_out_of_bailiwick() -> Result<()> {
+        let resolver = RecursiveResolver::builder()
+            .backend(out_of_bailiwick_backend()?)
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .follow_out_of_bailiwick(false)
+            .build();
+        let result = resolver.resolve(&"a.b.".parse()?, A).await;
+        assert_eq!(
+            "Server failure: referral only contains out-of-bailiwick nameservers",
+            result.unwrap_err().to_string()
+        );
+        Ok(())
+    }
+
+    /// The server for a. answers with an address for a name in b. along with the CNAME
+    fn out_of_bailiwick_answer_backend() -> Result<FakeBackend> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.a.", A, refer!(ns!("a.", "ns.a."), a!("ns.a.", "10.0.0.2")))?;
+        let mut answer = answer!(cname!("www.a.", "www.b."));
+        answer.add_answer(a!("www.b.", "10.6.6.6"));
+        b.add("10.0.0.2", "www.a.", A, answer)?;
+        b.add("10.0.0.1", "www.b.", A, answer!(a!("www.b.", "10.0.0.42")))?;
+        Ok(b)
+    }
+
+    #[tokio::test]
+    async fn test_strict() -> Result<()> {
+        let roots = vec![IpAddr::V4("10.0.0.1".parse()?)];
+        let lenient = RecursiveResolver::builder()
+            .backend(out_of_bailiwick_answer_backend()?)
+            .roots(roots.clone())
+            .build();
+        let result = lenient.resolve(&name!("www.a."), A).await?;
+        assert_eq!(result, [cname!("www.a.", "www.b."), a!("www.b.", "10.0.0.42")]);
+
+        let strict = RecursiveResolver::builder()
+            .backend(out_of_bailiwick_answer_backend()?)
+            .roots(roots.clone())
+            .strict(true)
+            .build();
+        let result = strict.resolve(&name!("www.a."), A).await;
+        assert_eq!(
+            "Inconsistent response: the answer from a server for a. holds www.b., which is out of bailiwick",
+            result.unwrap_err().to_string()
+        );
+
+        // a response to another question fails at once, with the other nameserver untried
+        let mut b = FakeBackend::new();
+        let mut referral = refer!(ns!("a.", "ns1.a."), a!("ns1.a.", "10.0.0.2"));
+        referral.add_name_server(ns!("a.", "ns2.a."));
+        referral.add_additional(a!("ns2.a.", "10.0.0.2"));
+        b.add("10.0.0.1", "www.a.", A, referral)?;
+        let mut answer = answer!(a!("www.a.", "10.6.6.6"));
+        answer.add_query(hickory_proto::op::Query::query(name!("mail.a."), A));
+        b.add("10.0.0.2", "www.a.", A, answer)?;
+        let b = Arc::new(b);
+        let strict =
+            RecursiveResolver::builder().backend(b.clone()).roots(roots).strict(true).build();
+        let result = strict.resolve(&name!("www.a."), A).await;
+        assert!(matches!(result, Err(ResolutionError::Inconsistent(_))), "{result:?}");
+        assert_eq!(b.query_count(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_follow_cname() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.b.", A, answer!(cname!("www.b.", "host.b.")))?;
+        b.add("10.0.0.1", "host.b.", A, answer!(a!("host.b.", "10.0.0.42")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        let result = resolver.resolve(&"www.b.".parse()?, A).await?;
+        assert_eq!(result, [cname!("www.b.", "host.b."), a!("host.b.", "10.0.0.42")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_follow_unordered_cnames() -> Result<()> {
+        // the final record first, and the CNAMEs of the chain interleaved with the wrong order
+        let mut answer = answer!(a!("host.b.", "10.0.0.42"));
+        answer.add_answers([
+            cname!("alias.b.", "host.b."),
+            a!("unrelated.b.", "10.0.0.43"),
+            cname!("www.b.", "alias.b."),
+        ]);
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.b.", A, answer)?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        let result = resolver.resolve(&"www.b.".parse()?, A).await?;
+        assert_eq!(
+            result,
+            [
+                cname!("www.b.", "alias.b."),
+                cname!("alias.b.", "host.b."),
+                a!("host.b.", "10.0.0.42")
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_follow_unordered_dangling_cnames() -> Result<()> {
+        let mut answer = answer!(cname!("alias.b.", "host.b."));
+        answer.add_answer(cname!("www.b.", "alias.b."));
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.b.", A, answer)?;
+        // only the missing end of the chain is resolved
+        b.add("10.0.0.1", "host.b.", A, answer!(a!("host.b.", "10.0.0.42")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        let result = resolver.resolve(&"www.b.".parse()?, A).await?;
+        assert_eq!(
+            result,
+            [
+                cname!("www.b.", "alias.b."),
+                cname!("alias.b.", "host.b."),
+                a!("host.b.", "10.0.0.42")
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_multi_string_txt() -> Result<()> {
+        let txt = RData::TXT(rdata::TXT::new(vec!["v=DKIM1; k=rsa; ".into(), "p=MIIB".into()]));
+        let txt = Record::from_rdata("host.b.".parse()?, 60, txt);
+        let mut answer = answer!(cname!("www.b.", "host.b."));
+        answer.add_answer(txt.clone());
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.b.", RecordType::TXT, answer)?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        let result = resolver.resolve(&"www.b.".parse()?, RecordType::TXT).await?;
+        assert_eq!(result, [cname!("www.b.", "host.b."), txt]);
+        let Some(RData::TXT(txt)) = result[1].data() else {
+            panic!("expected TXT rdata");
+        };
+        let strings: Vec<&[u8]> = txt.iter().map(|s| s.as_ref()).collect();
+        assert_eq!(strings, [b"v=DKIM1; k=rsa; ".as_slice(), b"p=MIIB".as_slice()]);
+        Ok(())
+    }
+
+    fn soa(zone: &str) -> Result<Record> {
+        let soa = rdata::SOA::new(
+            Name::from_str(&format!("ns.{zone}"))?,
+            Name::from_str(&format!("hostmaster.{zone}"))?,
+            1,
+            3600,
+            600,
+            86400,
+            300,
+        );
+        Ok(Record::from_rdata(zone.parse()?, 300, RData::SOA(soa)))
+    }
+
+    /// An authoritative response without answers and the SOA of the zone in authority
+    fn nodata(zone: &str) -> Result<Message> {
+        let mut message = Message::new();
+        message.set_authoritative(true);
+        message.add_name_server(soa(zone)?);
+        Ok(message)
+    }
+
+    #[tokio::test]
+    async fn test_nodata() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", AAAA, nodata("b.")?)?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        assert!(resolver.resolve(&"a.b.".parse()?, AAAA).await?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_nodata() -> Result<()> {
+        let mut authoritative = Message::new();
+        authoritative.set_authoritative(true);
+        assert!(is_nodata(&authoritative));
+        assert!(is_nodata(&nodata("b.")?));
+        // the AA bit is set by some servers on referrals too, which the NS records tell
+        let mut referral = refer!(ns!("b.", "ns.b."), a!("ns.b.", "10.0.0.2"));
+        assert!(!is_nodata(&referral));
+        referral.set_authoritative(true);
+        assert!(!is_nodata(&referral));
+        referral.add_name_server(soa("b.")?);
+        assert!(is_nodata(&referral));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_non_terminal() -> Result<()> {
+        let mut b = FakeBackend::new();
+        // b.example. only exists because a.b.example. does
+        b.add("10.0.0.1", "b.example.", A, nodata("example.")?)?;
+        b.add("10.0.0.1", "a.b.example.", A, answer!(a!("a.b.example.", "10.0.0.42")))?;
+        let b = Arc::new(b);
+        let resolver =
+            RecursiveResolver::with_backend(b.clone(), vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        for _ in 0..2 {
+            assert!(resolver.resolve(&name!("b.example."), A).await?.is_empty());
+        }
+        // the second time comes from the negative cache, as NODATA
+        assert_eq!(b.query_count(), 1);
+        let query = Query { to_resolve: name!("b.example."), record_type: A };
+        let negative = resolver.negative_cache.get(&query, Instant::now());
+        assert!(negative.is_some_and(|n| !n.nxdomain));
+        let result = resolver.resolve(&name!("a.b.example."), A).await?;
+        assert_eq!(result, [a!("a.b.example.", "10.0.0.42")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dname_then_cname() -> Result<()> {
+        let target = name!("b.example.").to_bytes()?;
+        let rdata = RData::Unknown { code: DNAME, rdata: rdata::NULL::with(target) };
+        let dname = Record::from_rdata(name!("a.example."), 300, rdata);
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.a.example.", A, answer!(dname.clone()))?;
+        b.add("10.0.0.1", "www.b.example.", A, answer!(cname!("www.b.example.", "host.c.")))?;
+        b.add("10.0.0.1", "host.c.", A, answer!(a!("host.c.", "10.0.0.42")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        let result = resolver.resolve(&name!("www.a.example."), A).await?;
+        let mut synthesized = cname!("www.a.example.", "www.b.example.");
+        synthesized.set_ttl(300);
+        assert_eq!(
+            result,
+            [dname, synthesized, cname!("www.b.example.", "host.c."), a!("host.c.", "10.0.0.42")]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dname_out_of_zone() -> Result<()> {
+        let target = name!("c.").to_bytes()?;
+        let rdata = RData::Unknown { code: DNAME, rdata: rdata::NULL::with(target) };
+        // the server for a.b. has no say over the names in b.
+        let dname = Record::from_rdata(name!("b."), 300, rdata);
+        assert!(synthesize_cname(&dname, &name!("www.a.b."), &name!("a.b.")).is_none());
+        assert!(synthesize_cname(&dname, &name!("www.a.b."), &name!("b.")).is_some());
+
+        let mut response = answer!(dname);
+        response.set_authoritative(false);
+        let referral = refer!(ns!("a.b.", "ns.a.b."), a!("ns.a.b.", "10.0.0.2"));
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.a.b.", A, referral)?;
+        b.add("10.0.0.2", "www.a.b.", A, response)?;
+        b.add("10.0.0.1", "www.a.c.", A, answer!(a!("www.a.c.", "10.0.0.42")))?;
+        let b = Arc::new(b);
+        let resolver =
+            RecursiveResolver::with_backend(b.clone(), vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        let result = resolver.resolve(&name!("www.a.b."), A).await;
+        assert!(result.is_err(), "the DNAME must not be followed, got {result:?}");
+        assert_eq!(b.query_count(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cname_to_nodata() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.b.", AAAA, answer!(cname!("www.b.", "host.b.")))?;
+        b.add("10.0.0.1", "host.b.", AAAA, nodata("b.")?)?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        let result = resolver.resolve(&"www.b.".parse()?, AAAA).await?;
+        assert_eq!(result, [cname!("www.b.", "host.b.")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cname_bounce_between_zones() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.a.", A, refer!(ns!("a.", "ns.a."), a!("ns.a.", "10.0.0.2")))?;
+        b.add("10.0.0.1", "www.b.", A, refer!(ns!("b.", "ns.b."), a!("ns.b.", "10.0.0.3")))?;
+        b.add("10.0.0.2", "www.a.", A, answer!(cname!("www.a.", "www.b.")))?;
+        b.add("10.0.0.3", "www.b.", A, answer!(cname!("www.b.", "www.a.")))?;
+        let resolver = RecursiveResolverBuilder::default()
+            .backend(b)
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .strict_cnames(true)
+            .build();
+
+        let result = resolver.resolve(&name!("www.a."), A).await;
+        match result {
+            Err(ResolutionError::ServFail(e)) => assert_eq!(e, "CNAME loop detected at www.a."),
+            _ => panic!("the loop must be detected, got {result:?}"),
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_negative_caching() -> Result<()> {
+        let mut nxdomain = nodata("b.")?;
+        nxdomain.set_response_code(ResponseCode::NXDomain);
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, nxdomain)?;
+        b.add("10.0.0.1", "a.b.", AAAA, nodata("b.")?)?;
+        let b = Arc::new(b);
+        let resolver =
+            RecursiveResolver::with_backend(b.clone(), vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        for _ in 0..2 {
+            assert!(matches!(
+                resolver.resolve(&name!("a.b."), A).await,
+                Err(ResolutionError::NxDomain)
+            ));
+            assert!(resolver.resolve(&name!("a.b."), AAAA).await?.is_empty());
+        }
+        // the second round of answers came from the negative cache
+        assert_eq!(b.query_count(), 2);
+        resolver.flush_cache();
+        assert!(matches!(
+            resolver.resolve(&name!("a.b."), A).await,
+            Err(ResolutionError::NxDomain)
+        ));
+        assert_eq!(b.query_count(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_flush_negative() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.example.", A, nodata("example.")?)?;
+        b.add("10.0.0.1", "a.c.", A, nodata("c.")?)?;
+        let b = Arc::new(b);
+        let resolver =
+            RecursiveResolver::with_backend(b.clone(), vec![IpAddr::V4("10.0.0.1".parse()?)]);
+        for name in [name!("a.b.example."), name!("a.c.")] {
+            assert!(resolver.resolve(&name, A).await?.is_empty());
+        }
+        // the negative answer for a.b.example. is scoped to the zone of its SOA
+        let query = Query { to_resolve: name!("a.b.example."), record_type: A };
+        let negative = resolver.negative_cache.get(&query, Instant::now());
+        assert_eq!(negative.map(|n| n.zone), Some(name!("example.")));
+
+        // flushing b.example. leaves the answers from example. alone
+        resolver.flush_negative(&name!("b.example."));
+        assert!(resolver.negative_cache.get(&query, Instant::now()).is_some());
+        resolver.flush_negative(&name!("example"));
+        assert!(resolver.negative_cache.get(&query, Instant::now()).is_none());
+        let query = Query { to_resolve: name!("a.c."), record_type: A };
+        assert!(resolver.negative_cache.get(&query, Instant::now()).is_some());
+        assert_eq!(b.query_count(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_skip_negative_cache() -> Result<()> {
+        let mut nxdomain = nodata("b.")?;
+        nxdomain.set_response_code(ResponseCode::NXDomain);
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, nxdomain)?;
+        b.add("10.0.0.2", "c.b.", A, answer!(cname!("c.b.", "a.b.")))?;
+        let b = Arc::new(b);
+        let resolver =
+            RecursiveResolver::with_backend(b.clone(), vec![IpAddr::V4("10.0.0.1".parse()?)]);
+        let skip = ResolveOptions { skip_negative_cache: true, ..ResolveOptions::default() };
+
+        assert!(matches!(
+            resolver.resolve(&name!("a.b."), A).await,
+            Err(ResolutionError::NxDomain)
+        ));
+        assert!(matches!(
+            resolver.resolve(&name!("a.b."), A).await,
+            Err(ResolutionError::NxDomain)
+        ));
+        assert_eq!(b.query_count(), 1);
+        assert!(matches!(
+            resolver.resolve_with_options(&name!("a.b."), A, skip).await,
+            Err(ResolutionError::NxDomain)
+        ));
+        assert_eq!(b.query_count(), 2);
+        // the fresh answer is cached again
+        assert!(matches!(
+            resolver.resolve(&name!("a.b."), A).await,
+            Err(ResolutionError::NxDomain)
+        ));
+        assert_eq!(b.query_count(), 2);
+
+        // the answers of the given servers are not cached, and the names that they lead to
+        // are resolved with the negative cache
+        let servers = ResolveOptions {
+            servers: Some(vec![IpAddr::V4("10.0.0.2".parse()?)]),
+            skip_negative_cache: true,
+            ..ResolveOptions::default()
+        };
+        assert!(matches!(
+            resolver.resolve_with_options(&name!("c.b."), A, servers).await,
+            Err(ResolutionError::NxDomain)
+        ));
+        assert_eq!(b.query_count(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_apex_ns() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, refer!(ns!("b.", "ns.b."), a!("ns.b.", "10.0.0.2")))?;
+        b.add("10.0.0.2", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        // the child has an additional nameserver, not known to the parent
+        let mut apex = answer!(ns!("b.", "ns.b."));
+        apex.add_answer(ns!("b.", "ns2.b."));
+        b.add("10.0.0.2", "b.", RecordType::NS, apex)?;
+        let b = Arc::new(b);
+        let resolver =
+            RecursiveResolver::with_backend(b.clone(), vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        resolver.resolve(&name!("a.b."), A).await?;
+        // the delegation from the referral is followed to get the apex records
+        let expected = vec![ns!("b.", "ns.b."), ns!("b.", "ns2.b.")];
+        assert_eq!(resolver.resolve(&name!("b."), RecordType::NS).await?, expected);
+        assert_eq!(b.query_count(), 3);
+        assert_eq!(resolver.resolve(&name!("b."), RecordType::NS).await?, expected);
+        assert_eq!(b.query_count(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_related_records() -> Result<()> {
+        let aaaa =
+            Record::from_rdata(name!("a.b."), 60, RData::AAAA(rdata::AAAA::from_str("::1")?));
+        let mut answer = answer!(a!("a.b.", "10.0.0.42"));
+        answer.add_additional(aaaa.clone());
+        // not within b. so it can't be trusted
+        answer.add_additional(a!("a.c.", "10.0.0.43"));
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, refer!(ns!("b.", "ns.b."), a!("ns.b.", "10.0.0.2")))?;
+        b.add("10.0.0.2", "a.b.", A, answer)?;
+        let b = Arc::new(b);
+        let resolver =
+            RecursiveResolver::with_backend(b.clone(), vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        resolver.resolve(&name!("a.b."), A).await?;
+        assert_eq!(b.query_count(), 2);
+        assert_eq!(resolver.resolve(&name!("a.b."), AAAA).await?, [aaaa]);
+        assert_eq!(b.query_count(), 2);
+        assert_eq!(resolver.get_cached(&name!("a.c."), A), CacheResponse::None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_related_additional_addresses() -> Result<()> {
+        let mx = RData::MX(rdata::MX::new(10, name!("mx.b.")));
+        let mut answer = answer!(Record::from_rdata(name!("b."), 60, mx));
+        answer.add_additional(a!("mx.b.", "10.0.0.25"));
+        // nothing refers to other.b. so it is left out
+        answer.add_additional(a!("other.b.", "10.0.0.26"));
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "b.", MX, answer)?;
+        let b = Arc::new(b);
+        let resolver =
+            RecursiveResolver::with_backend(b.clone(), vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        resolver.resolve(&name!("b."), MX).await?;
+        assert_eq!(resolver.resolve(&name!("mx.b."), A).await?, [a!("mx.b.", "10.0.0.25")]);
+        assert_eq!(b.query_count(), 1);
+        assert_eq!(resolver.get_cached(&name!("other.b."), A), CacheResponse::None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cname_query() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.b.", CNAME, answer!(cname!("www.b.", "host.b.")))?;
+        // there is no A record for host.b. so resolution would fail if it was followed
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        let result = resolver.resolve(&"www.b.".parse()?, CNAME).await?;
+        assert_eq!(result, [cname!("www.b.", "host.b.")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_zero_ttl_answer() -> Result<()> {
+        let mut zero_ttl = a!("a.b.", "10.0.0.2");
+        zero_ttl.set_ttl(0);
+        let mut answer = answer!(a!("a.b.", "10.0.0.1"));
+        answer.add_answer(zero_ttl.clone());
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, answer)?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        let result = resolver.resolve(&"a.b.".parse()?, A).await?;
+        assert_eq!(result, [a!("a.b.", "10.0.0.1"), zero_ttl]);
+        assert_eq!(result[1].ttl(), 0);
+        // a set is cached with its lowest ttl, so nothing is cached
+        assert_eq!(resolver.get_cached(&"a.b.".parse()?, A), CacheResponse::None);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_root_zone() -> Result<()> {
+        let soa = rdata::SOA::new(
+            name!("a.root-servers.net."),
+            name!("nstld.verisign-grs.com."),
+            2024101400,
+            1800,
+            900,
+            604800,
+            86400,
+        );
+        let soa = Record::from_rdata(Name::root(), 86400, RData::SOA(soa));
+        let key = DNSKEY::new(true, true, false, Algorithm::RSASHA256, vec![3, 1, 0, 1]);
+        let key = Record::from_rdata(Name::root(), 172800, RData::DNSSEC(DNSSECRData::DNSKEY(key)));
+        let answers = [
+            (RecordType::NS, ns!(".", "a.root-servers.net.")),
+            (RecordType::SOA, soa),
+            (RecordType::DNSKEY, key),
+        ];
+
+        let mut b = FakeBackend::new();
+        for (record_type, record) in &answers {
+            b.add("10.0.0.1", ".", *record_type, answer!(record.clone()))?;
+        }
+        let b = Arc::new(b);
+        let resolver =
+            RecursiveResolver::with_backend(b.clone(), vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        for (i, (record_type, record)) in answers.iter().enumerate() {
+            let expected = vec![record.clone()];
+            assert_eq!(resolver.resolve(&Name::root(), *record_type).await?, expected);
+            // the second time the answer comes from the cache
+            assert_eq!(resolver.resolve(&Name::root(), *record_type).await?, expected);
+            assert_eq!(b.query_count(), i + 1);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_caching_disabled() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, refer!(ns!("b.", "ns.b."), a!("ns.b.", "10.0.0.2")))?;
+        b.add("10.0.0.2", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        let b = Arc::new(b);
+        let resolver = RecursiveResolver::builder()
+            .backend(b.clone())
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .caching(false)
+            .build();
+
+        for i in 1..=3 {
+            let result = resolver.resolve(&"a.b.".parse()?, A).await?;
+            assert_eq!(result, [a!("a.b.", "10.0.0.42")]);
+            assert_eq!(b.query_count(), i * 2);
+        }
+        assert_eq!(resolver.cache_len(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sort_answers() -> Result<()> {
+        let mut answer = answer!(a!("a.b.", "10.0.0.3"));
+        answer.add_answers([a!("a.b.", "10.0.0.1"), a!("a.b.", "10.0.0.2")]);
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, answer)?;
+        let resolver = RecursiveResolver::builder()
+            .backend(b)
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .sort_answers(true)
+            .build();
+
+        let result = resolver.resolve(&"a.b.".parse()?, A).await?;
+        assert_eq!(
+            result,
+            [a!("a.b.", "10.0.0.1"), a!("a.b.", "10.0.0.2"), a!("a.b.", "10.0.0.3")]
+        );
+        Ok(())
+    }
+
+    /// Creates a record, keeping the casing of the names. Parsing names with from_str
+    /// lowercases them as part of the IDNA processing.
+    fn mixed_case(name: &str, rdata: RData) -> Result<Record> {
+        Ok(Record::from_rdata(Name::from_ascii(name)?, 60, rdata))
+    }
+
+    #[tokio::test]
+    async fn test_mixed_case_owner_names() -> Result<()> {
+        let ns = mixed_case("B.", RData::NS(rdata::NS(Name::from_ascii("Ns.b.")?)))?;
+        let glue = mixed_case("nS.B.", RData::A(rdata::A("10.0.0.2".parse()?)))?;
+        let cname = mixed_case("WWW.b.", RData::CNAME(rdata::CNAME(Name::from_ascii("Host.B.")?)))?;
+        let host = mixed_case("hOST.b.", RData::A(rdata::A("10.0.0.42".parse()?)))?;
+
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.b.", A, refer!(ns, glue))?;
+        b.add("10.0.0.2", "www.b.", A, answer!(cname))?;
+        b.add("10.0.0.2", "host.b.", A, answer!(host))?;
+        let resolver = RecursiveResolver::builder()
+            .backend(b)
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .follow_out_of_bailiwick(false)
+            .build();
+
+        let result = resolver.resolve(&"www.b.".parse()?, A).await?;
+        assert_eq!(result, [cname!("www.b.", "host.b."), a!("host.b.", "10.0.0.42")]);
+        // the answer is served from the cache using a name with yet another casing
+        let result = resolver.resolve(&Name::from_ascii("Www.B.")?, A).await?;
+        assert_eq!(result, [cname!("www.b.", "host.b."), a!("host.b.", "10.0.0.42")]);
+        Ok(())
+    }
+
+    /// Returns a name with labels of the given lengths
+    fn long_name(label_lengths: &[usize]) -> Result<Name> {
+        let labels: Vec<String> = label_lengths.iter().map(|len| "a".repeat(*len)).collect();
+        Ok(Name::from_str(&format!("{}.", labels.join(".")))?)
+    }
+
+    #[tokio::test]
+    async fn test_resolve_max_length_name() -> Result<()> {
+        let name = long_name(&[63, 63, 63, 61])?;
+        assert_eq!(255, wire_length(&name));
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", &name.to_string(), A, answer!(a!(name.to_string(), "10.0.0.42")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        let result = resolver.resolve(&name, A).await?;
+        assert_eq!(result, [a!(name.to_string(), "10.0.0.42")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_too_long_name() -> Result<()> {
+        let name = long_name(&[63, 63, 63, 62])?;
+        let resolver = RecursiveResolver::with_backend(FakeBackend::new(), vec![]);
+        let result = resolver.resolve(&name, A).await;
+        assert!(matches!(result, Err(ResolutionError::NameTooLong(256))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_served_zones() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        b.add("10.0.0.1", "a.c.", A, answer!(a!("a.c.", "10.0.0.43")))?;
+        let resolver = RecursiveResolver::builder()
+            .backend(b)
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .served_zones(vec!["b.".parse()?])
+            .build();
+
+        let result = resolver.resolve(&"a.b.".parse()?, A).await?;
+        assert_eq!(result, [a!("a.b.", "10.0.0.42")]);
+        let result = resolver.resolve(&"a.c.".parse()?, A).await;
+        assert!(matches!(result, Err(ResolutionError::Refused)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_ptr() -> Result<()> {
+        let ptr = Record::from_rdata(
+            "4.3.2.1.in-addr.arpa.".parse()?,
+            60,
+            RData::PTR(rdata::PTR("host.example.net.".parse()?)),
+        );
+        let name = "4.3.2.1.in-addr.arpa.";
+        let mut b = FakeBackend::new();
+        b.add(
+            "10.0.0.1",
+            name,
+            PTR,
+            refer!(
+                ns!("in-addr.arpa.", "a.in-addr-servers.arpa."),
+                a!("a.in-addr-servers.arpa.", "10.0.0.2")
+            ),
+        )?;
+        // the operator of 1.0.0.0/8 uses a glueless nameserver in a different tree
+        b.add("10.0.0.2", name, PTR, refer!(ns!("1.in-addr.arpa.", "ns.example.net.")))?;
+        b.add("10.0.0.1", "ns.example.net.", A, answer!(a!("ns.example.net.", "10.0.0.3")))?;
+        b.add(
+            "10.0.0.3",
+            name,
+            PTR,
+            refer!(
+                ns!("3.2.1.in-addr.arpa.", "ns.3.2.1.in-addr.arpa."),
+                a!("ns.3.2.1.in-addr.arpa.", "10.0.0.4")
+            ),
+        )?;
+        b.add("10.0.0.4", name, PTR, answer!(ptr.clone()))?;
+        let mut sibling = ptr.clone();
+        sibling.set_name("5.3.2.1.in-addr.arpa.".parse()?);
+        b.add("10.0.0.4", "5.3.2.1.in-addr.arpa.", PTR, answer!(sibling.clone()))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        let result = resolver.resolve(&name.parse()?, PTR).await?;
+        assert_eq!(result, [ptr]);
+        // only the nameserver for 3.2.1.in-addr.arpa. knows about this name, so the
+        // cached delegation must be used
+        let result = resolver.resolve(&"5.3.2.1.in-addr.arpa.".parse()?, PTR).await?;
+        assert_eq!(result, [sibling]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_start_at_cached_delegation() -> Result<()> {
+        let mut b = FakeBackend::new();
+        let referral = refer!(ns!("com.", "a.gtld.com."), a!("a.gtld.com.", "10.0.0.2"));
+        b.add("10.0.0.1", "www.example.com.", A, referral)?;
+        b.add("10.0.0.2", "www.example.com.", A, answer!(a!("www.example.com.", "10.0.0.42")))?;
+        // the root has no response for example.com. so it must not be asked
+        b.add("10.0.0.2", "example.com.", A, answer!(a!("example.com.", "10.0.0.43")))?;
+        let b = Arc::new(b);
+        let resolver =
+            RecursiveResolver::with_backend(b.clone(), vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        resolver.resolve(&name!("www.example.com."), A).await?;
+        assert_eq!(b.query_count(), 2);
+        let result = resolver.resolve(&name!("example.com."), A).await?;
+        assert_eq!(result, [a!("example.com.", "10.0.0.43")]);
+        assert_eq!(b.query_count(), 3);
+        Ok(())
+    }
+
+    /// Options for a resolution that starts at the servers
+    fn servers(servers: Vec<IpAddr>) -> ResolveOptions {
+        ResolveOptions { servers: Some(servers), ..ResolveOptions::default() }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_with_servers() -> Result<()> {
+        let mut b = FakeBackend::new();
+        // the root has no response configured, so it must not be asked
+        b.add("10.0.0.5", "www.example.com.", A, answer!(a!("www.example.com.", "10.0.0.42")))?;
+        let mut nxdomain = nodata("example.com.")?;
+        nxdomain.set_response_code(ResponseCode::NXDomain);
+        b.add("10.0.0.5", "nx.example.com.", A, nxdomain)?;
+        let b = Arc::new(b);
+        let resolver =
+            RecursiveResolver::with_backend(b.clone(), vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        let start = vec![IpAddr::V4("10.0.0.5".parse()?)];
+        let result =
+            resolver.resolve_with_options(&name!("www.example.com."), A, servers(start)).await?;
+        assert_eq!(result, [a!("www.example.com.", "10.0.0.42")]);
+        assert_eq!(b.query_count(), 1);
+
+        // what the given servers answer stays out of the cache
+        let start = vec![IpAddr::V4("10.0.0.5".parse()?)];
+        let result =
+            resolver.resolve_with_options(&name!("nx.example.com."), A, servers(start)).await;
+        assert!(matches!(result, Err(ResolutionError::NxDomain)));
+        let cached = resolver.get_cached(&name!("www.example.com."), A);
+        assert!(matches!(cached, CacheResponse::None));
+        let query = Query { to_resolve: name!("nx.example.com."), record_type: A };
+        assert!(resolver.negative_cache.get(&query, Instant::now()).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_delegation() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", NS, refer!(ns!("b.", "ns.b."), a!("ns.b.", "10.0.0.2")))?;
+        let mut referral = refer!(ns!("a.b.", "ns1.a.b."), a!("ns1.a.b.", "10.0.0.3"));
+        referral.add_name_server(ns!("a.b.", "ns2.a.b."));
+        referral.add_additional(a!("ns2.a.b.", "10.0.0.4"));
+        b.add("10.0.0.2", "a.b.", NS, referral)?;
+        let b = Arc::new(b);
+        let resolver =
+            RecursiveResolver::with_backend(b.clone(), vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        let (ns, glue) = resolver.delegation(&name!("a.b.")).await?;
+        assert_eq!(ns, [ns!("a.b.", "ns1.a.b."), ns!("a.b.", "ns2.a.b.")]);
+        assert_eq!(glue, [a!("ns1.a.b.", "10.0.0.3"), a!("ns2.a.b.", "10.0.0.4")]);
+        assert_eq!(b.query_count(), 2);
+        // the servers of a.b. are never asked, and the second time it comes from the cache
+        let (ns, _) = resolver.delegation(&name!("a.b.")).await?;
+        assert_eq!(ns.len(), 2);
+        assert_eq!(b.query_count(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_authority_for() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.b.", SOA, refer!(ns!("b.", "ns.b."), a!("ns.b.", "10.0.0.2")))?;
+        b.add("10.0.0.1", "b.", SOA, refer!(ns!("b.", "ns.b."), a!("ns.b.", "10.0.0.2")))?;
+        b.add("10.0.0.2", "www.b.", SOA, nodata("b.")?)?;
+        b.add("10.0.0.2", "b.", SOA, answer!(soa("b.")?))?;
+        b.add("10.0.0.2", "b.", NS, answer!(ns!("b.", "ns.b.")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        let (apex, soa_record, ns) = resolver.authority_for(&name!("www.b.")).await?;
+        assert_eq!(apex, name!("b."));
+        assert_eq!(soa_record, soa("b.")?);
+        assert_eq!(ns, [ns!("b.", "ns.b.")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upstream_stats() -> Result<()> {
+        let mut servfail = Message::new();
+        servfail.set_response_code(ResponseCode::ServFail);
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        b.add("10.0.0.1", "c.b.", A, answer!(a!("c.b.", "10.0.0.43")))?;
+        b.add("10.0.0.2", "a.b.", A, servfail)?;
+        let mut refused = Message::new();
+        refused.set_response_code(ResponseCode::Refused);
+        b.add("10.0.0.2", "e.b.", A, refused)?;
+        let resolver = RecursiveResolver::with_backend(b, vec![]);
+
+        let first = vec![IpAddr::V4("10.0.0.1".parse()?)];
+        let second = vec![IpAddr::V4("10.0.0.2".parse()?)];
+        resolver.resolve_with_options(&name!("a.b."), A, servers(first.clone())).await?;
+        resolver.resolve_with_options(&name!("c.b."), A, servers(first)).await?;
+        assert!(resolver
+            .resolve_with_options(&name!("a.b."), A, servers(second.clone()))
+            .await
+            .is_err());
+        // no response at all
+        assert!(resolver
+            .resolve_with_options(&name!("d.b."), A, servers(second.clone()))
+            .await
+            .is_err());
+        // REFUSED is a failed response, just like SERVFAIL
+        assert!(resolver.resolve_with_options(&name!("e.b."), A, servers(second)).await.is_err());
+
+        let stats = resolver.upstream_stats();
+        let summary: Vec<(String, u64, u64, u64)> = stats
+            .iter()
+            .map(|(ip, c)| (ip.to_string(), c.queries, c.successes, c.servfails))
+            .collect();
+        assert_eq!(summary, [("10.0.0.1".to_string(), 2, 2, 0), ("10.0.0.2".to_string(), 3, 0, 2)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_restore_upstream_stats() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.2", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        let b = Arc::new(b);
+        let roots = vec![IpAddr::V4("10.0.0.1".parse()?), IpAddr::V4("10.0.0.2".parse()?)];
+        let resolver = RecursiveResolver::with_backend(b.clone(), vec![]);
+        // 10.0.0.1 has no responses, so it never responds
+        let first = vec![IpAddr::V4("10.0.0.1".parse()?)];
+        assert!(resolver.resolve_with_options(&name!("a.b."), A, servers(first)).await.is_err());
+        let saved = format_upstream_stats(&resolver.upstream_stats());
+        let stats = parse_upstream_stats(&saved)?;
+        assert_eq!(stats, resolver.upstream_stats());
+        // huge counters from a file that was tampered with don't overflow
+        let max = u64::MAX;
+        let line =
+            format!("10.0.0.9 queries {max} successes {max} servfails 0 average-latency-ms {max}");
+        let tampered = parse_upstream_stats(&line)?;
+        assert!(tampered[0].1.average_latency() > Duration::ZERO);
+        assert!(parse_upstream_stats("10.0.0.9 queries 1").is_err());
+
+        // whatever the order is, the server that responds is asked first after the restart
+        for seed in 0..4 {
+            let resolver = RecursiveResolver::builder()
+                .backend(b.clone())
+                .roots(roots.clone())
+                .upstream_stats(stats.clone())
+                .caching(false)
+                .seed(seed)
+                .build();
+            let count = b.query_count();
+            assert_eq!(resolver.resolve(&name!("a.b."), A).await?, [a!("a.b.", "10.0.0.42")]);
+            assert_eq!(b.query_count(), count + 1);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_resolve_ds() -> Result<()> {
+        let ds = |zone: &str| -> Result<Record> {
+            let ds = DS::new(4711, Algorithm::ECDSAP256SHA256, DigestType::SHA256, vec![0; 32]);
+            Ok(Record::from_rdata(name!(zone), 60, RData::DNSSEC(DNSSECRData::DS(ds))))
+        };
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.a.b.", A, refer!(ns!("b.", "ns.b."), a!("ns.b.", "10.0.0.2")))?;
+        b.add(
+            "10.0.0.2",
+            "www.a.b.",
+            A,
+            refer!(ns!("a.b.", "ns.a.b."), a!("ns.a.b.", "10.0.0.3")),
+        )?;
+        b.add("10.0.0.3", "www.a.b.", A, answer!(a!("www.a.b.", "10.0.0.42")))?;
+        b.add("10.0.0.2", "a.b.", RecordType::DS, answer!(ds("a.b.")?))?;
+        // a parent that refers DS queries to the child, with the DS in the authority section
+        let mut referral = refer!(ns!("c.b.", "ns.c.b."), a!("ns.c.b.", "10.0.0.4"));
+        referral.add_name_server(ds("c.b.")?);
+        b.add("10.0.0.2", "c.b.", RecordType::DS, referral)?;
+        let b = Arc::new(b);
+        let resolver =
+            RecursiveResolver::with_backend(b.clone(), vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        // the delegation of a.b. is cached, but its DS is still asked for at b.
+        resolver.resolve(&name!("www.a.b."), A).await?;
+        let count = b.query_count();
+        assert_eq!(resolver.resolve(&name!("a.b."), RecordType::DS).await?, [ds("a.b.")?]);
+        assert_eq!(b.query_count(), count + 1);
+        // the servers of c.b. have no responses, and are never asked
+        assert_eq!(resolver.resolve(&name!("c.b."), RecordType::DS).await?, [ds("c.b.")?]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stub_zones() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.9", "www.corp.", A, answer!(a!("www.corp.", "10.0.0.80")))?;
+        b.add("10.0.0.1", "www.b.", A, answer!(a!("www.b.", "10.0.0.42")))?;
+        let b = Arc::new(b);
+        let resolver = RecursiveResolver::builder()
+            .backend(b.clone())
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .stub_zones(vec!["corp=10.0.0.9".parse()?])
+            .build();
+
+        // the roots have no responses for corp., so they are never asked
+        assert_eq!(resolver.resolve(&name!("www.corp."), A).await?, [a!("www.corp.", "10.0.0.80")]);
+        assert_eq!(b.query_count(), 1);
+        // names outside of the stub zones start at the roots, as usual
+        assert_eq!(resolver.resolve(&name!("www.b."), A).await?, [a!("www.b.", "10.0.0.42")]);
+
+        assert!("corp=10.0.0.9,10.0.0.10".parse::<StubZone>().is_ok());
+        assert!("corp".parse::<StubZone>().is_err());
+        assert!("corp=ns.corp".parse::<StubZone>().is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cache_only() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "c.b.", A, answer!(a!("c.b.", "10.0.0.43")))?;
+        let b = Arc::new(b);
+        let offline = RecursiveResolverBuilder::default()
+            .backend(b.clone())
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .cache_only(true)
+            .build();
+        let query = Query { to_resolve: name!("a.b."), record_type: A };
+        offline.cache.store(query, vec![a!("a.b.", "10.0.0.42")], Instant::now());
+
+        let result = offline.resolve(&name!("a.b."), A).await?;
+        assert_eq!(result, [a!("a.b.", "10.0.0.42")]);
+        let result = offline.resolve(&name!("c.b."), A).await;
+        assert!(matches!(result, Err(ResolutionError::NotCached)), "{result:?}");
+        assert_eq!(b.query_count(), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_cache_directives() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, answer!(a!("a.b.", "10.0.0.43")))?;
+        let b = Arc::new(b);
+        let resolver = RecursiveResolverBuilder::default()
+            .backend(b.clone())
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .serve_stale(true)
+            .build();
+        let query =
+            |name: &str| -> Result<Query> { Ok(Query { to_resolve: name!(name), record_type: A }) };
+        let cached = a!("a.b.", "10.0.0.42");
+        resolver.cache.store(query("a.b.")?, vec![cached.clone()], Instant::now());
+
+        let only_if_cached = ResolveOptions { only_if_cached: true, ..ResolveOptions::default() };
+        let result =
+            resolver.resolve_with_options(&name!("a.b."), A, only_if_cached.clone()).await?;
+        assert_eq!(result, [cached]);
+        let result = resolver.resolve_with_options(&name!("c.b."), A, only_if_cached).await;
+        assert!(matches!(result, Err(ResolutionError::NotCached)), "{result:?}");
+        assert_eq!(b.query_count(), 0);
+
+        let no_cache = ResolveOptions { no_cache: true, ..ResolveOptions::default() };
+        let result = resolver.resolve_with_options(&name!("a.b."), A, no_cache).await?;
+        assert_eq!(result, [a!("a.b.", "10.0.0.43")]);
+        assert_eq!(b.query_count(), 1);
+        // the fresh answer replaced the cached one
+        assert_eq!(resolver.resolve(&name!("a.b."), A).await?, [a!("a.b.", "10.0.0.43")]);
+        assert_eq!(b.query_count(), 1);
+
+        // the records of d.b. expired five seconds ago, and there is no response for it
+        let expired = Record::from_rdata(name!("d.b."), 5, RData::A("10.0.0.44".parse()?));
+        let stored = Instant::now() - Duration::from_secs(10);
+        let max_stale = |seconds| ResolveOptions {
+            max_stale: Some(Duration::from_secs(seconds)),
+            ..ResolveOptions::default()
+        };
+        resolver.cache.store(query("d.b.")?, vec![expired.clone()], stored);
+        assert!(resolver.resolve_with_options(&name!("d.b."), A, max_stale(1)).await.is_err());
+        let stale = resolver.resolve_with_options(&name!("d.b."), A, max_stale(60)).await?;
+        assert_eq!(stale, [Record::from_rdata(name!("d.b."), 30, RData::A("10.0.0.44".parse()?))]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_expired_not_kept() -> Result<()> {
+        // without serve_stale or an outage window, nothing answers with expired records
+        let resolver = RecursiveResolver::with_backend(FakeBackend::new(), Vec::new());
+        let query = Query { to_resolve: name!("d.b."), record_type: A };
+        let expired = Record::from_rdata(name!("d.b."), 5, RData::A("10.0.0.44".parse()?));
+        let stored = Instant::now() - Duration::from_secs(10);
+        resolver.cache.store(query.clone(), vec![expired], stored);
+        assert!(resolver.cache.get_stale_answer(&query, Instant::now(), MAX_STALE).is_none());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_primed_without_glue() -> Result<()> {
+        // the priming response has no addresses for the root nameservers
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", ".", NS, answer!(ns!(".", "ns.root.")))?;
+        b.add("10.0.0.1", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+        resolver.prime().await?;
+
+        assert!(matches!(resolver.get_cached(&name!("a.b."), A), CacheResponse::None));
+        assert_eq!(resolver.resolve(&name!("a.b."), A).await?, [a!("a.b.", "10.0.0.42")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_outage() -> Result<()> {
+        // only up.b. has a response, the queries for d.b. fail as if the network was down
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "up.b.", A, answer!(a!("up.b.", "10.0.0.45")))?;
+        let resolver = RecursiveResolver::builder()
+            .backend(b)
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .outage_window(Duration::from_millis(50))
+            .build();
+        let query = Query { to_resolve: name!("d.b."), record_type: A };
+        let expired = Record::from_rdata(name!("d.b."), 5, RData::A("10.0.0.44".parse()?));
+        let stored = Instant::now() - Duration::from_secs(10);
+
+        // a single failure is not an outage
+        resolver.cache.store(query, vec![expired], stored);
+        assert!(resolver.resolve(&name!("d.b."), A).await.is_err());
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        // the expired records are answered for as long as the outage lasts
+        for _ in 0..2 {
+            let stale = resolver.resolve(&name!("d.b."), A).await?;
+            let expected = Record::from_rdata(name!("d.b."), 30, RData::A("10.0.0.44".parse()?));
+            assert_eq!(stale, [expected]);
+        }
+
+        // once an upstream responds, the expired records are no longer answered
+        assert_eq!(resolver.resolve(&name!("up.b."), A).await?, [a!("up.b.", "10.0.0.45")]);
+        assert!(resolver.resolve(&name!("d.b."), A).await.is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_glueless_parallelism() -> Result<()> {
+        let mut b = FakeBackend::new();
+        let mut referral = refer!(ns!("a.", "ns1.b."));
+        referral.add_name_server(ns!("a.", "ns2.b."));
+        referral.add_name_server(ns!("a.", "ns3.b."));
+        b.add("10.0.0.1", "www.a.", A, referral.clone())?;
+        for (i, ns) in ["ns1.b.", "ns2.b.", "ns3.b."].into_iter().enumerate() {
+            b.add("10.0.0.1", ns, A, answer!(a!(ns, format!("10.0.0.1{}", i + 1))))?;
+            let aaaa = rdata::AAAA(format!("2001:db8::1{}", i + 1).parse()?);
+            b.add(
+                "10.0.0.1",
+                ns,
+                AAAA,
+                answer!(Record::from_rdata(name!(ns), 300, RData::AAAA(aaaa))),
+            )?;
+        }
+        // only the third nameserver responds, the addresses of all are resolved up front
+        b.add("10.0.0.13", "www.a.", A, answer!(a!("www.a.", "10.0.0.42")))?;
+        let b = Arc::new(b);
+        let resolver = RecursiveResolverBuilder::default()
+            .backend(b.clone())
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .glueless_parallelism(3)
+            .build();
+
+        // the glue has the addresses of every nameserver, at the ttl of the NS records
+        let mut state = ResolutionState::new(&resolver);
+        let mut glue = Vec::new();
+        state.resolve_glueless(referral.name_servers(), &mut glue, 0).await;
+        glue.sort_by_key(|r| (r.name().clone(), r.record_type()));
+        let mut expected = Vec::new();
+        for (i, ns) in ["ns1.b.", "ns2.b.", "ns3.b."].into_iter().enumerate() {
+            let a = rdata::A(format!("10.0.0.1{}", i + 1).parse()?);
+            let aaaa = rdata::AAAA(format!("2001:db8::1{}", i + 1).parse()?);
+            expected.push(Record::from_rdata(name!(ns), 60, RData::A(a)));
+            expected.push(Record::from_rdata(name!(ns), 60, RData::AAAA(aaaa)));
+        }
+        assert_eq!(glue, expected);
+        assert_eq!(state.queries, 6);
+
+        // whichever nameserver is tried first, the one that answers is already known
+        for seed in 0..4 {
+            let resolver = RecursiveResolverBuilder::default()
+                .backend(b.clone())
+                .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+                .glueless_parallelism(3)
+                .seed(seed)
+                .build();
+            let result = resolver.resolve(&name!("www.a."), A).await?;
+            assert_eq!(result, [a!("www.a.", "10.0.0.42")]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_nameserver_name_is_cname() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.a.", A, refer!(ns!("a.", "ns.b.")))?;
+        // the name of the nameserver points to the host that has the address
+        let mut alias = answer!(cname!("ns.b.", "host.b."));
+        alias.add_answer(a!("host.b.", "10.0.0.2"));
+        b.add("10.0.0.1", "ns.b.", A, alias)?;
+        b.add("10.0.0.2", "www.a.", A, answer!(a!("www.a.", "10.0.0.42")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+        let result = resolver.resolve(&name!("www.a."), A).await?;
+        assert_eq!(result, [a!("www.a.", "10.0.0.42")]);
+
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.a.", A, refer!(ns!("a.", "ns.b.")))?;
+        b.add("10.0.0.1", "ns.b.", A, answer!(cname!("ns.b.", "host.b.")))?;
+        b.add("10.0.0.1", "host.b.", A, answer!(cname!("host.b.", "ns.b.")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+        let result = resolver.resolve(&name!("www.a."), A).await;
+        assert_eq!(
+            "Server failure: Broken DNS config, seen (Name(\"ns.b.\"), A) twice",
+            result.unwrap_err().to_string()
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_relative_name() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.example.com.", A, answer!(a!("www.example.com.", "10.0.0.42")))?;
+        let b = Arc::new(b);
+        let resolver =
+            RecursiveResolver::with_backend(b.clone(), vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        let relative = Name::from_str("www.example.com")?;
+        assert!(!relative.is_fqdn());
+        let result = resolver.resolve(&relative, A).await?;
+        assert_eq!(result, [a!("www.example.com.", "10.0.0.42")]);
+        // both forms share the cache entry
+        let result = resolver.resolve(&name!("www.example.com."), A).await?;
+        assert_eq!(result, [a!("www.example.com.", "10.0.0.42")]);
+        assert_eq!(b.query_count(), 1);
+        assert_eq!(resolver.cache.len(), 1);
+        assert!(matches!(resolver.get_cached(&relative, A), CacheResponse::Authoritative(_)));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_local_ptr_records() -> Result<()> {
+        let mut b = FakeBackend::new();
+        let external = Record::from_rdata(
+            name!("1.2.0.192.in-addr.arpa."),
+            60,
+            RData::PTR(rdata::PTR(name!("www.example.com."))),
+        );
+        b.add("10.0.0.1", "1.2.0.192.in-addr.arpa.", PTR, answer!(external.clone()))?;
+        let b = Arc::new(b);
+        let resolver = RecursiveResolverBuilder::default()
+            .backend(b.clone())
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .local_records(parse_hosts("10.0.0.7 printer.lab\n")?)
+            .build();
+
+        let result = resolver.resolve(&name!("7.0.0.10.in-addr.arpa."), PTR).await?;
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].data(), Some(&RData::PTR(rdata::PTR(name!("printer.lab.")))));
+        assert_eq!(b.query_count(), 0);
+
+        // the other types of the name are local too
+        let result = resolver.resolve(&name!("7.0.0.10.in-addr.arpa."), A).await?;
+        assert!(result.is_empty());
+        assert_eq!(b.query_count(), 0);
+
+        let result = resolver.resolve(&name!("1.2.0.192.in-addr.arpa."), PTR).await?;
+        assert_eq!(result, [external]);
+        assert_eq!(b.query_count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_special_use_names() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "www.b.", A, answer!(cname!("www.b.", "app.localhost.")))?;
+        b.add("10.0.0.1", "www.c.", A, refer!(ns!("c.", "ns.invalid.")))?;
+        let b = Arc::new(b);
+        let resolver = RecursiveResolver::builder()
+            .backend(b.clone())
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .build();
+
+        let result = resolver.resolve(&name!("localhost."), A).await?;
+        assert_eq!(result, [a!("localhost.", "127.0.0.1")]);
+        let result = resolver.resolve(&name!("app.localhost"), AAAA).await?;
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].data(), Some(&RData::AAAA(rdata::AAAA(Ipv6Addr::LOCALHOST))));
+        assert!(resolver.resolve(&name!("localhost."), MX).await?.is_empty());
+        let result = resolver.resolve(&name!("something.invalid."), A).await;
+        assert!(matches!(result, Err(ResolutionError::NxDomain)));
+        assert_eq!(b.query_count(), 0);
+
+        let soa = resolver.synthetic_authority(&name!("something.invalid."));
+        assert_eq!(soa.map(|r| r.name().clone()), Some(name!("invalid.")));
+
+        // the names that resolution leads to are answered the same way
+        let result = resolver.resolve(&name!("www.b."), A).await?;
+        assert_eq!(result, [cname!("www.b.", "app.localhost."), a!("app.localhost.", "127.0.0.1")]);
+        assert_eq!(b.query_count(), 1);
+        assert!(resolver.resolve(&name!("www.c."), A).await.is_err());
+        assert_eq!(b.query_count(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_local_use_policy() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "nas.home.arpa.", A, answer!(a!("nas.home.arpa.", "10.0.0.9")))?;
+        b.add("10.0.0.2", "tv.home.arpa.", A, answer!(a!("tv.home.arpa.", "10.0.0.10")))?;
+        let b = Arc::new(b);
+        let roots = vec![IpAddr::V4("10.0.0.1".parse()?)];
+        let resolver = RecursiveResolver::builder().backend(b.clone()).roots(roots.clone()).build();
+        let result = resolver.resolve(&name!("nas.home.arpa."), A).await;
+        assert!(matches!(result, Err(ResolutionError::NxDomain)));
+        let soa = resolver.synthetic_authority(&name!("nas.home.arpa."));
+        assert_eq!(soa.map(|r| r.name().clone()), Some(name!("home.arpa.")));
+        assert_eq!(b.query_count(), 0);
+
+        let resolver = RecursiveResolver::builder()
+            .backend(b.clone())
+            .roots(roots.clone())
+            .local_use_policy(LocalUsePolicy::Recurse)
+            .build();
+        let result = resolver.resolve(&name!("nas.home.arpa."), A).await?;
+        assert_eq!(result, [a!("nas.home.arpa.", "10.0.0.9")]);
+        assert_eq!(resolver.synthetic_authority(&name!("nas.home.arpa.")), None);
+        assert_eq!(b.query_count(), 1);
+
+        // a stub zone for the local names is used rather than answering NXDOMAIN
+        let stub = StubZone { zone: name!("home.arpa."), servers: vec!["10.0.0.2".parse()?] };
+        let resolver = RecursiveResolver::builder()
+            .backend(b.clone())
+            .roots(roots)
+            .stub_zones(vec![stub])
+            .build();
+        let result = resolver.resolve(&name!("tv.home.arpa."), A).await?;
+        assert_eq!(result, [a!("tv.home.arpa.", "10.0.0.10")]);
+        assert_eq!(resolver.synthetic_authority(&name!("tv.home.arpa.")), None);
+        assert_eq!(b.query_count(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_static_zone() -> Result<()> {
+        let zone = StaticZone::parse(
+            "$ORIGIN lab.\n@ 60 IN SOA ns.lab. hostmaster.lab. 1 3600 600 86400 60\n\
+             www 60 IN A 10.0.0.80\nalias 60 IN CNAME www.lab.\next 60 IN CNAME host.c.\n\
+             sub 60 IN NS ns.sub.lab.\nns.sub 60 IN A 10.0.0.54\n",
+            None,
+        )?;
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "host.c.", A, answer!(a!("host.c.", "10.0.0.42")))?;
+        b.add("10.0.0.54", "www.sub.lab.", A, answer!(a!("www.sub.lab.", "10.0.0.55")))?;
+        let b = Arc::new(b);
+        let resolver = RecursiveResolver::builder()
+            .backend(b.clone())
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .static_zones(vec![zone])
+            .build();
+
+        // the aliases are followed, within the zone and out of it
+        let result = resolver.resolve(&name!("alias.lab."), A).await?;
+        assert_eq!(result, [cname!("alias.lab.", "www.lab."), a!("www.lab.", "10.0.0.80")]);
+        let result = resolver.resolve(&name!("alias.lab."), CNAME).await?;
+        assert_eq!(result, [cname!("alias.lab.", "www.lab.")]);
+        assert_eq!(b.query_count(), 0);
+        let result = resolver.resolve(&name!("ext.lab."), A).await?;
+        assert_eq!(result, [cname!("ext.lab.", "host.c."), a!("host.c.", "10.0.0.42")]);
+        assert_eq!(b.query_count(), 1);
+
+        // the names below the zone cut start at the nameservers of the delegation
+        let result = resolver.resolve(&name!("www.sub.lab."), A).await?;
+        assert_eq!(result, [a!("www.sub.lab.", "10.0.0.55")]);
+        assert_eq!(b.query_count(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_default() {
+        let resolver = RecursiveResolver::default();
+        assert_eq!(resolver.roots, ROOT_SERVERS);
+    }
+
+    #[tokio::test]
+    async fn test_default_builder() -> Result<()> {
+        let mut b = FakeBackend::new();
+        b.add("192.36.148.17", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+        let resolver = RecursiveResolverBuilder::default().backend(b).build();
+        let result = resolver.resolve(&"a.b.".parse()?, A).await?;
+        assert_eq!(result, [a!("a.b.", "10.0.0.42")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retry_servfail_response() -> Result<()> {
+        // different seeds try the servers in different orders
+        for seed in 0..4 {
+            let mut servfail = Message::new();
+            servfail.set_response_code(ResponseCode::ServFail);
+            let mut b = FakeBackend::new();
+            b.add("10.0.0.1", "a.b.", A, servfail)?;
+            b.add("10.0.0.2", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+            let resolver = RecursiveResolver::builder()
+                .backend(b)
+                .roots(vec![IpAddr::V4("10.0.0.1".parse()?), IpAddr::V4("10.0.0.2".parse()?)])
+                .seed(seed)
+                .build();
+            let result = resolver.resolve(&"a.b.".parse()?, A).await?;
+            assert_eq!(result, [a!("a.b.", "10.0.0.42")]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_retry_refused_response() -> Result<()> {
+        for seed in 0..4 {
+            let mut refused = Message::new();
+            refused.set_response_code(ResponseCode::Refused);
+            let mut b = FakeBackend::new();
+            b.add("10.0.0.1", "a.b.", A, refused)?;
+            b.add("10.0.0.2", "a.b.", A, answer!(a!("a.b.", "10.0.0.42")))?;
+            let resolver = RecursiveResolver::builder()
+                .backend(b)
+                .roots(vec![IpAddr::V4("10.0.0.1".parse()?), IpAddr::V4("10.0.0.2".parse()?)])
+                .seed(seed)
+                .build();
+            let result = resolver.resolve(&"a.b.".parse()?, A).await?;
+            assert_eq!(result, [a!("a.b.", "10.0.0.42")]);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_upstream_cooldown() -> Result<()> {
+        tokio::time::pause();
+        // there are no responses configured, so every query gets SERVFAIL
+        let b = Arc::new(FakeBackend::new());
+        let resolver = RecursiveResolver::builder()
+            .backend(b.clone())
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .upstream_cooldown(Duration::from_millis(100))
+            .build();
+
+        for _ in 0..3 {
+            let result = resolver.resolve(&name!("a.b."), A).await;
+            assert!(matches!(result, Err(ResolutionError::ServFail(_))));
+        }
+        let result = resolver.resolve(&name!("a.b."), A).await;
+        let retry_after = Duration::from_millis(100);
+        assert!(
+            matches!(result, Err(ResolutionError::Overloaded { retry_after: r, .. }) if r == retry_after)
+        );
+        assert_eq!(b.query_count(), 3);
+
+        // still cooling down just before the end, and retried after it
+        tokio::time::advance(Duration::from_millis(99)).await;
+        let result = resolver.resolve(&name!("a.b."), A).await;
+        assert!(matches!(result, Err(ResolutionError::Overloaded { .. })));
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert!(matches!(
+            resolver.resolve(&name!("a.b."), A).await,
+            Err(ResolutionError::ServFail(_))
+        ));
+        assert_eq!(b.query_count(), 4);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stale_glue() -> Result<()> {
+        // the glue for ns.c. in the referral to b. is an old address that no longer responds
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, refer!(ns!("b.", "ns.c."), a!("ns.c.", "10.0.0.2")))?;
+        b.add("10.0.0.1", "ns.c.", A, refer!(ns!("c.", "ns.c."), a!("ns.c.", "10.0.0.3")))?;
+        b.add("10.0.0.3", "ns.c.", A, answer!(a!("ns.c.", "10.0.0.3")))?;
+        b.add("10.0.0.3", "a.b.", A, answer!(a!("a.b.", "10.0.0.10")))?;
+        let resolver = RecursiveResolver::builder()
+            .backend(b)
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .caching(false)
+            .stale_glue_timeout(Duration::from_secs(60))
+            .build();
+
+        for _ in 0..3 {
+            assert!(resolver.resolve(&name!("a.b."), A).await.is_err());
+        }
+        // the glue has failed too many times, so the address of ns.c. is resolved instead
+        let result = resolver.resolve(&name!("a.b."), A).await?;
+        assert_eq!(result, vec![a!("a.b.", "10.0.0.10")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stale_glue_cached() -> Result<()> {
+        // the glue for ns.c. that gets cached is an old address that no longer responds
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "x.c.", A, refer!(ns!("c.", "ns.c."), a!("ns.c.", "10.0.0.2")))?;
+        b.add("10.0.0.1", "a.b.", A, refer!(ns!("b.", "ns.c.")))?;
+        b.add("10.0.0.1", "ns.c.", A, refer!(ns!("c.", "ns.c."), a!("ns.c.", "10.0.0.3")))?;
+        b.add("10.0.0.3", "ns.c.", A, answer!(a!("ns.c.", "10.0.0.3")))?;
+        b.add("10.0.0.3", "a.b.", A, answer!(a!("a.b.", "10.0.0.10")))?;
+        let resolver = RecursiveResolver::builder()
+            .backend(b)
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .stale_glue_timeout(Duration::from_secs(60))
+            .build();
+
+        for _ in 0..3 {
+            assert!(resolver.resolve(&name!("x.c."), A).await.is_err());
+        }
+        // the address of ns.c. in the cache is the glue, so it is resolved again
+        let result = resolver.resolve(&name!("a.b."), A).await?;
+        assert_eq!(result, vec![a!("a.b.", "10.0.0.10")]);
+
+        // ns.c. of e. resolves to an address that no longer responds
+        let mut referral = refer!(ns!("e.", "ns.c."));
+        referral.add_name_server(ns!("e.", "ns.d."));
+        referral.add_additional(a!("ns.d.", "10.0.0.4"));
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "x.f.", A, refer!(ns!("f.", "ns.f."), a!("ns.f.", "10.0.0.2")))?;
+        b.add("10.0.0.1", "a.e.", A, referral)?;
+        b.add("10.0.0.1", "ns.c.", A, answer!(a!("ns.c.", "10.0.0.2")))?;
+        b.add("10.0.0.4", "a.e.", A, answer!(a!("a.e.", "10.0.0.10")))?;
+        let b = Arc::new(b);
+        let resolver = RecursiveResolver::builder()
+            .backend(b.clone())
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .caching(false)
+            .shuffle_nameservers(false)
+            .stale_glue_timeout(Duration::from_secs(60))
+            .build();
+
+        for _ in 0..3 {
+            assert!(resolver.resolve(&name!("x.f."), A).await.is_err());
+        }
+        assert_eq!(b.query_count(), 6);
+        // the address isn't glue, so ns.d. is asked without querying ns.c. at all
+        let result = resolver.resolve(&name!("a.e."), A).await?;
+        assert_eq!(result, vec![a!("a.e.", "10.0.0.10")]);
+        assert_eq!(b.query_count(), 9);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_stale_nameserver_last() -> Result<()> {
+        // the only nameserver of b. doesn't answer for x.b., but does for a.b.
+        let mut b = FakeBackend::new();
+        for name in ["x.b.", "a.b."] {
+            b.add("10.0.0.1", name, A, refer!(ns!("b.", "ns.c.")))?;
+        }
+        b.add("10.0.0.1", "ns.c.", A, answer!(a!("ns.c.", "10.0.0.2")))?;
+        b.add("10.0.0.2", "a.b.", A, answer!(a!("a.b.", "10.0.0.10")))?;
+        let resolver = RecursiveResolver::builder()
+            .backend(b)
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .caching(false)
+            .stale_glue_timeout(Duration::from_secs(60))
+            .build();
+
+        for _ in 0..3 {
+            assert!(resolver.resolve(&name!("x.b."), A).await.is_err());
+        }
+        // the address keeps failing, but is still tried as there is no other
+        let result = resolver.resolve(&name!("a.b."), A).await?;
+        assert_eq!(result, vec![a!("a.b.", "10.0.0.10")]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unresolvable_nameserver() -> Result<()> {
+        // ns.c. doesn't exist, so only ns.d. of the two nameservers of b. can answer
+        let mut nxdomain = nodata("c.")?;
+        nxdomain.set_response_code(ResponseCode::NXDomain);
+        let mut referral = refer!(ns!("b.", "ns.c."));
+        referral.add_name_server(ns!("b.", "ns.d."));
+        for seed in 0..4 {
+            let mut b = FakeBackend::new();
+            b.add("10.0.0.1", "a.b.", A, referral.clone())?;
+            b.add("10.0.0.1", "ns.c.", A, nxdomain.clone())?;
+            b.add("10.0.0.1", "ns.d.", A, answer!(a!("ns.d.", "10.0.0.2")))?;
+            b.add("10.0.0.2", "a.b.", A, answer!(a!("a.b.", "10.0.0.10")))?;
+            let resolver = RecursiveResolver::builder()
+                .backend(b)
+                .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+                .seed(seed)
+                .build();
+            let result = resolver.resolve(&name!("a.b."), A).await?;
+            assert_eq!(result, vec![a!("a.b.", "10.0.0.10")]);
+        }
+
+        // when no nameserver can be resolved, the name to resolve isn't the one missing
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, refer!(ns!("b.", "ns.c.")))?;
+        b.add("10.0.0.1", "ns.c.", A, nxdomain)?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+        let result = resolver.resolve(&name!("a.b."), A).await;
+        assert!(matches!(result, Err(ResolutionError::ServFail(_))), "{result:?}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_nameservers() -> Result<()> {
+        // none of the hundred nameservers of b. respond
+        let mut referral = Message::new();
+        for i in 0..100 {
+            let ns_name = format!("ns{i}.b.");
+            referral.add_name_server(ns!("b.", ns_name));
+            referral.add_additional(a!(ns_name, format!("10.0.1.{i}")));
+        }
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.b.", A, referral)?;
+        let b = Arc::new(b);
+        let resolver = RecursiveResolver::builder()
+            .backend(b.clone())
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .min_nameservers(100)
+            .build();
+
+        assert!(resolver.resolve(&name!("a.b."), A).await.is_err());
+        // the referral from the root, and a query to each of the nameservers tried
+        assert_eq!(b.query_count(), 1 + DEFAULT_MAX_NAMESERVERS);
+
+        // none of the hundred glueless nameservers of c. can be resolved
+        let mut referral = Message::new();
+        for i in 0..100 {
+            referral.add_name_server(ns!("c.", format!("ns{i}.d.")));
+        }
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", "a.c.", A, referral)?;
+        let b = Arc::new(b);
+        let resolver = RecursiveResolver::builder()
+            .backend(b.clone())
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .min_nameservers(100)
+            .glueless_parallelism(100)
+            .max_queries(1000)
+            .build();
+
+        assert!(resolver.resolve(&name!("a.c."), A).await.is_err());
+        // only the nameservers that may be tried are resolved, for both A and AAAA up front
+        // and once more for A when tried
+        assert!(b.query_count() <= 1 + 3 * DEFAULT_MAX_NAMESERVERS);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_max_queries() -> Result<()> {
+        // every nameserver of b. is glueless and fails, each needing two queries
+        let mut referral = refer!(ns!("b.", "ns0.c."));
+        let mut b = FakeBackend::new();
+        for i in 0..10 {
+            let ns_name = format!("ns{i}.c.");
+            referral.add_name_server(ns!("b.", ns_name));
+            b.add("10.0.0.1", &ns_name, A, answer!(a!(ns_name, format!("10.0.1.{i}"))))?;
+        }
+        b.add("10.0.0.1", "a.b.", A, referral)?;
+        let resolver = RecursiveResolver::builder()
+            .backend(b)
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .min_nameservers(10)
+            .max_queries(8)
+            .build();
+
+        let result = resolver.resolve(&"a.b.".parse()?, A).await;
+        assert!(matches!(result, Err(ResolutionError::TooManyQueries(8))), "{result:?}");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_glueless_sibling_tld_delegation() -> Result<()> {
+        let mut b = FakeBackend::new();
+        // both com. and org. are served by a.gtld.net. and neither delegation has glue
+        b.add("10.0.0.1", "www.example.com.", A, refer!(ns!("com.", "a.gtld.net.")))?;
+        b.add("10.0.0.1", "ns.example.org.", A, refer!(ns!("org.", "a.gtld.net.")))?;
+        b.add(
+            "10.0.0.1",
+            "a.gtld.net.",
+            A,
+            refer!(ns!("net.", "a.gtld.net."), a!("a.gtld.net.", "10.0.0.2")),
+        )?;
+        b.add("10.0.0.2", "a.gtld.net.", A, answer!(a!("a.gtld.net.", "10.0.0.2")))?;
+        b.add("10.0.0.2", "www.example.com.", A, refer!(ns!("example.com.", "ns.example.org.")))?;
+        b.add("10.0.0.2", "ns.example.org.", A, answer!(a!("ns.example.org.", "10.0.0.3")))?;
+        b.add("10.0.0.3", "www.example.com.", A, answer!(a!("www.example.com.", "10.0.0.42")))?;
+        let resolver = RecursiveResolver::with_backend(b, vec![IpAddr::V4("10.0.0.1".parse()?)]);
+
+        // a.gtld.net. is resolved once for com. and is needed again, from the cache, for org.
+        let result = resolver.resolve(&"www.example.com.".parse()?, A).await?;
+        assert_eq!(result, [a!("www.example.com.", "10.0.0.42")]);
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_cross_referencing_domains() -> Result<()> {
         let mut b = FakeBackend::new();
@@ -0,0 +1,103 @@
+use hickory_proto::op::{Message, Query};
+use lru::LruCache;
+use std::net::SocketAddr;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::sync::watch;
+
+/// At most this many queries are tracked, the oldest are forgotten first. The sources of
+/// UDP queries can be spoofed, so this bounds what a flood of them can make the state hold.
+const MAX_TRACKED: usize = 10_000;
+
+/// UDP clients retransmit a query they haven't had a response to in time. A retransmit
+/// that arrives within the window of the original query, while it is still being
+/// resolved or shortly after, is answered with the response to the original instead of
+/// being resolved once more.
+#[derive(Debug)]
+pub struct Retransmits {
+    window: Duration,
+    /// In the order the queries arrived, which is also the order their windows end
+    state: Mutex<LruCache<Key, InFlight>>,
+}
+
+/// A retransmit comes from the same source with the same id and question
+#[derive(Debug, Hash, PartialEq, Eq)]
+struct Key {
+    peer: SocketAddr,
+    id: u16,
+    queries: Vec<Query>,
+}
+
+#[derive(Debug)]
+struct InFlight {
+    start: Instant,
+    response: watch::Receiver<Option<Message>>,
+}
+
+/// How a query is to be answered
+pub enum Arrival {
+    /// Resolve the query, and send the response for the retransmits of it to use
+    First(watch::Sender<Option<Message>>),
+    /// Answer with the response to the original query, once there is one
+    Retransmit(watch::Receiver<Option<Message>>),
+}
+
+impl Retransmits {
+    pub fn new(window: Duration) -> Self {
+        let capacity = NonZeroUsize::new(MAX_TRACKED).unwrap();
+        Retransmits { window, state: Mutex::new(LruCache::new(capacity)) }
+    }
+
+    /// Tracks the query from `peer`, and returns if it is a retransmit of an earlier one
+    pub fn check(&self, peer: SocketAddr, message: &Message, now: Instant) -> Arrival {
+        let key = Key { peer, id: message.id(), queries: message.queries().to_vec() };
+        let mut state = self.state.lock().unwrap();
+        while state.peek_lru().is_some_and(|(_, f)| now.duration_since(f.start) >= self.window) {
+            state.pop_lru();
+        }
+        // peeked, to keep the entries in the order their windows end
+        if let Some(in_flight) = state.peek(&key) {
+            return Arrival::Retransmit(in_flight.response.clone());
+        }
+        let (sender, response) = watch::channel(None);
+        state.push(key, InFlight { start: now, response });
+        Arrival::First(sender)
+    }
+}
+
+/// Waits for the response to the original query. Returns None if it was never sent, such
+/// as when resolving it failed.
+pub async fn original_response(mut response: watch::Receiver<Option<Message>>) -> Option<Message> {
+    let response = response.wait_for(Option::is_some).await.ok()?;
+    response.clone()
+}
+
+#[cfg(test)]
+mod test {
+    use crate::retransmit::{Arrival, Retransmits, MAX_TRACKED};
+    use hickory_proto::op::{Message, Query};
+    use hickory_proto::rr::{Name, RecordType};
+    use std::net::SocketAddr;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_tracked_queries() {
+        let retransmits = Retransmits::new(Duration::from_secs(10));
+        let mut message = Message::new();
+        message.add_query(Query::query(Name::from_ascii("a.b.").unwrap(), RecordType::A));
+        let now = Instant::now();
+        for port in 0..MAX_TRACKED + 10 {
+            let peer = SocketAddr::from(([192, 0, 2, 1], port as u16));
+            retransmits.check(peer, &message, now);
+        }
+        assert_eq!(retransmits.state.lock().unwrap().len(), MAX_TRACKED);
+
+        // the queries are forgotten once their window has passed
+        let peer = SocketAddr::from(([192, 0, 2, 2], 53));
+        let later = now + Duration::from_secs(10);
+        assert!(matches!(retransmits.check(peer, &message, later), Arrival::First(_)));
+        assert_eq!(retransmits.state.lock().unwrap().len(), 1);
+        assert!(matches!(retransmits.check(peer, &message, later), Arrival::Retransmit(_)));
+    }
+}
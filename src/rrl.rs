@@ -0,0 +1,136 @@
+use hickory_proto::op::{Query, ResponseCode};
+use hickory_proto::rr::{Name, RecordType};
+use lru::LruCache;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// The length of the prefixes that clients are grouped by, a single client can often
+/// use any address within them
+const IPV4_PREFIX: u32 = 24;
+const IPV6_PREFIX: u32 = 56;
+
+/// Every this many responses over the limit is truncated instead of dropped, which lets a
+/// legitimate client behind a spoofed prefix retry over TCP
+const SLIP: u32 = 2;
+
+/// Limits are kept per second
+const WINDOW: Duration = Duration::from_secs(1);
+
+/// At most this many responses are tracked, the least recently sent are forgotten first.
+/// A flood of spoofed queries for distinct names can't make the state grow beyond it.
+const MAX_TRACKED: usize = 100_000;
+
+/// What to do with a response
+#[derive(Debug, PartialEq)]
+pub enum Action {
+    Send,
+    /// Send a truncated response, making the client retry over TCP
+    Truncate,
+    Drop,
+}
+
+/// Response rate limiting, limits the rate of identical responses sent to the clients of
+/// a prefix. Without it, spoofed queries can use the daemon to amplify traffic aimed at
+/// the owner of the spoofed address. This differs from limiting the queries of a client
+/// in that a client asking for many different names is not limited.
+#[derive(Debug)]
+pub struct ResponseRateLimiter {
+    responses_per_second: u32,
+    state: Mutex<LruCache<Key, Window>>,
+}
+
+/// Responses are considered identical if they answer the same question with the same code
+#[derive(Debug, Hash, PartialEq, Eq)]
+struct Key {
+    prefix: IpAddr,
+    name: Name,
+    record_type: RecordType,
+    response_code: ResponseCode,
+}
+
+#[derive(Debug)]
+struct Window {
+    start: Instant,
+    responses: u32,
+}
+
+impl ResponseRateLimiter {
+    pub fn new(responses_per_second: u32) -> Self {
+        let capacity = NonZeroUsize::new(MAX_TRACKED).unwrap();
+        ResponseRateLimiter { responses_per_second, state: Mutex::new(LruCache::new(capacity)) }
+    }
+
+    /// Counts the response to the query from `client`, and returns what to do with it
+    pub fn check(
+        &self,
+        client: IpAddr,
+        query: &Query,
+        response_code: ResponseCode,
+        now: Instant,
+    ) -> Action {
+        let key = Key {
+            prefix: prefix(client),
+            name: query.name().to_lowercase(),
+            record_type: query.query_type(),
+            response_code,
+        };
+        let mut state = self.state.lock().unwrap();
+        // the responses from earlier windows are forgotten, starting with the least recent
+        while state.peek_lru().is_some_and(|(_, w)| now.duration_since(w.start) >= WINDOW) {
+            state.pop_lru();
+        }
+        let window = state.get_or_insert_mut(key, || Window { start: now, responses: 0 });
+        if now.duration_since(window.start) >= WINDOW {
+            *window = Window { start: now, responses: 0 };
+        }
+        window.responses += 1;
+        match window.responses.checked_sub(self.responses_per_second) {
+            None | Some(0) => Action::Send,
+            Some(excess) if excess % SLIP == 0 => Action::Truncate,
+            Some(_) => Action::Drop,
+        }
+    }
+}
+
+/// Returns the prefix of the address that clients are grouped by
+fn prefix(address: IpAddr) -> IpAddr {
+    match address {
+        IpAddr::V4(v4) => Ipv4Addr::from(u32::from(v4) & (u32::MAX << (32 - IPV4_PREFIX))).into(),
+        IpAddr::V6(v6) => {
+            Ipv6Addr::from(u128::from(v6) & (u128::MAX << (128 - IPV6_PREFIX))).into()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::rrl::{prefix, ResponseRateLimiter, MAX_TRACKED};
+    use hickory_proto::op::{Query, ResponseCode};
+    use hickory_proto::rr::{Name, RecordType};
+    use std::net::IpAddr;
+    use std::time::Instant;
+
+    #[test]
+    fn test_prefix() {
+        let v4: IpAddr = "192.0.2.17".parse().unwrap();
+        assert_eq!(prefix(v4), "192.0.2.0".parse::<IpAddr>().unwrap());
+        let v6: IpAddr = "2001:db8:1:2ff:1::1".parse().unwrap();
+        assert_eq!(prefix(v6), "2001:db8:1:200::".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn test_max_tracked() {
+        let limiter = ResponseRateLimiter::new(10);
+        let client: IpAddr = "192.0.2.17".parse().unwrap();
+        let now = Instant::now();
+        // every spoofed query is for a distinct name, all within the same window
+        for i in 0..MAX_TRACKED + 10 {
+            let name = Name::from_ascii(format!("{i}.example.")).unwrap();
+            let query = Query::query(name, RecordType::A);
+            limiter.check(client, &query, ResponseCode::NoError, now);
+        }
+        assert_eq!(limiter.state.lock().unwrap().len(), MAX_TRACKED);
+    }
+}
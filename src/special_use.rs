@@ -0,0 +1,76 @@
+use crate::zone::ZoneAnswer;
+use anyhow::bail;
+use hickory_proto::rr::rdata::{A, AAAA};
+use hickory_proto::rr::{Name, RData, Record, RecordType};
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::str::FromStr;
+
+/// The ttl of the loopback addresses answered for localhost
+const LOCALHOST_TTL: u32 = 300;
+
+/// How the names of the domains that are only meaningful within a local network are handled,
+/// such as home.arpa. of RFC 8375 and local. of RFC 6762. The local records and the static
+/// zones answer for them either way, and the stub zones for them are resolved.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum LocalUsePolicy {
+    /// Answer the names without records with NXDOMAIN, rather than leaking them to the roots
+    #[default]
+    NxDomain,
+    /// Resolve them like any other name, for networks with a stub zone for them
+    Recurse,
+}
+
+impl FromStr for LocalUsePolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nxdomain" => Ok(LocalUsePolicy::NxDomain),
+            "recurse" => Ok(LocalUsePolicy::Recurse),
+            _ => bail!("expected 'nxdomain' or 'recurse', got '{s}'"),
+        }
+    }
+}
+
+/// Returns the apex of the special-use domain of RFC 6761 section 6 that the name is within,
+/// for the domains that a resolver answers for without recursing
+pub fn special_use_apex(name: &Name, policy: LocalUsePolicy) -> Option<Name> {
+    let mut apexes = vec![localhost(), invalid()];
+    if policy == LocalUsePolicy::NxDomain {
+        apexes.extend(local_use());
+    }
+    apexes.into_iter().find(|apex| apex.zone_of(name))
+}
+
+fn localhost() -> Name {
+    Name::from_ascii("localhost.").unwrap()
+}
+
+fn invalid() -> Name {
+    Name::from_ascii("invalid.").unwrap()
+}
+
+fn local_use() -> [Name; 2] {
+    ["home.arpa.", "local."].map(|apex| Name::from_ascii(apex).unwrap())
+}
+
+/// Answers the queries for the special-use names, RFC 6761 section 6. Names within
+/// localhost. are the loopback addresses, section 6.3, and names within invalid. don't
+/// exist, section 6.4. The local-use domains are handled by the policy. Returns None for
+/// the names that are resolved as usual.
+pub fn lookup(name: &Name, record_type: RecordType, policy: LocalUsePolicy) -> Option<ZoneAnswer> {
+    let local_use =
+        policy == LocalUsePolicy::NxDomain && local_use().iter().any(|a| a.zone_of(name));
+    if local_use || invalid().zone_of(name) {
+        return Some(ZoneAnswer::NxDomain);
+    }
+    if !localhost().zone_of(name) {
+        return None;
+    }
+    let data = match record_type {
+        RecordType::A => RData::A(A(Ipv4Addr::LOCALHOST)),
+        RecordType::AAAA => RData::AAAA(AAAA(Ipv6Addr::LOCALHOST)),
+        _ => return Some(ZoneAnswer::NoData),
+    };
+    Some(ZoneAnswer::Records(vec![Record::from_rdata(name.clone(), LOCALHOST_TTL, data)]))
+}
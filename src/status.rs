@@ -0,0 +1,57 @@
+use crate::resolver::RecursiveResolver;
+use crate::zone::ZoneAnswer;
+use hickory_proto::rr::rdata::TXT;
+use hickory_proto::rr::{Name, RData, Record, RecordType};
+use std::time::Instant;
+
+/// A pseudo-zone with TXT records that describe the running daemon, answered locally
+/// like a static zone but with values that are looked up for every query:
+/// - version.<zone> the version of the resolver
+/// - uptime.<zone> the number of seconds since the zone was created
+/// - cache-size.<zone> the number of entries in the cache
+#[derive(Debug)]
+pub struct StatusZone {
+    apex: Name,
+    started: Instant,
+}
+
+impl StatusZone {
+    pub fn new(mut apex: Name) -> Self {
+        apex.set_fqdn(true);
+        StatusZone { apex, started: Instant::now() }
+    }
+
+    /// Answers the query if the name is within the zone. The values change all the time,
+    /// so the records have a zero ttl to keep them from being cached.
+    pub fn lookup(
+        &self,
+        name: &Name,
+        record_type: RecordType,
+        resolver: &RecursiveResolver,
+    ) -> Option<ZoneAnswer> {
+        if !self.apex.zone_of(name) {
+            return None;
+        }
+        if *name == self.apex {
+            return Some(ZoneAnswer::NoData);
+        }
+        let label = match name.num_labels() == self.apex.num_labels() + 1 {
+            true => name.iter().next().map(|l| String::from_utf8_lossy(l).to_lowercase()),
+            false => None,
+        };
+        let value = match label.as_deref() {
+            Some("version") => env!("CARGO_PKG_VERSION").to_string(),
+            Some("uptime") => self.started.elapsed().as_secs().to_string(),
+            Some("cache-size") => resolver.cache_len().to_string(),
+            _ => return Some(ZoneAnswer::NxDomain),
+        };
+        let answer = match record_type {
+            RecordType::TXT => {
+                let txt = RData::TXT(TXT::new(vec![value]));
+                ZoneAnswer::Records(vec![Record::from_rdata(name.clone(), 0, txt)])
+            }
+            _ => ZoneAnswer::NoData,
+        };
+        Some(answer)
+    }
+}
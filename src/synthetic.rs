@@ -0,0 +1,88 @@
+use anyhow::bail;
+use hickory_proto::rr::rdata::SOA;
+use hickory_proto::rr::{Name, RData, Record};
+use std::str::FromStr;
+
+/// The SOA that the authority section of locally generated negative answers holds, such
+/// as those from a hosts file, which need one to be cached by the clients, RFC 2308
+/// section 5. The zone of the reverse names is in-addr.arpa. or ip6.arpa., while every
+/// other name is the apex of a zone of its own.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SyntheticSoa(pub SOA);
+
+impl Default for SyntheticSoa {
+    fn default() -> Self {
+        let mname = Name::from_ascii("localhost.").unwrap();
+        let rname = Name::from_ascii("nobody.invalid.").unwrap();
+        SyntheticSoa(SOA::new(mname, rname, 1, 3600, 600, 86400, 300))
+    }
+}
+
+impl SyntheticSoa {
+    /// Returns the SOA record of the zone, with the minimum field as its ttl
+    pub fn record(&self, zone: &Name) -> Record {
+        Record::from_rdata(zone.clone(), self.0.minimum(), RData::SOA(self.0.clone()))
+    }
+
+    /// Returns the SOA record for a locally generated name, at the apex of its zone
+    pub fn record_for(&self, name: &Name) -> Record {
+        let reverse = ["in-addr.arpa.", "ip6.arpa."].map(|zone| Name::from_ascii(zone).unwrap());
+        match reverse.into_iter().find(|zone| zone.zone_of(name)) {
+            Some(zone) => self.record(&zone),
+            None => self.record(name),
+        }
+    }
+}
+
+/// Parses '<mname> <rname> <serial> <refresh> <retry> <expire> <minimum>', the fields of
+/// the SOA in the order of its presentation format
+impl FromStr for SyntheticSoa {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [mname, rname, serial, refresh, retry, expire, minimum] = fields[..] else {
+            bail!("expected '<mname> <rname> <serial> <refresh> <retry> <expire> <minimum>', got '{s}'");
+        };
+        let mut mname = Name::from_str(mname)?;
+        mname.set_fqdn(true);
+        let mut rname = Name::from_str(rname)?;
+        rname.set_fqdn(true);
+        Ok(SyntheticSoa(SOA::new(
+            mname,
+            rname,
+            serial.parse()?,
+            refresh.parse()?,
+            retry.parse()?,
+            expire.parse()?,
+            minimum.parse()?,
+        )))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::name;
+    use crate::synthetic::SyntheticSoa;
+    use anyhow::Result;
+    use hickory_proto::rr::{Name, RData};
+    use std::str::FromStr;
+
+    #[test]
+    fn test_parse_synthetic_soa() -> Result<()> {
+        let soa: SyntheticSoa = "ns.lab hostmaster.lab. 7 3600 600 86400 60".parse()?;
+        assert_eq!(soa.0.mname(), &name!("ns.lab."));
+        assert_eq!(soa.0.rname(), &name!("hostmaster.lab."));
+        assert_eq!(soa.0.serial(), 7);
+        let record = soa.record(&name!("1.0.0.10.in-addr.arpa."));
+        assert_eq!(record.ttl(), 60);
+        assert!(matches!(record.data(), Some(RData::SOA(_))));
+        let record = soa.record_for(&name!("1.0.0.10.in-addr.arpa."));
+        assert_eq!(record.name(), &name!("in-addr.arpa."));
+        assert_eq!(soa.record_for(&name!("printer.lab.")).name(), &name!("printer.lab."));
+
+        assert!("ns.lab hostmaster.lab 7 3600 600 86400".parse::<SyntheticSoa>().is_err());
+        assert!("ns.lab hostmaster.lab 7 3600 600 86400 x".parse::<SyntheticSoa>().is_err());
+        Ok(())
+    }
+}
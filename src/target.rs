@@ -5,7 +5,7 @@ use crate::resolver::ResolutionError::ServFail;
 use async_trait::async_trait;
 use hickory_proto::rr::{Name, RData, Record, RecordType};
 use rand::seq::SliceRandom;
-use rand::thread_rng;
+use rand::Rng;
 
 #[async_trait]
 pub trait TargetProvider {
@@ -23,11 +23,24 @@ pub(crate) struct RootsProvider<'a> {
 }
 
 impl<'a> RootsProvider<'a> {
-    pub(crate) fn new(roots: &'a [IpAddr]) -> Self {
+    pub(crate) fn new(roots: &'a [IpAddr], rng: &mut impl Rng) -> Self {
         let mut shuffled_pointers: Vec<&IpAddr> = roots.iter().collect();
-        shuffled_pointers.shuffle(&mut thread_rng());
+        shuffled_pointers.shuffle(rng);
         RootsProvider { shuffled_pointers }
     }
+
+    /// Provides the servers in the order given, instead of shuffled
+    pub(crate) fn in_order(roots: &'a [IpAddr]) -> Self {
+        // the servers are tried from the end
+        RootsProvider { shuffled_pointers: roots.iter().rev().collect() }
+    }
+
+    /// Tries the servers that have never responded after the others
+    pub(crate) fn unreachable_last(mut self, unreachable: impl Fn(IpAddr) -> bool) -> Self {
+        // the servers are tried from the end
+        self.shuffled_pointers.sort_by_key(|ip| !unreachable(**ip));
+        self
+    }
 }
 
 #[async_trait]
@@ -43,10 +56,17 @@ pub(crate) struct NsProvider {
 }
 
 impl NsProvider {
-    pub(crate) fn new(nameservers: Vec<Record>, glue: Vec<Record>) -> Self {
+    pub(crate) fn new(nameservers: Vec<Record>, glue: Vec<Record>, rng: &mut impl Rng) -> Self {
         let mut shuffled_nameservers: Vec<Record> =
             nameservers.iter().filter(|r| r.record_type() == RecordType::NS).cloned().collect();
-        shuffled_nameservers.shuffle(&mut thread_rng());
+        shuffled_nameservers.shuffle(rng);
+        NsProvider { shuffled_nameservers, glue }
+    }
+
+    /// Provides the nameservers in the order of the NS records, instead of shuffled
+    pub(crate) fn in_order(nameservers: Vec<Record>, glue: Vec<Record>) -> Self {
+        let shuffled_nameservers =
+            nameservers.into_iter().rev().filter(|r| r.record_type() == RecordType::NS).collect();
         NsProvider { shuffled_nameservers, glue }
     }
 }
@@ -76,7 +96,7 @@ impl TargetProvider for NsProvider {
     }
 }
 
-fn find_in_glue(name: &Name, glue: &[Record]) -> Option<IpAddr> {
+pub(crate) fn find_in_glue(name: &Name, glue: &[Record]) -> Option<IpAddr> {
     glue.iter()
         .filter(|r| r.record_type() == RecordType::A)
         .filter(|r| r.name() == name)
@@ -98,13 +118,24 @@ pub(crate) fn get_name_if_ns(record: &Record) -> Option<Result<&Name, Resolution
     }
 }
 
+/// Returns true if the nameserver that the NS record points to is within the delegated zone
+pub(crate) fn is_in_bailiwick(record: &Record) -> bool {
+    matches!(get_name_if_ns(record), Some(Ok(name)) if record.name().zone_of(name))
+}
+
 #[cfg(test)]
 mod tests {
-    use crate::target::{find_in_glue, get_name_if_ns, get_target, NsProvider, TargetProvider};
+    use crate::target::{
+        find_in_glue, get_name_if_ns, get_target, is_in_bailiwick, NsProvider, RootsProvider,
+        Target, TargetProvider,
+    };
     use crate::{a, name, ns};
     use anyhow::Result;
     use hickory_proto::rr::{rdata, RecordType};
     use hickory_proto::rr::{IntoName, Name, RData, Record};
+    use rand::rngs::StdRng;
+    use rand::{thread_rng, SeedableRng};
+    use std::net::IpAddr;
     use std::str::FromStr;
 
     #[test]
@@ -138,6 +169,15 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn test_is_in_bailiwick() -> Result<()> {
+        assert!(is_in_bailiwick(&ns!("example.com.", "ns0.example.com.")));
+        assert!(is_in_bailiwick(&ns!("example.com.", "ns0.EXAMPLE.com.")));
+        assert!(!is_in_bailiwick(&ns!("example.com.", "ns0.example.net.")));
+        assert!(!is_in_bailiwick(&a!("example.com.", "127.0.0.1")));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_get_target_invalid_input() -> Result<()> {
         // the case where the record is of the wrong type
@@ -156,10 +196,57 @@ mod tests {
 
     #[tokio::test]
     async fn test_ns_provider_next() -> Result<()> {
-        let mut provider =
-            NsProvider::new(vec![ns!("com.", "ns0.com.")], vec![a!("ns0.com.", "7.6.5.4")]);
+        let mut provider = NsProvider::new(
+            vec![ns!("com.", "ns0.com.")],
+            vec![a!("ns0.com.", "7.6.5.4")],
+            &mut thread_rng(),
+        );
         assert!(provider.next().await?.is_some());
         assert!(provider.next().await?.is_none());
         Ok(())
     }
+
+    async fn selection_order(seed: u64) -> Result<Vec<String>> {
+        let mut nameservers = Vec::new();
+        for i in 0..10 {
+            nameservers.push(ns!("com.", format!("ns{i}.com.")));
+        }
+        let mut provider = NsProvider::new(nameservers, vec![], &mut StdRng::seed_from_u64(seed));
+        let mut order = Vec::new();
+        while let Some(Target::Name(name)) = provider.next().await? {
+            order.push(name.to_string());
+        }
+        Ok(order)
+    }
+
+    #[tokio::test]
+    async fn test_seeded_selection_order() -> Result<()> {
+        assert_eq!(10, selection_order(42).await?.len());
+        assert_eq!(selection_order(42).await?, selection_order(42).await?);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_in_order() -> Result<()> {
+        let nameservers = (0..10)
+            .map(|i| -> Result<Record> { Ok(ns!("com.", format!("ns{i}.com."))) })
+            .collect::<Result<Vec<_>>>()?;
+        let mut provider = NsProvider::in_order(nameservers, vec![]);
+        let mut order = Vec::new();
+        while let Some(Target::Name(name)) = provider.next().await? {
+            order.push(name.to_string());
+        }
+        let expected: Vec<String> = (0..10).map(|i| format!("ns{i}.com.")).collect();
+        assert_eq!(order, expected);
+
+        let roots: Vec<IpAddr> =
+            vec!["10.0.0.3".parse()?, "10.0.0.1".parse()?, "10.0.0.2".parse()?];
+        let mut provider = RootsProvider::in_order(&roots);
+        let mut order = Vec::new();
+        while let Some(Target::Ip(ip)) = provider.next().await? {
+            order.push(ip);
+        }
+        assert_eq!(order, roots);
+        Ok(())
+    }
 }
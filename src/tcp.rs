@@ -0,0 +1,188 @@
+use crate::backend::parse_message;
+use crate::resolver::ResolutionError;
+use crate::resolver::ResolutionError::ServFail;
+use hickory_proto::op::Message;
+use hickory_proto::rr::rdata::opt::{EdnsCode, EdnsOption};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tokio::net::TcpStream;
+
+/// The EDNS TCP keepalive option, [RFC7828](https://datatracker.ietf.org/doc/html/rfc7828)
+pub const KEEPALIVE_OPTION_CODE: u16 = 11;
+
+/// Writes a message in the TCP framing of
+/// [RFC1035 section 4.2.2](https://datatracker.ietf.org/doc/html/rfc1035#section-4.2.2),
+/// a two octet length followed by the message.
+#[cfg(test)]
+pub async fn write_message<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &Message,
+) -> Result<(), ResolutionError> {
+    write_bytes(writer, &message.to_vec()?).await
+}
+
+/// Writes an already serialized message, with the same framing as write_message(). The
+/// prefix is written separately from the message to avoid copying it into a larger buffer.
+pub async fn write_bytes<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    bytes: &[u8],
+) -> Result<(), ResolutionError> {
+    let Ok(length) = u16::try_from(bytes.len()) else {
+        return Err(ServFail(format!("message of {} octets is too large", bytes.len())));
+    };
+    writer.write_all(&length.to_be_bytes()).await?;
+    writer.write_all(bytes).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads a single length prefixed message, allocating only what the prefix says is needed
+pub async fn read_message<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Message, ResolutionError> {
+    let length = reader.read_u16().await?;
+    let mut buf = vec![0u8; length as usize];
+    reader.read_exact(&mut buf).await?;
+    parse_message(&buf)
+}
+
+/// Returns the idle timeout that the server asked for with the EDNS keepalive option
+pub fn keepalive(message: &Message) -> Option<Duration> {
+    let edns = message.extensions().as_ref()?;
+    let Some(EdnsOption::Unknown(_, data)) = edns.option(EdnsCode::from(KEEPALIVE_OPTION_CODE))
+    else {
+        return None;
+    };
+    // the timeout is given in units of 100 milliseconds
+    let timeout = u16::from_be_bytes(*data.first_chunk::<2>()?);
+    Some(Duration::from_millis(u64::from(timeout) * 100))
+}
+
+/// Idle TCP connections, kept to be reused for later queries to the same server. Each
+/// connection is closed once it has been idle for the idle timeout, or the shorter
+/// timeout the server asked for.
+#[derive(Debug, Clone)]
+pub struct TcpPool {
+    idle_timeout: Duration,
+    idle: Arc<Mutex<HashMap<IpAddr, Vec<IdleConnection>>>>,
+}
+
+#[derive(Debug)]
+struct IdleConnection {
+    stream: TcpStream,
+    closes_at: Instant,
+}
+
+impl TcpPool {
+    pub fn new(idle_timeout: Duration) -> Self {
+        TcpPool { idle_timeout, idle: Arc::new(Mutex::new(HashMap::new())) }
+    }
+
+    /// Takes an idle connection to target out of the pool, if there is one
+    pub fn take(&self, target: IpAddr) -> Option<TcpStream> {
+        let mut idle = self.idle.lock().unwrap();
+        let connections = idle.get_mut(&target)?;
+        let now = Instant::now();
+        connections.retain(|c| c.closes_at > now);
+        connections.pop().map(|c| c.stream)
+    }
+
+    /// Puts a connection back in the pool, closing it after the idle timeout or the
+    /// keepalive timeout of the server, whichever is shorter
+    pub fn put(&self, target: IpAddr, stream: TcpStream, keepalive: Option<Duration>) {
+        let timeout = keepalive.map_or(self.idle_timeout, |k| k.min(self.idle_timeout));
+        if timeout.is_zero() {
+            return;
+        }
+        let closes_at = Instant::now() + timeout;
+        let mut idle = self.idle.lock().unwrap();
+        idle.entry(target).or_default().push(IdleConnection { stream, closes_at });
+        let idle = self.idle.clone();
+        tokio::spawn(async move {
+            tokio::time::sleep(timeout).await;
+            let now = Instant::now();
+            let mut idle = idle.lock().unwrap();
+            // dropping the streams closes them
+            idle.values_mut().for_each(|c| c.retain(|c| c.closes_at > now));
+            idle.retain(|_, c| !c.is_empty());
+        });
+    }
+
+    /// The number of idle connections in the pool
+    #[cfg(test)]
+    fn len(&self) -> usize {
+        self.idle.lock().unwrap().values().map(Vec::len).sum()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::tcp::{keepalive, read_message, write_message, TcpPool, KEEPALIVE_OPTION_CODE};
+    use anyhow::Result;
+    use hickory_proto::op::{Edns, Message};
+    use hickory_proto::rr::rdata::opt::EdnsOption;
+    use hickory_proto::rr::rdata::TXT;
+    use hickory_proto::rr::{Name, RData, Record};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::time::Duration;
+    use tokio::io::AsyncReadExt;
+    use tokio::net::{TcpListener, TcpStream};
+
+    #[tokio::test]
+    async fn test_large_message() -> Result<()> {
+        let mut message = Message::new();
+        for i in 0..200 {
+            let txt = TXT::new(vec![format!("{i:0>250}")]);
+            message.add_answer(Record::from_rdata(Name::root(), 60, RData::TXT(txt)));
+        }
+        assert!(message.to_vec()?.len() > 50_000);
+
+        let listener = TcpListener::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).await?;
+        let address = listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            read_message(&mut stream).await
+        });
+        let mut stream = TcpStream::connect(address).await?;
+        write_message(&mut stream, &message).await?;
+        assert_eq!(server.await??.answers(), message.answers());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_idle_timeout() -> Result<()> {
+        let listener = TcpListener::bind(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), 0)).await?;
+        let address = listener.local_addr()?;
+        let server = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await?;
+            // returns once the client has closed the connection
+            let read = stream.read(&mut [0u8; 1]).await?;
+            anyhow::Ok(read)
+        });
+        let target = IpAddr::V4(Ipv4Addr::LOCALHOST);
+        let pool = TcpPool::new(Duration::from_secs(60));
+        // the server asks for a shorter timeout than the configured one
+        pool.put(target, TcpStream::connect(address).await?, Some(Duration::from_millis(50)));
+        assert_eq!(pool.len(), 1);
+
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(pool.len(), 0);
+        assert_eq!(server.await??, 0);
+        assert!(pool.take(target).is_none());
+        Ok(())
+    }
+
+    #[test]
+    fn test_keepalive() {
+        let mut message = Message::new();
+        assert_eq!(keepalive(&message), None);
+        let mut edns = Edns::new();
+        let option = EdnsOption::Unknown(KEEPALIVE_OPTION_CODE, 25u16.to_be_bytes().to_vec());
+        edns.options_mut().insert(option);
+        message.set_edns(edns);
+        assert_eq!(keepalive(&message), Some(Duration::from_millis(2500)));
+    }
+}
@@ -0,0 +1,167 @@
+use anyhow::bail;
+use lru::LruCache;
+use std::fmt::{Display, Formatter};
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The number of upstreams that are kept track of, the least recently queried are forgotten
+const MAX_UPSTREAMS: usize = 10_000;
+
+/// Counts the queries sent to each upstream and how they went, to help spotting the
+/// upstreams that misbehave
+#[derive(Debug)]
+pub(crate) struct UpstreamStats {
+    state: Mutex<LruCache<IpAddr, UpstreamCounters>>,
+}
+
+impl Default for UpstreamStats {
+    fn default() -> Self {
+        let capacity = NonZeroUsize::new(MAX_UPSTREAMS).unwrap();
+        UpstreamStats { state: Mutex::new(LruCache::new(capacity)) }
+    }
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct UpstreamCounters {
+    pub queries: u64,
+    /// Queries that got a response other than SERVFAIL or REFUSED
+    pub successes: u64,
+    /// Queries that got SERVFAIL or REFUSED
+    pub servfails: u64,
+    /// The summed latency of the successful queries
+    pub latency: Duration,
+}
+
+/// How a query to an upstream went
+pub(crate) enum QueryOutcome {
+    Success,
+    /// A SERVFAIL or REFUSED response
+    ServFail,
+    /// There was no usable response, such as on timeouts
+    Failure,
+}
+
+impl UpstreamCounters {
+    /// The average latency of the successful queries
+    pub fn average_latency(&self) -> Duration {
+        match self.successes {
+            0 => Duration::ZERO,
+            successes => Duration::from_nanos((self.latency.as_nanos() / successes as u128) as u64),
+        }
+    }
+}
+
+impl Display for UpstreamCounters {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "queries {} successes {} servfails {} average-latency-ms {}",
+            self.queries,
+            self.successes,
+            self.servfails,
+            self.average_latency().as_millis()
+        )
+    }
+}
+
+/// Parses the lines of '<upstream> <counters>' that the stats are saved as, the same as
+/// the upstream-stats admin command lists them. The latency is only kept as the average,
+/// so the restored latency is the average times the number of successes.
+pub fn parse_upstream_stats(contents: &str) -> anyhow::Result<Vec<(IpAddr, UpstreamCounters)>> {
+    contents
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            let words: Vec<&str> = line.split_whitespace().collect();
+            let [upstream, "queries", queries, "successes", successes, "servfails", servfails, "average-latency-ms", latency] =
+                words[..]
+            else {
+                bail!("expected '<upstream> <counters>', got '{line}'");
+            };
+            let successes: u64 = successes.parse()?;
+            let counters = UpstreamCounters {
+                queries: queries.parse()?,
+                successes,
+                servfails: servfails.parse()?,
+                // saturating, as the file may have been tampered with
+                latency: Duration::from_millis(latency.parse::<u64>()?.saturating_mul(successes)),
+            };
+            Ok((upstream.parse()?, counters))
+        })
+        .collect()
+}
+
+/// Formats the stats to be read back with parse_upstream_stats
+pub fn format_upstream_stats(stats: &[(IpAddr, UpstreamCounters)]) -> String {
+    stats.iter().map(|(upstream, counters)| format!("{upstream} {counters}\n")).collect()
+}
+
+impl UpstreamStats {
+    /// Starts out with stats from earlier, such as from before a restart
+    pub(crate) fn restore(stats: Vec<(IpAddr, UpstreamCounters)>) -> Self {
+        let upstream_stats = UpstreamStats::default();
+        {
+            let mut state = upstream_stats.state.lock().unwrap();
+            for (upstream, counters) in stats {
+                state.push(upstream, counters);
+            }
+        }
+        upstream_stats
+    }
+
+    pub(crate) fn record(&self, upstream: IpAddr, outcome: QueryOutcome, latency: Duration) {
+        let mut state = self.state.lock().unwrap();
+        let counters = state.get_or_insert_mut(upstream, UpstreamCounters::default);
+        counters.queries = counters.queries.saturating_add(1);
+        match outcome {
+            QueryOutcome::Success => {
+                counters.successes = counters.successes.saturating_add(1);
+                counters.latency = counters.latency.saturating_add(latency);
+            }
+            QueryOutcome::ServFail => counters.servfails = counters.servfails.saturating_add(1),
+            QueryOutcome::Failure => {}
+        }
+    }
+
+    /// Returns true if the upstream has been queried without ever responding
+    pub(crate) fn unreachable(&self, upstream: IpAddr) -> bool {
+        let state = self.state.lock().unwrap();
+        state.peek(&upstream).is_some_and(|c| c.queries > 0 && c.successes == 0 && c.servfails == 0)
+    }
+
+    /// Returns the counters of every upstream that has been queried, sorted by address
+    pub(crate) fn snapshot(&self) -> Vec<(IpAddr, UpstreamCounters)> {
+        let state = self.state.lock().unwrap();
+        let mut result: Vec<_> = state.iter().map(|(ip, c)| (*ip, c.clone())).collect();
+        result.sort_by_key(|(ip, _)| *ip);
+        result
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::upstream_stats::{QueryOutcome, UpstreamStats, MAX_UPSTREAMS};
+    use std::net::IpAddr;
+    use std::time::Duration;
+
+    #[test]
+    fn test_max_upstreams() {
+        let stats = UpstreamStats::default();
+        let first = IpAddr::from([10, 0, 0, 0]);
+        stats.record(first, QueryOutcome::Failure, Duration::ZERO);
+        for i in 1..=MAX_UPSTREAMS as u32 {
+            stats.record(
+                IpAddr::from((10 << 24 | i).to_be_bytes()),
+                QueryOutcome::Success,
+                Duration::ZERO,
+            );
+        }
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.len(), MAX_UPSTREAMS);
+        // the least recently queried upstream is the one forgotten
+        assert!(!snapshot.iter().any(|(ip, _)| *ip == first));
+        assert!(!stats.unreachable(first));
+    }
+}
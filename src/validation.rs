@@ -0,0 +1,596 @@
+use crate::cache::{NegativeAnswer, Query};
+use crate::denial::{self, Proof};
+use crate::dnssec::{self, ValidationStatus, Validator};
+use crate::resolver::{
+    in_rrset, RecursiveResolver, ResolutionError, ResolutionState, ResolveOptions, DNAME,
+};
+use async_recursion::async_recursion;
+use hickory_proto::rr::dnssec::rdata::DNSSECRData;
+use hickory_proto::rr::{Name, RData, Record, RecordType};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tracing::debug;
+
+/// The records of an answer, with how they fared in DNSSEC validation
+#[derive(Debug)]
+pub struct ValidatedRecords {
+    pub records: Vec<Record>,
+    pub status: ValidationStatus,
+}
+
+/// What is known about the keys of a zone
+#[derive(Debug)]
+enum ZoneKeys {
+    /// The DNSKEY records of the zone, which there is a chain of trust to
+    Secure(Vec<Record>),
+    Insecure,
+    Bogus(String),
+}
+
+impl RecursiveResolver {
+    /// Resolves the name like resolve_with_options, and validates the answer if the resolver
+    /// was built to, unless the options disable checking. Each RRset of the answer is
+    /// validated with the keys of the zone that signed it, or of the zone it is in if it
+    /// isn't signed. The chain of trust to the keys goes from the trust anchors down through
+    /// the DS records of each zone. A zone is unsigned if the NSEC or NSEC3 records of its
+    /// parent prove that there are no DS records for it, and those records prove the
+    /// NXDOMAIN and NODATA answers as well. A Bogus NXDOMAIN is returned as Bogus records,
+    /// the others as the error.
+    pub async fn resolve_validated(
+        &self,
+        to_resolve: &Name,
+        record_type: RecordType,
+        options: ResolveOptions,
+    ) -> Result<ValidatedRecords, ResolutionError> {
+        let checking_disabled = options.checking_disabled;
+        let result = self.resolve_with_options(to_resolve, record_type, options).await;
+        let validator = match self.validator() {
+            Some(validator) if !checking_disabled => validator,
+            _ => {
+                return result.map(|records| ValidatedRecords {
+                    records,
+                    status: ValidationStatus::Indeterminate,
+                })
+            }
+        };
+        let (records, nxdomain) = match result {
+            Ok(records) => (records, false),
+            Err(ResolutionError::NxDomain) => (Vec::new(), true),
+            Err(e) => return Err(e),
+        };
+        let status = match negative_at(to_resolve, record_type, &records) {
+            // nothing signs the answers the resolver makes up itself
+            _ if self.synthetic_authority(to_resolve).is_some() => ValidationStatus::Insecure,
+            None => self.validate_records(validator, &records).await,
+            // the CNAMEs are validated like the records of any answer
+            Some(name) => {
+                let chain = self.validate_records(validator, &records).await;
+                match (chain, self.validate_negative(validator, &name, record_type).await) {
+                    (ValidationStatus::Bogus(reason), _) | (_, ValidationStatus::Bogus(reason)) => {
+                        ValidationStatus::Bogus(reason)
+                    }
+                    (ValidationStatus::Secure, status) | (status, _) => status,
+                }
+            }
+        };
+        debug!(?status, "Validated the answer");
+        match status {
+            ValidationStatus::Bogus(_) => Ok(ValidatedRecords { records, status }),
+            _ if nxdomain => Err(ResolutionError::NxDomain),
+            _ => Ok(ValidatedRecords { records, status }),
+        }
+    }
+
+    /// Validates the negative answer for the name, at the end of the CNAME chain of the
+    /// answer. It is resolved again for the NXDOMAIN or NODATA response, which usually is in
+    /// the negative cache by now. A response without an SOA can only come from an unsigned
+    /// zone.
+    async fn validate_negative(
+        &self,
+        validator: &Validator,
+        name: &Name,
+        record_type: RecordType,
+    ) -> ValidationStatus {
+        let (result, denial) = self.resolve_dnssec(name, record_type).await;
+        let negative = match result {
+            Ok(records) => negative_at(name, record_type, &records).is_some(),
+            Err(e) => matches!(e, ResolutionError::NxDomain),
+        };
+        match denial {
+            // the chain of CNAMEs ends with the last denial
+            Some((query, denial)) if negative && query.record_type == record_type => {
+                self.validate_denial(validator, &query.to_resolve, record_type, &denial).await
+            }
+            _ => match self.authority_for(name).await {
+                Ok((zone, _, _)) => match self.zone_keys(validator, &zone).await {
+                    ZoneKeys::Insecure => ValidationStatus::Insecure,
+                    ZoneKeys::Secure(_) => {
+                        ValidationStatus::Bogus(format!("no SOA came with the denial of {name}"))
+                    }
+                    ZoneKeys::Bogus(reason) => ValidationStatus::Bogus(reason),
+                },
+                Err(e) => ValidationStatus::Bogus(format!("no zone found for {name}: {e}")),
+            },
+        }
+    }
+
+    /// Returns Secure if every RRset of the records is, otherwise the worst status of them
+    async fn validate_records(
+        &self,
+        validator: &Validator,
+        records: &[Record],
+    ) -> ValidationStatus {
+        let mut status = ValidationStatus::Secure;
+        for (name, record_type) in rrsets(records) {
+            let rrset = |r: &&Record| r.name() == name && r.record_type() == record_type;
+            if records.iter().filter(rrset).any(|r| synthesized(r, records)) {
+                continue;
+            }
+            let rrset: Vec<Record> =
+                records.iter().filter(|r| in_rrset(r, name, record_type)).cloned().collect();
+            match self.validate_rrset(validator, name, record_type, &rrset).await {
+                ValidationStatus::Bogus(reason) => return ValidationStatus::Bogus(reason),
+                ValidationStatus::Secure => {}
+                other => status = other,
+            }
+        }
+        status
+    }
+
+    /// Validates the RRset, which is given along with the RRSIG records covering it
+    async fn validate_rrset(
+        &self,
+        validator: &Validator,
+        name: &Name,
+        record_type: RecordType,
+        rrset: &[Record],
+    ) -> ValidationStatus {
+        let zone = match signer(rrset) {
+            Some(signer) if signer.zone_of(name) => signer,
+            Some(signer) => {
+                return ValidationStatus::Bogus(format!("{name} is signed by {signer}"));
+            }
+            None => {
+                // the DS records of a zone are in its parent
+                let owner = match record_type {
+                    RecordType::DS => name.base_name(),
+                    _ => name.clone(),
+                };
+                match self.authority_for(&owner).await {
+                    Ok((apex, _, _)) => apex,
+                    Err(e) => {
+                        return ValidationStatus::Bogus(format!("no zone found for {name}: {e}"));
+                    }
+                }
+            }
+        };
+        match self.zone_keys(validator, &zone).await {
+            ZoneKeys::Secure(keys) if dnssec::verify_rrset(rrset, &keys, now()) => {
+                ValidationStatus::Secure
+            }
+            ZoneKeys::Secure(_) => {
+                ValidationStatus::Bogus(format!("no valid signature for {name} {record_type}"))
+            }
+            ZoneKeys::Insecure => ValidationStatus::Insecure,
+            ZoneKeys::Bogus(reason) => ValidationStatus::Bogus(reason),
+        }
+    }
+
+    /// Returns the DNSKEY records of the zone if there is a chain of trust to them, from a
+    /// trust anchor for the zone or from the keys of the parent zone through the DS records
+    #[async_recursion]
+    async fn zone_keys(&self, validator: &Validator, zone: &Name) -> ZoneKeys {
+        if validator.anchored(zone) {
+            return match self.resolve_dnssec(zone, RecordType::DNSKEY).await.0 {
+                Ok(keys) => {
+                    let trusted = validator.trusted_keys(zone, &keys);
+                    verified_keys(zone, keys, &trusted)
+                }
+                Err(e) => ZoneKeys::Bogus(format!("the DNSKEY records of {zone}: {e}")),
+            };
+        }
+        if zone.is_root() {
+            return ZoneKeys::Insecure;
+        }
+        let (records, denial) = match self.resolve_dnssec(zone, RecordType::DS).await {
+            (Ok(records), denial) => (records, denial),
+            (Err(e), _) => return ZoneKeys::Bogus(format!("the DS records of {zone}: {e}")),
+        };
+        let ds: Vec<_> = records
+            .iter()
+            .filter_map(|r| match r.data() {
+                Some(RData::DNSSEC(DNSSECRData::DS(ds))) if r.name() == zone => Some(ds.clone()),
+                _ => None,
+            })
+            .collect();
+        if ds.is_empty() {
+            // the zone is unsigned if its parent proves that there are no DS records for it
+            return match denial {
+                Some((_, negative)) if negative.zone.num_labels() < zone.num_labels() => {
+                    match self.validate_denial(validator, zone, RecordType::DS, &negative).await {
+                        ValidationStatus::Bogus(reason) => ZoneKeys::Bogus(reason),
+                        _ => ZoneKeys::Insecure,
+                    }
+                }
+                _ => ZoneKeys::Bogus(format!("nothing proves that {zone} has no DS records")),
+            };
+        }
+        if !dnssec::supported(&ds) {
+            return ZoneKeys::Insecure;
+        }
+        // the DS records are in the parent zone, and every step up has fewer labels
+        let parent = match signer(&records) {
+            Some(parent) if parent.zone_of(zone) && parent.num_labels() < zone.num_labels() => {
+                parent
+            }
+            _ => return ZoneKeys::Bogus(format!("the DS records of {zone} are not signed")),
+        };
+        match self.zone_keys(validator, &parent).await {
+            ZoneKeys::Secure(keys) if dnssec::verify_rrset(&records, &keys, now()) => {}
+            ZoneKeys::Secure(_) => {
+                return ZoneKeys::Bogus(format!("no valid signature for the DS records of {zone}"));
+            }
+            other => return other,
+        }
+        match self.resolve_dnssec(zone, RecordType::DNSKEY).await.0 {
+            Ok(keys) => {
+                let trusted = dnssec::keys_for_ds(zone, &keys, &ds);
+                verified_keys(zone, keys, &trusted)
+            }
+            Err(e) => ZoneKeys::Bogus(format!("the DNSKEY records of {zone}: {e}")),
+        }
+    }
+
+    /// Validates the NXDOMAIN or NODATA response for the name and type, by the NSEC or NSEC3
+    /// records that came with it from the zone of its SOA, RFC 4035 section 5.4
+    async fn validate_denial(
+        &self,
+        validator: &Validator,
+        name: &Name,
+        record_type: RecordType,
+        negative: &NegativeAnswer,
+    ) -> ValidationStatus {
+        let zone = &negative.zone;
+        if !zone.zone_of(name) {
+            return ValidationStatus::Bogus(format!("the SOA of {zone} came with {name}"));
+        }
+        let keys = match self.zone_keys(validator, zone).await {
+            ZoneKeys::Secure(keys) => keys,
+            ZoneKeys::Insecure => return ValidationStatus::Insecure,
+            ZoneKeys::Bogus(reason) => return ValidationStatus::Bogus(reason),
+        };
+        // the SOA is signed along with the records that prove the denial
+        for (owner, covered) in rrsets(&negative.proof) {
+            let rrset: Vec<Record> =
+                negative.proof.iter().filter(|r| in_rrset(r, owner, covered)).cloned().collect();
+            if !dnssec::verify_rrset(&rrset, &keys, now()) {
+                return ValidationStatus::Bogus(format!(
+                    "no valid signature for {owner} {covered}"
+                ));
+            }
+        }
+        let proof = match negative.nxdomain {
+            true => denial::nxdomain(zone, name, &negative.proof),
+            false => denial::nodata(zone, name, record_type, &negative.proof),
+        };
+        match proof {
+            Proof::Denied => ValidationStatus::Secure,
+            Proof::Insecure => ValidationStatus::Insecure,
+            Proof::Missing => ValidationStatus::Bogus(format!(
+                "nothing proves that {name} {record_type} doesn't exist"
+            )),
+        }
+    }
+
+    /// Resolves a DS or DNSKEY RRset, which is needed whether or not the zone is served,
+    /// along with the negative answer if there are no such records
+    async fn resolve_dnssec(
+        &self,
+        zone: &Name,
+        record_type: RecordType,
+    ) -> (Result<Vec<Record>, ResolutionError>, Option<(Query, NegativeAnswer)>) {
+        let mut state = ResolutionState::new(self);
+        let result = state.resolve_inner(zone, record_type, 1).await;
+        (result, state.denial)
+    }
+}
+
+/// Returns the keys of the zone as Secure if the DNSKEY RRset is signed by one of the
+/// trusted keys
+fn verified_keys(zone: &Name, keys: Vec<Record>, trusted: &[Record]) -> ZoneKeys {
+    let rrset: Vec<Record> =
+        keys.iter().filter(|r| in_rrset(r, zone, RecordType::DNSKEY)).cloned().collect();
+    match dnssec::verify_rrset(&rrset, trusted, now()) {
+        true => ZoneKeys::Secure(keys),
+        false => {
+            ZoneKeys::Bogus(format!("the DNSKEY records of {zone} are not signed by a trusted key"))
+        }
+    }
+}
+
+/// Returns the name at the end of the CNAME chain of the answer if there are no records of
+/// the type there, the name whose denial validates the answer
+fn negative_at(to_resolve: &Name, record_type: RecordType, records: &[Record]) -> Option<Name> {
+    if matches!(record_type, RecordType::CNAME | RecordType::ANY) {
+        return records.is_empty().then(|| to_resolve.clone());
+    }
+    let mut name = to_resolve.clone();
+    // each CNAME leads one step further, the answer can't have a loop
+    for _ in 0..=records.len() {
+        if records.iter().any(|r| r.name() == &name && r.record_type() == record_type) {
+            return None;
+        }
+        match records.iter().find_map(|r| match r.data() {
+            Some(RData::CNAME(target)) if r.name() == &name => Some(target.0.clone()),
+            _ => None,
+        }) {
+            Some(target) => name = target,
+            None => break,
+        }
+    }
+    Some(name)
+}
+
+/// The name and type of each RRset among the records, other than the RRSIG records
+fn rrsets(records: &[Record]) -> Vec<(&Name, RecordType)> {
+    let mut rrsets = Vec::new();
+    for r in records {
+        let rrset = (r.name(), r.record_type());
+        if r.record_type() != RecordType::RRSIG && !rrsets.contains(&rrset) {
+            rrsets.push(rrset);
+        }
+    }
+    rrsets
+}
+
+/// The signer of the first RRSIG record among the records
+fn signer(records: &[Record]) -> Option<Name> {
+    records.iter().find_map(|r| match r.data() {
+        Some(RData::DNSSEC(DNSSECRData::RRSIG(sig))) => Some(sig.signer_name().clone()),
+        _ => None,
+    })
+}
+
+/// Returns true if the record is a CNAME synthesized from one of the DNAME records, which
+/// is validated through the DNAME, RFC 6672 section 5.3.3
+fn synthesized(record: &Record, records: &[Record]) -> bool {
+    record.record_type() == RecordType::CNAME
+        && records.iter().any(|r| {
+            r.record_type() == DNAME && r.name() != record.name() && r.name().zone_of(record.name())
+        })
+}
+
+/// The current time, as the seconds since the epoch that RRSIG records use
+fn now() -> u32 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs() as u32)
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use crate::dnssec::test_signer::{dnskey, ds, sign};
+    use crate::dnssec::{TrustAnchor, ValidationStatus};
+    use crate::fake_backend::FakeBackend;
+    use crate::resolver::{RecursiveResolver, ResolutionError, ResolveOptions, DNAME};
+    use crate::{a, answer, name, ns};
+    use anyhow::Result;
+    use hickory_proto::op::{Header, Message, ResponseCode};
+    use hickory_proto::rr::dnssec::rdata::{DNSSECRData, NSEC};
+    use hickory_proto::rr::RecordType::{A, AAAA, CNAME, DNSKEY, DS, NS, SOA, TXT};
+    use hickory_proto::rr::{rdata, Name, RData, Record, RecordType};
+    use hickory_proto::serialize::binary::BinEncodable;
+    use std::net::IpAddr;
+    use std::str::FromStr;
+
+    /// An authoritative answer with the records and the signature of the zone over them
+    fn signed(records: Vec<Record>, zone: &str) -> Result<Message> {
+        let mut message = Message::new();
+        message.set_authoritative(true);
+        let signature = sign(&records, &name!(zone));
+        message.insert_answers([records, vec![signature]].concat());
+        Ok(message)
+    }
+
+    /// A referral to the zone, with its DS record signed by the root if it has one
+    fn referral(zone: &str, ip: &str, signed: bool) -> Result<Message> {
+        let mut message = Message::new();
+        message.add_name_server(ns!(zone, format!("ns.{zone}")));
+        message.add_additional(a!(format!("ns.{zone}"), ip));
+        if signed {
+            let ds = ds(&name!(zone));
+            message.add_name_server(sign(std::slice::from_ref(&ds), &Name::root()));
+            message.add_name_server(ds);
+        }
+        Ok(message)
+    }
+
+    /// A NODATA response from the zone
+    fn nodata(zone: &str) -> Result<Message> {
+        let soa = rdata::SOA::new(name!("ns.test."), name!(zone), 1, 3600, 600, 86400, 300);
+        let mut message = Message::new();
+        message.set_authoritative(true);
+        message.add_name_server(Record::from_rdata(name!(zone), 300, RData::SOA(soa)));
+        Ok(message)
+    }
+
+    /// A negative answer from the signed zone, with the NSEC records that prove it
+    fn denial(zone: &str, nsecs: &[(&str, &str, &[RecordType])]) -> Result<Message> {
+        let mut message = nodata(zone)?;
+        let soa = message.take_name_servers();
+        message.add_name_server(sign(&soa, &name!(zone)));
+        message.add_name_servers(soa);
+        for (owner, next, types) in nsecs {
+            let nsec = NSEC::new(name!(next), types.to_vec());
+            let nsec =
+                Record::from_rdata(name!(owner), 300, RData::DNSSEC(DNSSECRData::NSEC(nsec)));
+            message.add_name_server(sign(std::slice::from_ref(&nsec), &name!(zone)));
+            message.add_name_server(nsec);
+        }
+        Ok(message)
+    }
+
+    /// A signed root with the signed zone secure. and the unsigned zones insecure. and
+    /// forged. below it, the root only proves that insecure. is unsigned. The signature of
+    /// broken.secure. doesn't verify, and alias.secure. is a DNAME for secure.
+    pub(crate) fn signed_zones(validate: bool) -> Result<RecursiveResolver> {
+        let mut b = FakeBackend::new();
+        b.add("10.0.0.1", ".", DNSKEY, signed(vec![dnskey(&Name::root())], ".")?)?;
+        let names = [
+            ("www.secure.", A),
+            ("broken.secure.", A),
+            ("www.alias.secure.", A),
+            ("www.secure.", AAAA),
+            ("www.secure.", TXT),
+            ("broken.secure.", AAAA),
+            ("nx.secure.", A),
+            ("forged.secure.", A),
+        ];
+        for (name, record_type) in [names.as_slice(), &[("secure.", DNSKEY)]].concat() {
+            b.add("10.0.0.1", name, record_type, referral("secure.", "10.0.0.2", true)?)?;
+        }
+        b.add("10.0.0.1", "secure.", DS, signed(vec![ds(&name!("secure."))], ".")?)?;
+        b.add("10.0.0.2", "secure.", DNSKEY, signed(vec![dnskey(&name!("secure."))], "secure.")?)?;
+        b.add(
+            "10.0.0.2",
+            "www.secure.",
+            A,
+            signed(vec![a!("www.secure.", "10.0.0.42")], "secure.")?,
+        )?;
+        // the signature is over another address than the one in the answer
+        let signature = sign(&[a!("broken.secure.", "10.0.0.42")], &name!("secure."));
+        let mut broken = answer!(a!("broken.secure.", "10.0.0.66"));
+        broken.add_answer(signature);
+        b.add("10.0.0.2", "broken.secure.", A, broken)?;
+        let www = [("www.secure.", "secure.", [A, RecordType::RRSIG, RecordType::NSEC].as_slice())];
+        b.add("10.0.0.2", "www.secure.", AAAA, denial("secure.", &www)?)?;
+        let apex = [SOA, NS, DNSKEY, RecordType::RRSIG, RecordType::NSEC];
+        let nx = [("secure.", "alias.secure.", &apex[..]), ("broken.secure.", "www.secure.", &[A])];
+        let mut nxdomain = denial("secure.", &nx)?;
+        nxdomain.set_response_code(ResponseCode::NXDomain);
+        b.add("10.0.0.2", "nx.secure.", A, nxdomain)?;
+        // the denials without the NSEC records, or without the signature of the SOA
+        b.add("10.0.0.2", "www.secure.", TXT, denial("secure.", &[])?)?;
+        b.add("10.0.0.2", "broken.secure.", AAAA, nodata("secure.")?)?;
+        let mut forged = denial("secure.", &[])?;
+        forged.set_response_code(ResponseCode::NXDomain);
+        b.add("10.0.0.2", "forged.secure.", A, forged)?;
+        // the CNAME that the DNAME implies is unsigned
+        let target = name!("secure.").to_bytes()?;
+        let rdata = RData::Unknown { code: DNAME, rdata: rdata::NULL::with(target) };
+        let dname = Record::from_rdata(name!("alias.secure."), 300, rdata);
+        b.add("10.0.0.2", "www.alias.secure.", A, signed(vec![dname], "secure.")?)?;
+
+        let unsigned = [NS, RecordType::RRSIG, RecordType::NSEC];
+        b.add("10.0.0.1", "insecure.", DS, denial(".", &[("insecure.", "secure.", &unsigned)])?)?;
+        b.add("10.0.0.1", "forged.", DS, nodata(".")?)?;
+        for (zone, ip) in [("insecure.", "10.0.0.3"), ("forged.", "10.0.0.4")] {
+            let www = format!("www.{zone}");
+            b.add("10.0.0.1", &www, A, referral(zone, ip, false)?)?;
+            b.add("10.0.0.1", &www, AAAA, referral(zone, ip, false)?)?;
+            b.add(ip, &www, A, answer!(a!(www, "10.0.0.43")))?;
+            b.add(ip, &www, SOA, nodata(zone)?)?;
+            b.add(ip, &www, AAAA, nodata(zone)?)?;
+            b.add("10.0.0.1", zone, SOA, referral(zone, ip, false)?)?;
+            let soa = nodata(zone)?.name_servers().to_vec();
+            b.add(ip, zone, SOA, answer!(soa[0].clone()))?;
+            b.add(ip, zone, NS, answer!(ns!(zone, format!("ns.{zone}"))))?;
+        }
+
+        let Some(RData::DNSSEC(DNSSECRData::DS(anchor))) = ds(&Name::root()).data().cloned() else {
+            panic!("not a DS record");
+        };
+        Ok(RecursiveResolver::builder()
+            .backend(b)
+            .roots(vec![IpAddr::V4("10.0.0.1".parse()?)])
+            .trust_anchors(vec![TrustAnchor::new(Name::root(), anchor)])
+            .validate(validate)
+            .build())
+    }
+
+    #[tokio::test]
+    async fn test_secure() -> Result<()> {
+        let validated = signed_zones(true)?
+            .resolve_validated(&name!("www.secure."), A, ResolveOptions::default())
+            .await?;
+        assert_eq!(ValidationStatus::Secure, validated.status);
+        assert_eq!(2, validated.records.len());
+        // the same answer, from a resolver that doesn't validate
+        let validated = signed_zones(false)?
+            .resolve_validated(&name!("www.secure."), A, ResolveOptions::default())
+            .await?;
+        assert_eq!(ValidationStatus::Indeterminate, validated.status);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_insecure() -> Result<()> {
+        let validated = signed_zones(true)?
+            .resolve_validated(&name!("www.insecure."), A, ResolveOptions::default())
+            .await?;
+        assert_eq!(ValidationStatus::Insecure, validated.status);
+        assert_eq!(vec![a!("www.insecure.", "10.0.0.43")], validated.records);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_denial() -> Result<()> {
+        let resolver = signed_zones(true)?;
+        let www = name!("www.secure.");
+        let validated = resolver.resolve_validated(&www, AAAA, ResolveOptions::default()).await?;
+        assert_eq!(ValidationStatus::Secure, validated.status);
+        assert!(validated.records.is_empty());
+        // the denial is kept along with the negative answer in the cache
+        let validated = resolver.resolve_validated(&www, AAAA, ResolveOptions::default()).await?;
+        assert_eq!(ValidationStatus::Secure, validated.status);
+
+        let nx = name!("nx.secure.");
+        let result = resolver.resolve_validated(&nx, A, ResolveOptions::default()).await;
+        assert!(matches!(result, Err(ResolutionError::NxDomain)), "{result:?}");
+
+        let www = name!("www.insecure.");
+        let validated = resolver.resolve_validated(&www, AAAA, ResolveOptions::default()).await?;
+        assert_eq!(ValidationStatus::Insecure, validated.status);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_forged_denial() -> Result<()> {
+        let resolver = signed_zones(true)?;
+        for (name, record_type) in
+            [("www.secure.", TXT), ("broken.secure.", AAAA), ("forged.secure.", A)]
+        {
+            let options = ResolveOptions::default();
+            let validated = resolver.resolve_validated(&name!(name), record_type, options).await?;
+            assert!(matches!(validated.status, ValidationStatus::Bogus(_)), "{name} {record_type}");
+            assert!(validated.records.is_empty());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_forged_insecure() -> Result<()> {
+        // the DS records of forged. are denied without the NSEC records that prove it
+        let validated = signed_zones(true)?
+            .resolve_validated(&name!("www.forged."), A, ResolveOptions::default())
+            .await?;
+        assert!(matches!(validated.status, ValidationStatus::Bogus(_)), "{:?}", validated.status);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_bogus() -> Result<()> {
+        let validated = signed_zones(true)?
+            .resolve_validated(&name!("broken.secure."), A, ResolveOptions::default())
+            .await?;
+        assert!(matches!(validated.status, ValidationStatus::Bogus(_)), "{:?}", validated.status);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_dname() -> Result<()> {
+        let validated = signed_zones(true)?
+            .resolve_validated(&name!("www.alias.secure."), A, ResolveOptions::default())
+            .await?;
+        assert_eq!(ValidationStatus::Secure, validated.status);
+        assert!(validated.records.iter().any(|r| r.record_type() == CNAME));
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+use anyhow::bail;
+use hickory_proto::rr::{Name, RData, Record, RecordType};
+use hickory_proto::serialize::txt::Parser;
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+
+/// A zone that the resolver is authoritative for, loaded from a zone file in the format of
+/// RFC 1035 section 5. Names within it are answered from its records without recursing,
+/// except for the names below the zone cuts of delegations within it, which are resolved
+/// starting at the nameservers of the delegation.
+#[derive(Debug)]
+pub struct StaticZone {
+    apex: Name,
+    soa: Record,
+    /// The records of the zone by owner name
+    records: HashMap<Name, Vec<Record>>,
+    /// The owner names and every name between them and the apex, which exist as well
+    names: HashSet<Name>,
+    /// The names below the apex that have NS records
+    cuts: Vec<Name>,
+}
+
+/// The answer from a StaticZone to a query for a name within it
+#[derive(Debug, PartialEq)]
+pub enum ZoneAnswer {
+    Records(Vec<Record>),
+    NoData,
+    NxDomain,
+}
+
+impl StaticZone {
+    /// Parses the zone file contents, with `path` used to resolve relative $INCLUDE paths.
+    /// The origin is taken from the $ORIGIN directive, and the zone needs an SOA at it.
+    pub fn parse(contents: &str, path: Option<PathBuf>) -> anyhow::Result<Self> {
+        let (apex, sets) = Parser::new(contents, path, None).parse()?;
+        let mut records: HashMap<Name, Vec<Record>> = HashMap::new();
+        for record in sets.into_values().flatten() {
+            records.entry(record.name().clone()).or_default().push(record);
+        }
+        let soa = records
+            .get(&apex)
+            .and_then(|rs| rs.iter().find(|r| r.record_type() == RecordType::SOA).cloned());
+        let Some(soa) = soa else {
+            bail!("there is no SOA record at the origin {apex}");
+        };
+        let mut names = HashSet::new();
+        for name in records.keys() {
+            let mut name = name.clone();
+            while apex.zone_of(&name) && names.insert(name.clone()) && name != apex {
+                name = name.base_name();
+            }
+        }
+        let cuts = records
+            .iter()
+            .filter(|(name, rs)| {
+                **name != apex && rs.iter().any(|r| r.record_type() == RecordType::NS)
+            })
+            .map(|(name, _)| name.clone())
+            .collect();
+        Ok(StaticZone { apex, soa, records, names, cuts })
+    }
+
+    pub fn apex(&self) -> &Name {
+        &self.apex
+    }
+
+    /// The SOA record at the apex, for the authority section of negative answers
+    pub fn soa(&self) -> &Record {
+        &self.soa
+    }
+
+    /// Returns true if the zone answers for the name, which is within the zone but not
+    /// below one of its zone cuts
+    pub fn answers_for(&self, name: &Name) -> bool {
+        self.apex.zone_of(name) && !self.cuts.iter().any(|cut| cut.zone_of(name))
+    }
+
+    /// Returns the NS records of the zone cut that the name is at or below, with the
+    /// addresses of the nameservers that are within the zone as glue. The records below
+    /// a cut are only glue, so the cut closest to the apex is the one that applies.
+    pub fn delegation(&self, name: &Name) -> Option<(Vec<Record>, Vec<Record>)> {
+        let cut =
+            self.cuts.iter().filter(|cut| cut.zone_of(name)).min_by_key(|c| c.num_labels())?;
+        let ns: Vec<Record> = self.records[cut]
+            .iter()
+            .filter(|r| r.record_type() == RecordType::NS)
+            .cloned()
+            .collect();
+        let glue = ns
+            .iter()
+            .filter_map(|r| match r.data() {
+                Some(RData::NS(target)) => self.records.get(&target.0),
+                _ => None,
+            })
+            .flatten()
+            .filter(|r| matches!(r.record_type(), RecordType::A | RecordType::AAAA))
+            .cloned()
+            .collect();
+        Some((ns, glue))
+    }
+
+    /// Answers the query, RFC 1034 section 4.3.2. A CNAME is answered as it is, and a
+    /// name that doesn't exist is answered from the wildcard at its closest encloser, if
+    /// there is one. Returns None if the zone doesn't answer for the name.
+    pub fn lookup(&self, name: &Name, record_type: RecordType) -> Option<ZoneAnswer> {
+        if !self.answers_for(name) {
+            return None;
+        }
+        if let Some(records) = self.records.get(name) {
+            return Some(matching(records, record_type));
+        }
+        if self.names.contains(name) {
+            // an empty non-terminal, RFC 8020
+            return Some(ZoneAnswer::NoData);
+        }
+        let mut encloser = name.base_name();
+        while !self.names.contains(&encloser) {
+            encloser = encloser.base_name();
+        }
+        let wildcard = Name::from_ascii("*").ok()?.append_domain(&encloser).ok()?;
+        let Some(records) = self.records.get(&wildcard) else {
+            return Some(ZoneAnswer::NxDomain);
+        };
+        let answer = match matching(records, record_type) {
+            ZoneAnswer::Records(records) => ZoneAnswer::Records(
+                records
+                    .into_iter()
+                    .map(|mut r| {
+                        r.set_name(name.clone());
+                        r
+                    })
+                    .collect(),
+            ),
+            other => other,
+        };
+        Some(answer)
+    }
+}
+
+/// Returns the records of the type, or the CNAME, of a name that exists
+fn matching(records: &[Record], record_type: RecordType) -> ZoneAnswer {
+    let of_type = |t: RecordType| -> Vec<Record> {
+        records.iter().filter(|r| r.record_type() == t).cloned().collect()
+    };
+    let answers = match of_type(record_type) {
+        answers if answers.is_empty() => of_type(RecordType::CNAME),
+        answers => answers,
+    };
+    match answers.is_empty() {
+        true => ZoneAnswer::NoData,
+        false => ZoneAnswer::Records(answers),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::zone::{StaticZone, ZoneAnswer};
+    use crate::{a, name};
+    use anyhow::Result;
+    use hickory_proto::rr::{rdata, Name, RData, Record, RecordType};
+    use std::str::FromStr;
+
+    const ZONE: &str = "$ORIGIN lab.
+$TTL 60
+@       IN SOA ns.lab. hostmaster.lab. 1 3600 600 86400 60
+        IN NS  ns.lab.
+ns      IN A   10.0.0.53
+www     IN A   10.0.0.80
+*.apps  IN A   10.0.0.81
+a.b.c   IN A   10.0.0.82
+sub     IN NS  ns.sub.lab.
+ns.sub  IN A   10.0.0.54
+";
+
+    #[test]
+    fn test_lookup() -> Result<()> {
+        let zone = StaticZone::parse(ZONE, None)?;
+        assert_eq!(zone.apex(), &name!("lab."));
+        assert_eq!(
+            zone.lookup(&name!("www.lab."), RecordType::A),
+            Some(ZoneAnswer::Records(vec![a!("www.lab.", "10.0.0.80")]))
+        );
+        assert_eq!(
+            zone.lookup(&name!("WWW.lab."), RecordType::A),
+            Some(ZoneAnswer::Records(vec![a!("www.lab.", "10.0.0.80")]))
+        );
+        // the wildcard matches any name below apps.lab. that doesn't exist
+        assert_eq!(
+            zone.lookup(&name!("grafana.apps.lab."), RecordType::A),
+            Some(ZoneAnswer::Records(vec![a!("grafana.apps.lab.", "10.0.0.81")]))
+        );
+        assert_eq!(
+            zone.lookup(&name!("grafana.apps.lab."), RecordType::AAAA),
+            Some(ZoneAnswer::NoData)
+        );
+        assert_eq!(zone.lookup(&name!("www.lab."), RecordType::AAAA), Some(ZoneAnswer::NoData));
+        assert_eq!(zone.lookup(&name!("b.c.lab."), RecordType::A), Some(ZoneAnswer::NoData));
+        assert_eq!(zone.lookup(&name!("x.b.c.lab."), RecordType::A), Some(ZoneAnswer::NxDomain));
+        assert_eq!(zone.lookup(&name!("mail.lab."), RecordType::A), Some(ZoneAnswer::NxDomain));
+        // delegated and outside of the zone
+        assert_eq!(zone.lookup(&name!("www.sub.lab."), RecordType::A), None);
+        assert_eq!(zone.lookup(&name!("www.example."), RecordType::A), None);
+
+        assert!(StaticZone::parse("$ORIGIN lab.\nwww 60 IN A 10.0.0.80\n", None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_delegation() -> Result<()> {
+        let zone = StaticZone::parse(ZONE, None)?;
+        let (ns, glue) = zone.delegation(&name!("www.sub.lab.")).expect("sub.lab. is delegated");
+        assert_eq!(ns.len(), 1);
+        assert_eq!(ns[0].name(), &name!("sub.lab."));
+        assert_eq!(glue, [a!("ns.sub.lab.", "10.0.0.54")]);
+        assert_eq!(zone.delegation(&name!("www.lab.")), None);
+        Ok(())
+    }
+}